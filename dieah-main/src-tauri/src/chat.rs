@@ -1,8 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::time::Duration;
 
 use futures_util::{SinkExt, StreamExt};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Map, Value};
 use tauri::{AppHandle, Emitter, State};
 use tokio::sync::{mpsc, oneshot};
@@ -10,7 +10,8 @@ use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
 use url::Url;
 
-use crate::state::{AppState, GatewayCommand, GatewayHandle};
+use crate::settings::load_settings;
+use crate::state::{AppState, GatewayCommand, GatewayHandle, PendingSend, DEFAULT_GATEWAY_KEY};
 
 #[derive(Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -29,6 +30,48 @@ pub struct GatewayConnectOptions {
   pub locale: Option<String>,
 }
 
+/// Typed view of the `connect` response payload (the gateway "hello")
+///
+/// Every field is optional so older or newer gateways still deserialize; the
+/// raw payload is emitted unchanged on `gateway-hello`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayHello {
+  #[serde(default)]
+  pub protocol: Option<u32>,
+  #[serde(default)]
+  pub server: Option<GatewayServerInfo>,
+  #[serde(default)]
+  pub features: Option<GatewayFeatures>,
+  /// Session and presence state at connect time
+  #[serde(default)]
+  pub snapshot: Option<Value>,
+  #[serde(default)]
+  pub auth: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayServerInfo {
+  #[serde(default)]
+  pub version: Option<String>,
+  #[serde(default)]
+  pub commit: Option<String>,
+  #[serde(default)]
+  pub host: Option<String>,
+  #[serde(default)]
+  pub conn_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayFeatures {
+  #[serde(default)]
+  pub methods: Vec<String>,
+  #[serde(default)]
+  pub events: Vec<String>,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ChatHistoryPayload {
@@ -60,33 +103,47 @@ pub async fn gateway_connect(
   app: AppHandle,
   state: State<'_, AppState>,
   options: GatewayConnectOptions,
-) -> Result<Value, String> {
+  key: Option<String>,
+) -> Result<GatewayHello, String> {
+  let key = key.unwrap_or_else(|| DEFAULT_GATEWAY_KEY.to_string());
   let (tx, rx) = mpsc::channel(64);
   let (ready_tx, ready_rx) = oneshot::channel();
 
   {
     let mut guard = state.gateway.lock().await;
-    if let Some(existing) = guard.as_ref() {
+    if let Some(existing) = guard.get(&key) {
       if !existing.tx.is_closed() {
-        return Err("gateway already connected".to_string());
+        return Err(format!("gateway '{}' already connected", key));
       }
     }
-    *guard = Some(GatewayHandle { tx: tx.clone() });
+    guard.insert(key.clone(), GatewayHandle { tx: tx.clone() });
   }
 
-  tauri::async_runtime::spawn(gateway_task(app, options, rx, ready_tx));
+  let settings = load_settings();
+  let keepalive = GatewayKeepalive {
+    ping_interval: (settings.gateway_ping_interval_secs > 0)
+      .then(|| Duration::from_secs(settings.gateway_ping_interval_secs as u64)),
+    pong_timeout: Duration::from_secs(settings.gateway_pong_timeout_secs.max(1) as u64),
+  };
+  let flow = GatewayFlowControl {
+    max_in_flight: settings.gateway_max_in_flight.max(1) as usize,
+    max_queued: settings.gateway_max_queued as usize,
+  };
+
+  tauri::async_runtime::spawn(gateway_task(app, key, options, keepalive, flow, rx, ready_tx));
 
   match ready_rx.await {
-    Ok(Ok(payload)) => Ok(payload),
+    Ok(Ok(payload)) => serde_json::from_value(payload).map_err(|err| format!("invalid gateway hello: {}", err)),
     Ok(Err(err)) => Err(err),
     Err(_) => Err("gateway handshake failed".to_string()),
   }
 }
 
 #[tauri::command]
-pub async fn gateway_disconnect(state: State<'_, AppState>) -> Result<(), String> {
+pub async fn gateway_disconnect(state: State<'_, AppState>, key: Option<String>) -> Result<(), String> {
+  let key = key.unwrap_or_else(|| DEFAULT_GATEWAY_KEY.to_string());
   let mut guard = state.gateway.lock().await;
-  if let Some(handle) = guard.take() {
+  if let Some(handle) = guard.remove(&key) {
     let _ = handle.tx.send(GatewayCommand::Disconnect).await;
   }
   Ok(())
@@ -97,13 +154,25 @@ pub async fn gateway_request(
   state: State<'_, AppState>,
   method: String,
   params: Option<Value>,
+  key: Option<String>,
+) -> Result<Value, String> {
+  send_gateway_request(&state, method, params, key).await
+}
+
+/// Send a request on the connection named by `key` (the default one if `None`).
+async fn send_gateway_request(
+  state: &AppState,
+  method: String,
+  params: Option<Value>,
+  key: Option<String>,
 ) -> Result<Value, String> {
+  let key = key.unwrap_or_else(|| DEFAULT_GATEWAY_KEY.to_string());
   let handle = {
     let guard = state.gateway.lock().await;
     guard
-      .as_ref()
+      .get(&key)
       .cloned()
-      .ok_or_else(|| "gateway not connected".to_string())?
+      .ok_or_else(|| format!("gateway '{}' not connected", key))?
   };
 
   let (respond_to, response) = oneshot::channel();
@@ -126,6 +195,7 @@ pub async fn gateway_request(
 pub async fn chat_history(
   state: State<'_, AppState>,
   payload: ChatHistoryPayload,
+  key: Option<String>,
 ) -> Result<Value, String> {
   let mut params = Map::new();
   params.insert("sessionKey".to_string(), Value::String(payload.session_key));
@@ -135,18 +205,48 @@ pub async fn chat_history(
       Value::Number(serde_json::Number::from(limit as u64)),
     );
   }
-  gateway_request(
-    state,
+  send_gateway_request(
+    &state,
     "chat.history".to_string(),
     Some(Value::Object(params)),
+    key,
   )
   .await
 }
 
 #[tauri::command]
-pub async fn chat_send(state: State<'_, AppState>, payload: ChatSendPayload) -> Result<Value, String> {
+pub async fn chat_send(
+  state: State<'_, AppState>,
+  payload: ChatSendPayload,
+  key: Option<String>,
+) -> Result<Value, String> {
+  send_chat(&state, payload, key).await
+}
+
+async fn send_chat(state: &AppState, payload: ChatSendPayload, key: Option<String>) -> Result<Value, String> {
+  let session_key = payload.session_key;
+
+  // Reuse the key of an unacknowledged send of the same message, so a retry after a
+  // dropped connection is deduped by the gateway instead of delivered twice.
+  let idempotency_key = {
+    let mut pending_sends = state.pending_sends.lock().await;
+    let key = match (payload.idempotency_key, pending_sends.get(&session_key)) {
+      (Some(key), _) => key,
+      (None, Some(pending)) if pending.message == payload.message => pending.idempotency_key.clone(),
+      (None, _) => uuid::Uuid::new_v4().to_string(),
+    };
+    pending_sends.insert(
+      session_key.clone(),
+      PendingSend {
+        message: payload.message.clone(),
+        idempotency_key: key.clone(),
+      },
+    );
+    key
+  };
+
   let mut params = Map::new();
-  params.insert("sessionKey".to_string(), Value::String(payload.session_key));
+  params.insert("sessionKey".to_string(), Value::String(session_key.clone()));
   params.insert("message".to_string(), Value::String(payload.message));
   if let Some(thinking) = payload.thinking {
     params.insert("thinking".to_string(), Value::String(thinking));
@@ -163,46 +263,127 @@ pub async fn chat_send(state: State<'_, AppState>, payload: ChatSendPayload) ->
       Value::Number(serde_json::Number::from(timeout_ms)),
     );
   }
-  let idempotency_key = payload
-    .idempotency_key
-    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
-  params.insert("idempotencyKey".to_string(), Value::String(idempotency_key));
+  params.insert("idempotencyKey".to_string(), Value::String(idempotency_key.clone()));
 
-  gateway_request(
+  let result = send_gateway_request(
     state,
     "chat.send".to_string(),
     Some(Value::Object(params)),
+    key,
   )
-  .await
+  .await;
+
+  if result.is_ok() {
+    let mut pending_sends = state.pending_sends.lock().await;
+    if pending_sends
+      .get(&session_key)
+      .is_some_and(|pending| pending.idempotency_key == idempotency_key)
+    {
+      pending_sends.remove(&session_key);
+    }
+  }
+
+  result
 }
 
 #[tauri::command]
 pub async fn chat_abort(
   state: State<'_, AppState>,
   payload: ChatAbortPayload,
+  key: Option<String>,
 ) -> Result<Value, String> {
   let mut params = Map::new();
   params.insert("sessionKey".to_string(), Value::String(payload.session_key));
   if let Some(run_id) = payload.run_id {
     params.insert("runId".to_string(), Value::String(run_id));
   }
-  gateway_request(
-    state,
+  send_gateway_request(
+    &state,
     "chat.abort".to_string(),
     Some(Value::Object(params)),
+    key,
   )
   .await
 }
 
+/// WebSocket ping cadence; `ping_interval` of `None` disables pings.
+///
+/// A ping not answered within `pong_timeout` marks the socket dead: the task
+/// emits `gateway-status` `stale` and closes, and the frontend reconnects once it
+/// sees the `disconnected` that follows.
+struct GatewayKeepalive {
+  ping_interval: Option<Duration>,
+  pong_timeout: Duration,
+}
+
+/// Limits on outstanding gateway requests.
+///
+/// At most `max_in_flight` requests await a response; further requests wait in a
+/// FIFO queue (so they go out in the order they were issued) and are rejected
+/// once `max_queued` are waiting.
+struct GatewayFlowControl {
+  max_in_flight: usize,
+  max_queued: usize,
+}
+
+/// Requests waiting for an in-flight slot, released in the order they arrived.
+struct GatewayQueue<T> {
+  flow: GatewayFlowControl,
+  waiting: VecDeque<T>,
+}
+
+impl<T> GatewayQueue<T> {
+  fn new(flow: GatewayFlowControl) -> Self {
+    Self { flow, waiting: VecDeque::new() }
+  }
+
+  /// Take a request, or hand it back if the queue is full. The queue bound only
+  /// applies once every in-flight slot is taken, so `max_queued = 0` means
+  /// "no waiting", not "no requests".
+  fn push(&mut self, in_flight: usize, request: T) -> Result<(), T> {
+    if in_flight >= self.flow.max_in_flight && self.waiting.len() >= self.flow.max_queued {
+      return Err(request);
+    }
+    self.waiting.push_back(request);
+    Ok(())
+  }
+
+  /// The next request to send, if an in-flight slot is free.
+  fn pop_ready(&mut self, in_flight: usize) -> Option<T> {
+    if in_flight >= self.flow.max_in_flight {
+      return None;
+    }
+    self.waiting.pop_front()
+  }
+
+  fn drain(&mut self) -> std::collections::vec_deque::Drain<'_, T> {
+    self.waiting.drain(..)
+  }
+}
+
+/// Where a gateway task reports its status and forwards gateway events.
+trait GatewayEvents: Send + Sync + 'static {
+  fn emit(&self, event: &str, payload: Value) -> tauri::Result<()>;
+}
+
+impl GatewayEvents for AppHandle {
+  fn emit(&self, event: &str, payload: Value) -> tauri::Result<()> {
+    Emitter::emit(self, event, payload)
+  }
+}
+
 async fn gateway_task(
-  app: AppHandle,
+  app: impl GatewayEvents,
+  key: String,
   options: GatewayConnectOptions,
+  keepalive: GatewayKeepalive,
+  flow: GatewayFlowControl,
   mut rx: mpsc::Receiver<GatewayCommand>,
   ready_tx: oneshot::Sender<Result<Value, String>>,
 ) {
   let _ = app.emit(
     "gateway-status",
-    json!({ "status": "connecting", "url": options.url }),
+    json!({ "key": key, "status": "connecting", "url": options.url }),
   );
 
   let url = match Url::parse(&options.url) {
@@ -211,7 +392,7 @@ async fn gateway_task(
       let _ = ready_tx.send(Err(format!("invalid gateway url: {}", err)));
       let _ = app.emit(
         "gateway-status",
-        json!({ "status": "error", "reason": "invalid url" }),
+        json!({ "key": key, "status": "error", "reason": "invalid url" }),
       );
       return;
     }
@@ -225,7 +406,7 @@ async fn gateway_task(
       let _ = ready_tx.send(Err(format!("failed to connect: {}", err)));
       let _ = app.emit(
         "gateway-status",
-        json!({ "status": "error", "reason": "connect failed" }),
+        json!({ "key": key, "status": "error", "reason": "connect failed" }),
       );
       return;
     }
@@ -236,32 +417,46 @@ async fn gateway_task(
   let mut connect_sent = false;
   let mut connect_request_id: Option<String> = None;
   let mut ready_tx = Some(ready_tx);
-  let connect_timer = tokio::time::sleep(Duration::from_millis(750));
-  tokio::pin!(connect_timer);
+  let mut queued: GatewayQueue<(String, Option<Value>, oneshot::Sender<Result<Value, String>>)> =
+    GatewayQueue::new(flow);
+
+  let ping_enabled = keepalive.ping_interval.is_some();
+  let ping_period = keepalive.ping_interval.unwrap_or(keepalive.pong_timeout);
+  let mut ping_timer = tokio::time::interval_at(tokio::time::Instant::now() + ping_period, ping_period);
+  ping_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+  let mut awaiting_pong = false;
+  let pong_deadline = tokio::time::sleep(keepalive.pong_timeout);
+  tokio::pin!(pong_deadline);
 
-  let _ = app.emit("gateway-status", json!({ "status": "open" }));
+  let _ = app.emit("gateway-status", json!({ "key": key, "status": "open" }));
+
+  // Send connect as soon as the socket is open; a later challenge is ignored once sent.
+  if let Some(id) = send_connect(&mut write, &connect_params, &mut pending, ready_tx.take()).await {
+    connect_sent = true;
+    connect_request_id = Some(id);
+  }
 
   loop {
     tokio::select! {
-      _ = &mut connect_timer, if !connect_sent => {
-        if let Some(id) = send_connect(&mut write, &connect_params, &mut pending, ready_tx.take()).await {
-          connect_sent = true;
-          connect_request_id = Some(id);
+      _ = ping_timer.tick(), if ping_enabled && !awaiting_pong => {
+        if let Err(err) = write.send(Message::Ping(Vec::new())).await {
+          let _ = app.emit("gateway-status", json!({ "key": key, "status": "error", "reason": format!("ping failed: {}", err) }));
+          break;
         }
+        awaiting_pong = true;
+        pong_deadline.as_mut().reset(tokio::time::Instant::now() + keepalive.pong_timeout);
+      }
+      _ = &mut pong_deadline, if awaiting_pong => {
+        // Not an error the user has to fix: the frontend reconnects after `disconnected`
+        let _ = app.emit("gateway-status", json!({ "key": key, "status": "stale", "reason": "pong timeout" }));
+        let _ = write.send(Message::Close(None)).await;
+        break;
       }
       Some(cmd) = rx.recv() => {
         match cmd {
           GatewayCommand::Request { method, params, respond_to } => {
-            let is_connect = method == "connect";
-            if is_connect && connect_sent {
-              let _ = respond_to.send(Err("gateway connect already in progress".to_string()));
-              continue;
-            }
-            if let Some(id) = send_request(&mut write, method, params, &mut pending, respond_to).await {
-              if connect_request_id.is_none() && is_connect {
-                connect_request_id = Some(id);
-                connect_sent = true;
-              }
+            if let Err((_, _, respond_to)) = queued.push(pending.len(), (method, params, respond_to)) {
+              let _ = respond_to.send(Err("gateway request queue full".to_string()));
             }
           }
           GatewayCommand::Disconnect => {
@@ -275,14 +470,17 @@ async fn gateway_task(
           Ok(Message::Text(text)) => {
             handle_incoming(&app, &text, &mut pending, &connect_params, &mut connect_sent, &mut connect_request_id, &mut write, &mut ready_tx).await;
           }
+          Ok(Message::Pong(_)) => {
+            awaiting_pong = false;
+          }
           Ok(Message::Close(frame)) => {
             let reason = frame.as_ref().map(|f| f.reason.to_string()).unwrap_or_default();
-            let _ = app.emit("gateway-status", json!({ "status": "closed", "reason": reason }));
+            let _ = app.emit("gateway-status", json!({ "key": key, "status": "closed", "reason": reason }));
             break;
           }
           Ok(_) => {}
           Err(err) => {
-            let _ = app.emit("gateway-status", json!({ "status": "error", "reason": err.to_string() }));
+            let _ = app.emit("gateway-status", json!({ "key": key, "status": "error", "reason": err.to_string() }));
             break;
           }
         }
@@ -291,20 +489,41 @@ async fn gateway_task(
         break;
       }
     }
+
+    // Send queued requests in order while in-flight slots are free
+    while let Some((method, params, respond_to)) = queued.pop_ready(pending.len()) {
+      let is_connect = method == "connect";
+      if is_connect && connect_sent {
+        let _ = respond_to.send(Err("gateway connect already in progress".to_string()));
+        continue;
+      }
+      if let Some(id) = send_request(&mut write, method, params, &mut pending, respond_to).await {
+        if connect_request_id.is_none() && is_connect {
+          connect_request_id = Some(id);
+          connect_sent = true;
+        }
+      }
+    }
   }
 
   for (_, sender) in pending.drain() {
     let _ = sender.send(Err("gateway disconnected".to_string()));
   }
+  for (_, _, respond_to) in queued.drain() {
+    let _ = respond_to.send(Err("gateway disconnected".to_string()));
+  }
+  // Close the command channel first, so a reconnect prompted by `disconnected`
+  // doesn't find this connection still registered.
+  drop(rx);
 
-  let _ = app.emit("gateway-status", json!({ "status": "disconnected" }));
+  let _ = app.emit("gateway-status", json!({ "key": key, "status": "disconnected" }));
   if let Some(ready_tx) = ready_tx {
     let _ = ready_tx.send(Err("gateway disconnected before handshake".to_string()));
   }
 }
 
 async fn handle_incoming(
-  app: &AppHandle,
+  app: &impl GatewayEvents,
   text: &str,
   pending: &mut HashMap<String, oneshot::Sender<Result<Value, String>>>,
   connect_params: &Value,
@@ -548,3 +767,329 @@ fn build_connect_params(options: &GatewayConnectOptions) -> Value {
   );
   params_json
 }
+
+#[cfg(test)]
+mod tests {
+  use std::future::Future;
+
+  use tokio::net::{TcpListener, TcpStream};
+  use tokio::time::Instant;
+
+  use super::*;
+
+  impl GatewayEvents for mpsc::UnboundedSender<(String, Value)> {
+    fn emit(&self, event: &str, payload: Value) -> tauri::Result<()> {
+      let _ = self.send((event.to_string(), payload));
+      Ok(())
+    }
+  }
+
+  /// Serve one WebSocket connection on a local port with `serve`, returning its URL.
+  async fn stub_gateway<F, Fut>(serve: F) -> String
+  where
+    F: FnOnce(WebSocketStream<TcpStream>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+  {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+      let (stream, _) = listener.accept().await.unwrap();
+      serve(tokio_tungstenite::accept_async(stream).await.unwrap()).await;
+    });
+    url
+  }
+
+  /// A gateway task under test and what it reports.
+  struct TestGateway {
+    tx: mpsc::Sender<GatewayCommand>,
+    events: mpsc::UnboundedReceiver<(String, Value)>,
+    ready: oneshot::Receiver<Result<Value, String>>,
+  }
+
+  impl TestGateway {
+    fn spawn(url: String, keepalive: GatewayKeepalive) -> Self {
+      let (tx, rx) = mpsc::channel(8);
+      let (events_tx, events) = mpsc::unbounded_channel();
+      let (ready_tx, ready) = oneshot::channel();
+      let options = serde_json::from_value(json!({ "url": url })).unwrap();
+      let flow = GatewayFlowControl { max_in_flight: 8, max_queued: 8 };
+      tokio::spawn(gateway_task(events_tx, "test".to_string(), options, keepalive, flow, rx, ready_tx));
+      Self { tx, events, ready }
+    }
+
+    async fn request(&self, method: &str) -> Result<Value, String> {
+      let (respond_to, response) = oneshot::channel();
+      self
+        .tx
+        .send(GatewayCommand::Request { method: method.to_string(), params: None, respond_to })
+        .await
+        .unwrap();
+      response.await.unwrap()
+    }
+
+    /// The next `gateway-status` status, skipping other events.
+    async fn next_status(&mut self) -> String {
+      loop {
+        let (event, payload) = tokio::time::timeout(Duration::from_secs(5), self.events.recv())
+          .await
+          .expect("gateway task went quiet")
+          .expect("gateway task ended");
+        if event == "gateway-status" {
+          return payload["status"].as_str().unwrap_or_default().to_string();
+        }
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn pings_follow_the_interval_while_pongs_arrive() {
+    let (pings_tx, mut pings) = mpsc::unbounded_channel();
+    let url = stub_gateway(|mut ws| async move {
+      // Reading is what answers pings
+      while let Some(Ok(msg)) = ws.next().await {
+        if msg.is_ping() {
+          let _ = pings_tx.send(Instant::now());
+        }
+      }
+    })
+    .await;
+
+    let interval = Duration::from_millis(50);
+    let started = Instant::now();
+    let mut gateway = TestGateway::spawn(
+      url,
+      GatewayKeepalive { ping_interval: Some(interval), pong_timeout: Duration::from_secs(2) },
+    );
+    assert_eq!(gateway.next_status().await, "connecting");
+    assert_eq!(gateway.next_status().await, "open");
+
+    let mut last = started;
+    for _ in 0..4 {
+      let at = tokio::time::timeout(Duration::from_secs(5), pings.recv()).await.unwrap().unwrap();
+      // No ping goes out before its interval has passed
+      assert!(at - last >= interval - Duration::from_millis(10), "ping after {:?}", at - last);
+      last = at;
+    }
+
+    // Answered pings never trip the timeout
+    assert!(gateway.events.try_recv().is_err());
+    gateway.tx.send(GatewayCommand::Disconnect).await.unwrap();
+    assert_eq!(gateway.next_status().await, "disconnected");
+  }
+
+  #[tokio::test]
+  async fn unanswered_ping_marks_the_gateway_stale_and_closes_it() {
+    let url = stub_gateway(|ws| async move {
+      // Hold the socket without reading, so no pong is ever sent
+      let _ws = ws;
+      std::future::pending::<()>().await;
+    })
+    .await;
+
+    let mut gateway = TestGateway::spawn(
+      url,
+      GatewayKeepalive {
+        ping_interval: Some(Duration::from_millis(50)),
+        pong_timeout: Duration::from_millis(100),
+      },
+    );
+    assert_eq!(gateway.next_status().await, "connecting");
+    assert_eq!(gateway.next_status().await, "open");
+    assert_eq!(gateway.next_status().await, "stale");
+    assert_eq!(gateway.next_status().await, "disconnected");
+
+    // The connection is unregistered by then, so the frontend's reconnect can take its key
+    assert!(gateway.tx.is_closed());
+  }
+
+  fn queue(max_in_flight: usize, max_queued: usize) -> GatewayQueue<u32> {
+    GatewayQueue::new(GatewayFlowControl { max_in_flight, max_queued })
+  }
+
+  #[test]
+  fn saturated_queue_applies_backpressure_in_order() {
+    let mut queue = queue(2, 2);
+    let mut in_flight = 0;
+
+    for request in 0..4 {
+      queue.push(in_flight, request).unwrap();
+      while queue.pop_ready(in_flight).is_some() {
+        in_flight += 1;
+      }
+    }
+    assert_eq!(in_flight, 2);
+
+    // Slots and queue are both full: the next request is handed back
+    assert_eq!(queue.push(in_flight, 4), Err(4));
+
+    // A response frees a slot for the oldest waiting request only
+    in_flight -= 1;
+    assert_eq!(queue.pop_ready(in_flight), Some(2));
+    in_flight += 1;
+    assert_eq!(queue.pop_ready(in_flight), None);
+
+    queue.push(in_flight, 5).unwrap();
+    assert_eq!(queue.drain().collect::<Vec<_>>(), [3, 5]);
+  }
+
+  #[test]
+  fn zero_queue_still_sends_while_slots_are_free() {
+    let mut queue = queue(1, 0);
+    queue.push(0, 1).unwrap();
+    assert_eq!(queue.pop_ready(0), Some(1));
+    assert_eq!(queue.push(1, 2), Err(2));
+  }
+
+  /// Answer every request with `ok`, reporting each method as it arrives.
+  async fn answer_requests(ws: &mut WebSocketStream<TcpStream>, methods: &mpsc::UnboundedSender<String>) {
+    while let Some(Ok(Message::Text(text))) = ws.next().await {
+      let frame: Value = serde_json::from_str(&text).unwrap();
+      let _ = methods.send(frame["method"].as_str().unwrap_or_default().to_string());
+      let res = json!({ "type": "res", "id": frame["id"], "ok": true, "payload": { "protocol": 3 } });
+      ws.send(Message::Text(res.to_string())).await.unwrap();
+    }
+  }
+
+  #[tokio::test]
+  async fn immediate_challenge_gets_exactly_one_connect() {
+    let (methods_tx, mut methods) = mpsc::unbounded_channel();
+    let url = stub_gateway(|mut ws| async move {
+      let challenge = json!({ "type": "event", "event": "connect.challenge", "payload": { "nonce": "n" } });
+      ws.send(Message::Text(challenge.to_string())).await.unwrap();
+      answer_requests(&mut ws, &methods_tx).await;
+    })
+    .await;
+
+    let mut gateway = TestGateway::spawn(
+      url,
+      GatewayKeepalive { ping_interval: None, pong_timeout: Duration::from_secs(1) },
+    );
+    let hello = tokio::time::timeout(Duration::from_secs(5), &mut gateway.ready).await.unwrap().unwrap();
+    assert_eq!(hello.unwrap()["protocol"], 3);
+
+    // The challenge reached the task before the hello did, so a second connect
+    // would be on the wire ahead of this request
+    gateway.request("status").await.unwrap();
+    let mut seen = Vec::new();
+    while let Ok(method) = methods.try_recv() {
+      seen.push(method);
+    }
+    assert_eq!(seen, ["connect", "status"]);
+  }
+
+  #[test]
+  fn hello_deserializes_known_fields_and_tolerates_the_rest() {
+    let hello: GatewayHello = serde_json::from_value(json!({
+      "type": "hello-ok",
+      "protocol": 3,
+      "server": { "version": "2026.1.4", "commit": "abc123", "host": "box", "connId": "c-1" },
+      "features": { "methods": ["chat.send", "chat.history"], "events": ["chat"] },
+      "snapshot": { "presence": [] },
+      "policy": { "maxPayload": 1048576 }
+    }))
+    .unwrap();
+
+    assert_eq!(hello.protocol, Some(3));
+    let server = hello.server.unwrap();
+    assert_eq!(server.version.as_deref(), Some("2026.1.4"));
+    assert_eq!(server.conn_id.as_deref(), Some("c-1"));
+    assert_eq!(hello.features.unwrap().methods, ["chat.send", "chat.history"]);
+    assert_eq!(hello.snapshot, Some(json!({ "presence": [] })));
+    assert!(hello.auth.is_none());
+
+    // An older gateway's bare payload still parses
+    let bare: GatewayHello = serde_json::from_value(json!({})).unwrap();
+    assert!(bare.protocol.is_none() && bare.server.is_none());
+  }
+
+  /// Register a connection under `key` whose requests are answered by `answer`.
+  async fn fake_connection<F>(state: &AppState, key: &str, mut answer: F)
+  where
+    F: FnMut(String, Option<Value>) -> Result<Value, String> + Send + 'static,
+  {
+    let (tx, mut rx) = mpsc::channel(8);
+    state.gateway.lock().await.insert(key.to_string(), GatewayHandle { tx });
+    tokio::spawn(async move {
+      while let Some(cmd) = rx.recv().await {
+        if let GatewayCommand::Request { method, params, respond_to } = cmd {
+          let _ = respond_to.send(answer(method, params));
+        }
+      }
+    });
+  }
+
+  /// A connection that answers with its own key and the method called.
+  async fn echo_connection(state: &AppState, key: &str) {
+    let name = key.to_string();
+    fake_connection(state, key, move |method, _| Ok(json!({ "key": name, "method": method }))).await;
+  }
+
+  fn chat_payload(session_key: &str, message: &str) -> ChatSendPayload {
+    ChatSendPayload {
+      session_key: session_key.to_string(),
+      message: message.to_string(),
+      thinking: None,
+      deliver: None,
+      attachments: None,
+      timeout_ms: None,
+      idempotency_key: None,
+    }
+  }
+
+  #[tokio::test]
+  async fn requests_go_to_the_connection_named_by_key() {
+    let state = AppState::default();
+    echo_connection(&state, "a").await;
+    echo_connection(&state, "b").await;
+
+    let a = send_gateway_request(&state, "status".to_string(), None, Some("a".to_string())).await;
+    assert_eq!(a.unwrap(), json!({ "key": "a", "method": "status" }));
+    let b = send_chat(&state, chat_payload("s1", "hi"), Some("b".to_string())).await;
+    assert_eq!(b.unwrap(), json!({ "key": "b", "method": "chat.send" }));
+
+    // No key means the default connection, which isn't open here
+    let default = send_gateway_request(&state, "status".to_string(), None, None).await;
+    assert_eq!(default.unwrap_err(), "gateway 'default' not connected");
+  }
+
+  #[tokio::test]
+  async fn a_closed_connection_leaves_the_other_key_working() {
+    let state = AppState::default();
+    echo_connection(&state, "b").await;
+    let (tx, rx) = mpsc::channel(1);
+    state.gateway.lock().await.insert("a".to_string(), GatewayHandle { tx });
+    drop(rx);
+
+    let a = send_chat(&state, chat_payload("s1", "hi"), Some("a".to_string())).await;
+    assert_eq!(a.unwrap_err(), "gateway request channel closed");
+    let b = send_chat(&state, chat_payload("s1", "hi"), Some("b".to_string())).await;
+    assert_eq!(b.unwrap()["key"], "b");
+  }
+
+  #[tokio::test]
+  async fn retry_after_a_disconnect_reuses_the_idempotency_key() {
+    let state = AppState::default();
+    let (keys_tx, mut keys) = mpsc::unbounded_channel();
+    let mut calls = 0;
+    fake_connection(&state, DEFAULT_GATEWAY_KEY, move |_, params| {
+      let _ = keys_tx.send(params.unwrap()["idempotencyKey"].as_str().unwrap().to_string());
+      calls += 1;
+      if calls == 1 {
+        Err("gateway disconnected".to_string())
+      } else {
+        Ok(json!({ "ok": true }))
+      }
+    })
+    .await;
+
+    assert!(send_chat(&state, chat_payload("s1", "hi"), None).await.is_err());
+    assert!(send_chat(&state, chat_payload("s1", "hi"), None).await.is_ok());
+    let first = keys.recv().await.unwrap();
+    assert_eq!(keys.recv().await.unwrap(), first);
+    assert!(state.pending_sends.lock().await.is_empty());
+
+    // Once acknowledged, sending the same text again is a new message
+    send_chat(&state, chat_payload("s1", "hi"), None).await.unwrap();
+    assert_ne!(keys.recv().await.unwrap(), first);
+  }
+}
@@ -32,10 +32,14 @@ fn default_settings() -> AppSettings {
     memory_enabled: true,
     memory_url: Some("http://127.0.0.1:8420".to_string()),
     memory_max_recent_messages: 10,
+    gateway_ping_interval_secs: 30,
+    gateway_pong_timeout_secs: 10,
+    gateway_max_in_flight: 32,
+    gateway_max_queued: 256,
   }
 }
 
-fn load_settings() -> AppSettings {
+pub(crate) fn load_settings() -> AppSettings {
   let path = settings_path();
   if let Ok(data) = fs::read_to_string(&path) {
     if let Ok(settings) = serde_json::from_str::<AppSettings>(&data) {
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde_json::Value;
 use tokio::sync::{mpsc, oneshot, Mutex};
 
@@ -15,14 +17,27 @@ pub enum GatewayCommand {
   Disconnect,
 }
 
+/// Connection key used when a command doesn't name one
+pub const DEFAULT_GATEWAY_KEY: &str = "default";
+
+/// A chat send that hasn't been acknowledged yet
+pub struct PendingSend {
+  pub message: String,
+  pub idempotency_key: String,
+}
+
 pub struct AppState {
-  pub gateway: Mutex<Option<GatewayHandle>>,
+  /// Open gateway connections, keyed by connection key
+  pub gateway: Mutex<HashMap<String, GatewayHandle>>,
+  /// Unacknowledged chat sends by session key, so a retry reuses the same idempotency key
+  pub pending_sends: Mutex<HashMap<String, PendingSend>>,
 }
 
 impl Default for AppState {
   fn default() -> Self {
     Self {
-      gateway: Mutex::new(None),
+      gateway: Mutex::new(HashMap::new()),
+      pending_sends: Mutex::new(HashMap::new()),
     }
   }
 }
@@ -36,6 +36,30 @@ pub struct AppSettings {
   pub memory_url: Option<String>,
   #[serde(default)]
   pub memory_max_recent_messages: u32,
+  #[serde(default = "default_gateway_ping_interval_secs")]
+  pub gateway_ping_interval_secs: u32,
+  #[serde(default = "default_gateway_pong_timeout_secs")]
+  pub gateway_pong_timeout_secs: u32,
+  #[serde(default = "default_gateway_max_in_flight")]
+  pub gateway_max_in_flight: u32,
+  #[serde(default = "default_gateway_max_queued")]
+  pub gateway_max_queued: u32,
+}
+
+fn default_gateway_ping_interval_secs() -> u32 {
+  30
+}
+
+fn default_gateway_pong_timeout_secs() -> u32 {
+  10
+}
+
+fn default_gateway_max_in_flight() -> u32 {
+  32
+}
+
+fn default_gateway_max_queued() -> u32 {
+  256
 }
 
 #[derive(Serialize)]
@@ -0,0 +1,69 @@
+//! Append-only audit trail of mutating operations
+//!
+//! Separate from conversation history: one JSON line per memory create,
+//! update or delete and per admin operation, for compliance review.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+
+/// One audited operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    /// Operation name, e.g. `delete_memory` or `maintenance`
+    pub operation: String,
+    /// What the operation acted on (memory id, `agent/topic`), if anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Caller identity from the request, when one was supplied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caller: Option<String>,
+}
+
+/// Audit log writer; entries are flushed and synced before `record` returns
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open (or create) the audit log at `path` for appending
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Path of the log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an entry for `operation`
+    pub fn record(&self, operation: &str, target: Option<&str>, caller: Option<&str>) -> Result<()> {
+        let entry = AuditEntry {
+            timestamp: Utc::now(),
+            operation: operation.to_string(),
+            target: target.map(str::to_string),
+            caller: caller.map(str::to_string),
+        };
+        let line = serde_json::to_string(&entry)?;
+
+        let mut file = self.file.lock().map_err(|e| Error::storage(e.to_string()))?;
+        writeln!(file, "{}", line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+//! Dieah Memory CLI
+//!
+//! Command-line access to the memory store without running the HTTP server.
+
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use uuid::Uuid;
+
+use dieah_memory::{
+    config::Config,
+    memory::{Memory, MemoryScope, MemoryStore, MemoryType},
+    retrieval::RetrievalEngine,
+};
+
+#[derive(Debug, Parser)]
+#[command(name = "dieah-memory-cli", about = "Manage the Dieah memory store")]
+struct Cli {
+    /// Override the data directory
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Create a memory
+    Add {
+        /// Memory content
+        content: String,
+        /// Scope: global, agent, topic, personal
+        #[arg(long, default_value = "global")]
+        scope: String,
+        /// Type: correction, preference, fact, workflow, constraint
+        #[arg(long = "type", default_value = "fact")]
+        memory_type: String,
+        #[arg(long)]
+        agent_id: Option<String>,
+        #[arg(long)]
+        topic_id: Option<String>,
+        #[arg(long)]
+        context: Option<String>,
+        /// Comma-separated tags
+        #[arg(long, value_delimiter = ',')]
+        tags: Vec<String>,
+    },
+    /// Search memories semantically
+    Search {
+        query: String,
+        #[arg(long)]
+        agent_id: Option<String>,
+    },
+    /// List memories
+    List {
+        #[arg(long)]
+        scope: Option<String>,
+        #[arg(long)]
+        agent_id: Option<String>,
+        #[arg(long)]
+        topic_id: Option<String>,
+        /// Include inactive memories
+        #[arg(long)]
+        all: bool,
+    },
+    /// Delete a memory by ID
+    Delete { id: String },
+    /// Print store statistics
+    Stats,
+    /// Re-embed every memory and rewrite the vector store
+    Reindex {
+        /// Only handle memories whose stored vector has a different dimension
+        /// than the current model (see `Config.reembed_mismatched_vectors`)
+        #[arg(long)]
+        mismatched_only: bool,
+    },
+    /// Embed memories that were stored without an embedding
+    EmbedPending,
+    /// Make the store match a file of curated memories (see the README)
+    Sync { path: PathBuf },
+    /// Export memories as JSON
+    Export {
+        /// Output file (defaults to stdout)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Export an agent's record, conversations and memories as one bundle
+    ExportAgent {
+        agent_id: String,
+        /// Output file (defaults to stdout)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Restore an agent from a bundle written by `export-agent`
+    ImportAgent { path: PathBuf },
+    /// Write token counts into the logs for messages stored without one
+    RecountTokens,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let config = match cli.data_dir {
+        Some(dir) => Config::with_data_dir(dir),
+        None => Config::default(),
+    };
+
+    // Only load the embedding model for commands that embed; when loaded, it
+    // settles the embedding dimension the store is opened with
+    let needs_model = matches!(
+        cli.command,
+        Command::Add { .. }
+            | Command::Search { .. }
+            | Command::Reindex { .. }
+            | Command::EmbedPending
+            | Command::Sync { .. }
+    );
+    let retrieval = needs_model.then(|| RetrievalEngine::new(config.clone())).transpose()?;
+    let config = retrieval.as_ref().map_or(config, |r| r.config().clone());
+    let store = MemoryStore::new(config.clone()).await?;
+    let engine = || retrieval.as_ref().ok_or_else(|| anyhow::anyhow!("embedding model not loaded"));
+
+    match cli.command {
+        Command::Add {
+            content,
+            scope,
+            memory_type,
+            agent_id,
+            topic_id,
+            context,
+            tags,
+        } => {
+            let scope: MemoryScope = scope.parse()?;
+            let memory_type: MemoryType = memory_type.parse()?;
+
+            let mut memory = match scope {
+                MemoryScope::Global => Memory::global(memory_type, content),
+                MemoryScope::Personal => Memory::personal(memory_type, content),
+                MemoryScope::Agent => {
+                    let agent_id = agent_id.ok_or_else(|| anyhow::anyhow!("--agent-id is required"))?;
+                    Memory::for_agent(agent_id, memory_type, content)
+                }
+                MemoryScope::Topic => {
+                    let agent_id = agent_id.ok_or_else(|| anyhow::anyhow!("--agent-id is required"))?;
+                    let topic_id = topic_id.ok_or_else(|| anyhow::anyhow!("--topic-id is required"))?;
+                    Memory::for_topic(agent_id, topic_id, memory_type, content)
+                }
+            };
+
+            if let Some(context) = context {
+                memory = memory.with_context(context);
+            }
+            if !tags.is_empty() {
+                memory = memory.with_tags(tags);
+            }
+
+            let retrieval = engine()?;
+            let memory = retrieval.embed_and_save(&store, memory).await?;
+            println!("{}", memory.id);
+        }
+        Command::Search { query, agent_id } => {
+            let retrieval = engine()?;
+            let context = retrieval
+                .retrieve(&store, &query, agent_id.as_deref(), None, 0, None)
+                .await?;
+
+            for memory in &context.memories {
+                println!(
+                    "{:.3}\t{}\t{}\t{}\t{}",
+                    memory.score, memory.id, memory.scope, memory.memory_type, memory.content
+                );
+            }
+        }
+        Command::List {
+            scope,
+            agent_id,
+            topic_id,
+            all,
+        } => {
+            let scope = scope.as_deref().map(MemoryScope::try_from).transpose()?;
+            let memories = store.list_memories(scope, agent_id.as_deref(), topic_id.as_deref(), None, !all)?;
+
+            for memory in &memories {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    memory.id,
+                    memory.scope,
+                    memory.memory_type,
+                    if memory.active { "active" } else { "inactive" },
+                    memory.content
+                );
+            }
+        }
+        Command::Delete { id } => {
+            let id = Uuid::parse_str(&id)?;
+            if store.get_memory(id)?.is_none() {
+                anyhow::bail!("memory {} not found", id);
+            }
+            store.delete_memory(id).await?;
+            println!("deleted {}", id);
+        }
+        Command::Stats => {
+            let memories = store.list_memories(None, None, None, None, false)?;
+            let active = memories.iter().filter(|m| m.active).count();
+
+            println!("data_dir\t{}", config.data_dir.display());
+            println!("memories\t{}", memories.len());
+            println!("active\t{}", active);
+            for scope in [
+                MemoryScope::Global,
+                MemoryScope::Agent,
+                MemoryScope::Topic,
+                MemoryScope::Personal,
+            ] {
+                let count = memories.iter().filter(|m| m.scope == scope).count();
+                println!("scope.{}\t{}", scope, count);
+            }
+
+            let agents = store.jsonl().list_agents()?;
+            let mut topics = 0;
+            for agent in &agents {
+                topics += store.jsonl().list_topics(agent)?.len();
+            }
+            println!("agents\t{}", agents.len());
+            println!("topics\t{}", topics);
+        }
+        Command::Reindex { mismatched_only: true } => {
+            let report = store.migrate_mismatched_vectors(engine()?).await?;
+
+            println!("checked\t{}", report.checked);
+            println!("mismatched\t{}", report.mismatched.len());
+            println!("reembedded\t{}", report.reembedded);
+            for (id, error) in &report.failed {
+                println!("failed\t{}\t{}", id, error);
+            }
+            if !config.reembed_mismatched_vectors {
+                for id in &report.mismatched {
+                    println!("skipped\t{}", id);
+                }
+            }
+        }
+        Command::Reindex { mismatched_only: false } => {
+            let retrieval = engine()?;
+            let memories = store.list_memories(None, None, None, None, false)?;
+            let total = memories.len();
+
+            // Always re-embed, even if content is unchanged since the last save
+            for memory in memories {
+                store.reembed_memory(memory.id, retrieval).await?;
+            }
+            println!("reindexed {} memories", total);
+        }
+        Command::Sync { path } => {
+            let report = store.sync_from_file(&path, engine()?).await?;
+
+            println!("created\t{}", report.created);
+            println!("updated\t{}", report.updated);
+            println!("unchanged\t{}", report.unchanged);
+            println!("deactivated\t{}", report.deactivated);
+        }
+        Command::EmbedPending => {
+            let retrieval = engine()?;
+            let memories = store.list_unembedded()?;
+            let total = memories.len();
+
+            for memory in memories {
+                store.reembed_memory(memory.id, retrieval).await?;
+            }
+            println!("embedded {} memories", total);
+        }
+        Command::Export { out } => {
+            let memories = store.list_memories(None, None, None, None, false)?;
+            let json = serde_json::to_string_pretty(&memories)?;
+
+            match out {
+                Some(path) => std::fs::write(path, json)?,
+                None => println!("{}", json),
+            }
+        }
+        Command::ExportAgent { agent_id, out } => match out {
+            Some(path) => {
+                let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+                store.export_bundle(&agent_id, writer).await?;
+            }
+            None => store.export_bundle(&agent_id, std::io::stdout().lock()).await?,
+        },
+        Command::ImportAgent { path } => {
+            let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+            let report = store.import_bundle(reader).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Command::RecountTokens => {
+            let fixed = store.recount_message_tokens()?;
+            println!("recounted tokens for {} messages", fixed);
+        }
+    }
+
+    Ok(())
+}
@@ -3,24 +3,34 @@
 //! HTTP API for the memory system.
 
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
+    body::{Body, Bytes},
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{delete, get, post},
     Json, Router,
 };
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::io::{BufWriter, Write};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use tower_http::cors::{Any, CorsLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid;
 
 use dieah_memory::{
+    audit::AuditLog,
     config::Config,
+    error::Error,
     embedding::TokenCounter,
-    memory::{Memory, MemoryScope, MemoryStore, MemoryType},
-    message::{Message, Role},
-    retrieval::{ContextBudget, RetrievalEngine},
+    memory::{AppendOutcome, ConsistencyReport, Memory, MemoryScope, MemoryStore, MemoryType, MergeReport, RebuildReport, Visibility},
+    message::{Attachment, Message, Role},
+    retrieval::{ContextBudget, RetrievalContext, RetrievalEngine, RetrievalQuery},
+    storage::{validate_id, JsonlStorage, LogPosition, MaintenanceReport, MemoryFacets},
 };
 
 /// Application state shared across handlers
@@ -28,6 +38,27 @@ struct AppState {
     store: MemoryStore,
     retrieval: RetrievalEngine,
     token_counter: TokenCounter,
+    audit_log: AuditLog,
+}
+
+impl AppState {
+    /// Record a mutation in the audit log. A failed write is only logged:
+    /// the change itself has already been made.
+    fn audit(&self, operation: &str, target: Option<&str>, caller: Option<&str>) {
+        if let Err(e) = self.audit_log.record(operation, target, caller) {
+            tracing::warn!("Failed to write audit entry for {}: {}", operation, e);
+        }
+    }
+
+    /// Load a memory the caller may see. One that is private to someone else
+    /// is reported as missing, so its existence isn't revealed either.
+    fn visible_memory(&self, id: Uuid, caller: Option<&str>) -> Result<Memory, StatusCode> {
+        self.store
+            .get_memory(id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .filter(|m| m.is_visible_to(caller))
+            .ok_or(StatusCode::NOT_FOUND)
+    }
 }
 
 type SharedState = Arc<RwLock<AppState>>;
@@ -43,50 +74,173 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     // Load configuration
-    let config = Config::default();
-    tracing::info!("Starting Dieah Memory Server on port {}", config.server_port);
+    let mut config = Config::default();
+    if let Ok(addr) = std::env::var("DIEAH_BIND_ADDR") {
+        config.bind_address = addr;
+    }
+    // Fail before loading the model if the address is unusable
+    let addr = config.socket_addr()?;
+    tracing::info!("Starting Dieah Memory Server on {}", addr);
     tracing::info!("Data directory: {:?}", config.data_dir);
 
-    // Initialize components
+    // Initialize components; the engine settles the embedding dimension first
+    let retrieval = RetrievalEngine::new(config)?;
+    let config = retrieval.config().clone();
     let store = MemoryStore::new(config.clone()).await?;
-    let retrieval = RetrievalEngine::new(config.clone())?;
+    let degraded = store.degraded().map(str::to_string);
+    if config.check_consistency_on_startup {
+        let report = store.check_consistency(&retrieval).await?;
+        for problem in &report.problems {
+            tracing::error!("Embedding dimension mismatch: {}", problem);
+        }
+    }
     let token_counter = TokenCounter::for_gpt()?;
+    let audit_log = AuditLog::open(config.audit_log_path())?;
+    // A clone shares the model, so warming it up needs no lock on the state
+    let warmup = config.warmup_embedding.then(|| retrieval.embedding_service().clone());
 
     let state = Arc::new(RwLock::new(AppState {
         store,
         retrieval,
         token_counter,
+        audit_log,
     }));
 
-    // Build router
-    let app = Router::new()
+    let app = app(&config, state, degraded.as_deref())?;
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("Server listening on http://{}", addr);
+
+    if let Some(embedding) = warmup {
+        tokio::spawn(async move {
+            let started = std::time::Instant::now();
+            match embedding.probe().await {
+                Ok(_) => tracing::info!("Embedding model warmed up in {:?}", started.elapsed()),
+                Err(e) => tracing::warn!("Embedding warmup failed: {}", e),
+            }
+        });
+    }
+
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+/// All routes and layers over `state`, as configured; `degraded` is the
+/// store's failure reason, if any
+fn app(config: &Config, state: SharedState, degraded: Option<&str>) -> anyhow::Result<Router> {
+    let mut router = Router::new()
         // Health check
         .route("/health", get(health))
         // Memory CRUD
         .route("/memories", get(list_memories).post(create_memory))
+        .route("/memories/delete", post(delete_memories))
         .route("/memories/:id", get(get_memory).delete(delete_memory))
+        .route("/memories/:id/reembed", post(reembed_memory))
+        .route("/memories/:id/pin", post(pin_memory))
         // Retrieval
         .route("/retrieve", post(retrieve_context))
+        .route("/retrieve/batch", post(retrieve_batch))
+        .route("/retrieve/by-message", post(retrieve_by_message))
+        .route("/context/assemble", post(assemble_context))
         // Messages
         .route("/messages", post(append_message))
+        .route("/messages/batch", post(append_messages))
         .route("/messages/:agent_id/:topic_id", get(get_messages))
+        .route("/messages/:agent_id/:topic_id/:message_id", get(get_message))
+        .route("/export/:agent_id/:topic_id", get(export_topic))
         // Token counting
         .route("/tokens/count", post(count_tokens))
+        .route("/tokens/estimate", post(estimate_tokens))
         .route("/tokens/budget/:agent_id/:topic_id", get(get_token_budget))
         // Agents and topics
         .route("/agents", get(list_agents))
         .route("/agents/:agent_id/topics", get(list_topics))
+        .route("/agents/:agent_id/learned", get(list_learned))
+        .route("/agents/:agent_id/topics/:topic_id/promote", post(promote_topic))
+        .route("/topics/merge", post(merge_topics))
+        // Batch
+        .route("/batch", post(batch))
+        // Admin
+        .route("/admin/maintenance", post(run_maintenance))
+        .route("/admin/check-consistency", get(check_consistency))
+        .route("/admin/rebuild-metadata", post(rebuild_metadata));
+
+    // Debug tooling, off unless explicitly enabled
+    if config.debug_endpoints {
+        router = router
+            .route("/debug/memories/:id/vector", get(debug_memory_vector))
+            .route("/debug/memories/:id/neighbors", get(debug_memory_neighbors))
+            .route("/debug/explain-retrieval", post(debug_explain_retrieval));
+    }
+
+    if config.read_only {
+        tracing::info!("Read-only mode: mutating routes return 405");
+        router = router.layer(middleware::from_fn(reject_mutations));
+    }
+
+    if let Some(reason) = degraded {
+        tracing::error!("Degraded mode ({}): metadata routes return 503", reason);
+        router = router.layer(middleware::from_fn(reject_metadata));
+    }
+
+    let request_id = HeaderName::from_bytes(config.request_id_header.as_bytes())
+        .map_err(|_| anyhow::anyhow!("Invalid request id header: {:?}", config.request_id_header))?;
+
+    // Layers run outermost-last: the id is set first, so the span and the
+    // response header both see it
+    Ok(router
         // Add CORS
         .layer(CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any))
-        .with_state(state);
+        .layer(PropagateRequestIdLayer::new(request_id.clone()))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span(request_id.clone())))
+        .layer(SetRequestIdLayer::new(request_id, MakeRequestUuid))
+        .with_state(state))
+}
 
-    let port = config.server_port;
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
-    tracing::info!("Server listening on http://127.0.0.1:{}", port);
+/// POST routes that only read; any other POST, PATCH or DELETE mutates state.
+/// `/batch` is checked per operation instead.
+const READ_ONLY_POSTS: &[&str] = &[
+    "/retrieve",
+    "/retrieve/batch",
+    "/retrieve/by-message",
+    "/context/assemble",
+    "/debug/explain-retrieval",
+    "/tokens/count",
+    "/tokens/estimate",
+    "/batch",
+];
+
+/// Middleware for `Config.read_only`: answer mutating requests with 405
+async fn reject_mutations(req: Request, next: Next) -> Response {
+    let mutating = match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => false,
+        Method::POST => !READ_ONLY_POSTS.contains(&req.uri().path()),
+        _ => true,
+    };
 
-    axum::serve(listener, app).await?;
+    if mutating {
+        return StatusCode::METHOD_NOT_ALLOWED.into_response();
+    }
+    next.run(req).await
+}
 
-    Ok(())
+/// Route prefixes that need the metadata database; everything else (history,
+/// retrieval, token counting) is served without it
+const METADATA_ROUTES: &[&str] = &["/memories", "/agents", "/topics", "/admin", "/tokens/budget", "/debug"];
+
+/// Middleware for a degraded store (`Config.degrade_on_metadata_failure`):
+/// answer routes that need the metadata database with 503
+async fn reject_metadata(req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let needs_metadata = METADATA_ROUTES
+        .iter()
+        .any(|prefix| path == *prefix || path.starts_with(&format!("{}/", prefix)));
+
+    if needs_metadata {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Metadata database unavailable").into_response();
+    }
+    next.run(req).await
 }
 
 // === Handlers ===
@@ -95,6 +249,48 @@ async fn health() -> &'static str {
     "ok"
 }
 
+/// Tracing span for one request, recording its id so every log line it emits
+/// can be correlated with the client's trace
+fn request_span(header: HeaderName) -> impl Fn(&Request) -> tracing::Span + Clone {
+    move |req: &Request| {
+        let request_id = req
+            .headers()
+            .get(&header)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-");
+        tracing::info_span!(
+            "request",
+            method = %req.method(),
+            uri = %req.uri(),
+            request_id = %request_id,
+        )
+    }
+}
+
+/// Header identifying the caller, used to filter private memories
+///
+/// The value is taken on trust: nothing authenticates it, so any client can
+/// claim any caller. It separates cooperating callers, not adversaries; put
+/// the server behind something that sets it if access must be enforced.
+const CALLER_HEADER: &str = "x-dieah-caller";
+
+/// Read the caller identity from the request headers, if present
+fn caller_from(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CALLER_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Validate a client-supplied limit and clamp it to `Config.max_query_limit`
+fn clamp_limit(limit: usize, max: usize) -> Result<usize, StatusCode> {
+    if limit == 0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(limit.min(max))
+}
+
 // --- Memory handlers ---
 
 #[derive(Debug, Deserialize)]
@@ -102,34 +298,78 @@ struct ListMemoriesQuery {
     scope: Option<String>,
     agent_id: Option<String>,
     topic_id: Option<String>,
+    /// Literal substring of the content, for search-as-you-type
+    contains: Option<String>,
     active_only: Option<bool>,
+    limit: Option<usize>,
+    /// Also return counts by scope and type for the same filters
+    #[serde(default)]
+    facets: bool,
+}
+
+/// A plain array, or `{ memories, facets }` when `?facets=true`
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum ListMemoriesResponse {
+    Memories(Vec<MemoryResponse>),
+    WithFacets {
+        memories: Vec<MemoryResponse>,
+        facets: MemoryFacets,
+    },
 }
 
 async fn list_memories(
     State(state): State<SharedState>,
+    headers: HeaderMap,
     Query(query): Query<ListMemoriesQuery>,
-) -> Result<Json<Vec<MemoryResponse>>, StatusCode> {
+) -> Result<Json<ListMemoriesResponse>, StatusCode> {
     let state = state.read().await;
 
-    let scope = query.scope.and_then(|s| match s.as_str() {
-        "global" => Some(MemoryScope::Global),
-        "agent" => Some(MemoryScope::Agent),
-        "topic" => Some(MemoryScope::Topic),
-        "personal" => Some(MemoryScope::Personal),
-        _ => None,
-    });
-
-    let memories = state
+    let scope = query
+        .scope
+        .as_deref()
+        .map(MemoryScope::try_from)
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let max = state.store.config().max_query_limit;
+    let limit = query.limit.map(|l| clamp_limit(l, max)).transpose()?;
+
+    let active_only = query.active_only.unwrap_or(true);
+    let mut memories = state
         .store
         .list_memories(
             scope,
             query.agent_id.as_deref(),
             query.topic_id.as_deref(),
-            query.active_only.unwrap_or(true),
+            query.contains.as_deref(),
+            active_only,
         )
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let caller = caller_from(&headers);
+    memories.retain(|m| m.is_visible_to(caller.as_deref()));
+    if let Some(limit) = limit {
+        memories.truncate(limit);
+    }
+    let memories = memories.into_iter().map(MemoryResponse::from).collect();
 
-    Ok(Json(memories.into_iter().map(MemoryResponse::from).collect()))
+    if !query.facets {
+        return Ok(Json(ListMemoriesResponse::Memories(memories)));
+    }
+
+    // Facets count every match, not just the page returned under `limit`
+    let facets = state
+        .store
+        .facet_counts(
+            scope,
+            query.agent_id.as_deref(),
+            query.topic_id.as_deref(),
+            query.contains.as_deref(),
+            active_only,
+            caller.as_deref(),
+        )
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(ListMemoriesResponse::WithFacets { memories, facets }))
 }
 
 #[derive(Debug, Deserialize)]
@@ -141,314 +381,1511 @@ struct CreateMemoryRequest {
     content: String,
     context: Option<String>,
     tags: Option<Vec<String>>,
+    pinned: Option<bool>,
+    visibility: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CreateMemoryResponse {
+    #[serde(flatten)]
+    memory: MemoryResponse,
+    /// Set when the memory was stored without an embedding (202)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    /// Older memories this one superseded (`Config.supersede_threshold`)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    superseded: Vec<String>,
 }
 
 async fn create_memory(
     State(state): State<SharedState>,
+    headers: HeaderMap,
     Json(req): Json<CreateMemoryRequest>,
-) -> Result<Json<MemoryResponse>, StatusCode> {
+) -> Result<(StatusCode, Json<CreateMemoryResponse>), StatusCode> {
     let state = state.write().await;
+    let (status, response) = create_memory_in(&state, caller_from(&headers), req).await?;
+    Ok((status, Json(response)))
+}
 
-    let scope = match req.scope.as_str() {
-        "global" => MemoryScope::Global,
-        "agent" => MemoryScope::Agent,
-        "topic" => MemoryScope::Topic,
-        "personal" => MemoryScope::Personal,
-        _ => return Err(StatusCode::BAD_REQUEST),
-    };
+/// Build, embed and save a memory; shared by `create_memory` and `/batch`
+async fn create_memory_in(
+    state: &AppState,
+    caller: Option<String>,
+    req: CreateMemoryRequest,
+) -> Result<(StatusCode, CreateMemoryResponse), StatusCode> {
+    let scope: MemoryScope = req.scope.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let memory_type: MemoryType = req.memory_type.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    let memory_type = match req.memory_type.as_str() {
-        "correction" => MemoryType::Correction,
-        "preference" => MemoryType::Preference,
-        "fact" => MemoryType::Fact,
-        "workflow" => MemoryType::Workflow,
-        "constraint" => MemoryType::Constraint,
-        _ => return Err(StatusCode::BAD_REQUEST),
-    };
+    let content = state.store.redact(&req.content);
 
     let mut memory = match scope {
-        MemoryScope::Global => Memory::global(memory_type, req.content),
+        MemoryScope::Global => Memory::global(memory_type, content),
         MemoryScope::Agent => {
             let agent_id = req.agent_id.ok_or(StatusCode::BAD_REQUEST)?;
-            Memory::for_agent(agent_id, memory_type, req.content)
+            Memory::for_agent(agent_id, memory_type, content)
         }
         MemoryScope::Topic => {
             let agent_id = req.agent_id.ok_or(StatusCode::BAD_REQUEST)?;
             let topic_id = req.topic_id.ok_or(StatusCode::BAD_REQUEST)?;
-            Memory::for_topic(agent_id, topic_id, memory_type, req.content)
+            Memory::for_topic(agent_id, topic_id, memory_type, content)
         }
-        MemoryScope::Personal => Memory::global(memory_type, req.content),
+        MemoryScope::Personal => Memory::personal(memory_type, content),
     };
 
     if let Some(context) = req.context {
-        memory = memory.with_context(context);
+        memory = memory.with_context(state.store.redact(&context));
     }
 
     if let Some(tags) = req.tags {
         memory = memory.with_tags(tags);
     }
 
+    if let Some(pinned) = req.pinned {
+        memory = memory.with_pinned(pinned);
+    }
+
+    let visibility = req
+        .visibility
+        .as_deref()
+        .map(str::parse::<Visibility>)
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+        .unwrap_or_default();
+    if visibility == Visibility::Private {
+        // A private memory needs an owner, which is the caller creating it
+        let owner = caller.ok_or(StatusCode::BAD_REQUEST)?;
+        memory = memory.with_owner(owner);
+    }
+
     // Embed and save
-    let memory = state
-        .retrieval
-        .embed_and_save(&state.store, memory)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let pending = state.store.config().save_unembedded_on_failure.then(|| memory.clone());
+    let (memory, superseded) = match state.retrieval.embed_and_save_superseding(&state.store, memory).await {
+        Ok(saved) => saved,
+        Err(Error::Embedding(e)) => {
+            let Some(pending) = pending else {
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            };
+            tracing::warn!("Embedding failed, storing memory unembedded: {}", e);
+            let memory = state
+                .store
+                .save_memory(pending)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            state.audit("create_memory", Some(&memory.id.to_string()), caller.as_deref());
+            return Ok((
+                StatusCode::ACCEPTED,
+                CreateMemoryResponse {
+                    memory: MemoryResponse::from(memory),
+                    note: Some("Embedding unavailable; stored unembedded until embed-pending runs".to_string()),
+                    superseded: Vec::new(),
+                },
+            ));
+        }
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    state.audit("create_memory", Some(&memory.id.to_string()), caller.as_deref());
+    for id in &superseded {
+        state.audit("supersede_memory", Some(&id.to_string()), caller.as_deref());
+    }
 
-    Ok(Json(MemoryResponse::from(memory)))
+    Ok((
+        StatusCode::OK,
+        CreateMemoryResponse {
+            memory: MemoryResponse::from(memory),
+            note: None,
+            superseded: superseded.iter().map(Uuid::to_string).collect(),
+        },
+    ))
 }
 
 async fn get_memory(
     State(state): State<SharedState>,
+    headers: HeaderMap,
     Path(id): Path<String>,
 ) -> Result<Json<MemoryResponse>, StatusCode> {
     let state = state.read().await;
 
     let uuid = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    let memory = state
-        .store
-        .get_memory(uuid)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(StatusCode::NOT_FOUND)?;
+    let memory = state.visible_memory(uuid, caller_from(&headers).as_deref())?;
 
     Ok(Json(MemoryResponse::from(memory)))
 }
 
 async fn delete_memory(
     State(state): State<SharedState>,
+    headers: HeaderMap,
     Path(id): Path<String>,
 ) -> Result<StatusCode, StatusCode> {
     let state = state.read().await;
 
     let uuid = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
 
+    let caller = caller_from(&headers);
+    state.visible_memory(uuid, caller.as_deref())?;
     state
         .store
         .delete_memory(uuid)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    state.audit("delete_memory", Some(&id), caller.as_deref());
 
     Ok(StatusCode::NO_CONTENT)
 }
 
-// --- Retrieval handlers ---
-
 #[derive(Debug, Deserialize)]
-struct RetrieveRequest {
-    query: String,
-    agent_id: Option<String>,
-    topic_id: Option<String>,
-    max_recent_messages: Option<usize>,
-}
-
-#[derive(Debug, Serialize)]
-struct RetrieveResponse {
-    memories: Vec<RetrievedMemoryResponse>,
-    recent_messages: Vec<MessageResponse>,
-    total_tokens: u32,
-    formatted_context: String,
+struct DeleteMemoriesRequest {
+    ids: Vec<String>,
 }
 
 #[derive(Debug, Serialize)]
-struct RetrievedMemoryResponse {
-    id: String,
-    content: String,
-    scope: String,
-    memory_type: String,
-    score: f32,
+struct DeleteMemoriesResponse {
+    deleted: Vec<String>,
+    not_found: Vec<String>,
 }
 
-async fn retrieve_context(
+async fn delete_memories(
     State(state): State<SharedState>,
-    Json(req): Json<RetrieveRequest>,
-) -> Result<Json<RetrieveResponse>, StatusCode> {
+    headers: HeaderMap,
+    Json(req): Json<DeleteMemoriesRequest>,
+) -> Result<Json<DeleteMemoriesResponse>, StatusCode> {
     let state = state.read().await;
 
-    let context = state
-        .retrieval
-        .retrieve(
-            &state.store,
-            &req.query,
-            req.agent_id.as_deref(),
-            req.topic_id.as_deref(),
-            req.max_recent_messages.unwrap_or(10),
-        )
+    let uuids = req
+        .ids
+        .iter()
+        .map(|id| Uuid::parse_str(id))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    // Memories the caller can't see are left alone and reported as not found
+    let caller = caller_from(&headers);
+    let mut visible = Vec::with_capacity(uuids.len());
+    for &id in &uuids {
+        match state.visible_memory(id, caller.as_deref()) {
+            Ok(_) => visible.push(id),
+            Err(StatusCode::NOT_FOUND) => {}
+            Err(status) => return Err(status),
+        }
+    }
+
+    let deleted = state
+        .store
+        .delete_memories(&visible)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    for id in &deleted {
+        state.audit("delete_memory", Some(&id.to_string()), caller.as_deref());
+    }
 
-    Ok(Json(RetrieveResponse {
-        memories: context
-            .memories
-            .iter()
-            .map(|m| RetrievedMemoryResponse {
-                id: m.id.to_string(),
-                content: m.content.clone(),
-                scope: m.scope.clone(),
-                memory_type: m.memory_type.clone(),
-                score: m.score,
-            })
-            .collect(),
-        recent_messages: context
-            .recent_messages
-            .iter()
-            .map(MessageResponse::from)
-            .collect(),
-        total_tokens: context.total_tokens,
-        formatted_context: context.format_for_prompt(),
-    }))
-}
-
-// --- Message handlers ---
+    let not_found = uuids
+        .iter()
+        .filter(|id| !deleted.contains(id))
+        .map(|id| id.to_string())
+        .collect();
 
-#[derive(Debug, Deserialize)]
-struct AppendMessageRequest {
-    agent_id: String,
-    topic_id: String,
-    role: String,
-    content: String,
+    Ok(Json(DeleteMemoriesResponse {
+        deleted: deleted.iter().map(|id| id.to_string()).collect(),
+        not_found,
+    }))
 }
 
-async fn append_message(
+async fn reembed_memory(
     State(state): State<SharedState>,
-    Json(req): Json<AppendMessageRequest>,
-) -> Result<Json<MessageResponse>, StatusCode> {
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<MemoryResponse>, StatusCode> {
     let state = state.read().await;
 
-    let role = match req.role.as_str() {
-        "system" => Role::System,
-        "user" => Role::User,
-        "assistant" => Role::Assistant,
-        "tool" => Role::Tool,
-        _ => return Err(StatusCode::BAD_REQUEST),
-    };
-
-    let tokens = state.token_counter.count(&req.content);
-
-    let message = Message::new(req.agent_id, req.topic_id, role, req.content).with_tokens(tokens);
+    let uuid = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    state
+    let caller = caller_from(&headers);
+    state.visible_memory(uuid, caller.as_deref())?;
+    let memory = state
         .store
-        .jsonl()
-        .append(&message)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .reembed_memory(uuid, &state.retrieval)
+        .await
+        .map_err(|e| match e {
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+    state.audit("reembed_memory", Some(&id), caller.as_deref());
 
-    Ok(Json(MessageResponse::from(&message)))
+    Ok(Json(MemoryResponse::from(memory)))
 }
 
 #[derive(Debug, Deserialize)]
-struct GetMessagesQuery {
-    limit: Option<usize>,
+struct PinMemoryRequest {
+    pinned: bool,
 }
 
-async fn get_messages(
+async fn pin_memory(
     State(state): State<SharedState>,
-    Path((agent_id, topic_id)): Path<(String, String)>,
-    Query(query): Query<GetMessagesQuery>,
-) -> Result<Json<Vec<MessageResponse>>, StatusCode> {
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(req): Json<PinMemoryRequest>,
+) -> Result<Json<MemoryResponse>, StatusCode> {
     let state = state.read().await;
 
-    let messages = if let Some(limit) = query.limit {
-        state
-            .store
-            .jsonl()
-            .read_last_n(&agent_id, &topic_id, limit)
-    } else {
-        state.store.jsonl().read_all(&agent_id, &topic_id)
-    }
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let uuid = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    Ok(Json(messages.iter().map(MessageResponse::from).collect()))
-}
+    let caller = caller_from(&headers);
+    state.visible_memory(uuid, caller.as_deref())?;
+    state
+        .store
+        .set_memory_pinned(uuid, req.pinned)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-// --- Token handlers ---
+    let memory = state.visible_memory(uuid, caller.as_deref())?;
+    let operation = if req.pinned { "pin_memory" } else { "unpin_memory" };
+    state.audit(operation, Some(&id), caller.as_deref());
 
-#[derive(Debug, Deserialize)]
-struct CountTokensRequest {
-    text: String,
+    Ok(Json(MemoryResponse::from(memory)))
 }
 
+// --- Debug handlers ---
+
 #[derive(Debug, Serialize)]
-struct CountTokensResponse {
-    tokens: u32,
+struct DebugVectorResponse {
+    id: String,
+    dimensions: usize,
+    vector: Vec<f32>,
 }
 
-async fn count_tokens(
+async fn debug_memory_vector(
     State(state): State<SharedState>,
-    Json(req): Json<CountTokensRequest>,
-) -> Json<CountTokensResponse> {
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<DebugVectorResponse>, StatusCode> {
     let state = state.read().await;
-    let tokens = state.token_counter.count(&req.text);
-    Json(CountTokensResponse { tokens })
+
+    let uuid = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    state.visible_memory(uuid, caller_from(&headers).as_deref())?;
+
+    let vector = state
+        .store
+        .vector()
+        .get_vector(uuid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(DebugVectorResponse {
+        id,
+        dimensions: vector.len(),
+        vector,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct DebugNeighborsQuery {
+    limit: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
-struct TokenBudgetResponse {
-    used: u32,
-    limit: u32,
-    remaining: u32,
-    utilization: f32,
-    status: String,
+struct DebugNeighborResponse {
+    id: String,
+    content: String,
+    distance: f32,
+    score: f32,
 }
 
-async fn get_token_budget(
+async fn debug_memory_neighbors(
     State(state): State<SharedState>,
-    Path((agent_id, topic_id)): Path<(String, String)>,
-) -> Result<Json<TokenBudgetResponse>, StatusCode> {
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Query(query): Query<DebugNeighborsQuery>,
+) -> Result<Json<Vec<DebugNeighborResponse>>, StatusCode> {
     let state = state.read().await;
 
-    let total_tokens = state
+    let uuid = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let caller = caller_from(&headers);
+    state.visible_memory(uuid, caller.as_deref())?;
+    let limit = clamp_limit(query.limit.unwrap_or(10), state.store.config().max_query_limit)?;
+
+    let neighbors = state
         .store
-        .jsonl()
-        .total_tokens(&agent_id, &topic_id)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .vector()
+        .neighbors(uuid, limit)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    // TODO: Get actual limit from agent config
-    let limit = 128000u32;
+    // Neighbors the caller can't see (or with no stored record) are left out
+    let mut visible = Vec::with_capacity(neighbors.len());
+    for n in neighbors {
+        match state.visible_memory(n.id, caller.as_deref()) {
+            Ok(_) => visible.push(DebugNeighborResponse {
+                id: n.id.to_string(),
+                content: n.content,
+                distance: n.distance,
+                score: n.score,
+            }),
+            Err(StatusCode::NOT_FOUND) => {}
+            Err(status) => return Err(status),
+        }
+    }
 
-    let mut budget = ContextBudget::new(
-        limit,
-        state.store.config().context_warning_threshold,
-        state.store.config().context_critical_threshold,
-    );
-    budget.add(total_tokens);
+    Ok(Json(visible))
+}
 
-    Ok(Json(TokenBudgetResponse {
-        used: budget.used,
-        limit: budget.limit,
-        remaining: budget.remaining(),
-        utilization: budget.utilization(),
-        status: budget.status().to_string(),
-    }))
+#[derive(Debug, Deserialize)]
+struct ExplainRetrievalRequest {
+    memory_id: String,
+    /// The query as it would be sent to `/retrieve`, overrides included
+    #[serde(flatten)]
+    retrieve: RetrieveRequest,
 }
 
-// --- Agent/Topic handlers ---
+#[derive(Debug, Serialize)]
+struct ExplainRetrievalResponse {
+    memory_id: String,
+    similarity: Option<f32>,
+    score: Option<f32>,
+    threshold: f32,
+    passed_threshold: bool,
+    excluded_by: Vec<String>,
+    retrieved: bool,
+    rank: Option<usize>,
+    result_count: usize,
+    limit: usize,
+}
 
-async fn list_agents(
+async fn debug_explain_retrieval(
     State(state): State<SharedState>,
-) -> Result<Json<Vec<String>>, StatusCode> {
+    headers: HeaderMap,
+    Json(req): Json<ExplainRetrievalRequest>,
+) -> Result<Json<ExplainRetrievalResponse>, StatusCode> {
     let state = state.read().await;
-    let agents = state
-        .store
-        .jsonl()
-        .list_agents()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(Json(agents))
+
+    let uuid = Uuid::parse_str(&req.memory_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let query = req
+        .retrieve
+        .into_query(state.store.config().max_query_limit, caller_from(&headers))?;
+
+    let explanation = state
+        .retrieval
+        .explain(&state.store, &query, uuid)
+        .await
+        .map_err(|e| match e {
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            e => retrieval_status(e),
+        })?;
+
+    Ok(Json(ExplainRetrievalResponse {
+        memory_id: explanation.memory_id.to_string(),
+        similarity: explanation.similarity,
+        score: explanation.score,
+        threshold: explanation.threshold,
+        passed_threshold: explanation.passed_threshold,
+        excluded_by: explanation.excluded_by,
+        retrieved: explanation.retrieved,
+        rank: explanation.rank,
+        result_count: explanation.result_count,
+        limit: explanation.limit,
+    }))
 }
 
-async fn list_topics(
-    State(state): State<SharedState>,
-    Path(agent_id): Path<String>,
-) -> Result<Json<Vec<String>>, StatusCode> {
+// --- Retrieval handlers ---
+
+#[derive(Debug, Deserialize)]
+struct RetrieveRequest {
+    query: String,
+    agent_id: Option<String>,
+    topic_id: Option<String>,
+    max_recent_messages: Option<usize>,
+    max_recent_tokens: Option<u32>,
+    /// Search every agent's memories, not just `agent_id`'s
+    #[serde(default)]
+    all_agents: bool,
+    /// Also return deactivated memories, ranked below active ones
+    #[serde(default)]
+    include_inactive: bool,
+    /// Hint tags; memories sharing them rank higher
+    #[serde(default)]
+    query_tags: Vec<String>,
+    /// RFC 3339 time to pick recent messages around, e.g. where a conversation is resumed
+    reference_time: Option<String>,
+    /// Only messages within this many seconds of the reference time
+    recent_window_secs: Option<u64>,
+    /// Memories to return, overriding `Config.max_retrieval_results`
+    limit: Option<usize>,
+    /// Similarity threshold (0.0 - 1.0), overriding `Config.min_similarity_score`
+    min_score: Option<f32>,
+    /// Only memories of these scopes (e.g. `["agent", "topic"]`)
+    #[serde(default)]
+    scopes: Vec<String>,
+    /// Only memories of these types; `?types=` takes precedence
+    #[serde(default)]
+    types: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RetrieveResponse {
+    memories: Vec<RetrievedMemoryResponse>,
+    recent_messages: Vec<MessageResponse>,
+    total_tokens: u32,
+    formatted_context: String,
+    /// Exactly which memories went into `formatted_context`, for audit logs
+    manifest: Vec<ManifestEntry>,
+    /// Distance metric behind `score` (`l2` or `cosine`)
+    metric: String,
+    /// Minimum score applied; low-confidence fallback results may fall below it
+    threshold: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    id: String,
+    score: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct RetrievedMemoryResponse {
+    id: String,
+    content: String,
+    scope: String,
+    memory_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    agent_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    score: f32,
+    pinned: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    low_confidence: bool,
+    active: bool,
+    /// Full stored record (tags, context, timestamps, counts), with `?hydrate=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<MemoryResponse>,
+}
+
+impl RetrieveRequest {
+    /// Validate ids and clamp limits before handing the request to the engine
+    fn into_query(self, max_query_limit: usize, caller: Option<String>) -> Result<RetrievalQuery, StatusCode> {
+        for (kind, id) in [("agent_id", &self.agent_id), ("topic_id", &self.topic_id)] {
+            if let Some(id) = id {
+                validate_id(kind, id).map_err(|_| StatusCode::BAD_REQUEST)?;
+            }
+        }
+
+        let limit = self.limit.map(|l| clamp_limit(l, max_query_limit)).transpose()?;
+        let min_score = match self.min_score {
+            Some(score) if !score.is_finite() => return Err(StatusCode::BAD_REQUEST),
+            score => score.map(|s| s.clamp(0.0, 1.0)),
+        };
+        let scopes = self
+            .scopes
+            .iter()
+            .map(|s| MemoryScope::try_from(s.trim()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let types = self
+            .types
+            .iter()
+            .map(|t| MemoryType::try_from(t.trim()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        Ok(RetrievalQuery {
+            query: self.query,
+            agent_id: self.agent_id,
+            topic_id: self.topic_id,
+            // Zero is allowed here: it means "no recent messages"
+            max_recent_messages: self.max_recent_messages.unwrap_or(10).min(max_query_limit),
+            max_recent_tokens: self.max_recent_tokens,
+            types,
+            caller,
+            all_agents: self.all_agents,
+            filter: None,
+            include_inactive: self.include_inactive,
+            query_tags: self.query_tags,
+            reference_time: self.reference_time.as_deref().map(parse_timestamp).transpose()?,
+            recent_window_secs: self.recent_window_secs,
+            limit,
+            min_score,
+            scopes,
+        })
+    }
+}
+
+impl From<&RetrievalContext> for RetrieveResponse {
+    fn from(context: &RetrievalContext) -> Self {
+        Self {
+            memories: context
+                .memories
+                .iter()
+                .map(|m| RetrievedMemoryResponse {
+                    id: m.id.to_string(),
+                    content: m.content.clone(),
+                    scope: m.scope.clone(),
+                    memory_type: m.memory_type.clone(),
+                    agent_id: m.agent_id.clone(),
+                    topic_id: m.topic_id.clone(),
+                    tags: m.tags.clone(),
+                    score: m.score,
+                    pinned: m.pinned,
+                    low_confidence: m.low_confidence,
+                    active: m.active,
+                    metadata: None,
+                })
+                .collect(),
+            recent_messages: context
+                .recent_messages
+                .iter()
+                .map(|m| MessageResponse::from(m).without_thinking())
+                .collect(),
+            total_tokens: context.total_tokens,
+            formatted_context: context.format_for_prompt(),
+            manifest: context
+                .manifest()
+                .into_iter()
+                .map(|(id, score)| ManifestEntry {
+                    id: id.to_string(),
+                    score,
+                })
+                .collect(),
+            metric: context.metric.to_string(),
+            threshold: context.threshold,
+        }
+    }
+}
+
+impl RetrieveResponse {
+    /// Attach each retrieved memory's stored record from SQLite
+    ///
+    /// Memories are already filtered by visibility during retrieval, so the
+    /// records need no further check. One missing from SQLite is left bare.
+    fn hydrate(&mut self, store: &MemoryStore) -> dieah_memory::Result<()> {
+        for memory in &mut self.memories {
+            let Ok(id) = Uuid::parse_str(&memory.id) else {
+                continue;
+            };
+            memory.metadata = store.get_memory(id)?.map(MemoryResponse::from);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RetrieveParams {
+    /// Comma-separated memory types, e.g. `constraint,workflow`
+    types: Option<String>,
+    /// Attach each memory's full stored record, saving a `GET /memories/:id` per hit
+    #[serde(default)]
+    hydrate: bool,
+}
+
+/// Map retrieval errors to a status, surfacing search timeouts as 504 and a
+/// missing metadata database as 503
+fn retrieval_status(e: Error) -> StatusCode {
+    match e {
+        Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn retrieve_context(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Query(params): Query<RetrieveParams>,
+    Json(req): Json<RetrieveRequest>,
+) -> Result<Json<RetrieveResponse>, StatusCode> {
+    let state = state.read().await;
+
+    let mut query = req.into_query(state.store.config().max_query_limit, caller_from(&headers))?;
+    if let Some(types) = params.types {
+        query.types = types
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(MemoryType::try_from)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+    }
+
+    let context = state
+        .retrieval
+        .retrieve_query(&state.store, &query)
+        .await
+        .map_err(retrieval_status)?;
+
+    let mut response = RetrieveResponse::from(&context);
+    if params.hydrate {
+        response.hydrate(&state.store).map_err(retrieval_status)?;
+    }
+    Ok(Json(response))
+}
+
+#[derive(Debug, Deserialize)]
+struct RetrieveByMessageRequest {
+    agent_id: String,
+    topic_id: String,
+    /// Position of a stored message, as returned by `POST /messages`
+    position: Option<LogPosition>,
+    /// Id of the message at `position`, checked on read
+    message_id: Option<Uuid>,
+    /// Inline message content, used instead of `position`
+    content: Option<String>,
+    max_recent_messages: Option<usize>,
+    max_recent_tokens: Option<u32>,
+}
+
+async fn retrieve_by_message(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(req): Json<RetrieveByMessageRequest>,
+) -> Result<Json<RetrieveResponse>, StatusCode> {
+    let state = state.read().await;
+
+    validate_id("agent_id", &req.agent_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    validate_id("topic_id", &req.topic_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let message = match (req.position, req.message_id, req.content) {
+        (Some(position), Some(id), None) => state
+            .store
+            .jsonl()
+            .read_at_offset(&req.agent_id, &req.topic_id, position, id)
+            .map_err(|e| match e {
+                Error::NotFound(_) | Error::Io(_) => StatusCode::NOT_FOUND,
+                _ => StatusCode::BAD_REQUEST,
+            })?,
+        (None, None, Some(content)) if !content.trim().is_empty() => {
+            Message::new(req.agent_id, req.topic_id, Role::User, content)
+        }
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let max = state.store.config().max_query_limit;
+    let query = RetrievalQuery {
+        max_recent_messages: req.max_recent_messages.unwrap_or(10).min(max),
+        max_recent_tokens: req.max_recent_tokens,
+        caller: caller_from(&headers),
+        ..RetrievalQuery::for_message(&message)
+    };
+
+    let context = state
+        .retrieval
+        .retrieve_query(&state.store, &query)
+        .await
+        .map_err(retrieval_status)?;
+
+    Ok(Json(RetrieveResponse::from(&context)))
+}
+
+#[derive(Debug, Deserialize)]
+struct AssembleContextRequest {
+    query: String,
+    agent_id: Option<String>,
+    topic_id: Option<String>,
+    /// Model whose context limit to fit; defaults to the agent's limit
+    model: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct AssembleContextResponse {
+    #[serde(flatten)]
+    context: RetrieveResponse,
+    budget: TokenBudgetResponse,
+}
+
+async fn assemble_context(
+    State(state): State<SharedState>,
+    Json(req): Json<AssembleContextRequest>,
+) -> Result<Json<AssembleContextResponse>, StatusCode> {
+    let state = state.read().await;
+
+    for (kind, id) in [("agent_id", &req.agent_id), ("topic_id", &req.topic_id)] {
+        if let Some(id) = id {
+            validate_id(kind, id).map_err(|_| StatusCode::BAD_REQUEST)?;
+        }
+    }
+
+    let assembled = state
+        .retrieval
+        .assemble(
+            &state.store,
+            &req.query,
+            req.agent_id.as_deref(),
+            req.topic_id.as_deref(),
+            req.model.as_deref(),
+        )
+        .await
+        .map_err(retrieval_status)?;
+
+    Ok(Json(AssembleContextResponse {
+        context: RetrieveResponse::from(&assembled.context),
+        budget: TokenBudgetResponse::from(&assembled.budget),
+    }))
+}
+
+async fn retrieve_batch(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(reqs): Json<Vec<RetrieveRequest>>,
+) -> Result<Json<Vec<RetrieveResponse>>, StatusCode> {
+    let state = state.read().await;
+
+    let max = state.store.config().max_query_limit;
+    if reqs.len() > max {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let caller = caller_from(&headers);
+    let queries = reqs
+        .into_iter()
+        .map(|req| req.into_query(max, caller.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let contexts = state
+        .retrieval
+        .retrieve_batch(&state.store, &queries)
+        .await
+        .map_err(retrieval_status)?;
+
+    Ok(Json(contexts.iter().map(RetrieveResponse::from).collect()))
+}
+
+// --- Message handlers ---
+
+#[derive(Debug, Deserialize)]
+struct AppendMessageRequest {
+    agent_id: String,
+    #[serde(default)]
+    topic_id: String,
+    /// Falls back to `Config.default_role` when omitted or blank
+    #[serde(default)]
+    role: Option<String>,
+    content: String,
+    thinking: Option<String>,
+    attachments: Option<Vec<Attachment>>,
+    /// RFC 3339 time the message was originally sent; defaults to now
+    timestamp: Option<String>,
+    /// Keep the message in the active log through compaction
+    #[serde(default)]
+    retain: bool,
+}
+
+/// How far ahead of the server clock a client-supplied timestamp may be
+const MAX_TIMESTAMP_SKEW_SECS: i64 = 300;
+
+/// Parse a client-supplied RFC 3339 timestamp, rejecting ones in the future
+fn parse_timestamp(raw: &str) -> Result<DateTime<Utc>, StatusCode> {
+    let timestamp = DateTime::parse_from_rfc3339(raw)
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+        .with_timezone(&Utc);
+
+    if timestamp > Utc::now() + chrono::Duration::seconds(MAX_TIMESTAMP_SKEW_SECS) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(timestamp)
+}
+
+#[derive(Debug, Serialize)]
+struct AppendMessageResponse {
+    #[serde(flatten)]
+    message: MessageResponse,
+    /// Where the message was written, for `POST /retrieve/by-message`.
+    /// Omitted when the append compacted the topic, since the log was rewritten.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<LogPosition>,
+    /// Whether this append triggered a compaction of the topic
+    compacted: bool,
+}
+
+impl AppendMessageResponse {
+    fn new(message: &Message, outcome: AppendOutcome) -> Self {
+        let compacted = outcome.compaction.is_some();
+        Self {
+            message: MessageResponse::from(message),
+            position: (!compacted).then_some(outcome.position),
+            compacted,
+        }
+    }
+}
+
+/// 400 body for a missing or invalid role
+#[derive(Debug, Serialize)]
+struct RoleErrorResponse {
+    error: String,
+    /// Built-in roles; with `Config.allow_custom_roles` any other non-empty
+    /// label is also accepted
+    accepted_roles: &'static [&'static str],
+}
+
+/// Role for an appended message, falling back to `Config.default_role`
+fn resolve_role(config: &Config, role: Option<&str>) -> Result<Role, RoleErrorResponse> {
+    let role = role
+        .filter(|r| !r.trim().is_empty())
+        .or(config.default_role.as_deref())
+        .ok_or_else(|| "role is required (no default role is configured)".to_string())
+        .and_then(|r| {
+            let role = if config.allow_custom_roles { r.parse() } else { Role::parse_known(r) };
+            role.map_err(|e| e.to_string())
+        });
+
+    role.map_err(|error| RoleErrorResponse {
+        error,
+        accepted_roles: &Role::KNOWN,
+    })
+}
+
+async fn append_message(
+    State(state): State<SharedState>,
+    Json(req): Json<AppendMessageRequest>,
+) -> Result<Json<AppendMessageResponse>, Response> {
+    let state = state.read().await;
+
+    // Report the accepted roles rather than a bare 400
+    if let Err(body) = resolve_role(state.store.config(), req.role.as_deref()) {
+        return Err((StatusCode::BAD_REQUEST, Json(body)).into_response());
+    }
+    append_message_in(&state, req).map(Json).map_err(IntoResponse::into_response)
+}
+
+/// Build and append a message; shared by `append_message` and `/batch`
+fn append_message_in(state: &AppState, req: AppendMessageRequest) -> Result<AppendMessageResponse, StatusCode> {
+    let message = build_message(state, req)?;
+
+    let outcome = state
+        .store
+        .append_message(&message)
+        .map_err(|e| match e {
+            Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    Ok(AppendMessageResponse::new(&message, outcome))
+}
+
+/// Append several messages in one request, e.g. when importing a conversation
+///
+/// All messages are validated before any is written; one bad message rejects
+/// the whole batch with 400.
+async fn append_messages(
+    State(state): State<SharedState>,
+    Json(reqs): Json<Vec<AppendMessageRequest>>,
+) -> Result<Json<Vec<AppendMessageResponse>>, StatusCode> {
+    let state = state.read().await;
+
+    if reqs.len() > state.store.config().max_query_limit {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let messages = reqs
+        .into_iter()
+        .map(|req| build_message(&state, req))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let outcomes = state
+        .store
+        .append_messages(&messages)
+        .map_err(|e| match e {
+            Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    Ok(Json(
+        messages
+            .iter()
+            .zip(outcomes)
+            .map(|(message, outcome)| AppendMessageResponse::new(message, outcome))
+            .collect(),
+    ))
+}
+
+/// Turn an append request into a redacted, token-counted message
+fn build_message(state: &AppState, req: AppendMessageRequest) -> Result<Message, StatusCode> {
+    let role = resolve_role(state.store.config(), req.role.as_deref()).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let timestamp = req.timestamp.as_deref().map(parse_timestamp).transpose()?;
+
+    let topic_id = if req.topic_id.trim().is_empty() {
+        state.store.config().default_topic_id.clone()
+    } else {
+        req.topic_id
+    };
+
+    let content = state.store.redact(&req.content);
+    let tokens = state.token_counter.count(&content);
+
+    let mut message = Message::new(req.agent_id, topic_id, role, content).with_tokens(tokens);
+    if let Some(thinking) = req.thinking.filter(|t| !t.trim().is_empty()) {
+        message = message.with_thinking(state.store.redact(&thinking));
+    }
+    if let Some(attachments) = req.attachments.filter(|a| !a.is_empty()) {
+        message = message.with_attachments(attachments);
+    }
+    if let Some(timestamp) = timestamp {
+        message = message.with_timestamp(timestamp);
+    }
+    if req.retain {
+        message = message.with_retain(true);
+    }
+
+    Ok(message)
+}
+
+#[derive(Debug, Deserialize)]
+struct GetMessagesQuery {
+    limit: Option<usize>,
+    include_thinking: Option<bool>,
+}
+
+async fn get_messages(
+    State(state): State<SharedState>,
+    Path((agent_id, topic_id)): Path<(String, String)>,
+    Query(query): Query<GetMessagesQuery>,
+) -> Result<Json<Vec<MessageResponse>>, StatusCode> {
+    let state = state.read().await;
+
+    let max = state.store.config().max_query_limit;
+    let limit = query.limit.map(|l| clamp_limit(l, max)).transpose()?;
+
+    let messages = if let Some(limit) = limit {
+        state
+            .store
+            .jsonl()
+            .read_last_n(&agent_id, &topic_id, limit)
+    } else {
+        state.store.jsonl().read_all(&agent_id, &topic_id)
+    }
+    .map_err(|e| match e {
+        Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
+
+    let include_thinking = query.include_thinking.unwrap_or(false);
+
+    Ok(Json(
+        messages
+            .iter()
+            .map(|m| {
+                let response = MessageResponse::from(m);
+                if include_thinking {
+                    response
+                } else {
+                    response.without_thinking()
+                }
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct GetMessageQuery {
+    include_thinking: Option<bool>,
+}
+
+async fn get_message(
+    State(state): State<SharedState>,
+    Path((agent_id, topic_id, message_id)): Path<(String, String, String)>,
+    Query(query): Query<GetMessageQuery>,
+) -> Result<Json<MessageResponse>, StatusCode> {
+    let state = state.read().await;
+
+    let uuid = Uuid::parse_str(&message_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let message = state
+        .store
+        .jsonl()
+        .find_message(&agent_id, &topic_id, uuid)
+        .map_err(|e| match e {
+            Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let response = MessageResponse::from(&message);
+    if query.include_thinking.unwrap_or(false) {
+        Ok(Json(response))
+    } else {
+        Ok(Json(response.without_thinking()))
+    }
+}
+
+/// `Write` adapter that feeds a streaming response body from a blocking task
+struct ChannelWriter(mpsc::Sender<std::io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "client disconnected"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Stream a topic's log as JSONL, a line at a time, so multi-GB topics never
+/// sit in memory
+async fn export_topic(
+    State(state): State<SharedState>,
+    Path((agent_id, topic_id)): Path<(String, String)>,
+) -> Result<Response, StatusCode> {
+    validate_id("agent_id", &agent_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    validate_id("topic_id", &topic_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    // A private reader, so the export doesn't hold the state lock while it streams
+    let jsonl = {
+        let state = state.read().await;
+        JsonlStorage::new(state.store.config()).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    };
+
+    let (tx, rx) = mpsc::channel::<std::io::Result<Bytes>>(16);
+    tokio::task::spawn_blocking(move || {
+        let writer = BufWriter::with_capacity(64 * 1024, ChannelWriter(tx.clone()));
+        if let Err(e) = jsonl.export_topic_jsonl(&agent_id, &topic_id, true, writer) {
+            tracing::warn!("Export of {}/{} failed: {}", agent_id, topic_id, e);
+            // Abort the body so the client sees a truncated transfer, not a short export
+            let _ = tx.blocking_send(Err(std::io::Error::other(e.to_string())));
+        }
+    });
+
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(body)).into_response())
+}
+
+// --- Batch handler ---
+
+/// One operation in a `POST /batch` request, e.g.
+/// `{ "method": "create_memory", "params": { ... } }`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+enum BatchOp {
+    CreateMemory(CreateMemoryRequest),
+    Retrieve(RetrieveRequest),
+    AppendMessage(AppendMessageRequest),
+    CountTokens(CountTokensRequest),
+}
+
+impl BatchOp {
+    /// Whether the op's standalone handler takes the write lock
+    fn needs_write(&self) -> bool {
+        matches!(self, Self::CreateMemory(_))
+    }
+
+    /// Whether the op changes stored state, and so is refused in read-only mode
+    fn mutates(&self) -> bool {
+        matches!(self, Self::CreateMemory(_) | Self::AppendMessage(_))
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BatchOutput {
+    Memory(CreateMemoryResponse),
+    Retrieve(RetrieveResponse),
+    Message(AppendMessageResponse),
+    Tokens(CountTokensResponse),
+}
+
+/// Outcome of one batch op: the status its standalone endpoint would have
+/// returned, plus the response body when it succeeded
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<BatchOutput>,
+}
+
+async fn run_batch_op(
+    state: &AppState,
+    caller: Option<String>,
+    op: BatchOp,
+) -> Result<(StatusCode, BatchOutput), StatusCode> {
+    if state.store.config().read_only && op.mutates() {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    match op {
+        BatchOp::CreateMemory(req) => {
+            let (status, response) = create_memory_in(state, caller, req).await?;
+            Ok((status, BatchOutput::Memory(response)))
+        }
+        BatchOp::Retrieve(req) => {
+            let query = req.into_query(state.store.config().max_query_limit, caller)?;
+            let context = state
+                .retrieval
+                .retrieve_query(&state.store, &query)
+                .await
+                .map_err(retrieval_status)?;
+            Ok((StatusCode::OK, BatchOutput::Retrieve(RetrieveResponse::from(&context))))
+        }
+        BatchOp::AppendMessage(req) => Ok((StatusCode::OK, BatchOutput::Message(append_message_in(state, req)?))),
+        BatchOp::CountTokens(req) => {
+            let tokens = state.token_counter.count(&req.text);
+            Ok((StatusCode::OK, BatchOutput::Tokens(CountTokensResponse { tokens })))
+        }
+    }
+}
+
+/// Run ops in order; a failing op is reported in its slot and doesn't stop the rest
+async fn run_batch(
+    state: &AppState,
+    caller: Option<String>,
+    ops: Vec<BatchOp>,
+) -> Result<Vec<BatchResult>, StatusCode> {
+    if ops.len() > state.store.config().max_query_limit {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut results = Vec::with_capacity(ops.len());
+    for op in ops {
+        let result = match run_batch_op(state, caller.clone(), op).await {
+            Ok((status, output)) => BatchResult {
+                status: status.as_u16(),
+                result: Some(output),
+            },
+            Err(status) => BatchResult {
+                status: status.as_u16(),
+                result: None,
+            },
+        };
+        results.push(result);
+    }
+    Ok(results)
+}
+
+async fn batch(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(ops): Json<Vec<BatchOp>>,
+) -> Result<Json<Vec<BatchResult>>, StatusCode> {
+    let caller = caller_from(&headers);
+
+    // One lock for the whole batch, so later ops see earlier ones' writes
+    let results = if ops.iter().any(BatchOp::needs_write) {
+        let state = state.write().await;
+        run_batch(&state, caller, ops).await?
+    } else {
+        let state = state.read().await;
+        run_batch(&state, caller, ops).await?
+    };
+
+    Ok(Json(results))
+}
+
+// --- Token handlers ---
+
+#[derive(Debug, Deserialize)]
+struct CountTokensRequest {
+    text: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CountTokensResponse {
+    tokens: u32,
+}
+
+async fn count_tokens(
+    State(state): State<SharedState>,
+    Json(req): Json<CountTokensRequest>,
+) -> Json<CountTokensResponse> {
+    let state = state.read().await;
+    let tokens = state.token_counter.count(&req.text);
+    Json(CountTokensResponse { tokens })
+}
+
+#[derive(Debug, Serialize)]
+struct TokenBudgetResponse {
+    used: u32,
+    limit: u32,
+    remaining: u32,
+    utilization: f32,
+    status: String,
+}
+
+impl From<&ContextBudget> for TokenBudgetResponse {
+    fn from(budget: &ContextBudget) -> Self {
+        Self {
+            used: budget.used,
+            limit: budget.limit,
+            remaining: budget.remaining(),
+            utilization: budget.utilization(),
+            status: budget.status().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct EstimateTokensRequest {
+    /// Tokenizer and context limit to use; defaults to the server's tokenizer
+    model: Option<String>,
+    /// Override the model's context limit
+    limit: Option<u32>,
+    #[serde(default)]
+    memories: Vec<String>,
+    #[serde(default)]
+    messages: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EstimateTokensResponse {
+    memory_tokens: u32,
+    message_tokens: u32,
+    #[serde(flatten)]
+    budget: TokenBudgetResponse,
+}
+
+async fn estimate_tokens(
+    State(state): State<SharedState>,
+    Json(req): Json<EstimateTokensRequest>,
+) -> Result<Json<EstimateTokensResponse>, StatusCode> {
+    let state = state.read().await;
+
+    let counter = req
+        .model
+        .as_deref()
+        .map(TokenCounter::new)
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let counter = counter.as_ref().unwrap_or(&state.token_counter);
+    let model_limit = state.store.config().context_limit_for(req.model.as_deref(), None);
+
+    let memory_tokens: u32 = req.memories.iter().map(|t| counter.count(t)).sum();
+    let message_tokens: u32 = req.messages.iter().map(|t| counter.count(t)).sum();
+
+    let mut budget = ContextBudget::new(
+        req.limit.unwrap_or(model_limit).max(1),
+        state.store.config().context_warning_threshold,
+        state.store.config().context_critical_threshold,
+    );
+    budget.add(memory_tokens + message_tokens);
+
+    Ok(Json(EstimateTokensResponse {
+        memory_tokens,
+        message_tokens,
+        budget: TokenBudgetResponse::from(&budget),
+    }))
+}
+
+async fn get_token_budget(
+    State(state): State<SharedState>,
+    Path((agent_id, topic_id)): Path<(String, String)>,
+) -> Result<Json<TokenBudgetResponse>, StatusCode> {
+    let state = state.read().await;
+
+    let total_tokens = state
+        .store
+        .jsonl()
+        .total_tokens(&agent_id, &topic_id)
+        .map_err(|e| match e {
+            Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    let limit = state
+        .store
+        .context_limit(Some(&agent_id), None)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .max(1);
+
+    let mut budget = ContextBudget::new(
+        limit,
+        state.store.config().context_warning_threshold,
+        state.store.config().context_critical_threshold,
+    );
+    budget.add(total_tokens);
+
+    Ok(Json(TokenBudgetResponse::from(&budget)))
+}
+
+// --- Agent/Topic handlers ---
+
+async fn list_agents(
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<String>>, StatusCode> {
+    let state = state.read().await;
+    let agents = state
+        .store
+        .jsonl()
+        .list_agents()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(agents))
+}
+
+async fn list_topics(
+    State(state): State<SharedState>,
+    Path(agent_id): Path<String>,
+) -> Result<Json<Vec<String>>, StatusCode> {
     let state = state.read().await;
     let topics = state
         .store
         .jsonl()
         .list_topics(&agent_id)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| match e {
+            Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
     Ok(Json(topics))
 }
 
+/// Memory types shown in the "recently learned" feed
+const LEARNED_TYPES: [MemoryType; 3] = [
+    MemoryType::Correction,
+    MemoryType::Preference,
+    MemoryType::Constraint,
+];
+
+/// Feed size when `?limit=` is omitted
+const DEFAULT_LEARNED_LIMIT: usize = 20;
+
+#[derive(Debug, Deserialize)]
+struct LearnedQuery {
+    /// RFC 3339 timestamp; only memories created at or after it
+    since: Option<String>,
+    limit: Option<usize>,
+}
+
+/// What an agent has learned recently: its newest corrections, preferences
+/// and constraints, newest first. `context` and `topic_id` link each one back
+/// to where it came from.
+async fn list_learned(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(agent_id): Path<String>,
+    Query(query): Query<LearnedQuery>,
+) -> Result<Json<Vec<MemoryResponse>>, StatusCode> {
+    let state = state.read().await;
+
+    validate_id("agent_id", &agent_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let since = query.since.as_deref().map(parse_timestamp).transpose()?;
+    let max = state.store.config().max_query_limit;
+    let limit = clamp_limit(query.limit.unwrap_or(DEFAULT_LEARNED_LIMIT), max)?;
+
+    let caller = caller_from(&headers);
+    let memories = state
+        .store
+        .list_learned(&agent_id, &LEARNED_TYPES, since, limit, caller.as_deref())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(memories.into_iter().map(MemoryResponse::from).collect()))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PromoteTopicRequest {
+    #[serde(default)]
+    memory_type: Option<String>,
+}
+
+async fn promote_topic(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path((agent_id, topic_id)): Path<(String, String)>,
+    req: Option<Json<PromoteTopicRequest>>,
+) -> Result<Json<MemoryResponse>, StatusCode> {
+    let state = state.write().await;
+
+    let req = req.map(|Json(req)| req).unwrap_or_default();
+    let memory_type: MemoryType = req
+        .memory_type
+        .as_deref()
+        .unwrap_or("fact")
+        .parse()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let memory = state
+        .store
+        .promote_topic(&agent_id, &topic_id, memory_type, &state.retrieval)
+        .await
+        .map_err(|e| match e {
+            Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+    let caller = caller_from(&headers);
+    let target = format!("{}/{}", agent_id, topic_id);
+    state.audit("promote_topic", Some(&target), caller.as_deref());
+    state.audit("create_memory", Some(&memory.id.to_string()), caller.as_deref());
+
+    Ok(Json(MemoryResponse::from(memory)))
+}
+
+#[derive(Debug, Deserialize)]
+struct MergeTopicsRequest {
+    agent_id: String,
+    source_topic: String,
+    dest_topic: String,
+}
+
+async fn merge_topics(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(req): Json<MergeTopicsRequest>,
+) -> Result<Json<MergeReport>, StatusCode> {
+    let state = state.write().await;
+
+    let report = state
+        .store
+        .merge_topics(&req.agent_id, &req.source_topic, &req.dest_topic)
+        .await
+        .map_err(|e| match e {
+            Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    let target = format!("{}/{} -> {}", req.agent_id, req.source_topic, req.dest_topic);
+    state.audit("merge_topics", Some(&target), caller_from(&headers).as_deref());
+    Ok(Json(report))
+}
+
+// --- Admin handlers ---
+
+async fn run_maintenance(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<MaintenanceReport>, StatusCode> {
+    let state = state.write().await;
+    let report = state
+        .store
+        .sqlite()
+        .maintenance()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    state.audit("maintenance", None, caller_from(&headers).as_deref());
+    Ok(Json(report))
+}
+
+/// Compare embedding dimensions across model, config and vector table.
+/// Answers 503 with the report when they disagree, so it can back a probe.
+async fn check_consistency(
+    State(state): State<SharedState>,
+) -> Result<(StatusCode, Json<ConsistencyReport>), StatusCode> {
+    let state = state.read().await;
+    let report = state
+        .store
+        .check_consistency(&state.retrieval)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let status = if report.is_consistent() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok((status, Json(report)))
+}
+
+async fn rebuild_metadata(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<RebuildReport>, StatusCode> {
+    let state = state.write().await;
+    let report = state
+        .store
+        .rebuild_metadata_from_logs()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    state.audit("rebuild_metadata", None, caller_from(&headers).as_deref());
+    Ok(Json(report))
+}
+
 // === Response types ===
 
 #[derive(Debug, Serialize)]
@@ -465,6 +1902,11 @@ struct MemoryResponse {
     last_used_at: Option<String>,
     retrieval_count: u32,
     active: bool,
+    pinned: bool,
+    visibility: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner_id: Option<String>,
+    weight: f32,
 }
 
 impl From<Memory> for MemoryResponse {
@@ -482,6 +1924,10 @@ impl From<Memory> for MemoryResponse {
             last_used_at: m.last_used_at.map(|dt| dt.to_rfc3339()),
             retrieval_count: m.retrieval_count,
             active: m.active,
+            pinned: m.pinned,
+            visibility: m.visibility.to_string(),
+            owner_id: m.owner_id,
+            weight: m.weight,
         }
     }
 }
@@ -495,6 +1941,20 @@ struct MessageResponse {
     content: String,
     tokens: u32,
     timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    retain: bool,
+}
+
+impl MessageResponse {
+    /// Drop thinking so it isn't exposed alongside prompt-facing content
+    fn without_thinking(mut self) -> Self {
+        self.thinking = None;
+        self
+    }
 }
 
 impl From<&Message> for MessageResponse {
@@ -507,6 +1967,791 @@ impl From<&Message> for MessageResponse {
             content: m.content.clone(),
             tokens: m.tokens,
             timestamp: m.timestamp.to_rfc3339(),
+            thinking: m.thinking().map(|t| t.to_string()),
+            attachments: m.attachments().to_vec(),
+            retain: m.retained(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use axum::body::to_bytes;
+    use dieah_memory::embedding::{EmbeddingBackend, EmbeddingService};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    const DIMENSIONS: usize = 64;
+
+    /// Bag-of-words stand-in for the embedding model, failing while `failing` is set
+    #[derive(Clone, Default)]
+    struct WordEmbedder {
+        failing: Arc<AtomicBool>,
+    }
+
+    impl EmbeddingBackend for WordEmbedder {
+        fn embed(&mut self, texts: Vec<String>) -> dieah_memory::Result<Vec<Vec<f32>>> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(Error::embedding("model unavailable"));
+            }
+            let embed = |text: &String| {
+                // Never all zeros, so every vector normalizes
+                let mut vector = vec![0.0; DIMENSIONS];
+                vector[0] = 0.01;
+                for word in text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+                    let hash = word
+                        .to_lowercase()
+                        .bytes()
+                        .fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+                    vector[(hash % DIMENSIONS as u64) as usize] += 1.0;
+                }
+                let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+                vector.iter().map(|v| v / norm).collect()
+            };
+            Ok(texts.iter().map(embed).collect())
+        }
+    }
+
+    /// The server's router over a fresh store in a temp dir
+    struct TestServer {
+        app: Router,
+        state: SharedState,
+        embedder: WordEmbedder,
+        _dir: tempfile::TempDir,
+    }
+
+    async fn test_server(configure: impl FnOnce(&mut Config)) -> TestServer {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::with_data_dir(dir.path());
+        config.embedding_dimensions = DIMENSIONS;
+        config.detect_embedding_dimensions = false;
+        configure(&mut config);
+
+        let embedder = WordEmbedder::default();
+        let service = EmbeddingService::with_backend(&config, embedder.clone()).unwrap();
+        let retrieval = RetrievalEngine::with_embedding_service(config.clone(), service).unwrap();
+        let store = MemoryStore::new(config.clone()).await.unwrap();
+        let degraded = store.degraded().map(str::to_string);
+        let state = Arc::new(RwLock::new(AppState {
+            store,
+            retrieval,
+            token_counter: TokenCounter::for_gpt().unwrap(),
+            audit_log: AuditLog::open(config.audit_log_path()).unwrap(),
+        }));
+
+        TestServer {
+            app: app(&config, state.clone(), degraded.as_deref()).unwrap(),
+            state,
+            embedder,
+            _dir: dir,
+        }
+    }
+
+    impl TestServer {
+        /// Send a request as `caller`, returning the status and the JSON body
+        /// (`Null` when the body is empty or not JSON)
+        async fn send(&self, method: Method, uri: &str, caller: Option<&str>, body: Option<Value>) -> (StatusCode, Value) {
+            let mut request = axum::http::Request::builder().method(method).uri(uri);
+            if let Some(caller) = caller {
+                request = request.header(CALLER_HEADER, caller);
+            }
+            let request = match body {
+                Some(body) => request
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string())),
+                None => request.body(Body::empty()),
+            }
+            .unwrap();
+
+            let response = self.app.clone().oneshot(request).await.unwrap();
+            let status = response.status();
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+        }
+    }
+
+    #[tokio::test]
+    async fn private_memories_stay_hidden_from_other_callers() {
+        let server = test_server(|c| {
+            c.debug_endpoints = true;
+            c.keyword_fallback = true;
+            c.min_similarity_score = 0.3;
+        })
+        .await;
+        let (status, created) = server
+            .send(
+                Method::POST,
+                "/memories",
+                Some("alice"),
+                Some(json!({
+                    "scope": "global",
+                    "memory_type": "fact",
+                    "content": "alice deploys on friday",
+                    "visibility": "private",
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let id = created["id"].as_str().unwrap().to_string();
+
+        let contains = |memories: &Value| memories.as_array().unwrap().iter().any(|m| m["id"] == id.as_str());
+        let query = json!({ "query": "who deploys on friday" });
+        let explain = json!({ "memory_id": id, "query": "who deploys on friday" });
+        let callers = [(Some("alice"), true), (Some("bob"), false), (None, false)];
+
+        for (caller, visible) in callers {
+            let (_, listed) = server.send(Method::GET, "/memories", caller, None).await;
+            assert_eq!(contains(&listed), visible);
+
+            let reads = [
+                server.send(Method::GET, &format!("/memories/{}", id), caller, None).await,
+                server.send(Method::GET, &format!("/debug/memories/{}/vector", id), caller, None).await,
+                server.send(Method::GET, &format!("/debug/memories/{}/neighbors", id), caller, None).await,
+                server.send(Method::POST, "/debug/explain-retrieval", caller, Some(explain.clone())).await,
+            ];
+            for (status, _) in reads {
+                let expected = if visible { StatusCode::OK } else { StatusCode::NOT_FOUND };
+                assert_eq!(status, expected, "caller {:?}", caller);
+            }
+
+            let (_, retrieved) = server.send(Method::POST, "/retrieve", caller, Some(query.clone())).await;
+            assert_eq!(contains(&retrieved["memories"]), visible);
+        }
+
+        // The keyword fallback answers from SQLite and must filter the same way
+        server.embedder.failing.store(true, Ordering::SeqCst);
+        for (caller, visible) in callers {
+            let (status, retrieved) = server.send(Method::POST, "/retrieve", caller, Some(query.clone())).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(contains(&retrieved["memories"]), visible);
+        }
+    }
+
+    #[test]
+    fn resolve_role_rejects_unknown_roles_unless_allowed() {
+        let mut config = Config::default();
+        assert_eq!(resolve_role(&config, Some("developer")).unwrap(), Role::Developer);
+
+        let error = resolve_role(&config, Some("narrator")).unwrap_err();
+        assert!(error.error.contains("narrator"));
+        assert_eq!(error.accepted_roles, Role::KNOWN);
+
+        config.allow_custom_roles = true;
+        assert_eq!(
+            resolve_role(&config, Some("narrator")).unwrap(),
+            Role::Other("narrator".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_embedding_stores_the_memory_unembedded_when_enabled() {
+        let memory = json!({ "scope": "global", "memory_type": "fact", "content": "deploys run on friday" });
+
+        let server = test_server(|_| {}).await;
+        server.embedder.failing.store(true, Ordering::SeqCst);
+        let (status, _) = server.send(Method::POST, "/memories", None, Some(memory.clone())).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(server.state.read().await.store.list_memories(None, None, None, None, false).unwrap().is_empty());
+
+        let server = test_server(|c| c.save_unembedded_on_failure = true).await;
+        server.embedder.failing.store(true, Ordering::SeqCst);
+        let (status, created) = server.send(Method::POST, "/memories", None, Some(memory)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(created["note"].as_str().unwrap().contains("unembedded"));
+        let id = created["id"].as_str().unwrap().to_string();
+
+        let (status, _) = server.send(Method::GET, &format!("/memories/{}", id), None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let pending = server.state.read().await.store.list_unembedded().unwrap();
+        assert_eq!(pending.iter().map(|m| m.id.to_string()).collect::<Vec<_>>(), [id.clone()]);
+
+        // Once the model is back, embedding it clears the queue
+        server.embedder.failing.store(false, Ordering::SeqCst);
+        let (status, _) = server.send(Method::POST, &format!("/memories/{}/reembed", id), None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(server.state.read().await.store.list_unembedded().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn thinking_is_stored_but_only_returned_on_request() {
+        let server = test_server(|_| {}).await;
+        let (status, appended) = server
+            .send(
+                Method::POST,
+                "/messages",
+                None,
+                Some(json!({
+                    "agent_id": "coder",
+                    "topic_id": "build",
+                    "role": "assistant",
+                    "content": "Run cargo test",
+                    "thinking": "The user wants the test command",
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let id = appended["id"].as_str().unwrap().to_string();
+
+        // Persisted alongside the message, not in its content
+        let stored = server.state.read().await.store.jsonl().read_all("coder", "build").unwrap();
+        assert_eq!(stored[0].content, "Run cargo test");
+        assert_eq!(stored[0].thinking(), Some("The user wants the test command"));
+
+        for uri in ["/messages/coder/build".to_string(), format!("/messages/coder/build/{}", id)] {
+            let (_, hidden) = server.send(Method::GET, &uri, None, None).await;
+            let (_, shown) = server.send(Method::GET, &format!("{}?include_thinking=true", uri), None, None).await;
+            let (hidden, shown) = match (hidden.as_array(), shown.as_array()) {
+                (Some(hidden), Some(shown)) => (hidden[0].clone(), shown[0].clone()),
+                _ => (hidden, shown),
+            };
+            assert_eq!(hidden["content"], "Run cargo test");
+            assert!(hidden.get("thinking").is_none(), "{}", uri);
+            assert_eq!(shown["thinking"], "The user wants the test command", "{}", uri);
+        }
+    }
+
+
+    #[test]
+    fn retrieve_overrides_are_clamped_and_parsed() {
+        let request = |body: Value| serde_json::from_value::<RetrieveRequest>(body).unwrap();
+
+        let query = request(json!({
+            "query": "q",
+            "limit": 500,
+            "min_score": 1.5,
+            "scopes": [" topic ", "agent"],
+            "types": ["workflow"],
+        }))
+        .into_query(50, None)
+        .unwrap();
+        assert_eq!(query.limit, Some(50));
+        assert_eq!(query.min_score, Some(1.0));
+        assert_eq!(query.scopes, [MemoryScope::Topic, MemoryScope::Agent]);
+        assert_eq!(query.types, [MemoryType::Workflow]);
+
+        let query = request(json!({ "query": "q", "min_score": -0.5 })).into_query(50, None).unwrap();
+        assert_eq!((query.limit, query.min_score), (None, Some(0.0)));
+        assert!(query.scopes.is_empty() && query.types.is_empty());
+
+        for invalid in [
+            json!({ "query": "q", "limit": 0 }),
+            json!({ "query": "q", "scopes": ["everywhere"] }),
+            json!({ "query": "q", "types": ["rumor"] }),
+        ] {
+            assert_eq!(request(invalid.clone()).into_query(50, None).unwrap_err(), StatusCode::BAD_REQUEST, "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn retrieve_overrides_take_effect() {
+        let server = test_server(|_| {}).await;
+        let mut created = Vec::new();
+        for (scope, memory_type, content) in [
+            ("global", "fact", "deploys run on friday"),
+            ("global", "fact", "deploys run on friday"),
+            ("agent", "constraint", "deploys run on friday"),
+            ("global", "fact", "lint before commit"),
+        ] {
+            let (status, memory) = server
+                .send(
+                    Method::POST,
+                    "/memories",
+                    None,
+                    Some(json!({ "scope": scope, "agent_id": "coder", "memory_type": memory_type, "content": content })),
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK, "{}", memory);
+            created.push(memory["id"].as_str().unwrap().to_string());
+        }
+        let server = &server;
+        let retrieve = move |overrides: Value| {
+            let mut body = json!({ "query": "deploys run on friday", "agent_id": "coder" });
+            body.as_object_mut().unwrap().extend(overrides.as_object().unwrap().clone());
+            async move {
+                let (status, response) = server.send(Method::POST, "/retrieve", None, Some(body)).await;
+                assert_eq!(status, StatusCode::OK, "{}", response);
+                let mut ids: Vec<String> = response["memories"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|m| m["id"].as_str().unwrap().to_string())
+                    .collect();
+                ids.sort();
+                (ids, response["threshold"].as_f64().unwrap())
+            }
+        };
+        let sorted = |indexes: &[usize]| {
+            let mut ids: Vec<String> = indexes.iter().map(|&i| created[i].clone()).collect();
+            ids.sort();
+            ids
+        };
+
+        let (ids, threshold) = retrieve(json!({})).await;
+        assert_eq!(ids, sorted(&[0, 1, 2]));
+        assert!((threshold - 0.7).abs() < 1e-6);
+
+        assert_eq!(retrieve(json!({ "limit": 1 })).await.0.len(), 1);
+        let (ids, threshold) = retrieve(json!({ "min_score": -3.0 })).await;
+        assert_eq!(ids, sorted(&[0, 1, 2, 3]));
+        assert_eq!(threshold, 0.0);
+        assert_eq!(retrieve(json!({ "scopes": ["agent"] })).await.0, sorted(&[2]));
+        assert_eq!(retrieve(json!({ "types": ["constraint"] })).await.0, sorted(&[2]));
+        assert_eq!(retrieve(json!({ "scopes": ["global"], "types": ["fact"] })).await.0, sorted(&[0, 1]));
+
+        let (status, _) = server
+            .send(Method::POST, "/retrieve", None, Some(json!({ "query": "q", "limit": 0 })))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+
+    #[tokio::test]
+    async fn debug_endpoints_are_only_routed_behind_the_flag() {
+        async fn create(server: &TestServer, content: &str) -> String {
+            let body = json!({ "scope": "global", "memory_type": "fact", "content": content });
+            let (status, memory) = server.send(Method::POST, "/memories", None, Some(body)).await;
+            assert_eq!(status, StatusCode::OK, "{}", memory);
+            memory["id"].as_str().unwrap().to_string()
+        }
+
+        let disabled = test_server(|_| {}).await;
+        let id = create(&disabled, "deploys run on friday").await;
+        for uri in [format!("/debug/memories/{}/vector", id), format!("/debug/memories/{}/neighbors", id)] {
+            assert_eq!(disabled.send(Method::GET, &uri, None, None).await.0, StatusCode::NOT_FOUND);
+        }
+        let explain = json!({ "memory_id": id, "query": "deploys" });
+        let (status, _) = disabled.send(Method::POST, "/debug/explain-retrieval", None, Some(explain)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let server = test_server(|c| c.debug_endpoints = true).await;
+        let target = create(&server, "deploys run on friday").await;
+        let twin = create(&server, "deploys run on friday").await;
+        let close = create(&server, "deploys run on friday at noon").await;
+        create(&server, "lint before commit").await;
+
+        let (status, vector) = server.send(Method::GET, &format!("/debug/memories/{}/vector", target), None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(vector["id"], target.as_str());
+        assert_eq!(vector["dimensions"], DIMENSIONS);
+        let values = vector["vector"].as_array().unwrap();
+        assert_eq!(values.len(), DIMENSIONS);
+        let norm = values.iter().map(|v| v.as_f64().unwrap().powi(2)).sum::<f64>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-4, "{}", norm);
+
+        // Nearest first, without the memory itself
+        let uri = format!("/debug/memories/{}/neighbors?limit=2", target);
+        let (status, neighbors) = server.send(Method::GET, &uri, None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let neighbors = neighbors.as_array().unwrap();
+        let ids: Vec<&str> = neighbors.iter().map(|n| n["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, [twin.as_str(), close.as_str()]);
+        assert!(neighbors[0]["score"].as_f64().unwrap() > neighbors[1]["score"].as_f64().unwrap());
+        assert!(neighbors[0]["distance"].as_f64().unwrap() < 1e-4);
+
+        let missing = Uuid::new_v4();
+        for (uri, expected) in [
+            (format!("/debug/memories/{}/neighbors?limit=0", target), StatusCode::BAD_REQUEST),
+            ("/debug/memories/not-a-uuid/vector".to_string(), StatusCode::BAD_REQUEST),
+            (format!("/debug/memories/{}/vector", missing), StatusCode::NOT_FOUND),
+            (format!("/debug/memories/{}/neighbors", missing), StatusCode::NOT_FOUND),
+        ] {
+            assert_eq!(server.send(Method::GET, &uri, None, None).await.0, expected, "{}", uri);
+        }
+    }
+
+
+    #[tokio::test]
+    async fn read_only_mode_refuses_only_mutations() {
+        let server = test_server(|c| {
+            c.read_only = true;
+            c.min_similarity_score = 0.3;
+        })
+        .await;
+        let id = {
+            let state = server.state.read().await;
+            let memory = Memory::global(MemoryType::Fact, "deploys run on friday");
+            state.retrieval.embed_and_save(&state.store, memory).await.unwrap().id
+        };
+        let memory = json!({ "scope": "global", "memory_type": "fact", "content": "lint before commit" });
+        let message = json!({ "agent_id": "coder", "topic_id": "build", "role": "user", "content": "hi" });
+        let query = json!({ "query": "when do deploys run" });
+
+        let requests = [
+            (Method::POST, "/memories".to_string(), Some(memory.clone()), StatusCode::METHOD_NOT_ALLOWED),
+            (Method::DELETE, format!("/memories/{}", id), None, StatusCode::METHOD_NOT_ALLOWED),
+            (Method::POST, format!("/memories/{}/pin", id), Some(json!({ "pinned": true })), StatusCode::METHOD_NOT_ALLOWED),
+            (Method::POST, "/messages".to_string(), Some(message.clone()), StatusCode::METHOD_NOT_ALLOWED),
+            (Method::GET, "/memories".to_string(), None, StatusCode::OK),
+            (Method::GET, format!("/memories/{}", id), None, StatusCode::OK),
+            (Method::POST, "/retrieve".to_string(), Some(query.clone()), StatusCode::OK),
+            (Method::POST, "/tokens/count".to_string(), Some(json!({ "text": "hi" })), StatusCode::OK),
+        ];
+        for (method, uri, body, expected) in requests {
+            let (status, _) = server.send(method.clone(), &uri, None, body).await;
+            assert_eq!(status, expected, "{} {}", method, uri);
+        }
+
+        // `/batch` itself is allowed; its mutating ops are refused one by one
+        let ops = json!([
+            { "method": "create_memory", "params": memory },
+            { "method": "retrieve", "params": query },
+            { "method": "append_message", "params": message },
+            { "method": "count_tokens", "params": { "text": "hi" } },
+        ]);
+        let (status, results) = server.send(Method::POST, "/batch", None, Some(ops)).await;
+        assert_eq!(status, StatusCode::OK);
+        let statuses: Vec<u64> = results.as_array().unwrap().iter().map(|r| r["status"].as_u64().unwrap()).collect();
+        assert_eq!(statuses, [405, 200, 405, 200]);
+        assert!(results[0].get("result").is_none());
+        assert_eq!(results[1]["result"]["memories"][0]["id"], id.to_string());
+
+        // Nothing was written
+        let (_, listed) = server.send(Method::GET, "/memories", None, None).await;
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        let (_, messages) = server.send(Method::GET, "/messages/coder/build", None, None).await;
+        assert!(messages.as_array().map_or(true, |m| m.is_empty()), "{}", messages);
+    }
+
+
+    #[tokio::test]
+    async fn deletes_are_written_to_the_audit_log() {
+        async fn entries(server: &TestServer) -> Vec<dieah_memory::audit::AuditEntry> {
+            let path = server.state.read().await.audit_log.path().to_path_buf();
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+
+        let server = test_server(|_| {}).await;
+        let mut ids = Vec::new();
+        for content in ["deploys run on friday", "lint before commit"] {
+            let body = json!({ "scope": "global", "memory_type": "fact", "content": content });
+            let (_, memory) = server.send(Method::POST, "/memories", Some("alice"), Some(body)).await;
+            ids.push(memory["id"].as_str().unwrap().to_string());
+        }
+        let before = entries(&server).await.len();
+
+        let (status, _) = server.send(Method::DELETE, &format!("/memories/{}", ids[0]), Some("alice"), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = server
+            .send(Method::POST, "/memories/delete", None, Some(json!({ "ids": [ids[1]] })))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let added = entries(&server).await.split_off(before);
+        let recorded: Vec<(&str, Option<&str>, Option<&str>)> = added
+            .iter()
+            .map(|e| (e.operation.as_str(), e.target.as_deref(), e.caller.as_deref()))
+            .collect();
+        assert_eq!(
+            recorded,
+            [
+                ("delete_memory", Some(ids[0].as_str()), Some("alice")),
+                ("delete_memory", Some(ids[1].as_str()), None),
+            ]
+        );
+
+        // A delete that finds nothing writes nothing
+        let missing = Uuid::new_v4();
+        let (status, _) = server.send(Method::DELETE, &format!("/memories/{}", missing), None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(entries(&server).await.len(), before + 2);
+    }
+
+
+    #[tokio::test]
+    async fn bad_roles_get_an_error_body_and_missing_ones_the_default() {
+        let message = |role: Option<&str>| {
+            let mut body = json!({ "agent_id": "coder", "topic_id": "build", "content": "hi" });
+            if let Some(role) = role {
+                body["role"] = json!(role);
+            }
+            body
+        };
+
+        let server = test_server(|_| {}).await;
+        let (status, body) = server.send(Method::POST, "/messages", None, Some(message(Some("narrator")))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("narrator"), "{}", body);
+        assert_eq!(body["accepted_roles"], json!(Role::KNOWN));
+
+        // No role and no default to fall back on
+        for role in [None, Some("  ")] {
+            let (status, body) = server.send(Method::POST, "/messages", None, Some(message(role))).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["error"], "role is required (no default role is configured)");
+        }
+
+        let server = test_server(|c| c.default_role = Some("user".to_string())).await;
+        for role in [None, Some(""), Some("assistant")] {
+            let (status, body) = server.send(Method::POST, "/messages", None, Some(message(role))).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+        }
+        let (_, stored) = server.send(Method::GET, "/messages/coder/build", None, None).await;
+        let roles: Vec<&str> = stored.as_array().unwrap().iter().map(|m| m["role"].as_str().unwrap()).collect();
+        assert_eq!(roles, ["user", "user", "assistant"]);
+    }
+
+
+    #[test]
+    fn parse_timestamp_accepts_past_rfc3339_times_only() {
+        let parsed = parse_timestamp("2024-03-01T12:00:00+02:00").unwrap();
+        assert_eq!(parsed, DateTime::parse_from_rfc3339("2024-03-01T10:00:00Z").unwrap());
+
+        // Clock skew within the allowance is fine; further ahead is not
+        let ahead = |secs: i64| (Utc::now() + chrono::Duration::seconds(secs)).to_rfc3339();
+        assert!(parse_timestamp(&ahead(60)).is_ok());
+        assert_eq!(parse_timestamp(&ahead(MAX_TIMESTAMP_SKEW_SECS + 60)), Err(StatusCode::BAD_REQUEST));
+
+        for invalid in ["", "yesterday", "2024-03-01", "2024-03-01T10:00:00", "1709287200"] {
+            assert_eq!(parse_timestamp(invalid), Err(StatusCode::BAD_REQUEST), "{:?}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn appended_messages_keep_a_client_timestamp() {
+        let server = test_server(|_| {}).await;
+        let message = |timestamp: &str| {
+            json!({ "agent_id": "coder", "topic_id": "build", "role": "user", "content": "hi", "timestamp": timestamp })
+        };
+
+        let (status, body) = server
+            .send(Method::POST, "/messages", None, Some(message("2024-03-01T12:00:00+02:00")))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let stored = DateTime::parse_from_rfc3339(body["timestamp"].as_str().unwrap()).unwrap();
+        assert_eq!(stored, DateTime::parse_from_rfc3339("2024-03-01T10:00:00Z").unwrap());
+
+        let future = (Utc::now() + chrono::Duration::days(1)).to_rfc3339();
+        for timestamp in [future.as_str(), "not a time"] {
+            let (status, _) = server.send(Method::POST, "/messages", None, Some(message(timestamp))).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", timestamp);
+        }
+    }
+
+
+    #[tokio::test]
+    async fn messages_without_a_topic_go_to_the_default_topic() {
+        for (default_topic, configured) in [("general", false), ("inbox", true)] {
+            let server = test_server(|c| {
+                if configured {
+                    c.default_topic_id = default_topic.to_string();
+                }
+            })
+            .await;
+            let bodies = [
+                json!({ "agent_id": "coder", "role": "user", "content": "no topic" }),
+                json!({ "agent_id": "coder", "topic_id": "  ", "role": "user", "content": "blank topic" }),
+                json!({ "agent_id": "coder", "topic_id": "build", "role": "user", "content": "own topic" }),
+            ];
+            for body in bodies {
+                let (status, response) = server.send(Method::POST, "/messages", None, Some(body)).await;
+                assert_eq!(status, StatusCode::OK, "{}", response);
+            }
+
+            let (_, defaulted) = server.send(Method::GET, &format!("/messages/coder/{}", default_topic), None, None).await;
+            let contents: Vec<&str> = defaulted.as_array().unwrap().iter().map(|m| m["content"].as_str().unwrap()).collect();
+            assert_eq!(contents, ["no topic", "blank topic"]);
+            assert!(defaulted.as_array().unwrap().iter().all(|m| m["topic_id"] == default_topic));
+            let (_, own) = server.send(Method::GET, "/messages/coder/build", None, None).await;
+            assert_eq!(own.as_array().unwrap().len(), 1);
+        }
+    }
+
+
+    #[test]
+    fn clamp_limit_rejects_zero_and_caps_at_the_maximum() {
+        assert_eq!(clamp_limit(0, 50), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(clamp_limit(1, 50), Ok(1));
+        assert_eq!(clamp_limit(50, 50), Ok(50));
+        assert_eq!(clamp_limit(usize::MAX, 50), Ok(50));
+    }
+
+    #[tokio::test]
+    async fn list_limits_are_clamped_to_max_query_limit() {
+        let server = test_server(|c| c.max_query_limit = 2).await;
+        for content in ["deploys run on friday", "lint before commit", "tests need a database"] {
+            let body = json!({ "scope": "global", "memory_type": "fact", "content": content });
+            assert_eq!(server.send(Method::POST, "/memories", None, Some(body)).await.0, StatusCode::OK);
+        }
+
+        for (uri, expected) in [("/memories?limit=1", 1), ("/memories?limit=1000", 2), ("/memories", 3)] {
+            let (status, listed) = server.send(Method::GET, uri, None, None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(listed.as_array().unwrap().len(), expected, "{}", uri);
+        }
+        assert_eq!(server.send(Method::GET, "/memories?limit=0", None, None).await.0, StatusCode::BAD_REQUEST);
+    }
+
+
+    #[tokio::test]
+    async fn estimate_tokens_sums_memories_and_messages_against_the_limit() {
+        let server = test_server(|_| {}).await;
+        let counter = TokenCounter::for_gpt().unwrap();
+        let memories = ["deploys run on friday", "lint before commit"];
+        let messages = ["when do deploys run?"];
+        let memory_tokens: u32 = memories.iter().map(|t| counter.count(t)).sum();
+        let message_tokens: u32 = messages.iter().map(|t| counter.count(t)).sum();
+        let used = memory_tokens + message_tokens;
+        let estimate = |extra: Value| {
+            let mut body = json!({ "memories": memories, "messages": messages });
+            body.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            body
+        };
+
+        let (status, body) = server.send(Method::POST, "/tokens/estimate", None, Some(estimate(json!({})))).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["memory_tokens"], memory_tokens);
+        assert_eq!(body["message_tokens"], message_tokens);
+        assert_eq!(body["used"], used);
+        assert_eq!(body["limit"], dieah_memory::config::DEFAULT_CONTEXT_LIMIT);
+        assert_eq!(body["remaining"], dieah_memory::config::DEFAULT_CONTEXT_LIMIT - used);
+        assert_eq!(body["status"], "ok");
+
+        // A model brings its own context limit; an explicit limit overrides it
+        let (_, body) = server
+            .send(Method::POST, "/tokens/estimate", None, Some(estimate(json!({ "model": "gpt-4" }))))
+            .await;
+        assert_eq!(body["limit"], 8192);
+        assert_eq!(body["used"], used);
+        let (_, body) = server
+            .send(Method::POST, "/tokens/estimate", None, Some(estimate(json!({ "model": "gpt-4", "limit": 4 }))))
+            .await;
+        assert_eq!((body["limit"].clone(), body["remaining"].clone()), (json!(4), json!(0)));
+        assert_eq!(body["status"], "critical");
+
+        let (status, body) = server.send(Method::POST, "/tokens/estimate", None, Some(json!({}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((body["used"].clone(), body["status"].clone()), (json!(0), json!("ok")));
+
+        let (status, _) = server
+            .send(Method::POST, "/tokens/estimate", None, Some(estimate(json!({ "model": "not-a-model" }))))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+
+    #[tokio::test]
+    async fn batch_ops_run_in_order_and_fail_independently() {
+        let server = test_server(|c| c.max_query_limit = 5).await;
+        let ops = json!([
+            { "method": "create_memory", "params": { "scope": "global", "memory_type": "fact", "content": "deploys run on friday" } },
+            { "method": "retrieve", "params": { "query": "deploys run on friday", "limit": 0 } },
+            { "method": "retrieve", "params": { "query": "deploys run on friday" } },
+            { "method": "append_message", "params": { "agent_id": "coder", "topic_id": "build", "role": "user", "content": "hi" } },
+            { "method": "count_tokens", "params": { "text": "hello world" } },
+        ]);
+        let (status, results) = server.send(Method::POST, "/batch", None, Some(ops)).await;
+        assert_eq!(status, StatusCode::OK, "{}", results);
+        let statuses: Vec<u64> = results.as_array().unwrap().iter().map(|r| r["status"].as_u64().unwrap()).collect();
+        assert_eq!(statuses, [200, 400, 200, 200, 200]);
+
+        // Later ops see earlier ones' writes
+        let created = results[0]["result"]["id"].as_str().unwrap();
+        assert_eq!(results[2]["result"]["memories"][0]["id"], created);
+        assert!(results[1].get("result").is_none());
+        assert_eq!(results[3]["result"]["content"], "hi");
+        let tokens = TokenCounter::for_gpt().unwrap().count("hello world");
+        assert_eq!(results[4]["result"]["tokens"], tokens);
+        let (_, messages) = server.send(Method::GET, "/messages/coder/build", None, None).await;
+        assert_eq!(messages.as_array().unwrap().len(), 1);
+
+        // More ops than `max_query_limit` is refused as a whole
+        let count = json!({ "method": "count_tokens", "params": { "text": "hi" } });
+        let ops = Value::Array(vec![count; 6]);
+        assert_eq!(server.send(Method::POST, "/batch", None, Some(ops)).await.0, StatusCode::BAD_REQUEST);
+        let (status, body) = server.send(Method::POST, "/batch", None, Some(json!([{ "method": "launch" }]))).await;
+        assert!(status.is_client_error(), "{} {}", status, body);
+    }
+
+
+    #[tokio::test]
+    async fn retrieve_reports_the_metric_behind_its_scores() {
+        use dieah_memory::storage::DistanceMetric;
+
+        // Cosine similarity 0.82 between the two texts
+        for (metric, name, expected) in [(DistanceMetric::L2, "l2", 0.73), (DistanceMetric::Cosine, "cosine", 0.82)] {
+            let server = test_server(|c| c.distance_metric = metric).await;
+            let memory = json!({ "scope": "global", "memory_type": "fact", "content": "deploys run on friday" });
+            assert_eq!(server.send(Method::POST, "/memories", None, Some(memory)).await.0, StatusCode::OK);
+
+            let query = json!({ "query": "deploys run on friday at noon", "min_score": 0.0 });
+            let (status, body) = server.send(Method::POST, "/retrieve", None, Some(query)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["metric"], name);
+            let score = body["memories"][0]["score"].as_f64().unwrap();
+            assert!((score - expected).abs() < 0.01, "{}: {}", name, score);
         }
     }
+
+
+    #[tokio::test]
+    async fn request_ids_are_echoed_or_generated() {
+        for name in ["x-request-id", "x-trace-id"] {
+            let server = test_server(|c| c.request_id_header = name.to_string()).await;
+            let health = |id: Option<&str>| {
+                let mut request = axum::http::Request::builder().uri("/health");
+                if let Some(id) = id {
+                    request = request.header(name, id);
+                }
+                server.app.clone().oneshot(request.body(Body::empty()).unwrap())
+            };
+
+            let response = health(Some("client-trace-42")).await.unwrap();
+            assert_eq!(response.headers()[name], "client-trace-42");
+
+            let response = health(None).await.unwrap();
+            let generated = response.headers()[name].to_str().unwrap();
+            assert!(Uuid::parse_str(generated).is_ok(), "{}", generated);
+        }
+
+        // The span carries the id for every log line of the request
+        let header = HeaderName::from_static("x-request-id");
+        let request = axum::http::Request::builder()
+            .uri("/health")
+            .header(&header, "client-trace-42")
+            .body(Body::empty())
+            .unwrap();
+        let span = request_span(header)(&request);
+        let metadata = span.metadata().unwrap();
+        assert_eq!(metadata.name(), "request");
+        assert!(metadata.fields().field("request_id").is_some());
+    }
+
+
+    #[tokio::test]
+    async fn hydrated_results_include_the_stored_record() {
+        let server = test_server(|_| {}).await;
+        let memory = json!({
+            "scope": "agent",
+            "agent_id": "coder",
+            "memory_type": "fact",
+            "content": "deploys run on friday",
+            "context": "from the release retro",
+            "tags": ["ops", "deploys"],
+        });
+        let (status, created) = server.send(Method::POST, "/memories", None, Some(memory)).await;
+        assert_eq!(status, StatusCode::OK);
+        let query = json!({ "query": "deploys run on friday", "agent_id": "coder" });
+
+        let (_, bare) = server.send(Method::POST, "/retrieve", None, Some(query.clone())).await;
+        assert!(bare["memories"][0].get("metadata").is_none());
+
+        let (status, hydrated) = server
+            .send(Method::POST, "/retrieve?hydrate=true&types=fact", None, Some(query))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let metadata = &hydrated["memories"][0]["metadata"];
+        assert_eq!(metadata["id"], created["id"]);
+        assert_eq!(metadata["tags"], json!(["ops", "deploys"]));
+        assert_eq!(metadata["context"], "from the release retro");
+        assert_eq!(metadata["created_at"], created["created_at"]);
+        assert!(DateTime::parse_from_rfc3339(metadata["created_at"].as_str().unwrap()).is_ok());
+        assert_eq!(metadata["agent_id"], "coder");
+    }
 }
@@ -0,0 +1,82 @@
+//! Whole-agent snapshots: the agent record, every topic's messages and the
+//! agent's memories in one JSON document
+//!
+//! Written by `MemoryStore::export_bundle` and restored by
+//! `MemoryStore::import_bundle`. Global and personal memories belong to no
+//! agent and are not included.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::memory::Memory;
+use crate::message::Message;
+use crate::storage::{AgentRecord, TopicRecord};
+
+/// Version of the bundle envelope written by `export_bundle`
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Envelope for an exported agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentBundle {
+    /// Bundle format version
+    pub version: u32,
+
+    /// When the bundle was written
+    pub exported_at: DateTime<Utc>,
+
+    /// Agent the bundle was exported from
+    pub agent_id: String,
+
+    /// The agent's settings, if it had a record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<AgentRecord>,
+
+    /// Every topic with a conversation log
+    pub topics: Vec<BundleTopic>,
+
+    /// Agent- and topic-scoped memories, inactive ones included
+    pub memories: Vec<BundleMemory>,
+}
+
+/// One topic of a bundle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleTopic {
+    pub topic_id: String,
+
+    /// The topic's record, for its name and creation time; counts are
+    /// recomputed from the messages on import
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record: Option<TopicRecord>,
+
+    /// Messages in log order
+    pub messages: Vec<Message>,
+}
+
+/// One memory of a bundle, with its stored vectors when
+/// `Config.bundle_embeddings` is set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleMemory {
+    #[serde(flatten)]
+    pub memory: Memory,
+
+    /// One vector per content chunk; empty when not exported
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub embeddings: Vec<Vec<f32>>,
+}
+
+/// Outcome of `MemoryStore::import_bundle`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BundleImportReport {
+    /// Topics restored
+    pub topics: usize,
+
+    /// Messages appended across all topics
+    pub messages: usize,
+
+    /// Memories restored
+    pub memories: usize,
+
+    /// Memories restored without usable vectors (none exported, or of another
+    /// dimension); `embed-pending` embeds them
+    pub unembedded: usize,
+}
@@ -0,0 +1,267 @@
+//! Cache of similarity search results, invalidated per scope on writes
+//!
+//! Entries are indexed by the scope of the query that produced them (agent,
+//! topic, all-agents), so saving or deleting a memory only drops the cached
+//! queries that could have returned it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use crate::memory::{Memory, MemoryScope};
+use crate::retrieval::{RetrievalQuery, RetrievedMemory};
+
+/// Which memories a cached query could have seen
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct QueryScope {
+    agent_id: Option<String>,
+    topic_id: Option<String>,
+    all_agents: bool,
+}
+
+impl QueryScope {
+    fn of(query: &RetrievalQuery) -> Self {
+        Self {
+            agent_id: query.agent_id.clone(),
+            topic_id: query.topic_id.clone(),
+            all_agents: query.all_agents,
+        }
+    }
+
+    /// Whether a query with this scope could retrieve `memory`, mirroring the
+    /// scope rules of `RetrievalEngine::search_scopes`
+    fn overlaps(&self, memory: &Memory, topic_isolation: bool) -> bool {
+        let agent_matches = || {
+            self.all_agents
+                || (self.agent_id.is_some() && self.agent_id == memory.agent_id)
+        };
+        match memory.scope {
+            MemoryScope::Global => true,
+            MemoryScope::Agent => agent_matches(),
+            MemoryScope::Topic => {
+                agent_matches()
+                    && (!topic_isolation
+                        || (self.topic_id.is_some() && self.topic_id == memory.topic_id))
+            }
+            MemoryScope::Personal => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, (QueryScope, Vec<RetrievedMemory>)>,
+    /// Keys per query scope, for invalidation
+    by_scope: HashMap<QueryScope, HashSet<String>>,
+    /// Insertion order, oldest first, for eviction
+    order: VecDeque<String>,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some((scope, _)) = self.entries.remove(key) {
+            if let Some(keys) = self.by_scope.get_mut(&scope) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.by_scope.remove(&scope);
+                }
+            }
+        }
+    }
+}
+
+/// Bounded cache of `RetrievalEngine::retrieve_query` search results
+///
+/// Holds at most `Config.search_cache_size` queries, evicting the oldest; a
+/// capacity of zero disables it. Only the similarity hits are cached, so
+/// pinned memories and recent messages are always read fresh.
+pub struct SearchCache {
+    capacity: usize,
+    topic_isolation: bool,
+    inner: Mutex<Inner>,
+}
+
+impl SearchCache {
+    pub fn new(capacity: usize, topic_isolation: bool) -> Self {
+        Self {
+            capacity,
+            topic_isolation,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Cache key for a query, or `None` if it can't be cached (disabled, or
+    /// it carries a caller-supplied filter)
+    pub fn key(&self, query: &RetrievalQuery) -> Option<String> {
+        if self.capacity == 0 || query.filter.is_some() {
+            return None;
+        }
+        Some(format!(
+            "{:?}|{:?}|{:?}|{:?}|{:?}|{}|{}|{:?}|{:?}|{:?}|{:?}",
+            query.query,
+            query.agent_id,
+            query.topic_id,
+            query.types,
+            query.caller,
+            query.all_agents,
+            query.include_inactive,
+            query.query_tags,
+            query.limit,
+            query.min_score,
+            query.scopes,
+        ))
+    }
+
+    /// Cached results for a key
+    pub fn get(&self, key: &str) -> Option<Vec<RetrievedMemory>> {
+        let inner = self.inner.lock().ok()?;
+        inner.entries.get(key).map(|(_, memories)| memories.clone())
+    }
+
+    /// Cache results for `query` under `key`, evicting the oldest entry when full
+    pub fn insert(&self, key: String, query: &RetrievalQuery, memories: Vec<RetrievedMemory>) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+
+        inner.remove(&key);
+        inner.order.retain(|k| k != &key);
+        while inner.entries.len() >= self.capacity {
+            let Some(oldest) = inner.order.pop_front() else {
+                break;
+            };
+            inner.remove(&oldest);
+        }
+
+        let scope = QueryScope::of(query);
+        inner.by_scope.entry(scope.clone()).or_default().insert(key.clone());
+        inner.order.push_back(key.clone());
+        inner.entries.insert(key, (scope, memories));
+    }
+
+    /// Drop every cached query that could have returned `memory`
+    pub fn invalidate(&self, memory: &Memory) {
+        self.invalidate_where(|scope| scope.overlaps(memory, self.topic_isolation));
+    }
+
+    /// Drop every cached query that could see an agent's memories, e.g. after
+    /// a topic merge re-pointed several of them
+    pub fn invalidate_agent(&self, agent_id: &str) {
+        self.invalidate_where(|scope| scope.all_agents || scope.agent_id.as_deref() == Some(agent_id));
+    }
+
+    fn invalidate_where(&self, stale_scope: impl Fn(&QueryScope) -> bool) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+
+        let stale: HashSet<String> = inner
+            .by_scope
+            .iter()
+            .filter(|(scope, _)| stale_scope(scope))
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect();
+        if stale.is_empty() {
+            return;
+        }
+
+        for key in &stale {
+            inner.remove(key);
+        }
+        inner.order.retain(|k| !stale.contains(k));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryType;
+
+    fn query(agent_id: Option<&str>, topic_id: Option<&str>, all_agents: bool) -> RetrievalQuery {
+        RetrievalQuery {
+            query: "deploy".to_string(),
+            agent_id: agent_id.map(str::to_string),
+            topic_id: topic_id.map(str::to_string),
+            all_agents,
+            ..Default::default()
+        }
+    }
+
+    /// Cache an empty result for each query, returning their keys
+    fn fill(cache: &SearchCache, queries: &[RetrievalQuery]) -> Vec<String> {
+        queries
+            .iter()
+            .map(|query| {
+                let key = cache.key(query).unwrap();
+                cache.insert(key.clone(), query, Vec::new());
+                key
+            })
+            .collect()
+    }
+
+    fn cached(cache: &SearchCache, keys: &[String]) -> Vec<bool> {
+        keys.iter().map(|key| cache.get(key).is_some()).collect()
+    }
+
+    #[test]
+    fn invalidate_drops_only_overlapping_scopes() {
+        let cache = SearchCache::new(16, true);
+        let keys = fill(
+            &cache,
+            &[
+                query(Some("a"), Some("foo"), false),
+                query(Some("a"), Some("bar"), false),
+                query(Some("b"), Some("foo"), false),
+                query(Some("b"), None, true),
+            ],
+        );
+
+        // With isolation a topic memory is only seen by queries naming its topic
+        cache.invalidate(&Memory::for_topic("a", "foo", MemoryType::Fact, "x"));
+        assert_eq!(cached(&cache, &keys), [false, true, true, true]);
+
+        cache.invalidate(&Memory::for_agent("a", MemoryType::Fact, "x"));
+        assert_eq!(cached(&cache, &keys), [false, false, true, false]);
+
+        cache.invalidate(&Memory::global(MemoryType::Fact, "x"));
+        assert_eq!(cached(&cache, &keys), [false, false, false, false]);
+    }
+
+    #[test]
+    fn invalidate_ignores_topics_without_isolation() {
+        let cache = SearchCache::new(16, false);
+        let keys = fill(
+            &cache,
+            &[
+                query(Some("a"), Some("foo"), false),
+                query(Some("a"), Some("bar"), false),
+                query(Some("b"), Some("foo"), false),
+            ],
+        );
+
+        cache.invalidate(&Memory::for_topic("a", "foo", MemoryType::Fact, "x"));
+        assert_eq!(cached(&cache, &keys), [false, false, true]);
+
+        // Personal memories are never searched, so nothing is stale
+        let keys = fill(&cache, &[query(Some("b"), None, true)]);
+        cache.invalidate(&Memory::personal(MemoryType::Fact, "x"));
+        assert_eq!(cached(&cache, &keys), [true]);
+    }
+
+    #[test]
+    fn full_cache_evicts_the_oldest() {
+        let cache = SearchCache::new(2, true);
+        let keys = fill(
+            &cache,
+            &[
+                query(Some("a"), None, false),
+                query(Some("b"), None, false),
+                query(Some("c"), None, false),
+            ],
+        );
+        assert_eq!(cached(&cache, &keys), [false, true, true]);
+
+        cache.invalidate_agent("b");
+        assert_eq!(cached(&cache, &keys), [false, false, true]);
+        assert!(SearchCache::new(0, true).key(&query(Some("a"), None, false)).is_none());
+    }
+}
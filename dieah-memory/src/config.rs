@@ -1,7 +1,19 @@
 //! Configuration for dieah-memory
 
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 
+use crate::embedding::InputTruncation;
+use crate::error::{Error, Result};
+use crate::redaction::Redactor;
+use crate::retrieval::{ScoreBlend, SupersedeAction};
+use crate::storage::DistanceMetric;
+use crate::storage::Durability;
+
+/// Context limit for models missing from `Config.model_context_limits`
+pub const DEFAULT_CONTEXT_LIMIT: u32 = 128000;
+
 /// Configuration for the memory system
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -14,20 +26,244 @@ pub struct Config {
     /// Embedding dimensions (384 for all-MiniLM-L6-v2)
     pub embedding_dimensions: usize,
 
+    /// Probe the embedding model at startup and use its real output size instead
+    /// of `embedding_dimensions`
+    pub detect_embedding_dimensions: bool,
+
     /// Maximum number of results to return from retrieval
     pub max_retrieval_results: usize,
 
     /// Minimum similarity score for retrieval (0.0 - 1.0)
     pub min_similarity_score: f32,
 
+    /// Distance metric for vector search, which also determines how distances
+    /// map to scores (L2 by default). Reported with every retrieval response.
+    pub distance_metric: DistanceMetric,
+
     /// Context window warning threshold (0.0 - 1.0)
     pub context_warning_threshold: f32,
 
     /// Context window critical threshold (0.0 - 1.0)
     pub context_critical_threshold: f32,
 
+    /// Context window per model name; a key also matches model names it prefixes
+    /// (`gpt-4o` covers `gpt-4o-2024-08-06`), longest key first
+    pub model_context_limits: HashMap<String, u32>,
+
     /// HTTP server port
     pub server_port: u16,
+
+    /// IP address the HTTP server binds to; loopback by default. The server
+    /// binary overrides it from `DIEAH_BIND_ADDR`.
+    pub bind_address: String,
+
+    /// Header carrying the per-request id: taken from the request when the
+    /// client sends one, generated otherwise, and echoed on the response
+    pub request_id_header: String,
+
+    /// Redact secrets from content before it is stored
+    pub redaction_enabled: bool,
+
+    /// Regex patterns matched by the redactor
+    pub redaction_patterns: Vec<String>,
+
+    /// Maximum size of inline attachment data stored with a message
+    pub max_inline_attachment_bytes: usize,
+
+    /// Rotate a topic's active log once it reaches this size (disabled if None)
+    pub rotate_after_bytes: Option<u64>,
+
+    /// Gzip rotated log segments
+    pub compress_rotated: bool,
+
+    /// When message appends are fsynced to disk
+    pub durability: Durability,
+
+    /// Upper bound applied to client-supplied limits on the HTTP API
+    pub max_query_limit: usize,
+
+    /// Embed a probe string in the background once the server is listening,
+    /// so the first real request doesn't pay for the model's first run
+    pub warmup_embedding: bool,
+
+    /// Log an error at server startup when the model's embedding dimension
+    /// disagrees with the config or the vector table (see
+    /// `/admin/check-consistency`)
+    pub check_consistency_on_startup: bool,
+
+    /// Include stored vectors in agent bundles (`MemoryStore::export_bundle`).
+    /// Makes bundles much larger, but imports into a store with the same model
+    /// skip re-embedding.
+    pub bundle_embeddings: bool,
+
+    /// Expose `/debug/*` endpoints for inspecting stored vectors
+    pub debug_endpoints: bool,
+
+    /// If the metadata database can't be opened, start anyway with metadata
+    /// operations unavailable (503 over HTTP) instead of failing, so history
+    /// stays up
+    pub degrade_on_metadata_failure: bool,
+
+    /// Reject every mutating route with 405, for serving a snapshot read-only
+    pub read_only: bool,
+
+    /// Maximum number of embedding calls in flight; further calls queue
+    pub max_concurrent_embeddings: usize,
+
+    /// Topic used for messages appended without a topic_id
+    pub default_topic_id: String,
+
+    /// Role used for messages appended without a role (e.g. quick notes); when
+    /// unset, omitting the role is rejected
+    pub default_role: Option<String>,
+
+    /// Accept role labels outside `Role::KNOWN` on appended messages, storing
+    /// them as-is; when false they are rejected with 400
+    pub allow_custom_roles: bool,
+
+    /// Min-max normalize global and agent search scores before merging them.
+    /// Makes the scopes comparable, at the cost of scores no longer reflecting
+    /// absolute similarity (the weakest hit in each scope becomes 0.0).
+    pub normalize_scope_scores: bool,
+
+    /// Number of retrieval queries whose search results are cached (0 disables
+    /// the cache). Writes only evict the queries whose scope they touch.
+    pub search_cache_size: usize,
+
+    /// Subtracted from the score of deactivated memories when a query asks for
+    /// them (`include_inactive`), so they rank below active matches
+    pub inactive_score_penalty: f32,
+
+    /// Added to a memory's score for each of the query's `query_tags` it
+    /// carries (case-insensitive), before ranking
+    pub tag_match_boost: f32,
+
+    /// When a newly embedded memory is at least this similar to an active,
+    /// unpinned memory in the same scope, treat it as superseding that one
+    /// (disabled if None)
+    pub supersede_threshold: Option<f32>,
+
+    /// What happens to a superseded memory
+    pub supersede_action: SupersedeAction,
+
+    /// Give up on an embedding call after this long (`None` waits forever), e.g.
+    /// when the model hangs on a first-time download
+    pub embedding_timeout_ms: Option<u64>,
+
+    /// When a query can't be embedded (timeout or model error), rank memories
+    /// by keyword overlap instead of failing the retrieval
+    pub keyword_fallback: bool,
+
+    /// Messages shorter than this many characters (after trimming) are never
+    /// treated as corrections, so "no, yes" doesn't become a memory
+    pub min_correction_length: usize,
+
+    /// Only retrieve topic-scoped memories belonging to the query's topic. When
+    /// false, any of the agent's topic memories can surface in any topic.
+    pub topic_isolation: bool,
+
+    /// Result slots reserved for agent and topic memories, so a few strong
+    /// global memories can't crowd an agent's own knowledge out entirely.
+    /// Only filled when that many agent memories clear the threshold.
+    pub min_agent_results: usize,
+
+    /// How scores combine when the same memory is returned by several scope searches
+    pub duplicate_score_blend: ScoreBlend,
+
+    /// Compact a topic once it holds more than this many messages (disabled if None)
+    pub compact_after_messages: Option<usize>,
+
+    /// Number of topics whose parsed messages are kept in memory, so repeated
+    /// reads of an unchanged log skip re-parsing it (0 disables the cache)
+    pub message_cache_topics: usize,
+
+    /// Count tokens when reading messages stored with a token count of 0 (e.g.
+    /// appended without one), so totals and budgets don't undercount. The log
+    /// itself is only corrected by the `recount-tokens` CLI command.
+    pub recount_zero_tokens: bool,
+
+    /// Messages kept verbatim after compaction; the rest become a summary
+    pub compact_keep_recent: usize,
+
+    /// Pick recent messages from within this many seconds either side of a
+    /// reference time (the query's `reference_time`, else now) instead of the
+    /// last N by position, e.g. to resume an old conversation where it was.
+    /// Still capped at `max_recent_messages`, keeping the closest. Disabled if None.
+    pub recent_window_secs: Option<u64>,
+
+    /// When no memory clears `min_similarity_score`, return the nearest ones
+    /// anyway, flagged as low-confidence
+    pub fallback_on_empty: bool,
+
+    /// Longest memory content, in tokens, handed to the embedding model as one
+    /// input (0 disables the limit). The default matches all-MiniLM-L6-v2's
+    /// maximum sequence length.
+    pub max_embedding_tokens: usize,
+
+    /// How content over `max_embedding_tokens` is fitted: keep the start, keep
+    /// both ends, or embed it as chunks (see `chunk_tokens`)
+    pub embedding_truncation: InputTruncation,
+
+    /// Maximum number of texts passed to the embedding model in one call
+    pub embedding_batch_size: usize,
+
+    /// If embedding fails when creating a memory over HTTP, store it unembedded
+    /// (picked up later by `embed-pending`) instead of failing the request
+    pub save_unembedded_on_failure: bool,
+
+    /// When `reindex --mismatched-only` finds a stored vector whose dimension
+    /// differs from the current model's, re-embed that memory; when false,
+    /// only report it
+    pub reembed_mismatched_vectors: bool,
+
+    /// Cap on active memories per scope (global, personal, each agent, each
+    /// agent topic), counted separately for shared memories and each owner's
+    /// private ones. Saving past it evicts the least recently used unpinned
+    /// memories there. Unbounded if None.
+    pub max_memories_per_scope: Option<usize>,
+
+    /// Delete memories evicted by `max_memories_per_scope` instead of
+    /// deactivating them
+    pub delete_evicted_memories: bool,
+
+    /// IVF partitions probed per vector search on an indexed table; more
+    /// raises recall at the cost of latency. None uses LanceDB's default (20),
+    /// which suits tables up to a few million rows.
+    pub search_nprobes: Option<usize>,
+
+    /// Re-rank `limit × refine_factor` index candidates by exact distance
+    /// before returning the top `limit`, recovering recall lost to quantization.
+    /// None skips refinement; 5-10 is a typical setting when recall matters.
+    pub search_refine_factor: Option<u32>,
+
+    /// Abandon a vector search after this many milliseconds (no limit if None)
+    pub vector_search_timeout_ms: Option<u64>,
+
+    /// LanceDB table for this store's vectors, so several logical stores (or
+    /// tests) can share one `vectors/` directory. ASCII letters, digits, `_`,
+    /// `-` and `.` only.
+    pub vector_table_name: String,
+
+    /// Score given to search hits when LanceDB returns no `_distance` column
+    /// (e.g. a query that fell back to a full scan). Such hits are kept and a
+    /// warning is logged instead of failing the search. Defaults to 0.5.
+    pub missing_distance_score: f32,
+
+    /// Collapse consecutive identical messages (same role and content, e.g.
+    /// retries) in retrieved recent history
+    pub dedup_recent_messages: bool,
+
+    /// Recent messages considered by `RetrievalEngine::assemble` before trimming
+    pub assembly_recent_messages: usize,
+
+    /// Embed long memories as several overlapping chunks instead of one vector
+    pub chunk_long_memories: bool,
+
+    /// Chunk window size in tokens
+    pub chunk_tokens: usize,
+
+    /// Tokens shared between consecutive chunks
+    pub chunk_overlap_tokens: usize,
 }
 
 impl Default for Config {
@@ -40,16 +276,113 @@ impl Default for Config {
             data_dir,
             embedding_model: "all-MiniLM-L6-v2".to_string(),
             embedding_dimensions: 384, // MiniLM-L6-v2 outputs 384-dim vectors
+            detect_embedding_dimensions: true,
             max_retrieval_results: 10,
             min_similarity_score: 0.7,
+            distance_metric: DistanceMetric::default(),
             context_warning_threshold: 0.8,
             context_critical_threshold: 0.95,
+            model_context_limits: default_model_context_limits(),
             server_port: 8420,
+            bind_address: "127.0.0.1".to_string(),
+            request_id_header: "x-request-id".to_string(),
+            redaction_enabled: true,
+            redaction_patterns: Redactor::default_patterns(),
+            max_inline_attachment_bytes: 1024 * 1024,
+            rotate_after_bytes: None,
+            compress_rotated: false,
+            durability: Durability::default(),
+            max_query_limit: 500,
+            warmup_embedding: false,
+            check_consistency_on_startup: true,
+            bundle_embeddings: false,
+            debug_endpoints: false,
+            degrade_on_metadata_failure: false,
+            read_only: false,
+            max_concurrent_embeddings: 2,
+            default_topic_id: "general".to_string(),
+            default_role: None,
+            allow_custom_roles: false,
+            normalize_scope_scores: false,
+            topic_isolation: true,
+            min_correction_length: 15,
+            search_cache_size: 0,
+            inactive_score_penalty: 0.1,
+            tag_match_boost: 0.05,
+            supersede_threshold: None,
+            supersede_action: SupersedeAction::Deactivate,
+            embedding_timeout_ms: Some(30_000),
+            keyword_fallback: false,
+            min_agent_results: 0,
+            duplicate_score_blend: ScoreBlend::Max,
+            compact_after_messages: None,
+            compact_keep_recent: 20,
+            message_cache_topics: 32,
+            recount_zero_tokens: false,
+            recent_window_secs: None,
+            fallback_on_empty: false,
+            max_embedding_tokens: 256,
+            embedding_truncation: InputTruncation::TruncateEnd,
+            embedding_batch_size: 256,
+            save_unembedded_on_failure: false,
+            reembed_mismatched_vectors: true,
+            max_memories_per_scope: None,
+            delete_evicted_memories: false,
+            search_nprobes: None,
+            search_refine_factor: None,
+            vector_search_timeout_ms: Some(5000),
+            vector_table_name: "memories".to_string(),
+            missing_distance_score: 0.5,
+            dedup_recent_messages: true,
+            assembly_recent_messages: 50,
+            chunk_long_memories: false,
+            chunk_tokens: 256,
+            chunk_overlap_tokens: 32,
         }
     }
 }
 
+/// Context windows of commonly used models
+fn default_model_context_limits() -> HashMap<String, u32> {
+    [
+        ("gpt-4", 8192),
+        ("gpt-4-turbo", 128000),
+        ("gpt-4o", 128000),
+        ("gpt-4.1", 1047576),
+        ("gpt-5", 400000),
+        ("o1", 200000),
+        ("o3", 200000),
+        ("o4-mini", 200000),
+        ("claude", 200000),
+        ("gemini-1.5", 1048576),
+        ("gemini-2", 1048576),
+    ]
+    .into_iter()
+    .map(|(model, limit)| (model.to_string(), limit))
+    .collect()
+}
+
 impl Config {
+    /// Resolve the context limit for a model
+    ///
+    /// An agent's own `context_limit` wins; otherwise the longest matching entry
+    /// in `model_context_limits`, falling back to `DEFAULT_CONTEXT_LIMIT`.
+    pub fn context_limit_for(&self, model: Option<&str>, agent_override: Option<u32>) -> u32 {
+        if let Some(limit) = agent_override.filter(|l| *l > 0) {
+            return limit;
+        }
+
+        model
+            .and_then(|model| {
+                self.model_context_limits
+                    .iter()
+                    .filter(|(key, _)| model.starts_with(key.as_str()))
+                    .max_by_key(|(key, _)| key.len())
+                    .map(|(_, limit)| *limit)
+            })
+            .unwrap_or(DEFAULT_CONTEXT_LIMIT)
+    }
+
     /// Create a new config with a custom data directory
     pub fn with_data_dir(data_dir: impl Into<PathBuf>) -> Self {
         Self {
@@ -58,11 +391,26 @@ impl Config {
         }
     }
 
+    /// Socket address for the HTTP server, from `bind_address` and `server_port`
+    pub fn socket_addr(&self) -> Result<SocketAddr> {
+        let ip: IpAddr = self
+            .bind_address
+            .trim()
+            .parse()
+            .map_err(|_| Error::config(format!("Invalid bind address: {:?}", self.bind_address)))?;
+        Ok(SocketAddr::new(ip, self.server_port))
+    }
+
     /// Get the path to the SQLite database
     pub fn sqlite_path(&self) -> PathBuf {
         self.data_dir.join("metadata.db")
     }
 
+    /// Get the path to the audit log of mutating operations
+    pub fn audit_log_path(&self) -> PathBuf {
+        self.data_dir.join("audit.jsonl")
+    }
+
     /// Get the path to the vector database
     pub fn vector_db_path(&self) -> PathBuf {
         self.data_dir.join("vectors")
@@ -84,3 +432,67 @@ impl Config {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_addr_combines_bind_address_and_port() {
+        let config = Config {
+            bind_address: " 0.0.0.0 ".to_string(),
+            server_port: 9000,
+            ..Default::default()
+        };
+        assert_eq!(config.socket_addr().unwrap(), "0.0.0.0:9000".parse().unwrap());
+
+        let config = Config {
+            bind_address: "::1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(config.socket_addr().unwrap(), "[::1]:8420".parse().unwrap());
+        assert_eq!(Config::default().socket_addr().unwrap(), "127.0.0.1:8420".parse().unwrap());
+
+        for invalid in ["localhost", "", "127.0.0.1:9000", "256.0.0.1"] {
+            let config = Config {
+                bind_address: invalid.to_string(),
+                ..Default::default()
+            };
+            match config.socket_addr() {
+                Err(Error::Config(message)) => assert!(message.contains("Invalid bind address"), "{}", message),
+                other => panic!("{:?} gave {:?}", invalid, other),
+            }
+        }
+    }
+
+
+    #[test]
+    fn context_limit_for_prefers_the_override_then_the_longest_prefix() {
+        let config = Config::default();
+
+        // Unknown or missing models get the default
+        assert_eq!(config.context_limit_for(None, None), DEFAULT_CONTEXT_LIMIT);
+        assert_eq!(config.context_limit_for(Some("llama-3"), None), DEFAULT_CONTEXT_LIMIT);
+        assert_eq!(config.context_limit_for(Some(""), None), DEFAULT_CONTEXT_LIMIT);
+
+        // The longest matching prefix wins: "gpt-4-turbo" over "gpt-4"
+        assert_eq!(config.context_limit_for(Some("gpt-4"), None), 8192);
+        assert_eq!(config.context_limit_for(Some("gpt-4-0613"), None), 8192);
+        assert_eq!(config.context_limit_for(Some("gpt-4-turbo-preview"), None), 128000);
+        assert_eq!(config.context_limit_for(Some("gpt-4.1-mini"), None), 1047576);
+        assert_eq!(config.context_limit_for(Some("claude-sonnet"), None), 200000);
+
+        // A positive override wins over any model; zero means none
+        assert_eq!(config.context_limit_for(Some("gpt-4"), Some(4000)), 4000);
+        assert_eq!(config.context_limit_for(None, Some(4000)), 4000);
+        assert_eq!(config.context_limit_for(Some("gpt-4"), Some(0)), 8192);
+
+        // Configured entries extend or replace the defaults
+        let mut config = Config::default();
+        config.model_context_limits.insert("llama-3".to_string(), 8000);
+        config.model_context_limits.insert("gpt-4".to_string(), 16000);
+        assert_eq!(config.context_limit_for(Some("llama-3-70b"), None), 8000);
+        assert_eq!(config.context_limit_for(Some("gpt-4"), None), 16000);
+        assert_eq!(config.context_limit_for(Some("gpt-4-turbo"), None), 128000);
+    }
+}
@@ -1,17 +1,53 @@
 //! Embedding generation using fastembed (local, no API keys)
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 
 use crate::config::Config;
 use crate::error::{Error, Result};
 
+/// How memory content longer than `Config.max_embedding_tokens` is fitted to
+/// the model's input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputTruncation {
+    /// Keep the beginning (what the model would do silently)
+    #[default]
+    TruncateEnd,
+    /// Keep the beginning and the end, dropping the middle
+    TruncateMiddle,
+    /// Embed the whole content as overlapping chunks, one vector each
+    Chunk,
+}
+
+/// Model behind an `EmbeddingService`, called on a blocking thread
+pub trait EmbeddingBackend: Send + 'static {
+    /// Embed each text, returning one vector per text in input order
+    fn embed(&mut self, texts: Vec<String>) -> Result<Vec<Vec<f32>>>;
+}
+
+impl EmbeddingBackend for TextEmbedding {
+    fn embed(&mut self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        TextEmbedding::embed(self, texts, None)
+            .map_err(|e| Error::embedding(format!("Embedding failed: {}", e)))
+    }
+}
+
 /// Embedding service for generating vector embeddings locally
+///
+/// Clones share the model and the concurrency limit.
+#[derive(Clone)]
 pub struct EmbeddingService {
-    model: Arc<Mutex<TextEmbedding>>,
+    model: Arc<Mutex<Box<dyn EmbeddingBackend>>>,
     dimensions: usize,
+    /// Bounds how many embedding calls may be in flight at once
+    permits: Arc<Semaphore>,
+    /// Largest slice handed to the model in one call
+    batch_size: usize,
+    /// How long one model call may run before it is reported as failed
+    timeout: Option<Duration>,
 }
 
 impl EmbeddingService {
@@ -25,21 +61,57 @@ impl EmbeddingService {
         )
         .map_err(|e| Error::embedding(format!("Failed to load embedding model: {}", e)))?;
 
+        Self::with_backend(config, model)
+    }
+
+    /// Create an embedding service around an already loaded model
+    pub fn with_backend(config: &Config, mut model: impl EmbeddingBackend) -> Result<Self> {
+        let dimensions = if config.detect_embedding_dimensions {
+            Self::probe_dimensions(&mut model, config.embedding_dimensions)?
+        } else {
+            config.embedding_dimensions
+        };
+
         Ok(Self {
-            model: Arc::new(Mutex::new(model)),
-            dimensions: config.embedding_dimensions,
+            model: Arc::new(Mutex::new(Box::new(model))),
+            dimensions,
+            permits: Arc::new(Semaphore::new(config.max_concurrent_embeddings.max(1))),
+            batch_size: config.embedding_batch_size.max(1),
+            timeout: config.embedding_timeout_ms.map(Duration::from_millis),
         })
     }
 
+    /// Embed a probe string to learn the model's real output size, warning if it
+    /// differs from the configured one
+    fn probe_dimensions(model: &mut impl EmbeddingBackend, configured: usize) -> Result<usize> {
+        let detected = model
+            .embed(vec!["dimension probe".to_string()])
+            .map_err(|e| Error::embedding(format!("Dimension probe failed: {}", e)))?
+            .first()
+            .map(Vec::len)
+            .ok_or_else(|| Error::embedding("Dimension probe returned no embedding"))?;
+
+        if detected != configured {
+            tracing::warn!(
+                "Embedding model outputs {} dimensions but config says {}; using {}",
+                detected,
+                configured,
+                detected
+            );
+        }
+        Ok(detected)
+    }
+
     /// Generate an embedding for a single text
+    ///
+    /// Blank input is rejected up front: the model would pool over nothing and
+    /// return a meaningless vector.
     pub async fn embed(&self, text: &str) -> Result<Vec<f32>> {
-        let model = self.model.clone();
-        let text = text.to_string();
-        
-        // Lock the model and run embedding
-        let mut guard = model.lock().await;
-        let embeddings = guard.embed(vec![text], None)
-            .map_err(|e| Error::embedding(format!("Embedding failed: {}", e)))?;
+        if text.trim().is_empty() {
+            return Err(Error::invalid_input("Cannot embed empty text"));
+        }
+
+        let embeddings = self.run(vec![text.to_string()]).await?;
 
         embeddings
             .into_iter()
@@ -48,22 +120,74 @@ impl EmbeddingService {
     }
 
     /// Generate embeddings for multiple texts
+    ///
+    /// Large inputs are embedded `Config.embedding_batch_size` texts at a time to
+    /// keep peak memory bounded; results are returned in input order.
     pub async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
-        if texts.is_empty() {
-            return Ok(Vec::new());
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for chunk in texts.chunks(self.batch_size) {
+            embeddings.extend(self.run(chunk.to_vec()).await?);
         }
 
-        let model = self.model.clone();
-        let texts = texts.to_vec();
+        Ok(embeddings)
+    }
 
-        // Lock the model and run embedding
-        let mut guard = model.lock().await;
-        let embeddings = guard.embed(texts, None)
-            .map_err(|e| Error::embedding(format!("Embedding failed: {}", e)))?;
+    /// Run the model on a blocking thread once a permit is available, so a
+    /// burst of requests queues instead of starving the async runtime
+    ///
+    /// With `Config.embedding_timeout_ms` set, a call that takes longer fails
+    /// with an embedding error. The blocking thread can't be cancelled, so it
+    /// keeps its permit until the model actually returns.
+    async fn run(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let embeddings = self.run_model(texts).await?;
 
+        // Never let a vector of the wrong size reach the store
+        if let Some(bad) = embeddings.iter().find(|e| e.len() != self.dimensions) {
+            return Err(Error::embedding(format!(
+                "Model returned a {}-dimension embedding, expected {}",
+                bad.len(),
+                self.dimensions
+            )));
+        }
         Ok(embeddings)
     }
 
+    /// Model call behind `run`, without the dimension check
+    async fn run_model(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| Error::embedding(e.to_string()))?;
+
+        let model = self.model.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            // Lock the model and run embedding
+            let mut guard = model.blocking_lock();
+            guard.embed(texts)
+        });
+
+        let joined = match self.timeout {
+            Some(limit) => tokio::time::timeout(limit, task)
+                .await
+                .map_err(|_| Error::embedding(format!("Embedding timed out after {:?}", limit)))?,
+            None => task.await,
+        };
+        joined.map_err(|e| Error::embedding(format!("Embedding task failed: {}", e)))?
+    }
+
+    /// Embed a probe string and return the size of the vector the loaded model
+    /// actually produces, which may differ from `dimensions()`
+    pub async fn probe(&self) -> Result<usize> {
+        self.run_model(vec!["dimension probe".to_string()])
+            .await?
+            .first()
+            .map(Vec::len)
+            .ok_or_else(|| Error::embedding("Dimension probe returned no embedding"))
+    }
+
     /// Get the embedding dimensions
     pub fn dimensions(&self) -> usize {
         self.dimensions
@@ -107,6 +231,80 @@ impl TokenCounter {
         self.bpe.encode_with_special_tokens(text).len() as u32
     }
 
+    /// Split text into windows of at most `window` tokens, each overlapping the
+    /// previous one by `overlap` tokens. Short text comes back as a single chunk.
+    pub fn chunk(&self, text: &str, window: usize, overlap: usize) -> Vec<String> {
+        let tokens = self.bpe.encode_with_special_tokens(text);
+        if window == 0 || tokens.len() <= window {
+            return vec![text.to_string()];
+        }
+
+        let step = window.saturating_sub(overlap).max(1);
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < tokens.len() {
+            let end = (start + window).min(tokens.len());
+            // A window can split a multi-byte character; skip it rather than fail
+            if let Ok(chunk) = self.bpe.decode(tokens[start..end].to_vec()) {
+                if !chunk.trim().is_empty() {
+                    chunks.push(chunk);
+                }
+            }
+            if end == tokens.len() {
+                break;
+            }
+            start += step;
+        }
+
+        if chunks.is_empty() {
+            chunks.push(text.to_string());
+        }
+        chunks
+    }
+
+    /// Shorten text to at most `max` tokens
+    ///
+    /// `TruncateEnd` keeps the first `max` tokens; `TruncateMiddle` keeps the
+    /// first and last halves, joined with an ellipsis. Text that already fits
+    /// is returned as-is, as it is for `Chunk`, which is handled by `chunk`.
+    pub fn truncate(&self, text: &str, max: usize, strategy: InputTruncation) -> String {
+        let tokens = self.bpe.encode_with_special_tokens(text);
+        if max == 0 || tokens.len() <= max || strategy == InputTruncation::Chunk {
+            return text.to_string();
+        }
+
+        match strategy {
+            InputTruncation::TruncateMiddle => {
+                let head = max.div_ceil(2);
+                let tail = max - head;
+                format!(
+                    "{} … {}",
+                    self.decode_cut(&tokens[..head], true).trim_end(),
+                    self.decode_cut(&tokens[tokens.len() - tail..], false).trim_start()
+                )
+            }
+            _ => self.decode_cut(&tokens[..max], true),
+        }
+    }
+
+    /// Decode tokens cut from a longer text, keeping the start or the end
+    ///
+    /// The cut can split a multi-byte character, so up to three tokens at the
+    /// cut are given up until the rest decodes.
+    fn decode_cut(&self, tokens: &[usize], keep_start: bool) -> String {
+        for drop in 0..tokens.len().min(4) {
+            let slice = if keep_start {
+                &tokens[..tokens.len() - drop]
+            } else {
+                &tokens[drop..]
+            };
+            if let Ok(text) = self.bpe.decode(slice.to_vec()) {
+                return text;
+            }
+        }
+        String::new()
+    }
+
     /// Count tokens with a fallback estimate if tokenization fails
     pub fn count_or_estimate(&self, text: &str) -> u32 {
         self.count(text)
@@ -124,3 +322,120 @@ impl Default for TokenCounter {
         Self::for_gpt().expect("Failed to create default token counter")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{FakeEmbedder, DIMENSIONS};
+
+    fn service(embedder: FakeEmbedder, configure: impl FnOnce(&mut Config)) -> EmbeddingService {
+        let mut config = Config {
+            embedding_dimensions: DIMENSIONS,
+            detect_embedding_dimensions: false,
+            ..Config::default()
+        };
+        configure(&mut config);
+        EmbeddingService::with_backend(&config, embedder).unwrap()
+    }
+
+    /// Run four embeddings at once, each taking 200ms with a 500ms timeout
+    async fn burst(max_concurrent: usize) -> Vec<Result<Vec<f32>>> {
+        let embedder = FakeEmbedder {
+            delay: Some(Duration::from_millis(200)),
+            ..FakeEmbedder::new(DIMENSIONS)
+        };
+        let service = service(embedder, |c| {
+            c.max_concurrent_embeddings = max_concurrent;
+            c.embedding_timeout_ms = Some(500);
+        });
+        let texts = ["one", "two", "three", "four"];
+        futures::future::join_all(texts.iter().map(|text| service.embed(text))).await
+    }
+
+    #[tokio::test]
+    async fn queued_calls_wait_for_a_permit_outside_the_timeout() {
+        // One permit: each call runs alone once admitted, so none times out
+        assert!(burst(1).await.iter().all(Result::is_ok));
+        // Four permits: all are admitted at once and queue on the model, so
+        // the last ones exceed the timeout
+        assert!(burst(4).await.iter().any(Result::is_err));
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_model() {
+        let embedder = FakeEmbedder::new(DIMENSIONS);
+        let calls = embedder.calls.clone();
+        let service = service(embedder, |_| {});
+
+        // As the server's warmup does: probe on a clone, then serve from the original
+        let warmup = service.clone();
+        assert_eq!(tokio::spawn(async move { warmup.probe().await }).await.unwrap().unwrap(), DIMENSIONS);
+        assert_eq!(service.embed("ready").await.unwrap().len(), DIMENSIONS);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn batches_larger_than_a_chunk_keep_input_order() {
+        let embedder = FakeEmbedder::new(DIMENSIONS);
+        let (calls, expected) = (embedder.calls.clone(), embedder.clone());
+        let service = service(embedder, |c| c.embedding_batch_size = 3);
+
+        let texts: Vec<String> = (0..10).map(|i| format!("memory number {}", i)).collect();
+        let embeddings = service.embed_batch(&texts).await.unwrap();
+
+        assert_eq!(embeddings.len(), texts.len());
+        for (text, embedding) in texts.iter().zip(&embeddings) {
+            assert_eq!(*embedding, expected.vector(text), "{}", text);
+        }
+        // Chunks of 3, 3, 3 and 1
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 4);
+        assert!(service.embed_batch(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_model_calls_time_out() {
+        let embedder = FakeEmbedder {
+            delay: Some(Duration::from_millis(300)),
+            ..FakeEmbedder::new(DIMENSIONS)
+        };
+        let slow = service(embedder.clone(), |c| c.embedding_timeout_ms = Some(50));
+        match slow.embed("hello").await {
+            Err(Error::Embedding(message)) => assert!(message.contains("timed out"), "{}", message),
+            other => panic!("expected a timeout, got {:?}", other.map(|v| v.len())),
+        }
+
+        // Without a limit the same model just takes its time
+        let patient = service(embedder, |c| c.embedding_timeout_ms = None);
+        assert_eq!(patient.embed("hello").await.unwrap().len(), DIMENSIONS);
+    }
+
+    #[tokio::test]
+    async fn blank_input_is_rejected_before_the_model() {
+        let embedder = FakeEmbedder::new(DIMENSIONS);
+        let calls = embedder.calls.clone();
+        let service = service(embedder, |_| {});
+
+        for text in ["", "   ", "\n\t"] {
+            assert!(matches!(service.embed(text).await, Err(Error::InvalidInput(_))), "{:?}", text);
+        }
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn vectors_of_the_wrong_size_are_rejected() {
+        // The model outputs 32 dimensions but the service expects DIMENSIONS
+        let service = service(FakeEmbedder::new(32), |_| {});
+
+        for result in [
+            service.embed("hello").await,
+            service.embed_batch(&["hello".to_string()]).await.map(|mut v| v.remove(0)),
+        ] {
+            match result {
+                Err(Error::Embedding(message)) => {
+                    assert!(message.contains("32-dimension"), "{}", message);
+                }
+                other => panic!("expected a dimension error, got {:?}", other.map(|v| v.len())),
+            }
+        }
+    }
+}
@@ -34,6 +34,12 @@ pub enum Error {
 
     #[error("Invalid input: {0}")]
     InvalidInput(String),
+
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
 }
 
 impl Error {
@@ -60,4 +66,12 @@ impl Error {
     pub fn invalid_input(msg: impl Into<String>) -> Self {
         Self::InvalidInput(msg.into())
     }
+
+    pub fn timeout(msg: impl Into<String>) -> Self {
+        Self::Timeout(msg.into())
+    }
+
+    pub fn unavailable(msg: impl Into<String>) -> Self {
+        Self::Unavailable(msg.into())
+    }
 }
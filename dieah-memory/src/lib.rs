@@ -28,16 +28,25 @@
 //! let context = store.retrieve_context(query, agent_id, topic_id).await?;
 //! ```
 
+pub mod audit;
+pub mod bundle;
+pub mod cache;
 pub mod config;
 pub mod embedding;
 pub mod error;
 pub mod memory;
 pub mod message;
+pub mod redaction;
 pub mod retrieval;
 pub mod storage;
+pub mod sync;
+
+#[cfg(test)]
+pub(crate) mod test_support;
 
 pub use config::Config;
 pub use error::{Error, Result};
 pub use memory::{Memory, MemoryScope, MemoryStore};
 pub use message::{Message, Role};
+pub use redaction::Redactor;
 pub use retrieval::RetrievalContext;
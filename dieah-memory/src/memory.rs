@@ -2,11 +2,24 @@
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::Path;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-use crate::config::Config;
-use crate::error::Result;
-use crate::storage::{JsonlStorage, SqliteStorage, VectorStorage};
+use crate::bundle::{AgentBundle, BundleImportReport, BundleMemory, BundleTopic, BUNDLE_VERSION};
+use crate::cache::SearchCache;
+use crate::config::{Config, DEFAULT_CONTEXT_LIMIT};
+use crate::error::{Error, Result};
+use crate::redaction::Redactor;
+use crate::retrieval::RetrievalEngine;
+use crate::message::Message;
+use crate::sync::{parse_memory_file, SyncReport};
+use crate::storage::{
+    validate_id, AgentRecord, CompactionReport, JsonlStorage, LogPosition, MemoryFacets,
+    SqliteStorage, TopicRecord, VectorStorage,
+};
 
 /// Scope of a memory
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -36,6 +49,28 @@ impl std::fmt::Display for MemoryScope {
     }
 }
 
+impl std::str::FromStr for MemoryScope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "global" => Ok(MemoryScope::Global),
+            "agent" => Ok(MemoryScope::Agent),
+            "topic" => Ok(MemoryScope::Topic),
+            "personal" => Ok(MemoryScope::Personal),
+            _ => Err(Error::invalid_input(format!("Unknown scope: {}", s))),
+        }
+    }
+}
+
+impl TryFrom<&str> for MemoryScope {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        s.parse()
+    }
+}
+
 /// Type of memory
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -68,6 +103,61 @@ impl std::fmt::Display for MemoryType {
     }
 }
 
+impl std::str::FromStr for MemoryType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "correction" => Ok(MemoryType::Correction),
+            "preference" => Ok(MemoryType::Preference),
+            "fact" => Ok(MemoryType::Fact),
+            "workflow" => Ok(MemoryType::Workflow),
+            "constraint" => Ok(MemoryType::Constraint),
+            _ => Err(Error::invalid_input(format!("Unknown memory type: {}", s))),
+        }
+    }
+}
+
+impl TryFrom<&str> for MemoryType {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Who can see a memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Visible to every caller
+    #[default]
+    Shared,
+    /// Only visible to the caller recorded as `owner_id`
+    Private,
+}
+
+impl std::fmt::Display for Visibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Visibility::Shared => write!(f, "shared"),
+            Visibility::Private => write!(f, "private"),
+        }
+    }
+}
+
+impl std::str::FromStr for Visibility {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "shared" => Ok(Visibility::Shared),
+            "private" => Ok(Visibility::Private),
+            _ => Err(Error::invalid_input(format!("Unknown visibility: {}", s))),
+        }
+    }
+}
+
 /// A learned memory that persists across conversations
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Memory {
@@ -117,12 +207,42 @@ pub struct Memory {
     /// Whether this memory is active
     #[serde(default = "default_true")]
     pub active: bool,
+
+    /// Pinned memories are always injected, regardless of similarity
+    #[serde(default)]
+    pub pinned: bool,
+
+    /// Who can see this memory
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Caller that owns this memory (required for private memories)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner_id: Option<String>,
+
+    /// SHA-256 of the content the stored vectors were computed from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+
+    /// Multiplier on this memory's similarity scores; lowered when a newer
+    /// memory supersedes it (`Config.supersede_action`)
+    #[serde(default = "default_weight")]
+    pub weight: f32,
 }
 
 fn default_true() -> bool {
     true
 }
 
+fn default_weight() -> f32 {
+    1.0
+}
+
+/// Hex SHA-256 of memory content, used to skip re-embedding unchanged content
+pub fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
 impl Memory {
     /// Create a new global memory
     pub fn global(memory_type: MemoryType, content: impl Into<String>) -> Self {
@@ -140,6 +260,22 @@ impl Memory {
             last_used_at: None,
             retrieval_count: 0,
             active: true,
+            pinned: false,
+            visibility: Visibility::Shared,
+            owner_id: None,
+            content_hash: None,
+            weight: 1.0,
+        }
+    }
+
+    /// Create a new personal memory
+    ///
+    /// Personal memories are stored with their own scope and are not returned
+    /// by the global search, so they don't leak into every agent's context.
+    pub fn personal(memory_type: MemoryType, content: impl Into<String>) -> Self {
+        Self {
+            scope: MemoryScope::Personal,
+            ..Self::global(memory_type, content)
         }
     }
 
@@ -163,6 +299,11 @@ impl Memory {
             last_used_at: None,
             retrieval_count: 0,
             active: true,
+            pinned: false,
+            visibility: Visibility::Shared,
+            owner_id: None,
+            content_hash: None,
+            weight: 1.0,
         }
     }
 
@@ -187,6 +328,11 @@ impl Memory {
             last_used_at: None,
             retrieval_count: 0,
             active: true,
+            pinned: false,
+            visibility: Visibility::Shared,
+            owner_id: None,
+            content_hash: None,
+            weight: 1.0,
         }
     }
 
@@ -202,6 +348,28 @@ impl Memory {
         self
     }
 
+    /// Pin or unpin the memory
+    pub fn with_pinned(mut self, pinned: bool) -> Self {
+        self.pinned = pinned;
+        self
+    }
+
+    /// Make the memory private to `owner_id`
+    pub fn with_owner(mut self, owner_id: impl Into<String>) -> Self {
+        self.visibility = Visibility::Private;
+        self.owner_id = Some(owner_id.into());
+        self
+    }
+
+    /// Whether a caller may see this memory; private memories are only
+    /// visible to their owner, and never to an anonymous caller
+    pub fn is_visible_to(&self, caller: Option<&str>) -> bool {
+        match self.visibility {
+            Visibility::Shared => true,
+            Visibility::Private => caller.is_some() && self.owner_id.as_deref() == caller,
+        }
+    }
+
     /// Set the embedding
     pub fn with_embedding(mut self, embedding: Vec<f32>) -> Self {
         self.embedding = Some(embedding);
@@ -215,31 +383,187 @@ impl Memory {
     }
 }
 
+/// Result of `MemoryStore::merge_topics`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MergeReport {
+    /// Messages moved from the source topic
+    pub messages_moved: usize,
+
+    /// Topic memories re-pointed at the destination
+    pub memories_moved: usize,
+
+    /// Messages in the destination topic after the merge
+    pub message_count: usize,
+}
+
+/// Result of `MemoryStore::migrate_mismatched_vectors`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VectorMigrationReport {
+    /// Memories whose stored vector was checked
+    pub checked: usize,
+
+    /// Memories whose stored vector had the wrong dimension
+    pub mismatched: Vec<Uuid>,
+
+    /// Mismatched memories that were re-embedded
+    pub reembedded: usize,
+
+    /// Mismatched memories that could not be re-embedded, with the error
+    pub failed: Vec<(Uuid, String)>,
+}
+
+/// Result of `MemoryStore::check_consistency`: every place an embedding
+/// dimension is decided, and whether they agree
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConsistencyReport {
+    /// `Config.embedding_dimensions` as configured
+    pub configured_dimensions: usize,
+
+    /// Size of a vector the loaded model produces right now
+    pub model_dimensions: usize,
+
+    /// Dimension the vector store writes and searches with
+    pub store_dimensions: usize,
+
+    /// Dimension declared by the vector table's schema (None if it has no
+    /// vector column)
+    pub table_dimensions: Option<usize>,
+
+    /// One line per disagreement; empty when consistent
+    pub problems: Vec<String>,
+}
+
+impl ConsistencyReport {
+    /// Whether every dimension agrees with the model's
+    pub fn is_consistent(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Topic row for a log's messages, keeping the name and creation time of an
+/// existing row
+fn topic_record(
+    agent_id: &str,
+    topic_id: &str,
+    messages: &[Message],
+    existing: Option<&TopicRecord>,
+) -> TopicRecord {
+    let tokens: u64 = messages.iter().map(|m| m.tokens as u64).sum();
+    let first = messages.iter().map(|m| m.timestamp).min();
+
+    TopicRecord {
+        id: topic_id.to_string(),
+        agent_id: agent_id.to_string(),
+        name: existing.map_or_else(|| topic_id.to_string(), |t| t.name.clone()),
+        created_at: existing
+            .map(|t| t.created_at)
+            .or(first)
+            .unwrap_or_else(Utc::now),
+        last_message_at: messages.iter().map(|m| m.timestamp).max(),
+        message_count: messages.len() as u32,
+        token_count: tokens.min(u32::MAX as u64) as u32,
+    }
+}
+
+/// Result of `MemoryStore::rebuild_metadata_from_logs`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RebuildReport {
+    /// Agents that had logs but no record, now created with defaults
+    pub agents_created: usize,
+
+    /// Topic rows rewritten from their logs
+    pub topics_rebuilt: usize,
+
+    /// Messages read across all topics
+    pub messages: usize,
+
+    /// Sum of message token counts across all topics
+    pub tokens: u64,
+}
+
+/// Result of `MemoryStore::append_message`
+#[derive(Debug, Clone)]
+pub struct AppendOutcome {
+    /// Where the message was written, for `JsonlStorage::read_at_offset`.
+    /// Stale if `compaction` is set, since compaction rewrites the log.
+    pub position: LogPosition,
+
+    /// Set when this append triggered a compaction of the topic
+    pub compaction: Option<CompactionReport>,
+}
+
 /// The main memory store that coordinates all storage backends
 pub struct MemoryStore {
     config: Config,
     sqlite: SqliteStorage,
     vector: VectorStorage,
     jsonl: JsonlStorage,
+    redactor: Option<Redactor>,
+    search_cache: SearchCache,
 }
 
 impl MemoryStore {
     /// Create a new memory store
+    ///
+    /// With `Config.degrade_on_metadata_failure`, a metadata database that
+    /// can't be opened (locked, corrupt, unreadable) doesn't stop the store:
+    /// it opens degraded, see `degraded`.
     pub async fn new(config: Config) -> Result<Self> {
         config.ensure_dirs()?;
 
-        let sqlite = SqliteStorage::new(&config)?;
+        let sqlite = match SqliteStorage::new(&config) {
+            Ok(sqlite) => sqlite,
+            Err(e) if config.degrade_on_metadata_failure => {
+                tracing::error!(
+                    "Failed to open metadata database {:?}: {}. Starting DEGRADED: memory \
+                     metadata, agents and topics are unavailable until it is repaired and \
+                     the store restarted",
+                    config.sqlite_path(),
+                    e
+                );
+                SqliteStorage::unavailable(&config, e.to_string())
+            }
+            Err(e) => return Err(e),
+        };
         let vector = VectorStorage::new(&config).await?;
         let jsonl = JsonlStorage::new(&config)?;
+        let redactor = Redactor::from_config(&config)?;
+        let search_cache = SearchCache::new(config.search_cache_size, config.topic_isolation);
 
         Ok(Self {
             config,
             sqlite,
             vector,
             jsonl,
+            redactor,
+            search_cache,
         })
     }
 
+    /// Why the store is running degraded, or None if it is healthy
+    ///
+    /// A degraded store has no metadata database: operations that need it fail
+    /// with `Error::Unavailable`, while conversation logs keep working. Retrieval
+    /// then checks similarity hits against the visibility, owner and active flag
+    /// stored with their vectors instead; pinned memories, weights and keyword
+    /// fallback are unavailable.
+    pub fn degraded(&self) -> Option<&str> {
+        self.sqlite.failure()
+    }
+
+    /// Cached similarity search results, invalidated by this store's writes
+    pub fn search_cache(&self) -> &SearchCache {
+        &self.search_cache
+    }
+
+    /// Drop cached searches that could return a memory as it was before a
+    /// write (`previous`) or as it is after (`updated`)
+    fn invalidate_cached(&self, previous: Option<&Memory>, updated: Option<&Memory>) {
+        for memory in [previous, updated].into_iter().flatten() {
+            self.search_cache.invalidate(memory);
+        }
+    }
+
     /// Get the configuration
     pub fn config(&self) -> &Config {
         &self.config
@@ -260,49 +584,1131 @@ impl MemoryStore {
         &self.jsonl
     }
 
+    /// Redact secrets from text if redaction is enabled
+    pub fn redact(&self, text: &str) -> String {
+        match &self.redactor {
+            Some(redactor) => redactor.redact(text),
+            None => text.to_string(),
+        }
+    }
+
+    /// Append a message to its topic log, compacting the topic if it has grown
+    /// past `Config.compact_after_messages`
+    ///
+    /// Compaction leaves `compact_keep_recent` messages plus a summary, so it
+    /// fires at most once per `compact_after_messages - compact_keep_recent`
+    /// appends rather than on every append.
+    pub fn append_message(&self, message: &Message) -> Result<AppendOutcome> {
+        let position = self.jsonl.append(message)?;
+        let compaction = self.compact_if_due(&message.agent_id, &message.topic_id)?;
+
+        Ok(AppendOutcome { position, compaction })
+    }
+
+    /// Append several messages, possibly across topics, validating all of them
+    /// before writing any
+    ///
+    /// Compaction is checked once per topic after the whole batch is written;
+    /// every message in a compacted topic carries that topic's report.
+    pub fn append_messages(&self, messages: &[Message]) -> Result<Vec<AppendOutcome>> {
+        let positions = self.jsonl.append_many(messages)?;
+
+        let mut compactions: Vec<(&str, &str, Option<CompactionReport>)> = Vec::new();
+        for message in messages {
+            let (agent_id, topic_id) = (message.agent_id.as_str(), message.topic_id.as_str());
+            if !compactions.iter().any(|(a, t, _)| *a == agent_id && *t == topic_id) {
+                compactions.push((agent_id, topic_id, self.compact_if_due(agent_id, topic_id)?));
+            }
+        }
+
+        Ok(messages
+            .iter()
+            .zip(positions)
+            .map(|(message, position)| {
+                let compaction = compactions
+                    .iter()
+                    .find(|(a, t, _)| *a == message.agent_id && *t == message.topic_id)
+                    .and_then(|(_, _, report)| report.clone());
+                AppendOutcome { position, compaction }
+            })
+            .collect())
+    }
+
+    /// Compact a topic if it has grown past `Config.compact_after_messages`
+    ///
+    /// Uses the log's running message count rather than re-reading it, so
+    /// this costs nothing on appends that don't compact.
+    fn compact_if_due(&self, agent_id: &str, topic_id: &str) -> Result<Option<CompactionReport>> {
+        match self.config.compact_after_messages {
+            Some(threshold) if threshold > self.config.compact_keep_recent => {
+                self.jsonl
+                    .compact_if_over(agent_id, topic_id, threshold, self.config.compact_keep_recent)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Save a memory with one vector per content chunk
+    ///
+    /// Content should already be redacted, since the vectors were computed from it.
+    pub async fn save_memory_with_vectors(&self, mut memory: Memory, embeddings: &[Vec<f32>]) -> Result<Memory> {
+        memory.context = memory.context.map(|c| self.redact(&c));
+        memory.content_hash = Some(content_hash(&memory.content));
+
+        let previous = self.sqlite.get_memory(memory.id)?;
+        self.sqlite.save_memory(&memory)?;
+        if !embeddings.is_empty() {
+            self.vector.upsert_memory_vectors(&memory, embeddings).await?;
+        }
+        self.invalidate_cached(previous.as_ref(), Some(&memory));
+        self.evict_over_cap(&memory).await?;
+
+        Ok(memory)
+    }
+
     /// Save a memory to all relevant stores
-    pub async fn save_memory(&self, memory: Memory) -> Result<Memory> {
+    ///
+    /// The vector upsert is skipped when the stored copy has the same content,
+    /// so metadata-only edits don't churn the vector store.
+    pub async fn save_memory(&self, mut memory: Memory) -> Result<Memory> {
+        memory.content = self.redact(&memory.content);
+        memory.context = memory.context.map(|c| self.redact(&c));
+
+        let vectors_current = self.vectors_current(&memory)?;
+        if memory.embedding.is_some() || vectors_current {
+            memory.content_hash = Some(content_hash(&memory.content));
+        }
+
         // Save to SQLite for metadata
+        let previous = self.sqlite.get_memory(memory.id)?;
         self.sqlite.save_memory(&memory)?;
 
         // Save to vector store if we have an embedding
-        if memory.embedding.is_some() {
+        if memory.embedding.is_some() && !vectors_current {
             self.vector.upsert_memory(&memory).await?;
+        } else if let Some(previous) = previous.as_ref().filter(|_| vectors_current) {
+            // Content unchanged, so no re-embed, but the index keeps its own copy
+            // of the tags and access fields
+            if previous.tags != memory.tags {
+                self.vector.update_tags(memory.id, &memory.tags).await?;
+            }
+            if (&previous.visibility, &previous.owner_id, previous.active)
+                != (&memory.visibility, &memory.owner_id, memory.active)
+            {
+                self.vector.update_access(&memory).await?;
+            }
         }
+        self.invalidate_cached(previous.as_ref(), Some(&memory));
+        self.evict_over_cap(&memory).await?;
 
         Ok(memory)
     }
 
+    /// Enforce `Config.max_memories_per_scope` after saving `memory`, returning
+    /// the evicted ids
+    ///
+    /// Evicted memories are deactivated, or deleted with
+    /// `Config.delete_evicted_memories`. Pinned memories are never evicted.
+    async fn evict_over_cap(&self, memory: &Memory) -> Result<Vec<Uuid>> {
+        let Some(cap) = self.config.max_memories_per_scope else {
+            return Ok(Vec::new());
+        };
+        if !memory.active {
+            return Ok(Vec::new());
+        }
+
+        let evicted = self.sqlite.lru_over_cap(memory, cap)?;
+        for &id in &evicted {
+            if self.config.delete_evicted_memories {
+                self.delete_memory(id).await?;
+            } else {
+                self.deactivate_memory(id).await?;
+            }
+        }
+        if !evicted.is_empty() {
+            tracing::info!("Evicted {} memories over the scope cap of {}", evicted.len(), cap);
+        }
+
+        Ok(evicted)
+    }
+
+    /// Whether the stored copy of this memory was embedded from the same content
+    pub fn vectors_current(&self, memory: &Memory) -> Result<bool> {
+        let hash = content_hash(&memory.content);
+        Ok(self
+            .sqlite
+            .get_memory(memory.id)?
+            .and_then(|stored| stored.content_hash)
+            .is_some_and(|stored| stored == hash))
+    }
+
     /// Get a memory by ID
     pub fn get_memory(&self, id: Uuid) -> Result<Option<Memory>> {
         self.sqlite.get_memory(id)
     }
 
-    /// List memories with optional filters
+    /// List memories with optional filters; `contains` is a literal,
+    /// case-insensitive substring match on content
     pub fn list_memories(
         &self,
         scope: Option<MemoryScope>,
         agent_id: Option<&str>,
         topic_id: Option<&str>,
+        contains: Option<&str>,
         active_only: bool,
     ) -> Result<Vec<Memory>> {
-        self.sqlite.list_memories(scope, agent_id, topic_id, active_only)
+        self.sqlite.list_memories(scope, agent_id, topic_id, contains, active_only)
+    }
+
+    /// An agent's most recently learned memories of the given types, newest first
+    pub fn list_learned(
+        &self,
+        agent_id: &str,
+        types: &[MemoryType],
+        since: Option<DateTime<Utc>>,
+        limit: usize,
+        caller: Option<&str>,
+    ) -> Result<Vec<Memory>> {
+        self.sqlite.list_learned(agent_id, types, since, limit, caller)
+    }
+
+    /// Count memories by scope and type under the same filters as `list_memories`
+    pub fn facet_counts(
+        &self,
+        scope: Option<MemoryScope>,
+        agent_id: Option<&str>,
+        topic_id: Option<&str>,
+        contains: Option<&str>,
+        active_only: bool,
+        caller: Option<&str>,
+    ) -> Result<MemoryFacets> {
+        self.sqlite.facet_counts(scope, agent_id, topic_id, contains, active_only, caller)
     }
 
     /// Delete a memory
     pub async fn delete_memory(&self, id: Uuid) -> Result<()> {
+        let previous = self.sqlite.get_memory(id)?;
         self.sqlite.delete_memory(id)?;
         self.vector.delete_memory(id).await?;
+        self.invalidate_cached(previous.as_ref(), None);
         Ok(())
     }
 
+    /// Delete several memories, returning the ids that were found and deleted
+    pub async fn delete_memories(&self, ids: &[Uuid]) -> Result<Vec<Uuid>> {
+        let previous = ids
+            .iter()
+            .map(|id| self.sqlite.get_memory(*id))
+            .collect::<Result<Vec<_>>>()?;
+        let deleted = self.sqlite.delete_memories(ids)?;
+        self.vector.delete_memories(ids).await?;
+        for memory in previous.iter().flatten() {
+            self.invalidate_cached(Some(memory), None);
+        }
+        Ok(deleted)
+    }
+
+    /// Distill a topic into an agent-scoped memory
+    ///
+    /// The memory's `topic_id` and context link back to the source topic.
+    pub async fn promote_topic(
+        &self,
+        agent_id: &str,
+        topic_id: &str,
+        memory_type: MemoryType,
+        engine: &RetrievalEngine,
+    ) -> Result<Memory> {
+        let summary = self
+            .jsonl
+            .summarize_topic(agent_id, topic_id)?
+            .ok_or_else(|| Error::not_found(format!("Topic {}/{}", agent_id, topic_id)))?;
+
+        let mut memory = Memory::for_agent(agent_id, memory_type, summary)
+            .with_context(format!("Promoted from topic {}/{}", agent_id, topic_id));
+        memory.topic_id = Some(topic_id.to_string());
+
+        engine.embed_and_save(self, memory).await
+    }
+
+    /// Context limit for a request: an explicit `model` is looked up in the config,
+    /// otherwise the agent record's model and `context_limit` are used
+    pub fn context_limit(&self, agent_id: Option<&str>, model: Option<&str>) -> Result<u32> {
+        if model.is_some() {
+            return Ok(self.config.context_limit_for(model, None));
+        }
+
+        // A degraded store has no agent records; fall back to the default limit
+        let agent = match self.degraded() {
+            Some(_) => None,
+            None => agent_id
+                .map(|id| self.sqlite.get_agent(id))
+                .transpose()?
+                .flatten(),
+        };
+        Ok(match agent {
+            Some(agent) => self
+                .config
+                .context_limit_for(Some(&agent.model), Some(agent.context_limit)),
+            None => self.config.context_limit_for(None, None),
+        })
+    }
+
+    /// Recreate agent and topic rows from the JSONL logs
+    ///
+    /// Topic counts and token totals are recomputed from the logs; existing topic
+    /// names and agent settings are kept. Agents without a record get defaults.
+    pub fn rebuild_metadata_from_logs(&self) -> Result<RebuildReport> {
+        let mut report = RebuildReport::default();
+
+        for agent_id in self.jsonl.list_agents()? {
+            let existing_topics = self.sqlite.list_topics(&agent_id)?;
+            let mut first_message_at: Option<DateTime<Utc>> = None;
+
+            let mut topics = Vec::new();
+            for topic_id in self.jsonl.list_topics(&agent_id)? {
+                let messages = self.jsonl.read_all(&agent_id, &topic_id)?;
+                if let Some(first) = messages.iter().map(|m| m.timestamp).min() {
+                    first_message_at = Some(first_message_at.map_or(first, |f| f.min(first)));
+                }
+
+                let existing = existing_topics.iter().find(|t| t.id == topic_id);
+                report.messages += messages.len();
+                report.tokens += messages.iter().map(|m| m.tokens as u64).sum::<u64>();
+                topics.push(topic_record(&agent_id, &topic_id, &messages, existing));
+            }
+
+            // Topics reference their agent, so make sure it exists first
+            if self.sqlite.get_agent(&agent_id)?.is_none() {
+                self.sqlite.save_agent(&AgentRecord {
+                    id: agent_id.clone(),
+                    name: agent_id.clone(),
+                    model: "unknown".to_string(),
+                    context_limit: DEFAULT_CONTEXT_LIMIT,
+                    color: "#6366F1".to_string(),
+                    created_at: first_message_at.unwrap_or_else(Utc::now),
+                })?;
+                report.agents_created += 1;
+            }
+
+            for topic in &topics {
+                self.sqlite.save_topic(topic)?;
+                report.topics_rebuilt += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Merge one of an agent's topics into another
+    ///
+    /// Messages are interleaved by timestamp into the destination log, the
+    /// source's topic memories are re-pointed at the destination, the source
+    /// log and row are deleted, and the destination row is recomputed.
+    pub async fn merge_topics(&self, agent_id: &str, source_topic: &str, dest_topic: &str) -> Result<MergeReport> {
+        if source_topic == dest_topic {
+            return Err(Error::invalid_input("Cannot merge a topic into itself"));
+        }
+        let source_messages = self.jsonl.count(agent_id, source_topic)?;
+        if source_messages == 0 {
+            return Err(Error::not_found(format!("Topic {}/{}", agent_id, source_topic)));
+        }
+
+        let merged = self.jsonl.merge_topics(agent_id, source_topic, dest_topic)?;
+        let memories_moved = self.sqlite.move_topic_memories(agent_id, source_topic, dest_topic)?;
+        self.vector.move_topic_memories(agent_id, source_topic, dest_topic).await?;
+
+        let existing = self
+            .sqlite
+            .list_topics(agent_id)?
+            .into_iter()
+            .find(|t| t.id == dest_topic);
+        self.sqlite.save_topic(&topic_record(agent_id, dest_topic, &merged, existing.as_ref()))?;
+        self.sqlite.delete_topic(agent_id, source_topic)?;
+        self.search_cache.invalidate_agent(agent_id);
+
+        Ok(MergeReport {
+            messages_moved: source_messages,
+            memories_moved,
+            message_count: merged.len(),
+        })
+    }
+
+    /// Write a snapshot of an agent (its record, every topic's messages and its
+    /// agent and topic memories) as one JSON document
+    ///
+    /// Stored vectors are included with `Config.bundle_embeddings`, so an
+    /// import into a store using the same model needs no re-embedding.
+    pub async fn export_bundle(&self, agent_id: &str, writer: impl Write) -> Result<()> {
+        validate_id("agent_id", agent_id)?;
+        let records = self.sqlite.list_topics(agent_id)?;
+
+        let mut topics = Vec::new();
+        for topic_id in self.jsonl.list_topics(agent_id)? {
+            topics.push(BundleTopic {
+                record: records.iter().find(|t| t.id == topic_id).cloned(),
+                messages: self.jsonl.read_all(agent_id, &topic_id)?,
+                topic_id,
+            });
+        }
+
+        let mut memories = Vec::new();
+        for memory in self.sqlite.list_memories(None, Some(agent_id), None, None, false)? {
+            let embeddings = if self.config.bundle_embeddings {
+                self.vector.get_vectors(memory.id).await?
+            } else {
+                Vec::new()
+            };
+            memories.push(BundleMemory { memory, embeddings });
+        }
+
+        let bundle = AgentBundle {
+            version: BUNDLE_VERSION,
+            exported_at: Utc::now(),
+            agent_id: agent_id.to_string(),
+            agent: self.sqlite.get_agent(agent_id)?,
+            topics,
+            memories,
+        };
+        serde_json::to_writer(writer, &bundle)?;
+        Ok(())
+    }
+
+    /// Restore an agent written by `export_bundle`
+    ///
+    /// The agent must not exist here yet: a bundle restores a snapshot rather
+    /// than merging into live state. Messages and memories keep their ids and
+    /// timestamps. Memories whose exported vectors don't fit this store's
+    /// dimension are saved unembedded for `embed-pending`.
+    pub async fn import_bundle(&self, reader: impl Read) -> Result<BundleImportReport> {
+        let bundle: AgentBundle = serde_json::from_reader(reader)?;
+        if bundle.version == 0 || bundle.version > BUNDLE_VERSION {
+            return Err(Error::invalid_input(format!(
+                "Unsupported bundle version {} (supported: {})",
+                bundle.version, BUNDLE_VERSION
+            )));
+        }
+
+        let agent_id = bundle.agent_id.as_str();
+        validate_id("agent_id", agent_id)?;
+        if self.sqlite.get_agent(agent_id)?.is_some() || !self.jsonl.list_topics(agent_id)?.is_empty() {
+            return Err(Error::invalid_input(format!("Agent {} already exists", agent_id)));
+        }
+
+        // Topics reference their agent, so it goes first
+        let agent = bundle.agent.unwrap_or_else(|| AgentRecord {
+            id: agent_id.to_string(),
+            name: agent_id.to_string(),
+            model: "unknown".to_string(),
+            context_limit: DEFAULT_CONTEXT_LIMIT,
+            color: "#6366F1".to_string(),
+            created_at: bundle.exported_at,
+        });
+        self.sqlite.save_agent(&AgentRecord {
+            id: agent_id.to_string(),
+            ..agent
+        })?;
+
+        let mut report = BundleImportReport::default();
+        for topic in bundle.topics {
+            let mut messages = topic.messages;
+            for message in &mut messages {
+                message.agent_id = agent_id.to_string();
+                message.topic_id = topic.topic_id.clone();
+            }
+            self.jsonl.append_many(&messages)?;
+            self.sqlite
+                .save_topic(&topic_record(agent_id, &topic.topic_id, &messages, topic.record.as_ref()))?;
+            report.topics += 1;
+            report.messages += messages.len();
+        }
+
+        let dimensions = self.vector.dimensions();
+        for BundleMemory { mut memory, embeddings } in bundle.memories {
+            let usable = !embeddings.is_empty() && embeddings.iter().all(|e| e.len() == dimensions);
+            if usable {
+                self.save_memory_with_vectors(memory, &embeddings).await?;
+            } else {
+                // No hash marks it as awaiting embedding
+                memory.embedding = None;
+                memory.content_hash = None;
+                self.save_memory(memory).await?;
+                report.unembedded += 1;
+            }
+            report.memories += 1;
+        }
+
+        Ok(report)
+    }
+
+    /// Write token counts into every topic log for messages stored without
+    /// one, returning how many were fixed (see `JsonlStorage::rewrite_token_counts`)
+    pub fn recount_message_tokens(&self) -> Result<usize> {
+        let mut fixed = 0;
+        for agent_id in self.jsonl.list_agents()? {
+            for topic_id in self.jsonl.list_topics(&agent_id)? {
+                fixed += self.jsonl.rewrite_token_counts(&agent_id, &topic_id)?;
+            }
+        }
+        Ok(fixed)
+    }
+
+    /// Memories awaiting embedding, e.g. saved while the model was unavailable
+    pub fn list_unembedded(&self) -> Result<Vec<Memory>> {
+        self.sqlite.list_unembedded()
+    }
+
+    /// Re-embed a single memory's content and replace its vector
+    pub async fn reembed_memory(&self, id: Uuid, engine: &RetrievalEngine) -> Result<Memory> {
+        let memory = self
+            .get_memory(id)?
+            .ok_or_else(|| Error::not_found(format!("Memory {}", id)))?;
+
+        let embeddings = engine.embed_content(&memory.content).await?;
+        let mut memory = memory;
+        memory.embedding = embeddings.first().cloned();
+        self.vector.upsert_memory_vectors(&memory, &embeddings).await?;
+        self.invalidate_cached(Some(&memory), None);
+
+        // Record the hash for memories saved before hashes were tracked
+        if memory.content_hash.is_none() {
+            memory.content_hash = Some(content_hash(&memory.content));
+            self.sqlite.save_memory(&memory)?;
+        }
+
+        Ok(memory)
+    }
+
+    /// Find memories whose stored vector doesn't match the current model's
+    /// dimension, e.g. after a model change, and re-embed just those
+    ///
+    /// With `Config.reembed_mismatched_vectors` off they are only reported.
+    /// A memory that fails to re-embed is recorded in the report rather than
+    /// aborting the rest, so a migration can be run again to pick up stragglers.
+    pub async fn migrate_mismatched_vectors(&self, engine: &RetrievalEngine) -> Result<VectorMigrationReport> {
+        let dimensions = engine.embedding_service().dimensions();
+        let mut report = VectorMigrationReport::default();
+
+        for memory in self.sqlite.list_memories(None, None, None, None, false)? {
+            // Memories with no vector at all are `embed-pending`'s job
+            let Some(vector) = self.vector.get_vector(memory.id).await? else {
+                continue;
+            };
+            report.checked += 1;
+            if vector.len() == dimensions {
+                continue;
+            }
+
+            report.mismatched.push(memory.id);
+            if !self.config.reembed_mismatched_vectors {
+                continue;
+            }
+            match self.reembed_memory(memory.id, engine).await {
+                Ok(_) => report.reembedded += 1,
+                Err(e) => {
+                    tracing::warn!("Failed to re-embed memory {}: {}", memory.id, e);
+                    report.failed.push((memory.id, e.to_string()));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Compare the live model's output dimension against the config, the
+    /// vector store and the vector table's schema
+    ///
+    /// A mismatch with the table means every upsert will fail; one with the
+    /// config only means detection papered over a misconfiguration.
+    pub async fn check_consistency(&self, engine: &RetrievalEngine) -> Result<ConsistencyReport> {
+        let mut report = ConsistencyReport {
+            configured_dimensions: engine.configured_dimensions(),
+            model_dimensions: engine.embedding_service().probe().await?,
+            store_dimensions: self.vector.dimensions(),
+            table_dimensions: self.vector.table_dimensions().await?,
+            problems: Vec::new(),
+        };
+
+        let model = report.model_dimensions;
+        if report.configured_dimensions != model {
+            report.problems.push(format!(
+                "Config.embedding_dimensions is {} but the model outputs {}",
+                report.configured_dimensions, model
+            ));
+        }
+        if report.store_dimensions != model {
+            report.problems.push(format!(
+                "Vector store uses {} dimensions but the model outputs {}",
+                report.store_dimensions, model
+            ));
+        }
+        match report.table_dimensions {
+            Some(table) if table != model => report.problems.push(format!(
+                "Vector table {} holds {}-dimension vectors but the model outputs {}; \
+                 drop the table and reindex",
+                self.config.vector_table_name, table, model
+            )),
+            Some(_) => {}
+            None => report.problems.push(format!(
+                "Vector table {} has no fixed-size vector column",
+                self.config.vector_table_name
+            )),
+        }
+
+        Ok(report)
+    }
+
     /// Deactivate a memory (soft delete)
-    pub fn deactivate_memory(&self, id: Uuid) -> Result<()> {
-        self.sqlite.set_memory_active(id, false)
+    pub async fn deactivate_memory(&self, id: Uuid) -> Result<()> {
+        self.set_memory_active(id, false).await
     }
 
     /// Reactivate a memory
-    pub fn reactivate_memory(&self, id: Uuid) -> Result<()> {
-        self.sqlite.set_memory_active(id, true)
+    pub async fn reactivate_memory(&self, id: Uuid) -> Result<()> {
+        self.set_memory_active(id, true).await
+    }
+
+    /// Set a memory's active flag in SQLite and in its vector rows
+    async fn set_memory_active(&self, id: Uuid, active: bool) -> Result<()> {
+        self.sqlite.set_memory_active(id, active)?;
+        let memory = self.sqlite.get_memory(id)?;
+        if let Some(memory) = &memory {
+            self.vector.update_access(memory).await?;
+        }
+        self.invalidate_cached(memory.as_ref(), None);
+        Ok(())
+    }
+
+    /// Pin or unpin a memory
+    pub fn set_memory_pinned(&self, id: Uuid, pinned: bool) -> Result<()> {
+        self.sqlite.set_memory_pinned(id, pinned)?;
+        self.invalidate_cached(self.sqlite.get_memory(id)?.as_ref(), None);
+        Ok(())
+    }
+
+    /// Make the store's memories from a sync file (see `crate::sync`) match it
+    ///
+    /// Entries are upserted under their stable ids, so re-syncing an unchanged
+    /// file touches nothing. Memories from an earlier sync of the same file
+    /// that it no longer contains are deactivated. Synced memories record the
+    /// file in their `context`, which is how they are recognized. The whole
+    /// file is validated before anything is written.
+    pub async fn sync_from_file(&self, path: impl AsRef<Path>, engine: &RetrievalEngine) -> Result<SyncReport> {
+        let path = path.as_ref();
+        let entries = parse_memory_file(&std::fs::read_to_string(path)?)?;
+        let source = format!("Synced from {}", std::fs::canonicalize(path)?.display());
+
+        let memories = entries
+            .iter()
+            .map(|entry| -> Result<Memory> { Ok(entry.to_memory()?.with_context(source.clone())) })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut report = SyncReport::default();
+        let mut synced = HashSet::new();
+        for memory in memories {
+            synced.insert(memory.id);
+
+            let memory = match self.get_memory(memory.id)? {
+                None => {
+                    report.created += 1;
+                    memory
+                }
+                Some(old)
+                    if old.active
+                        && old.content == self.redact(&memory.content)
+                        && old.memory_type == memory.memory_type
+                        && old.scope == memory.scope
+                        && old.agent_id == memory.agent_id
+                        && old.topic_id == memory.topic_id
+                        && old.tags == memory.tags
+                        && old.context == memory.context =>
+                {
+                    report.unchanged += 1;
+                    continue;
+                }
+                Some(old) => {
+                    report.updated += 1;
+                    // Upserts don't rewrite a memory's type or scope, so start over
+                    if (old.memory_type, old.scope, &old.agent_id, &old.topic_id)
+                        != (memory.memory_type, memory.scope, &memory.agent_id, &memory.topic_id)
+                    {
+                        self.delete_memory(old.id).await?;
+                    }
+                    // Keep usage history across edits
+                    Memory {
+                        created_at: old.created_at,
+                        last_used_at: old.last_used_at,
+                        retrieval_count: old.retrieval_count,
+                        pinned: old.pinned,
+                        ..memory
+                    }
+                }
+            };
+            engine.embed_and_save(self, memory).await?;
+        }
+
+        for memory in self.sqlite.list_memories(None, None, None, None, true)? {
+            if memory.context.as_deref() == Some(source.as_str()) && !synced.contains(&memory.id) {
+                self.deactivate_memory(memory.id).await?;
+                report.deactivated += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Set the multiplier applied to a memory's similarity scores
+    pub fn set_memory_weight(&self, id: Uuid, weight: f32) -> Result<()> {
+        self.sqlite.set_memory_weight(id, weight)?;
+        self.invalidate_cached(self.sqlite.get_memory(id)?.as_ref(), None);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Role;
+    use crate::test_support::{self, setup};
+
+    /// Messages in one topic, a minute apart from `start` minutes past a fixed time
+    fn messages_at(agent_id: &str, topic_id: &str, start: i64, contents: &[&str]) -> Vec<Message> {
+        let base = DateTime::parse_from_rfc3339("2026-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| {
+                Message::new(agent_id, topic_id, Role::User, *content)
+                    .with_timestamp(base + chrono::Duration::minutes(start + 2 * i as i64))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn appends_compact_once_past_the_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = test_support::store(dir.path(), |c| {
+            c.compact_after_messages = Some(5);
+            c.compact_keep_recent = 2;
+        })
+        .await;
+
+        let mut compactions = Vec::new();
+        for i in 0..8 {
+            let message = Message::new("agent", "foo", Role::User, format!("message {}", i));
+            if let Some(report) = store.append_message(&message).unwrap().compaction {
+                compactions.push((i, report));
+            }
+        }
+
+        // The sixth append crosses the threshold. The summary and two kept
+        // messages leave room for three more appends before the next one.
+        assert_eq!(compactions.len(), 1);
+        let (at, report) = &compactions[0];
+        assert_eq!(*at, 5);
+        assert_eq!(report.archived, 4);
+
+        let messages = store.jsonl().read_all("agent", "foo").unwrap();
+        assert_eq!(messages[0].role, Role::System);
+        let kept: Vec<&str> = messages[1..].iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(kept, ["message 4", "message 5", "message 6", "message 7"]);
+        assert_eq!(store.jsonl().message_count("agent", "foo").unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn merge_interleaves_every_message_and_removes_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        store.append_messages(&messages_at("agent", "a", 0, &["a1", "a2", "a3"])).unwrap();
+        store.append_messages(&messages_at("agent", "b", 1, &["b1", "b2"])).unwrap();
+        let memory = engine
+            .embed_and_save(&store, Memory::for_topic("agent", "a", MemoryType::Fact, "from topic a"))
+            .await
+            .unwrap();
+
+        let report = store.merge_topics("agent", "a", "b").await.unwrap();
+        assert_eq!((report.messages_moved, report.memories_moved, report.message_count), (3, 1, 5));
+
+        let merged = store.jsonl().read_all("agent", "b").unwrap();
+        let contents: Vec<&str> = merged.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["a1", "b1", "a2", "b2", "a3"]);
+        assert!(merged.iter().all(|m| m.topic_id == "b"));
+
+        assert_eq!(store.jsonl().list_topics("agent").unwrap(), ["b"]);
+        let topics = store.sqlite().list_topics("agent").unwrap();
+        assert_eq!(topics.iter().map(|t| t.id.as_str()).collect::<Vec<_>>(), ["b"]);
+        assert_eq!(topics[0].message_count, 5);
+        assert_eq!(store.get_memory(memory.id).unwrap().unwrap().topic_id.as_deref(), Some("b"));
+
+        // Nothing is left to merge a second time
+        assert!(matches!(store.merge_topics("agent", "a", "b").await, Err(Error::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn resync_applies_edits_and_deactivates_removed_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        let path = dir.path().join("memories.md");
+        let keyed = "---\nkey: deploys\ntype: fact\n---\nDeploys run on friday\n";
+        let unkeyed = "---\ntype: preference\n---\nAnswer briefly\n";
+
+        std::fs::write(&path, format!("{}{}", keyed, unkeyed)).unwrap();
+        let report = store.sync_from_file(&path, &engine).await.unwrap();
+        assert_eq!((report.created, report.updated, report.unchanged, report.deactivated), (2, 0, 0, 0));
+
+        let report = store.sync_from_file(&path, &engine).await.unwrap();
+        assert_eq!((report.created, report.updated, report.unchanged, report.deactivated), (0, 0, 2, 0));
+
+        // Edit the keyed entry and drop the other
+        std::fs::write(&path, keyed.replace("friday", "monday")).unwrap();
+        let report = store.sync_from_file(&path, &engine).await.unwrap();
+        assert_eq!((report.created, report.updated, report.unchanged, report.deactivated), (0, 1, 0, 1));
+
+        let active = store.list_memories(None, None, None, None, true).unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].content, "Deploys run on monday");
+        let stored = store.vector().get_vector(active[0].id).await.unwrap().unwrap();
+        assert_eq!(stored, engine.embed_content("Deploys run on monday").await.unwrap()[0]);
+    }
+
+    #[tokio::test]
+    async fn delete_memories_purges_both_stores_and_reports_what_existed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        let mut saved = Vec::new();
+        for content in ["first memory", "second memory", "kept memory"] {
+            let memory = Memory::global(MemoryType::Fact, content);
+            saved.push(engine.embed_and_save(&store, memory).await.unwrap().id);
+        }
+        let missing = Uuid::new_v4();
+
+        let mut deleted = store.delete_memories(&[saved[0], saved[1], missing]).await.unwrap();
+        deleted.sort();
+        let mut expected = vec![saved[0], saved[1]];
+        expected.sort();
+        assert_eq!(deleted, expected);
+
+        for id in &saved[..2] {
+            assert!(store.get_memory(*id).unwrap().is_none());
+            assert!(store.vector().get_vector(*id).await.unwrap().is_none());
+        }
+        assert!(store.get_memory(saved[2]).unwrap().is_some());
+        assert!(store.vector().get_vector(saved[2]).await.unwrap().is_some());
+
+        assert!(store.delete_memories(&[missing]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tag_only_edits_keep_the_vector_and_update_its_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let embedder = test_support::FakeEmbedder::new(test_support::DIMENSIONS);
+        let calls = embedder.calls.clone();
+        let engine = test_support::engine_with(&test_support::config(dir.path(), |_| {}), embedder);
+        let store = MemoryStore::new(engine.config().clone()).await.unwrap();
+        let embeds = || calls.load(std::sync::atomic::Ordering::SeqCst);
+
+        let memory = engine
+            .embed_and_save(&store, Memory::global(MemoryType::Fact, "deploys run on friday"))
+            .await
+            .unwrap();
+        assert_eq!(embeds(), 1);
+
+        let tagged = Memory {
+            tags: vec!["ops".to_string()],
+            ..store.get_memory(memory.id).unwrap().unwrap()
+        };
+        engine.embed_and_save(&store, tagged.clone()).await.unwrap();
+        assert_eq!(embeds(), 1);
+        assert_eq!(store.get_memory(memory.id).unwrap().unwrap().tags, ["ops"]);
+
+        let vector = store.vector().get_vector(memory.id).await.unwrap().unwrap();
+        let hits = store
+            .vector()
+            .search(&vector, 1, 0.0, &crate::storage::vector::SearchFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(hits[0].tags, ["ops"]);
+
+        // A content edit does re-embed
+        let edited = Memory {
+            content: "deploys run on monday".to_string(),
+            ..tagged
+        };
+        engine.embed_and_save(&store, edited).await.unwrap();
+        assert_eq!(embeds(), 2);
+    }
+
+    #[tokio::test]
+    async fn bundle_round_trips_into_a_fresh_store() {
+        let (source_dir, dest_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (source, engine) = setup(source_dir.path(), |c| c.bundle_embeddings = true).await;
+        source
+            .sqlite()
+            .save_agent(&AgentRecord {
+                id: "agent".to_string(),
+                name: "Agent".to_string(),
+                model: "gpt-4".to_string(),
+                context_limit: 8000,
+                color: "#000000".to_string(),
+                created_at: Utc::now(),
+            })
+            .unwrap();
+        let messages = messages_at("agent", "foo", 0, &["hello", "hi there"]);
+        source.append_messages(&messages).unwrap();
+        let memory = engine
+            .embed_and_save(&source, Memory::for_agent("agent", MemoryType::Preference, "answer briefly"))
+            .await
+            .unwrap();
+
+        let mut bundle = Vec::new();
+        source.export_bundle("agent", &mut bundle).await.unwrap();
+
+        let dest = test_support::store(dest_dir.path(), |_| {}).await;
+        let report = dest.import_bundle(bundle.as_slice()).await.unwrap();
+        assert_eq!((report.topics, report.messages, report.memories, report.unembedded), (1, 2, 1, 0));
+
+        assert_eq!(dest.sqlite().get_agent("agent").unwrap().unwrap().model, "gpt-4");
+        let imported = dest.jsonl().read_all("agent", "foo").unwrap();
+        assert_eq!(
+            imported.iter().map(|m| (m.id, m.timestamp)).collect::<Vec<_>>(),
+            messages.iter().map(|m| (m.id, m.timestamp)).collect::<Vec<_>>()
+        );
+        assert_eq!(dest.get_memory(memory.id).unwrap().unwrap().content, "answer briefly");
+        assert_eq!(
+            dest.vector().get_vector(memory.id).await.unwrap(),
+            source.vector().get_vector(memory.id).await.unwrap()
+        );
+
+        // A bundle restores a snapshot, it doesn't merge into an existing agent
+        let again = dest.import_bundle(bundle.as_slice()).await;
+        assert!(matches!(again, Err(Error::InvalidInput(_))));
+    }
+
+    #[tokio::test]
+    async fn rebuild_restores_a_dropped_topics_table_from_the_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = test_support::store(dir.path(), |_| {}).await;
+            let mut messages = messages_at("agent", "foo", 0, &["one", "two"]);
+            messages.extend(messages_at("agent", "bar", 1, &["three"]));
+            for message in &mut messages {
+                message.tokens = 5;
+            }
+            store.append_messages(&messages).unwrap();
+        }
+
+        let conn = rusqlite::Connection::open(dir.path().join("metadata.db")).unwrap();
+        conn.execute_batch("DROP TABLE topics").unwrap();
+        drop(conn);
+
+        let store = test_support::store(dir.path(), |_| {}).await;
+        assert!(store.sqlite().list_topics("agent").unwrap().is_empty());
+
+        let report = store.rebuild_metadata_from_logs().unwrap();
+        assert_eq!(report.agents_created, 1);
+        assert_eq!(report.topics_rebuilt, 2);
+        assert_eq!((report.messages, report.tokens), (3, 15));
+
+        let mut topics = store.sqlite().list_topics("agent").unwrap();
+        topics.sort_by(|a, b| a.id.cmp(&b.id));
+        let counts: Vec<_> = topics.iter().map(|t| (t.id.as_str(), t.message_count, t.token_count)).collect();
+        assert_eq!(counts, [("bar", 1, 5), ("foo", 2, 10)]);
+        assert!(store.sqlite().get_agent("agent").unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn promoted_topic_is_searchable_and_links_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| c.min_similarity_score = 0.3).await;
+        store
+            .append_messages(&messages_at("agent", "foo", 0, &["the staging database lives on host db7"]))
+            .unwrap();
+
+        let memory = store.promote_topic("agent", "foo", MemoryType::Fact, &engine).await.unwrap();
+        assert_eq!(memory.scope, MemoryScope::Agent);
+        assert_eq!(memory.agent_id.as_deref(), Some("agent"));
+        assert_eq!(memory.topic_id.as_deref(), Some("foo"));
+        assert_eq!(memory.context.as_deref(), Some("Promoted from topic agent/foo"));
+        assert!(memory.content.contains("the staging database lives on host db7"));
+
+        // Agent-scoped, so it is found from any of the agent's topics
+        let context = engine
+            .retrieve(&store, "staging database host db7", Some("agent"), Some("bar"), 0, None)
+            .await
+            .unwrap();
+        assert_eq!(context.memories.iter().map(|m| m.id).collect::<Vec<_>>(), [memory.id]);
+
+        let missing = store.promote_topic("agent", "empty", MemoryType::Fact, &engine).await;
+        assert!(matches!(missing, Err(Error::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn reembed_writes_a_vector_and_records_the_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        let memory = store
+            .save_memory(Memory::global(MemoryType::Fact, "deploys run on friday"))
+            .await
+            .unwrap();
+        assert!(store.vector().get_vector(memory.id).await.unwrap().is_none());
+        assert_eq!(store.list_unembedded().unwrap().len(), 1);
+
+        let reembedded = store.reembed_memory(memory.id, &engine).await.unwrap();
+        let expected = engine.embed_content("deploys run on friday").await.unwrap();
+        assert_eq!(reembedded.embedding.as_ref(), expected.first());
+        assert_eq!(store.vector().get_vector(memory.id).await.unwrap().as_ref(), expected.first());
+        assert_eq!(
+            store.get_memory(memory.id).unwrap().unwrap().content_hash,
+            Some(content_hash("deploys run on friday"))
+        );
+        assert!(store.list_unembedded().unwrap().is_empty());
+
+        // Re-embedding again replaces the vector rather than adding a row
+        store.reembed_memory(memory.id, &engine).await.unwrap();
+        assert_eq!(store.vector().get_vectors(memory.id).await.unwrap().len(), 1);
+
+        let missing = store.reembed_memory(Uuid::new_v4(), &engine).await;
+        assert!(matches!(missing, Err(Error::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn consistency_check_reports_a_model_of_another_dimension() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        let report = store.check_consistency(&engine).await.unwrap();
+        assert!(report.is_consistent(), "{:?}", report.problems);
+        assert_eq!(report.table_dimensions, Some(test_support::DIMENSIONS));
+
+        // A model swapped underneath a config and table that still say 64
+        let swapped = test_support::engine_with(store.config(), test_support::FakeEmbedder::new(32));
+        let report = store.check_consistency(&swapped).await.unwrap();
+        assert!(!report.is_consistent());
+        assert_eq!(
+            (report.configured_dimensions, report.model_dimensions, report.store_dimensions, report.table_dimensions),
+            (64, 32, 64, Some(64))
+        );
+        assert_eq!(report.problems.len(), 3);
+        assert!(report.problems[2].contains("drop the table and reindex"));
+    }
+
+    #[tokio::test]
+    async fn only_mismatched_vectors_are_reembedded() {
+        let dir = tempfile::tempdir().unwrap();
+        let (embedded, unembedded) = {
+            let (store, engine) = setup(dir.path(), |_| {}).await;
+            let mut embedded = Vec::new();
+            for content in ["first memory", "second memory"] {
+                let memory = Memory::global(MemoryType::Fact, content);
+                embedded.push(engine.embed_and_save(&store, memory).await.unwrap().id);
+            }
+            let unembedded = store.save_memory(Memory::global(MemoryType::Fact, "never embedded")).await.unwrap();
+
+            // Vectors that match the model are left alone
+            let report = store.migrate_mismatched_vectors(&engine).await.unwrap();
+            assert_eq!((report.checked, report.mismatched.len(), report.reembedded), (2, 0, 0));
+            (embedded, unembedded.id)
+        };
+
+        // The model changes to 32 dimensions; the table still holds 64
+        let reopen = |reembed: bool| {
+            let dir = dir.path();
+            async move {
+                let embedder = test_support::FakeEmbedder::new(32);
+                let calls = embedder.calls.clone();
+                let config = test_support::config(dir, |c| {
+                    c.embedding_dimensions = 32;
+                    c.reembed_mismatched_vectors = reembed;
+                });
+                let engine = test_support::engine_with(&config, embedder);
+                (MemoryStore::new(config).await.unwrap(), engine, calls)
+            }
+        };
+        let count = |calls: &std::sync::Arc<std::sync::atomic::AtomicUsize>| {
+            calls.load(std::sync::atomic::Ordering::SeqCst)
+        };
+
+        // Report only
+        let (store, engine, embeds) = reopen(false).await;
+        let mut report = store.migrate_mismatched_vectors(&engine).await.unwrap();
+        report.mismatched.sort();
+        let mut expected = embedded.clone();
+        expected.sort();
+        assert_eq!((report.checked, report.mismatched.clone()), (2, expected));
+        assert_eq!((report.reembedded, count(&embeds)), (0, 0));
+        drop(store);
+
+        // Re-embed: only the two mismatched memories reach the model, and the
+        // table's refusal is recorded per memory without losing their vectors
+        let (store, engine, embeds) = reopen(true).await;
+        let report = store.migrate_mismatched_vectors(&engine).await.unwrap();
+        assert_eq!(count(&embeds), 2);
+        assert_eq!((report.reembedded, report.failed.len()), (0, 2));
+        assert!(report.failed.iter().all(|(id, _)| embedded.contains(id)));
+        for id in &embedded {
+            assert_eq!(store.vector().get_vector(*id).await.unwrap().unwrap().len(), 64);
+        }
+        assert!(store.vector().get_vector(unembedded).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn scopes_and_types_parse_their_display_names() {
+        for scope in [MemoryScope::Global, MemoryScope::Agent, MemoryScope::Topic, MemoryScope::Personal] {
+            assert_eq!(scope.to_string().parse::<MemoryScope>().unwrap(), scope);
+            assert_eq!(MemoryScope::try_from(scope.to_string().as_str()).unwrap(), scope);
+        }
+        for memory_type in [
+            MemoryType::Correction,
+            MemoryType::Preference,
+            MemoryType::Fact,
+            MemoryType::Workflow,
+            MemoryType::Constraint,
+        ] {
+            assert_eq!(memory_type.to_string().parse::<MemoryType>().unwrap(), memory_type);
+            assert_eq!(MemoryType::try_from(memory_type.to_string().as_str()).unwrap(), memory_type);
+        }
+    }
+
+    #[test]
+    fn unknown_scopes_and_types_are_invalid_input() {
+        for scope in ["", "Global", "private", " agent"] {
+            assert!(matches!(scope.parse::<MemoryScope>(), Err(Error::InvalidInput(_))), "{:?}", scope);
+            assert!(MemoryScope::try_from(scope).is_err());
+        }
+        for memory_type in ["", "Fact", "note", "facts"] {
+            assert!(matches!(memory_type.parse::<MemoryType>(), Err(Error::InvalidInput(_))), "{:?}", memory_type);
+            assert!(MemoryType::try_from(memory_type).is_err());
+        }
+        match "personl".parse::<MemoryScope>() {
+            Err(Error::InvalidInput(message)) => assert!(message.contains("personl")),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn detected_dimension_drives_the_vector_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        // The config still names a 384-dimension model, but the loaded one outputs 48
+        let config = test_support::config(dir.path(), |c| {
+            c.embedding_dimensions = 384;
+            c.detect_embedding_dimensions = true;
+        });
+        let embedder = test_support::FakeEmbedder::new(48);
+        let calls = embedder.calls.clone();
+        let engine = test_support::engine_with(&config, embedder);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!((engine.configured_dimensions(), engine.config().embedding_dimensions), (384, 48));
+
+        let store = MemoryStore::new(engine.config().clone()).await.unwrap();
+        let memory = engine
+            .embed_and_save(&store, Memory::global(MemoryType::Fact, "deploys run on friday"))
+            .await
+            .unwrap();
+        assert_eq!(store.vector().table_dimensions().await.unwrap(), Some(48));
+        assert_eq!(store.vector().get_vector(memory.id).await.unwrap().unwrap().len(), 48);
+
+        // Only the stale config is reported; the store and table follow the model
+        let report = store.check_consistency(&engine).await.unwrap();
+        assert_eq!((report.store_dimensions, report.table_dimensions), (48, Some(48)));
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert!(report.problems[0].starts_with("Config.embedding_dimensions is 384"));
     }
 }
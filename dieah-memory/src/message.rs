@@ -4,27 +4,94 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::error::{Error, Result};
+
 /// Role of a message sender
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
+///
+/// Unrecognized role labels are kept as `Other` and serialize back to the
+/// same string, so logs written by newer clients still load.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum Role {
     System,
+    Developer,
     User,
     Assistant,
     Tool,
+    Other(String),
+}
+
+impl Role {
+    /// Role labels with dedicated variants; any other non-empty label is kept as `Other`
+    pub const KNOWN: [&'static str; 5] = ["system", "developer", "user", "assistant", "tool"];
+
+    /// Parse only the labels in `KNOWN`, rejecting anything that would become `Other`
+    ///
+    /// For input from clients; logs are read with the lenient `FromStr` so
+    /// roles written by newer clients still load.
+    pub fn parse_known(s: &str) -> Result<Self> {
+        match s.parse()? {
+            Role::Other(label) => Err(Error::invalid_input(format!(
+                "Unknown role '{}' (expected one of {})",
+                label,
+                Self::KNOWN.join(", ")
+            ))),
+            role => Ok(role),
+        }
+    }
 }
 
 impl std::fmt::Display for Role {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Role::System => write!(f, "system"),
+            Role::Developer => write!(f, "developer"),
             Role::User => write!(f, "user"),
             Role::Assistant => write!(f, "assistant"),
             Role::Tool => write!(f, "tool"),
+            Role::Other(label) => write!(f, "{}", label),
         }
     }
 }
 
+impl std::str::FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "system" => Ok(Role::System),
+            "developer" => Ok(Role::Developer),
+            "user" => Ok(Role::User),
+            "assistant" => Ok(Role::Assistant),
+            "tool" => Ok(Role::Tool),
+            _ if s.trim().is_empty() => Err(Error::invalid_input("Role must not be empty")),
+            _ => Ok(Role::Other(s.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Role> for String {
+    fn from(role: Role) -> Self {
+        role.to_string()
+    }
+}
+
+impl TryFrom<&str> for Role {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        s.parse()
+    }
+}
+
 /// A single message in a conversation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
@@ -80,15 +147,57 @@ impl Message {
         self
     }
 
+    /// Set the timestamp, e.g. when importing historical messages
+    pub fn with_timestamp(mut self, timestamp: DateTime<Utc>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
     /// Set metadata
     pub fn with_metadata(mut self, metadata: MessageMetadata) -> Self {
         self.metadata = Some(metadata);
         self
     }
+
+    /// Set the model's thinking, kept in metadata separate from content
+    pub fn with_thinking(mut self, thinking: impl Into<String>) -> Self {
+        self.metadata.get_or_insert_with(MessageMetadata::default).thinking = Some(thinking.into());
+        self
+    }
+
+    /// Get the model's thinking, if captured
+    pub fn thinking(&self) -> Option<&str> {
+        self.metadata.as_ref().and_then(|m| m.thinking.as_deref())
+    }
+
+    /// Set attachments
+    pub fn with_attachments(mut self, attachments: Vec<Attachment>) -> Self {
+        self.metadata.get_or_insert_with(MessageMetadata::default).attachments = attachments;
+        self
+    }
+
+    /// Get attachments, if any
+    pub fn attachments(&self) -> &[Attachment] {
+        self.metadata
+            .as_ref()
+            .map(|m| m.attachments.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Mark the message to be kept through compaction
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.metadata.get_or_insert_with(MessageMetadata::default).retain = retain;
+        self
+    }
+
+    /// Whether compaction must keep this message in the active log
+    pub fn retained(&self) -> bool {
+        self.metadata.as_ref().is_some_and(|m| m.retain)
+    }
 }
 
 /// Optional metadata for a message
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct MessageMetadata {
     /// Tool calls made in this message
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
@@ -105,6 +214,61 @@ pub struct MessageMetadata {
     /// Whether this message triggered a memory save
     #[serde(default)]
     pub triggered_memory: bool,
+
+    /// Files or media sent with this message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+
+    /// Keep this message in the active log through compaction
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub retain: bool,
+}
+
+/// An attachment carried either inline or by path reference
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    /// Attachment kind (e.g. "image", "file")
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// Display name or filename
+    pub name: String,
+
+    /// MIME type, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+
+    /// Inline data (base64 for binary content)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+
+    /// Path or URL reference to the content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+impl Attachment {
+    /// Check the attachment has exactly one source and inline data fits `max_inline_bytes`
+    pub fn validate(&self, max_inline_bytes: usize) -> Result<()> {
+        match (&self.data, &self.path) {
+            (Some(data), None) => {
+                if data.len() > max_inline_bytes {
+                    return Err(Error::invalid_input(format!(
+                        "Attachment {} is {} bytes inline (max {})",
+                        self.name,
+                        data.len(),
+                        max_inline_bytes
+                    )));
+                }
+                Ok(())
+            }
+            (None, Some(_)) => Ok(()),
+            _ => Err(Error::invalid_input(format!(
+                "Attachment {} must have exactly one of data or path",
+                self.name
+            ))),
+        }
+    }
 }
 
 /// A tool call within a message
@@ -155,6 +319,10 @@ pub struct TokenUsage {
     /// Tokens used by tool messages
     pub tool: u32,
 
+    /// Tokens used by messages with custom roles
+    #[serde(default)]
+    pub other: u32,
+
     /// Context limit for this agent/model
     pub limit: u32,
 
@@ -171,19 +339,21 @@ impl TokenUsage {
             user: 0,
             assistant: 0,
             tool: 0,
+            other: 0,
             limit,
             utilization: 0.0,
         }
     }
 
-    /// Add tokens for a role
-    pub fn add(&mut self, role: Role, tokens: u32) {
+    /// Add tokens for a role (developer messages count as system)
+    pub fn add(&mut self, role: &Role, tokens: u32) {
         self.total += tokens;
         match role {
-            Role::System => self.system += tokens,
+            Role::System | Role::Developer => self.system += tokens,
             Role::User => self.user += tokens,
             Role::Assistant => self.assistant += tokens,
             Role::Tool => self.tool += tokens,
+            Role::Other(_) => self.other += tokens,
         }
         self.utilization = self.total as f32 / self.limit as f32;
     }
@@ -198,3 +368,37 @@ impl TokenUsage {
         self.utilization >= threshold
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_role_survives_a_log_round_trip() {
+        let roles = [
+            Role::System,
+            Role::Developer,
+            Role::User,
+            Role::Assistant,
+            Role::Tool,
+            Role::Other("narrator".to_string()),
+        ];
+        for role in roles {
+            let message = Message::new("agent", "foo", role.clone(), "hi");
+            let line = serde_json::to_string(&message).unwrap();
+            assert!(line.contains(&format!("\"role\":\"{}\"", role)));
+            let read: Message = serde_json::from_str(&line).unwrap();
+            assert_eq!(read.role, role);
+        }
+        assert!(serde_json::from_str::<Role>("\"\"").is_err());
+    }
+
+    #[test]
+    fn parse_known_rejects_custom_roles() {
+        assert_eq!(Role::parse_known("developer").unwrap(), Role::Developer);
+        assert!(matches!(Role::parse_known("narrator"), Err(Error::InvalidInput(_))));
+        assert!(Role::parse_known(" ").is_err());
+        // Lenient parsing, used for logs, still keeps them
+        assert_eq!("narrator".parse::<Role>().unwrap(), Role::Other("narrator".to_string()));
+    }
+}
@@ -0,0 +1,72 @@
+//! Secret redaction applied before content is stored
+
+use regex::Regex;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+
+/// Replacement text for redacted matches
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Replaces secret-looking substrings with `[REDACTED]`
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    /// Create a redactor from regex patterns
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|p| {
+                Regex::new(p)
+                    .map_err(|e| Error::config(format!("Invalid redaction pattern {}: {}", p, e)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { patterns })
+    }
+
+    /// Build the redactor described by the config, if redaction is enabled
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        if !config.redaction_enabled {
+            return Ok(None);
+        }
+        Self::new(&config.redaction_patterns).map(Some)
+    }
+
+    /// Default patterns for common API key and token formats
+    pub fn default_patterns() -> Vec<String> {
+        [
+            // OpenAI / Anthropic style secret keys
+            r"sk-[A-Za-z0-9_\-]{20,}",
+            // AWS access key IDs
+            r"AKIA[0-9A-Z]{16}",
+            // GitHub tokens
+            r"gh[pousr]_[A-Za-z0-9]{36,}",
+            // Slack tokens
+            r"xox[baprs]-[A-Za-z0-9\-]{10,}",
+            // Google API keys
+            r"AIza[0-9A-Za-z_\-]{35}",
+            // Bearer tokens in pasted headers
+            r"(?i)bearer\s+[A-Za-z0-9._~+/\-]{16,}=*",
+            // PEM private keys
+            r"-----BEGIN [A-Z ]*PRIVATE KEY-----[\s\S]*?-----END [A-Z ]*PRIVATE KEY-----",
+        ]
+        .iter()
+        .map(|p| p.to_string())
+        .collect()
+    }
+
+    /// Redact all matches in a text
+    pub fn redact(&self, text: &str) -> String {
+        let mut output = text.to_string();
+        for pattern in &self.patterns {
+            if pattern.is_match(&output) {
+                output = pattern.replace_all(&output, REDACTED).into_owned();
+            }
+        }
+        output
+    }
+}
@@ -1,11 +1,43 @@
 //! Context retrieval for RAG-style memory injection
 
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
 use crate::config::Config;
-use crate::embedding::EmbeddingService;
-use crate::error::Result;
-use crate::memory::{Memory, MemoryStore};
+use crate::embedding::{EmbeddingService, InputTruncation, TokenCounter};
+use crate::error::{Error, Result};
+use crate::memory::{Memory, MemoryScope, MemoryStore, MemoryType};
 use crate::message::Message;
-use crate::storage::vector::SearchResult;
+use crate::storage::vector::{distance_between, score_from_distance, DistanceMetric, SearchFilter, SearchResult};
+
+/// Why a specific memory was or wasn't retrieved for a query, from
+/// `RetrievalEngine::explain`
+#[derive(Debug, Clone)]
+pub struct RetrievalExplanation {
+    pub memory_id: Uuid,
+    /// Similarity of the query to the memory's best-matching vector; None if
+    /// it has no vector yet
+    pub similarity: Option<f32>,
+    /// Score after weight, inactive penalty and tag boost, as ranking sees it
+    pub score: Option<f32>,
+    /// Similarity threshold applied to the query
+    pub threshold: f32,
+    /// Whether `similarity` clears `threshold`
+    pub passed_threshold: bool,
+    /// Scope, type or activity rules that keep it out of the
+    /// candidates, regardless of similarity
+    pub excluded_by: Vec<String>,
+    /// Whether the query's actual results include it
+    pub retrieved: bool,
+    /// 1-based position among the results, or where its score would place it
+    pub rank: Option<usize>,
+    /// Number of memories the query returned, and the most it could return
+    pub result_count: usize,
+    pub limit: usize,
+}
 
 /// Retrieved context ready for injection into prompts
 #[derive(Debug, Clone)]
@@ -18,6 +50,12 @@ pub struct RetrievalContext {
     
     /// Total tokens in this context
     pub total_tokens: u32,
+
+    /// Metric the memory scores were computed with
+    pub metric: DistanceMetric,
+
+    /// Minimum score a memory needed to be retrieved (`Config.min_similarity_score`)
+    pub threshold: f32,
 }
 
 impl RetrievalContext {
@@ -27,6 +65,8 @@ impl RetrievalContext {
             memories: Vec::new(),
             recent_messages: Vec::new(),
             total_tokens: 0,
+            metric: DistanceMetric::default(),
+            threshold: 0.0,
         }
     }
 
@@ -35,6 +75,11 @@ impl RetrievalContext {
         self.memories.is_empty() && self.recent_messages.is_empty()
     }
 
+    /// Ids and scores of the memories injected by `format_for_prompt`, in order
+    pub fn manifest(&self) -> Vec<(Uuid, f32)> {
+        self.memories.iter().map(|m| (m.id, m.score)).collect()
+    }
+
     /// Format the context for injection into a prompt
     pub fn format_for_prompt(&self) -> String {
         let mut parts = Vec::new();
@@ -42,9 +87,10 @@ impl RetrievalContext {
         if !self.memories.is_empty() {
             parts.push("## Relevant Memories\n".to_string());
             for memory in &self.memories {
+                let marker = if memory.low_confidence { " (low confidence)" } else { "" };
                 parts.push(format!(
-                    "- [{}] {}\n",
-                    memory.memory_type, memory.content
+                    "- [{}]{} {}\n",
+                    memory.memory_type, marker, memory.content
                 ));
             }
         }
@@ -60,6 +106,16 @@ impl RetrievalContext {
     }
 }
 
+/// Context trimmed to a model's token limit, ready to send
+#[derive(Debug, Clone)]
+pub struct AssembledContext {
+    pub context: RetrievalContext,
+    /// `context.format_for_prompt()`
+    pub prompt: String,
+    /// Budget snapshot against the model's limit after trimming
+    pub budget: ContextBudget,
+}
+
 /// A memory that was retrieved with its relevance score
 #[derive(Debug, Clone)]
 pub struct RetrievedMemory {
@@ -67,7 +123,17 @@ pub struct RetrievedMemory {
     pub content: String,
     pub scope: String,
     pub memory_type: String,
+    pub agent_id: Option<String>,
+    pub topic_id: Option<String>,
+    /// Tags from the stored memory, for filters and tag boosts
+    pub tags: Vec<String>,
     pub score: f32,
+    /// Injected because the memory is pinned rather than by similarity
+    pub pinned: bool,
+    /// Below `min_similarity_score`; only included by the empty-result fallback
+    pub low_confidence: bool,
+    /// False for deactivated memories, only retrieved with `include_inactive`
+    pub active: bool,
 }
 
 impl From<SearchResult> for RetrievedMemory {
@@ -77,27 +143,304 @@ impl From<SearchResult> for RetrievedMemory {
             content: result.content,
             scope: result.scope,
             memory_type: result.memory_type,
+            agent_id: result.agent_id,
+            topic_id: result.topic_id,
+            tags: result.tags,
             score: result.score,
+            pinned: false,
+            low_confidence: false,
+            active: result.active,
+        }
+    }
+}
+
+impl From<Memory> for RetrievedMemory {
+    fn from(memory: Memory) -> Self {
+        Self {
+            id: memory.id,
+            content: memory.content,
+            scope: memory.scope.to_string(),
+            memory_type: memory.memory_type.to_string(),
+            agent_id: memory.agent_id,
+            topic_id: memory.topic_id,
+            tags: memory.tags,
+            score: 1.0,
+            pinned: memory.pinned,
+            low_confidence: false,
+            active: memory.active,
+        }
+    }
+}
+
+/// Caller-supplied rule deciding whether a retrieved memory is kept
+#[derive(Clone)]
+pub struct MemoryFilter(Arc<dyn Fn(&RetrievedMemory) -> bool + Send + Sync>);
+
+impl MemoryFilter {
+    pub fn new(predicate: impl Fn(&RetrievedMemory) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(predicate))
+    }
+
+    /// Whether the memory passes the filter
+    pub fn keep(&self, memory: &RetrievedMemory) -> bool {
+        (self.0)(memory)
+    }
+}
+
+impl std::fmt::Debug for MemoryFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MemoryFilter")
+    }
+}
+
+/// A single retrieval request
+#[derive(Debug, Clone, Default)]
+pub struct RetrievalQuery {
+    pub query: String,
+    pub agent_id: Option<String>,
+    pub topic_id: Option<String>,
+    pub max_recent_messages: usize,
+    pub max_recent_tokens: Option<u32>,
+    /// Restrict memories to these types (empty means all types)
+    pub types: Vec<MemoryType>,
+    /// Identity of the caller; private memories of other owners are excluded
+    pub caller: Option<String>,
+    /// Search agent memories of every agent instead of only `agent_id`'s
+    pub all_agents: bool,
+    /// Applied to similarity hits after scoring, before truncation
+    pub filter: Option<MemoryFilter>,
+    /// Also return deactivated memories, scored down by `Config.inactive_score_penalty`
+    pub include_inactive: bool,
+    /// Hint tags, e.g. the current task's; each one a memory shares adds
+    /// `Config.tag_match_boost` to its score
+    pub query_tags: Vec<String>,
+    /// Pick recent messages nearest this time rather than the newest
+    pub reference_time: Option<DateTime<Utc>>,
+    /// Overrides `Config.recent_window_secs` for this query
+    pub recent_window_secs: Option<u64>,
+    /// Overrides `Config.max_retrieval_results` for this query
+    pub limit: Option<usize>,
+    /// Overrides `Config.min_similarity_score` for this query
+    pub min_score: Option<f32>,
+    /// Restrict memories to these scopes (empty means all scopes)
+    pub scopes: Vec<MemoryScope>,
+}
+
+impl RetrievalQuery {
+    /// Query using an existing message as the example, scoped to its agent and topic
+    ///
+    /// Only the visible content is used; thinking and attachments are left out.
+    pub fn for_message(message: &Message) -> Self {
+        Self {
+            query: message.content.trim().to_string(),
+            agent_id: Some(message.agent_id.clone()),
+            topic_id: Some(message.topic_id.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// Whether memories of `scope` may be returned
+    fn wants_scope(&self, scope: MemoryScope) -> bool {
+        self.scopes.is_empty() || self.scopes.contains(&scope)
+    }
+}
+
+/// Sort best score first; on equal scores active memories come before inactive ones
+fn sort_by_score(memories: &mut [RetrievedMemory]) {
+    memories.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(b.active.cmp(&a.active))
+    });
+}
+
+/// Cut score-sorted memories to `limit`, keeping at least `reserved` agent or
+/// topic memories when that many are available
+///
+/// Agent memories that would fall below the cut displace the weakest global
+/// ones above it; the result stays in score order.
+fn truncate_reserving_agent_slots(memories: &mut Vec<RetrievedMemory>, limit: usize, reserved: usize) {
+    if memories.len() <= limit {
+        return;
+    }
+
+    let is_agent = |m: &RetrievedMemory| m.scope == "agent" || m.scope == "topic";
+    let kept = memories[..limit].iter().filter(|m| is_agent(m)).count();
+    let missing = reserved.min(limit).saturating_sub(kept);
+
+    let promoted: Vec<usize> = (limit..memories.len())
+        .filter(|&i| is_agent(&memories[i]))
+        .take(missing)
+        .collect();
+    let demoted: Vec<usize> = (0..limit)
+        .rev()
+        .filter(|&i| !is_agent(&memories[i]))
+        .take(promoted.len())
+        .collect();
+
+    let mut index = 0;
+    memories.retain(|_| {
+        let i = index;
+        index += 1;
+        (i < limit && !demoted.contains(&i)) || promoted.contains(&i)
+    });
+}
+
+/// Add `boost` to each memory's score per tag it shares with `query_tags`
+fn boost_matching_tags(memories: &mut [RetrievedMemory], query_tags: &[String], boost: f32) {
+    if query_tags.is_empty() || boost == 0.0 {
+        return;
+    }
+    for memory in memories.iter_mut() {
+        let matches = memory
+            .tags
+            .iter()
+            .filter(|tag| query_tags.iter().any(|q| q.eq_ignore_ascii_case(tag)))
+            .count();
+        memory.score += boost * matches as f32;
+    }
+}
+
+/// Nearest memories checked for supersession when a memory is saved
+const SUPERSEDE_CANDIDATES: usize = 5;
+
+/// Rank offset for reciprocal rank fusion (the conventional k = 60)
+const RRF_K: f32 = 60.0;
+
+/// Min-max normalize scores within one result list to `[0.0, 1.0]`
+///
+/// A list whose scores are all equal maps to 1.0, so a lone hit from a scope
+/// still competes with the best hit of the other scope.
+fn normalize_scores(memories: &mut [RetrievedMemory]) {
+    let (min, max) = memories.iter().fold((f32::MAX, f32::MIN), |(min, max), m| {
+        (min.min(m.score), max.max(m.score))
+    });
+    let range = max - min;
+
+    for memory in memories.iter_mut() {
+        memory.score = if range > f32::EPSILON {
+            (memory.score - min) / range
+        } else {
+            1.0
+        };
+    }
+}
+
+/// How to combine the scores of a memory returned by more than one scope search
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScoreBlend {
+    /// Keep the best score
+    #[default]
+    Max,
+    /// Add the scores, so corroboration across scopes boosts the memory
+    Sum,
+    /// Average the scores
+    Mean,
+}
+
+/// What to do with an existing memory that a new one supersedes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SupersedeAction {
+    /// Deactivate it, so it is no longer retrieved
+    Deactivate,
+    /// Multiply its weight by this factor, so it ranks lower
+    Downweight(f32),
+}
+
+/// Collapse repeated memories into one entry, combining their scores with `blend`
+///
+/// The first occurrence keeps its position and fields.
+fn blend_duplicates(memories: Vec<RetrievedMemory>, blend: ScoreBlend) -> Vec<RetrievedMemory> {
+    let mut merged: Vec<(RetrievedMemory, f32, u32)> = Vec::with_capacity(memories.len());
+
+    for memory in memories {
+        match merged.iter_mut().find(|(m, _, _)| m.id == memory.id) {
+            Some((existing, sum, count)) => {
+                existing.score = existing.score.max(memory.score);
+                *sum += memory.score;
+                *count += 1;
+            }
+            None => {
+                let score = memory.score;
+                merged.push((memory, score, 1));
+            }
         }
     }
+
+    merged
+        .into_iter()
+        .map(|(mut memory, sum, count)| {
+            match blend {
+                ScoreBlend::Max => {}
+                ScoreBlend::Sum => memory.score = sum,
+                ScoreBlend::Mean => memory.score = sum / count as f32,
+            }
+            memory
+        })
+        .collect()
 }
 
 /// Retrieval engine for fetching relevant context
 pub struct RetrievalEngine {
     embedding_service: EmbeddingService,
+    token_counter: TokenCounter,
     config: Config,
+    /// `embedding_dimensions` as configured, before detection replaced it
+    configured_dimensions: usize,
 }
 
 impl RetrievalEngine {
     /// Create a new retrieval engine
+    ///
+    /// `config.embedding_dimensions` is replaced by the model's actual dimension
+    /// when detection is enabled; build the `MemoryStore` from `config()` so the
+    /// vector store agrees.
     pub fn new(config: Config) -> Result<Self> {
         let embedding_service = EmbeddingService::new(&config)?;
+        Self::with_embedding_service(config, embedding_service)
+    }
+
+    /// Create a retrieval engine around an existing embedding service, whose
+    /// dimension replaces `config.embedding_dimensions`
+    pub fn with_embedding_service(mut config: Config, embedding_service: EmbeddingService) -> Result<Self> {
+        let configured_dimensions = config.embedding_dimensions;
+        config.embedding_dimensions = embedding_service.dimensions();
+        let token_counter = TokenCounter::for_gpt()?;
         Ok(Self {
             embedding_service,
+            token_counter,
             config,
+            configured_dimensions,
         })
     }
 
+    /// Embedding dimension from the original config, which `config()` no
+    /// longer shows when detection changed it
+    pub fn configured_dimensions(&self) -> usize {
+        self.configured_dimensions
+    }
+
+    /// Number of memories returned for a query
+    fn result_limit(&self, query: &RetrievalQuery) -> usize {
+        query.limit.unwrap_or(self.config.max_retrieval_results)
+    }
+
+    /// Similarity threshold applied to a query
+    fn min_score(&self, query: &RetrievalQuery) -> f32 {
+        query.min_score.unwrap_or(self.config.min_similarity_score)
+    }
+
+    /// Configuration in effect, including the detected embedding dimension
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Get the embedding service
+    pub fn embedding_service(&self) -> &EmbeddingService {
+        &self.embedding_service
+    }
+
     /// Retrieve context for a query
     pub async fn retrieve(
         &self,
@@ -106,48 +449,493 @@ impl RetrievalEngine {
         agent_id: Option<&str>,
         topic_id: Option<&str>,
         max_recent_messages: usize,
+        max_recent_tokens: Option<u32>,
+    ) -> Result<RetrievalContext> {
+        let query = RetrievalQuery {
+            query: query.to_string(),
+            agent_id: agent_id.map(str::to_string),
+            topic_id: topic_id.map(str::to_string),
+            max_recent_messages,
+            max_recent_tokens,
+            ..Default::default()
+        };
+        self.retrieve_query(store, &query).await
+    }
+
+    /// Retrieve context for the memories most relevant to an existing message,
+    /// e.g. the latest user turn
+    pub async fn retrieve_by_message(
+        &self,
+        store: &MemoryStore,
+        message: &Message,
+        max_recent_messages: usize,
+        max_recent_tokens: Option<u32>,
+    ) -> Result<RetrievalContext> {
+        let query = RetrievalQuery {
+            max_recent_messages,
+            max_recent_tokens,
+            ..RetrievalQuery::for_message(message)
+        };
+        self.retrieve_query(store, &query).await
+    }
+
+    /// Retrieve context for a single `RetrievalQuery`, honoring its type filter
+    /// and caller
+    ///
+    /// Pinned memories are still included regardless of type. Search results
+    /// come from `MemoryStore::search_cache` when it holds this query.
+    pub async fn retrieve_query(
+        &self,
+        store: &MemoryStore,
+        query: &RetrievalQuery,
     ) -> Result<RetrievalContext> {
+        let cache_key = store.search_cache().key(query);
+        if let Some(memories) = cache_key.as_deref().and_then(|key| store.search_cache().get(key)) {
+            return self.build_context(store, memories, query);
+        }
+
         // Generate embedding for the query
-        let query_embedding = self.embedding_service.embed(query).await?;
+        let query_embedding = match self.embedding_service.embed(&query.query).await {
+            Ok(embedding) => embedding,
+            Err(Error::Embedding(e)) if self.config.keyword_fallback => {
+                tracing::warn!("Query embedding failed ({}), falling back to keywords", e);
+                let memories = self.keyword_search(store, query)?;
+                return self.build_context(store, memories, query);
+            }
+            Err(e) => return Err(e),
+        };
 
-        // Search for relevant memories
-        let mut memories = Vec::new();
+        let memories = self.search_memories(store, &query_embedding, query).await?;
+        if let Some(key) = cache_key {
+            store.search_cache().insert(key, query, memories.clone());
+        }
 
-        // Search global memories first
-        let global_results = store
-            .vector()
-            .search(
-                &query_embedding,
-                self.config.max_retrieval_results / 2,
-                self.config.min_similarity_score,
-                Some("global"),
-                None,
-            )
+        self.build_context(store, memories, query)
+    }
+
+    /// Rank memories by the share of the query's words they contain, for when
+    /// the query can't be embedded
+    ///
+    /// Covers the same scopes as `search_scopes`. Every hit is marked
+    /// `low_confidence`, since its score isn't a similarity.
+    fn keyword_search(&self, store: &MemoryStore, query: &RetrievalQuery) -> Result<Vec<RetrievedMemory>> {
+        let terms: Vec<String> = query
+            .query
+            .split_whitespace()
+            .map(|t| t.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+            .filter(|t| t.chars().count() >= 3)
+            .collect();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let agent_id = query.agent_id.as_deref();
+        let topic_id = query.topic_id.as_deref();
+        let agent_matches = |memory: &Memory| {
+            query.all_agents || (agent_id.is_some() && memory.agent_id.as_deref() == agent_id)
+        };
+        let topic_matches = |memory: &Memory| {
+            !self.config.topic_isolation || (topic_id.is_some() && memory.topic_id.as_deref() == topic_id)
+        };
+        let in_scope = |memory: &Memory| match memory.scope {
+            MemoryScope::Global => true,
+            MemoryScope::Agent => agent_matches(memory),
+            MemoryScope::Topic => agent_matches(memory) && topic_matches(memory),
+            MemoryScope::Personal => false,
+        };
+        let in_scope = |memory: &Memory| query.wants_scope(memory.scope) && in_scope(memory);
+
+        let caller = query.caller.as_deref();
+        let mut memories: Vec<RetrievedMemory> = store
+            .list_memories(None, None, None, None, !query.include_inactive)?
+            .into_iter()
+            .filter(|m| m.is_visible_to(caller) && in_scope(m))
+            .filter(|m| query.types.is_empty() || query.types.contains(&m.memory_type))
+            .filter_map(|m| {
+                let content = m.content.to_lowercase();
+                let hits = terms.iter().filter(|t| content.contains(t.as_str())).count();
+                let penalty = if m.active { 0.0 } else { self.config.inactive_score_penalty };
+                (hits > 0).then(|| RetrievedMemory {
+                    score: (hits as f32 / terms.len() as f32 * m.weight - penalty).max(0.0),
+                    low_confidence: true,
+                    ..RetrievedMemory::from(m)
+                })
+            })
+            .collect();
+
+        boost_matching_tags(&mut memories, &query.query_tags, self.config.tag_match_boost);
+        sort_by_score(&mut memories);
+        if let Some(filter) = &query.filter {
+            memories.retain(|m| filter.keep(m));
+        }
+        memories.truncate(self.result_limit(query));
+
+        Ok(memories)
+    }
+
+    /// Retrieve context for several sub-queries, fused with reciprocal rank fusion
+    ///
+    /// Each query is embedded and searched independently; a memory's fused rank is
+    /// the sum of `1 / (k + rank)` over every list it appears in. The reported
+    /// `score` is the best similarity the memory reached across queries.
+    pub async fn retrieve_multi(
+        &self,
+        store: &MemoryStore,
+        queries: &[String],
+        agent_id: Option<&str>,
+        topic_id: Option<&str>,
+        max_recent_messages: usize,
+        max_recent_tokens: Option<u32>,
+    ) -> Result<RetrievalContext> {
+        let embeddings = self.embedding_service.embed_batch(queries).await?;
+        let query = RetrievalQuery {
+            query: queries.join("\n"),
+            agent_id: agent_id.map(str::to_string),
+            topic_id: topic_id.map(str::to_string),
+            max_recent_messages,
+            max_recent_tokens,
+            ..Default::default()
+        };
+
+        let mut fused: HashMap<Uuid, (RetrievedMemory, f32)> = HashMap::new();
+        for embedding in &embeddings {
+            let ranked = self.search_memories(store, embedding, &query).await?;
+            for (rank, memory) in ranked.into_iter().enumerate() {
+                let contribution = 1.0 / (RRF_K + rank as f32 + 1.0);
+                let similarity = memory.score;
+                fused
+                    .entry(memory.id)
+                    .and_modify(|(existing, rrf)| {
+                        *rrf += contribution;
+                        existing.score = existing.score.max(similarity);
+                    })
+                    .or_insert((memory, contribution));
+            }
+        }
+
+        let mut fused: Vec<(RetrievedMemory, f32)> = fused.into_values().collect();
+        fused.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        fused.truncate(self.result_limit(&query));
+        let memories = fused.into_iter().map(|(memory, _)| memory).collect();
+
+        self.build_context(store, memories, &query)
+    }
+
+    /// Retrieve context for several independent queries
+    ///
+    /// All queries are embedded in a single batch, so the model lock is taken
+    /// once rather than per query. Contexts are returned in query order.
+    pub async fn retrieve_batch(
+        &self,
+        store: &MemoryStore,
+        queries: &[RetrievalQuery],
+    ) -> Result<Vec<RetrievalContext>> {
+        let texts: Vec<String> = queries.iter().map(|q| q.query.clone()).collect();
+        let embeddings = self.embedding_service.embed_batch(&texts).await?;
+
+        let mut contexts = Vec::with_capacity(queries.len());
+        for (query, embedding) in queries.iter().zip(&embeddings) {
+            let memories = self.search_memories(store, embedding, query).await?;
+            contexts.push(self.build_context(store, memories, query)?);
+        }
+
+        Ok(contexts)
+    }
+
+    /// Search global and agent memories for an embedding, best first
+    ///
+    /// Uses the query's agent, type filter and caller; its text is ignored.
+    async fn search_memories(
+        &self,
+        store: &MemoryStore,
+        query_embedding: &[f32],
+        query: &RetrievalQuery,
+    ) -> Result<Vec<RetrievedMemory>> {
+        let mut memories = self
+            .search_scopes(store, query_embedding, query, self.min_score(query))
             .await?;
-        memories.extend(global_results.into_iter().map(RetrievedMemory::from));
 
-        // Search agent-specific memories if agent_id provided
-        if let Some(aid) = agent_id {
-            let agent_results = store
+        // Nothing cleared the threshold: take the nearest memories anyway, flagged
+        if memories.is_empty() && self.config.fallback_on_empty {
+            memories = self
+                .search_scopes(store, query_embedding, query, f32::NEG_INFINITY)
+                .await?;
+            for memory in &mut memories {
+                memory.low_confidence = true;
+            }
+        }
+
+        // Deduplicate, boost tag matches, then sort by score
+        let mut memories = blend_duplicates(memories, self.config.duplicate_score_blend);
+        boost_matching_tags(&mut memories, &query.query_tags, self.config.tag_match_boost);
+        sort_by_score(&mut memories);
+        if let Some(filter) = &query.filter {
+            memories.retain(|m| filter.keep(m));
+        }
+        truncate_reserving_agent_slots(
+            &mut memories,
+            self.result_limit(query),
+            self.config.min_agent_results,
+        );
+
+        Ok(memories)
+    }
+
+    /// Search global, agent and topic memories scoring at least `min_score`,
+    /// dropping those the caller can't see
+    async fn search_scopes(
+        &self,
+        store: &MemoryStore,
+        query_embedding: &[f32],
+        query: &RetrievalQuery,
+        min_score: f32,
+    ) -> Result<Vec<RetrievedMemory>> {
+        let agent_id = query.agent_id.as_deref();
+        let types = query.types.as_slice();
+        let per_scope = (self.result_limit(query) / 2).max(1);
+        let mut memories = Vec::new();
+
+        // Search global memories first
+        if query.wants_scope(MemoryScope::Global) {
+            let global_filter = SearchFilter {
+                scope: Some("global"),
+                types,
+                ..Default::default()
+            };
+            let global_results = store
                 .vector()
-                .search(
-                    &query_embedding,
-                    self.config.max_retrieval_results / 2,
-                    self.config.min_similarity_score,
-                    Some("agent"),
-                    Some(aid),
-                )
+                .search(query_embedding, per_scope, min_score, &global_filter)
                 .await?;
-            memories.extend(agent_results.into_iter().map(RetrievedMemory::from));
+            memories.extend(self.scope_hits(store, query, global_results));
+        }
+
+        // Search agent-specific memories if agent_id provided, or every agent's
+        if agent_id.is_some() || query.all_agents {
+            let agent_filter = SearchFilter {
+                scope: Some("agent"),
+                agent_id: if query.all_agents { None } else { agent_id },
+                types,
+                ..Default::default()
+            };
+            if query.wants_scope(MemoryScope::Agent) {
+                let agent_results = store
+                    .vector()
+                    .search(query_embedding, per_scope, min_score, &agent_filter)
+                    .await?;
+                memories.extend(self.scope_hits(store, query, agent_results));
+            }
+
+            // Topic memories: only the current topic's under `topic_isolation`
+            // (none without a topic), otherwise any of the agent's topics
+            let topic_id = query.topic_id.as_deref();
+            if query.wants_scope(MemoryScope::Topic) && (!self.config.topic_isolation || topic_id.is_some()) {
+                let topic_filter = SearchFilter {
+                    scope: Some("topic"),
+                    topic_id: if self.config.topic_isolation { topic_id } else { None },
+                    ..agent_filter
+                };
+                let topic_results = store
+                    .vector()
+                    .search(query_embedding, per_scope, min_score, &topic_filter)
+                    .await?;
+                memories.extend(self.scope_hits(store, query, topic_results));
+            }
+        }
+
+        // SQLite is authoritative for visibility, activity, weight and tags. A
+        // degraded store has none, so `scope_hits` already filtered on the copies
+        // kept with the vectors; only the inactive penalty is left to apply.
+        if store.degraded().is_some() {
+            for memory in memories.iter_mut().filter(|m| !m.active) {
+                memory.score = (memory.score - self.config.inactive_score_penalty).max(0.0);
+            }
+            return Ok(memories);
+        }
+
+        // Hits whose row is gone are dropped rather than risk returning a
+        // private or deactivated memory
+        let caller = query.caller.as_deref();
+        let mut visible = Vec::with_capacity(memories.len());
+        for mut memory in memories {
+            match store.get_memory(memory.id)? {
+                Some(stored) if !stored.is_visible_to(caller) => continue,
+                Some(stored) if !stored.active && !query.include_inactive => continue,
+                Some(stored) => {
+                    memory.score *= stored.weight;
+                    if !stored.active {
+                        memory.score = (memory.score - self.config.inactive_score_penalty).max(0.0);
+                    }
+                    memory.active = stored.active;
+                    memory.tags = stored.tags;
+                    visible.push(memory);
+                }
+                None => continue,
+            }
         }
 
-        // Sort by score and deduplicate
-        memories.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
-        memories.truncate(self.config.max_retrieval_results);
+        Ok(visible)
+    }
+
+    /// One scope's search results as retrieved memories, normalized if configured
+    ///
+    /// A degraded store can't check hits against SQLite, so those the caller
+    /// can't see, or inactive ones unless the query asks for them, are dropped
+    /// here by the visibility and active flag stored with their vectors.
+    fn scope_hits(&self, store: &MemoryStore, query: &RetrievalQuery, results: Vec<SearchResult>) -> Vec<RetrievedMemory> {
+        let degraded = store.degraded().is_some();
+        let caller = query.caller.as_deref();
+        let mut hits: Vec<RetrievedMemory> = results
+            .into_iter()
+            .filter(|r| !degraded || (r.is_visible_to(caller) && (r.active || query.include_inactive)))
+            .map(RetrievedMemory::from)
+            .collect();
+        if self.config.normalize_scope_scores {
+            normalize_scores(&mut hits);
+        }
+        hits
+    }
 
-        // Get recent messages if topic provided
+    /// Explain how `memory_id` fares for `query`: its similarity, whether it
+    /// clears the threshold, which scope or filter rules exclude it, and where
+    /// it ranks among the query's real results
+    pub async fn explain(
+        &self,
+        store: &MemoryStore,
+        query: &RetrievalQuery,
+        memory_id: Uuid,
+    ) -> Result<RetrievalExplanation> {
+        // A memory private to someone else is treated as missing, so the
+        // explanation can't be used to probe for it
+        let memory = store
+            .get_memory(memory_id)?
+            .filter(|m| m.is_visible_to(query.caller.as_deref()))
+            .ok_or_else(|| Error::not_found(format!("Memory {}", memory_id)))?;
+        let query_embedding = self.embedding_service.embed(&query.query).await?;
+
+        let mut excluded_by = Vec::new();
+        let same_agent = query.all_agents || (query.agent_id.is_some() && query.agent_id == memory.agent_id);
+        match memory.scope {
+            MemoryScope::Global => {}
+            MemoryScope::Personal => excluded_by.push("personal memories are not retrieved by similarity".to_string()),
+            MemoryScope::Agent | MemoryScope::Topic if !same_agent => excluded_by.push(format!(
+                "belongs to agent {:?}, query is for {:?}",
+                memory.agent_id, query.agent_id
+            )),
+            MemoryScope::Agent => {}
+            MemoryScope::Topic => {
+                if self.config.topic_isolation && (query.topic_id.is_none() || query.topic_id != memory.topic_id) {
+                    excluded_by.push(format!(
+                        "belongs to topic {:?}, query is for {:?} (topic isolation)",
+                        memory.topic_id, query.topic_id
+                    ));
+                }
+            }
+        }
+        if !query.wants_scope(memory.scope) {
+            excluded_by.push(format!("scope {} is not in the query's scopes", memory.scope));
+        }
+        if !query.types.is_empty() && !query.types.contains(&memory.memory_type) {
+            excluded_by.push(format!("type {} is not in the query's types", memory.memory_type));
+        }
+        if !memory.active && !query.include_inactive {
+            excluded_by.push("inactive".to_string());
+        }
+
+        let metric = store.vector().metric();
+        let similarity = store
+            .vector()
+            .get_vectors(memory_id)
+            .await?
+            .iter()
+            .map(|v| score_from_distance(distance_between(&query_embedding, v, metric), metric))
+            .reduce(f32::max);
+        let threshold = self.min_score(query);
+        let score = similarity.map(|similarity| {
+            let mut candidate = RetrievedMemory {
+                score: similarity * memory.weight,
+                ..RetrievedMemory::from(memory.clone())
+            };
+            if !memory.active {
+                candidate.score = (candidate.score - self.config.inactive_score_penalty).max(0.0);
+            }
+            boost_matching_tags(std::slice::from_mut(&mut candidate), &query.query_tags, self.config.tag_match_boost);
+            candidate.score
+        });
+
+        let results = self.search_memories(store, &query_embedding, query).await?;
+        let position = results.iter().position(|m| m.id == memory_id);
+        let rank = match (position, score) {
+            (Some(i), _) => Some(i + 1),
+            (None, Some(score)) => Some(results.iter().filter(|m| m.score > score).count() + 1),
+            (None, None) => None,
+        };
+
+        Ok(RetrievalExplanation {
+            memory_id,
+            similarity,
+            score,
+            threshold,
+            passed_threshold: similarity.is_some_and(|s| s >= threshold),
+            excluded_by,
+            retrieved: position.is_some(),
+            rank,
+            result_count: results.len(),
+            limit: self.result_limit(query),
+        })
+    }
+
+    /// Prepend pinned memories, then attach recent messages and token totals
+    fn build_context(
+        &self,
+        store: &MemoryStore,
+        memories: Vec<RetrievedMemory>,
+        query: &RetrievalQuery,
+    ) -> Result<RetrievalContext> {
+        let agent_id = query.agent_id.as_deref();
+        let topic_id = query.topic_id.as_deref();
+        let max_recent_messages = query.max_recent_messages;
+        let max_recent_tokens = query.max_recent_tokens;
+
+        // Pinned memories always come first; similarity hits fill the remaining slots
+        let caller = query.caller.as_deref();
+        let pinned = match store.degraded() {
+            Some(_) => Vec::new(),
+            None => store.sqlite().list_pinned_memories(agent_id, topic_id)?,
+        };
+        let mut combined: Vec<RetrievedMemory> = pinned
+            .into_iter()
+            .filter(|m| m.is_visible_to(caller))
+            .map(RetrievedMemory::from)
+            .collect();
+        for memory in memories {
+            if !combined.iter().any(|m| m.id == memory.id) {
+                combined.push(memory);
+            }
+        }
+        combined.truncate(self.result_limit(query));
+        let memories = combined;
+
+        // Get recent messages if topic provided: the newest, or those nearest a
+        // reference time when a window or reference is given
         let recent_messages = if let (Some(aid), Some(tid)) = (agent_id, topic_id) {
-            store.jsonl().read_last_n(aid, tid, max_recent_messages)?
+            let window = query
+                .recent_window_secs
+                .or(self.config.recent_window_secs)
+                // A window too large for chrono is no window at all
+                .and_then(|secs| i64::try_from(secs).ok().and_then(chrono::Duration::try_seconds));
+            let mut messages = if window.is_some() || query.reference_time.is_some() {
+                let reference = query.reference_time.unwrap_or_else(Utc::now);
+                store.jsonl().read_around(aid, tid, reference, window, max_recent_messages)?
+            } else {
+                store.jsonl().read_last_n(aid, tid, max_recent_messages)?
+            };
+            if self.config.dedup_recent_messages {
+                messages.dedup_by(|a, b| a.role == b.role && a.content == b.content);
+            }
+            match max_recent_tokens {
+                Some(cap) => self.trim_to_token_cap(messages, cap),
+                None => messages,
+            }
         } else {
             Vec::new()
         };
@@ -163,21 +951,228 @@ impl RetrievalEngine {
             memories,
             recent_messages,
             total_tokens: memory_tokens + message_tokens,
+            metric: store.vector().metric(),
+            threshold: self.min_score(query),
         })
     }
 
+    /// Retrieve memories and recent messages, trimmed to fit the model's limit
+    ///
+    /// The limit is resolved by `MemoryStore::context_limit`. Content is filled up
+    /// to the warning threshold so
+    /// the context alone never puts the budget into warning: memories first, in
+    /// rank order, then the newest messages that still fit.
+    pub async fn assemble(
+        &self,
+        store: &MemoryStore,
+        query: &str,
+        agent_id: Option<&str>,
+        topic_id: Option<&str>,
+        model: Option<&str>,
+    ) -> Result<AssembledContext> {
+        let limit = store.context_limit(agent_id, model)?.max(1);
+        let target = (limit as f32 * self.config.context_warning_threshold) as u32;
+
+        let request = RetrievalQuery {
+            query: query.to_string(),
+            agent_id: agent_id.map(str::to_string),
+            topic_id: topic_id.map(str::to_string),
+            max_recent_messages: self.config.assembly_recent_messages,
+            ..Default::default()
+        };
+        let mut context = self.retrieve_query(store, &request).await?;
+
+        // Memories take priority; drop the lowest-ranked ones that don't fit
+        let mut used = 0u32;
+        let mut keep = 0usize;
+        for memory in &context.memories {
+            let tokens = self.token_counter.count(&memory.content);
+            if used + tokens > target {
+                break;
+            }
+            used += tokens;
+            keep += 1;
+        }
+        context.memories.truncate(keep);
+
+        let messages = std::mem::take(&mut context.recent_messages);
+        context.recent_messages = self.trim_to_token_cap(messages, target - used);
+        let message_tokens: u32 = context
+            .recent_messages
+            .iter()
+            .map(|m| if m.tokens > 0 { m.tokens } else { self.token_counter.count(&m.content) })
+            .sum();
+        context.total_tokens = used + message_tokens;
+
+        let mut budget = ContextBudget::new(
+            limit,
+            self.config.context_warning_threshold,
+            self.config.context_critical_threshold,
+        );
+        budget.add(context.total_tokens);
+
+        Ok(AssembledContext {
+            prompt: context.format_for_prompt(),
+            context,
+            budget,
+        })
+    }
+
+    /// Keep the newest messages whose combined tokens fit within `cap`
+    fn trim_to_token_cap(&self, messages: Vec<Message>, cap: u32) -> Vec<Message> {
+        let mut used = 0u32;
+        let mut keep = 0usize;
+
+        for message in messages.iter().rev() {
+            let tokens = if message.tokens > 0 {
+                message.tokens
+            } else {
+                self.token_counter.count(&message.content)
+            };
+            if used + tokens > cap {
+                break;
+            }
+            used += tokens;
+            keep += 1;
+        }
+
+        let start = messages.len() - keep;
+        messages[start..].to_vec()
+    }
+
+    /// Embed memory content, fitted to the model's input
+    ///
+    /// With `Config.chunk_long_memories` or the `Chunk` truncation strategy,
+    /// content longer than the chunk window gets one vector per chunk; the
+    /// window never exceeds `max_embedding_tokens`. Otherwise content over
+    /// `max_embedding_tokens` is shortened per `embedding_truncation` and
+    /// embedded as one vector.
+    pub async fn embed_content(&self, content: &str) -> Result<Vec<Vec<f32>>> {
+        let inputs = self.embedding_inputs(content);
+        if let [single] = inputs.as_slice() {
+            return Ok(vec![self.embedding_service.embed(single).await?]);
+        }
+        self.embedding_service.embed_batch(&inputs).await
+    }
+
+    /// The text(s) actually handed to the model for a memory's content
+    pub fn embedding_inputs(&self, content: &str) -> Vec<String> {
+        let max = self.config.max_embedding_tokens;
+        let strategy = self.config.embedding_truncation;
+
+        if self.config.chunk_long_memories || strategy == InputTruncation::Chunk {
+            let window = match max {
+                0 => self.config.chunk_tokens,
+                max => self.config.chunk_tokens.min(max),
+            };
+            return self
+                .token_counter
+                .chunk(content, window, self.config.chunk_overlap_tokens);
+        }
+
+        vec![self.token_counter.truncate(content, max, strategy)]
+    }
+
     /// Embed and save a memory
-    pub async fn embed_and_save(&self, store: &MemoryStore, mut memory: Memory) -> Result<Memory> {
-        // Generate embedding for the memory content
-        let embedding = self.embedding_service.embed(&memory.content).await?;
-        memory.embedding = Some(embedding);
+    pub async fn embed_and_save(&self, store: &MemoryStore, memory: Memory) -> Result<Memory> {
+        let (memory, _) = self.embed_and_save_superseding(store, memory).await?;
+        Ok(memory)
+    }
+
+    /// Embed and save a memory, also returning the ids of the memories it
+    /// superseded (see `Config.supersede_threshold`)
+    pub async fn embed_and_save_superseding(
+        &self,
+        store: &MemoryStore,
+        mut memory: Memory,
+    ) -> Result<(Memory, Vec<Uuid>)> {
+        // Redact before embedding so secrets never reach the model or vector store
+        memory.content = store.redact(&memory.content);
+
+        // Metadata-only edits keep the existing vectors
+        if store.vectors_current(&memory)? {
+            return Ok((store.save_memory(memory).await?, Vec::new()));
+        }
+
+        // Generate embeddings for the memory content
+        let embeddings = self.embed_content(&memory.content).await?;
+        memory.embedding = embeddings.first().cloned();
+
+        // Look for superseded memories before this one is in the index to match itself
+        let superseded = match (self.config.supersede_threshold, &memory.embedding) {
+            (Some(threshold), Some(embedding)) => self.find_superseded(store, &memory, embedding, threshold).await?,
+            _ => Vec::new(),
+        };
 
         // Save to store
-        store.save_memory(memory).await
+        let memory = store.save_memory_with_vectors(memory, &embeddings).await?;
+
+        for &id in &superseded {
+            match self.config.supersede_action {
+                SupersedeAction::Deactivate => store.deactivate_memory(id).await?,
+                SupersedeAction::Downweight(factor) => {
+                    if let Some(old) = store.get_memory(id)? {
+                        store.set_memory_weight(id, old.weight * factor)?;
+                    }
+                }
+            }
+            tracing::info!("Memory {} superseded by {}", id, memory.id);
+        }
+
+        Ok((memory, superseded))
+    }
+
+    /// Active, unpinned memories in `memory`'s scope scoring at least `threshold`
+    /// against its embedding
+    ///
+    /// Similarity alone stands in for contradiction: a near-identical statement
+    /// in the same scope is taken to replace the older one.
+    async fn find_superseded(
+        &self,
+        store: &MemoryStore,
+        memory: &Memory,
+        embedding: &[f32],
+        threshold: f32,
+    ) -> Result<Vec<Uuid>> {
+        let scope = memory.scope.to_string();
+        let filter = SearchFilter {
+            scope: Some(&scope),
+            agent_id: memory.agent_id.as_deref(),
+            topic_id: memory.topic_id.as_deref(),
+            ..Default::default()
+        };
+        let hits = store
+            .vector()
+            .search(embedding, SUPERSEDE_CANDIDATES, threshold, &filter)
+            .await?;
+
+        let mut superseded = Vec::new();
+        for hit in hits {
+            if hit.id == memory.id {
+                continue;
+            }
+            match store.get_memory(hit.id)? {
+                Some(old) if old.active && !old.pinned && old.owner_id == memory.owner_id => {
+                    superseded.push(old.id)
+                }
+                _ => {}
+            }
+        }
+        Ok(superseded)
+    }
+
+    /// Whether a message is too short to be worth saving as a correction,
+    /// per `Config.min_correction_length`
+    fn too_short_for_correction(&self, user_message: &str) -> bool {
+        user_message.trim().chars().count() < self.config.min_correction_length
     }
 
     /// Detect if a message contains a correction
     pub fn detect_correction(&self, user_message: &str, assistant_message: &str) -> Option<String> {
+        if self.too_short_for_correction(user_message) {
+            return None;
+        }
+
         let correction_indicators = [
             "no,",
             "no that's",
@@ -226,6 +1221,10 @@ impl RetrievalEngine {
         user_message: &str,
         agent_id: &str,
     ) -> Option<Memory> {
+        if self.too_short_for_correction(user_message) {
+            return None;
+        }
+
         // Simple heuristic: if the message contains correction-like patterns
         let correction_patterns = [
             ("always ", "preference"),
@@ -243,13 +1242,9 @@ impl RetrievalEngine {
         
         for (pattern, memory_type) in correction_patterns {
             if user_lower.contains(pattern) {
-                let mtype = match memory_type {
-                    "preference" => crate::memory::MemoryType::Preference,
-                    "constraint" => crate::memory::MemoryType::Constraint,
-                    "fact" => crate::memory::MemoryType::Fact,
-                    "workflow" => crate::memory::MemoryType::Workflow,
-                    _ => crate::memory::MemoryType::Correction,
-                };
+                let mtype = memory_type
+                    .parse()
+                    .unwrap_or(crate::memory::MemoryType::Correction);
                 
                 return Some(Memory::for_agent(agent_id, mtype, user_message));
             }
@@ -260,6 +1255,7 @@ impl RetrievalEngine {
 }
 
 /// Context budget manager for tracking token usage
+#[derive(Debug, Clone)]
 pub struct ContextBudget {
     pub limit: u32,
     pub used: u32,
@@ -314,3 +1310,1089 @@ impl ContextBudget {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Role;
+    use crate::test_support::{self, setup, FakeEmbedder};
+
+    async fn save(store: &MemoryStore, engine: &RetrievalEngine, memory: Memory) -> Uuid {
+        engine.embed_and_save(store, memory).await.unwrap().id
+    }
+
+    fn ids(context: &RetrievalContext) -> Vec<Uuid> {
+        context.memories.iter().map(|m| m.id).collect()
+    }
+
+    #[tokio::test]
+    async fn pinned_memories_are_injected_whatever_the_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| c.min_similarity_score = 0.3).await;
+        let pinned = save(
+            &store,
+            &engine,
+            Memory::global(MemoryType::Preference, "always answer in french").with_pinned(true),
+        )
+        .await;
+        let topic_pin = save(
+            &store,
+            &engine,
+            Memory::for_topic("agent", "foo", MemoryType::Fact, "release branch is frozen").with_pinned(true),
+        )
+        .await;
+        let related = save(&store, &engine, Memory::global(MemoryType::Fact, "deploys run on friday")).await;
+
+        let context = engine
+            .retrieve(&store, "when do deploys run", Some("agent"), Some("foo"), 0, None)
+            .await
+            .unwrap();
+        let found = ids(&context);
+        assert_eq!(found.len(), 3);
+        assert!(found[..2].contains(&pinned) && found[..2].contains(&topic_pin));
+        assert!(context.memories[..2].iter().all(|m| m.pinned));
+        assert_eq!(found[2], related);
+
+        // A topic's pins stay in that topic
+        let context = engine
+            .retrieve(&store, "when do deploys run", Some("agent"), Some("bar"), 0, None)
+            .await
+            .unwrap();
+        assert_eq!(ids(&context), [pinned, related]);
+    }
+
+    #[tokio::test]
+    async fn degraded_store_filters_hits_on_their_vector_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let configure = |c: &mut Config| {
+            c.min_similarity_score = 0.3;
+            c.max_retrieval_results = 10;
+            c.degrade_on_metadata_failure = true;
+        };
+        let (shared, private, inactive) = {
+            let (store, engine) = setup(dir.path(), configure).await;
+            let shared = save(&store, &engine, Memory::global(MemoryType::Fact, "deploys run on friday")).await;
+            let private = save(
+                &store,
+                &engine,
+                Memory::global(MemoryType::Fact, "deploys run on monday").with_owner("alice"),
+            )
+            .await;
+            let inactive = save(&store, &engine, Memory::global(MemoryType::Fact, "deploys run on sunday")).await;
+            store.deactivate_memory(inactive).await.unwrap();
+            (shared, private, inactive)
+        };
+
+        // Replace the metadata database with something SQLite can't open
+        let db = dir.path().join("metadata.db");
+        std::fs::remove_file(&db).unwrap();
+        std::fs::create_dir(&db).unwrap();
+        let (store, engine) = setup(dir.path(), configure).await;
+        assert!(store.degraded().is_some());
+
+        let retrieve = |caller: Option<&str>, include_inactive: bool| {
+            let query = RetrievalQuery {
+                query: "when do deploys run".to_string(),
+                caller: caller.map(str::to_string),
+                include_inactive,
+                ..Default::default()
+            };
+            let (store, engine) = (&store, &engine);
+            async move {
+                let mut found = ids(&engine.retrieve_query(store, &query).await.unwrap());
+                found.sort();
+                found
+            }
+        };
+        let sorted = |mut expected: Vec<Uuid>| {
+            expected.sort();
+            expected
+        };
+
+        assert_eq!(retrieve(None, false).await, [shared]);
+        assert_eq!(retrieve(Some("bob"), false).await, [shared]);
+        assert_eq!(retrieve(Some("alice"), false).await, sorted(vec![shared, private]));
+        assert_eq!(retrieve(Some("alice"), true).await, sorted(vec![shared, private, inactive]));
+    }
+
+    #[tokio::test]
+    async fn chunked_memories_count_once_and_follow_their_topic() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| {
+            c.chunk_long_memories = true;
+            c.chunk_tokens = 8;
+            c.chunk_overlap_tokens = 0;
+            c.min_similarity_score = 0.3;
+        })
+        .await;
+        let topic_memory = |content: String| Memory::for_topic("agent", "a", MemoryType::Fact, content);
+        let long = save(&store, &engine, topic_memory("deploy friday ".repeat(40))).await;
+        let short = save(&store, &engine, topic_memory("deploy friday".to_string())).await;
+        assert!(store.vector().get_vectors(long).await.unwrap().len() > 2);
+
+        // Every row scores alike, so the long memory's chunks could fill a
+        // two-row page on their own
+        let embedding = engine.embedding_service().embed("deploy friday").await.unwrap();
+        let filter = SearchFilter {
+            scope: Some("topic"),
+            agent_id: Some("agent"),
+            ..Default::default()
+        };
+        let mut hits: Vec<Uuid> = store
+            .vector()
+            .search(&embedding, 2, 0.0, &filter)
+            .await
+            .unwrap()
+            .iter()
+            .map(|hit| hit.id)
+            .collect();
+        hits.sort();
+        let mut expected = vec![long, short];
+        expected.sort();
+        assert_eq!(hits, expected);
+
+        // Merging moves every chunk, so the memory is found from its new topic only
+        store
+            .append_message(&Message::new("agent", "a", Role::User, "hi"))
+            .unwrap();
+        store.merge_topics("agent", "a", "b").await.unwrap();
+        let retrieve = |topic: &'static str| engine.retrieve(&store, "deploy friday", Some("agent"), Some(topic), 0, None);
+        let mut found = ids(&retrieve("b").await.unwrap());
+        found.sort();
+        assert_eq!(found, expected);
+        assert!(retrieve("a").await.unwrap().memories.is_empty());
+    }
+
+    #[tokio::test]
+    async fn timed_out_queries_fall_back_to_keywords_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| c.min_similarity_score = 0.3).await;
+        let deploys = save(&store, &engine, Memory::global(MemoryType::Fact, "deploys run on friday")).await;
+        save(&store, &engine, Memory::global(MemoryType::Fact, "tests need a database")).await;
+
+        let slow_engine = |keyword_fallback: bool| {
+            let config = Config {
+                embedding_timeout_ms: Some(50),
+                keyword_fallback,
+                ..store.config().clone()
+            };
+            let embedder = FakeEmbedder {
+                delay: Some(std::time::Duration::from_millis(300)),
+                ..FakeEmbedder::new(config.embedding_dimensions)
+            };
+            test_support::engine_with(&config, embedder)
+        };
+
+        let failed = slow_engine(false).retrieve(&store, "when do deploys run", None, None, 0, None).await;
+        assert!(matches!(failed, Err(Error::Embedding(message)) if message.contains("timed out")));
+
+        let context = slow_engine(true)
+            .retrieve(&store, "when do deploys run", None, None, 0, None)
+            .await
+            .unwrap();
+        assert_eq!(ids(&context), [deploys]);
+        assert!(context.memories[0].low_confidence);
+    }
+
+    #[tokio::test]
+    async fn each_truncation_strategy_shapes_the_model_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = TokenCounter::for_gpt().unwrap();
+        let content = (0..200).map(|i| format!("word{}", i)).collect::<Vec<_>>().join(" ");
+        assert!(counter.count(&content) > 50);
+
+        // What the model receives when embedding `text` under `strategy`
+        let model_inputs = |strategy: InputTruncation, text: String| {
+            let config = test_support::config(dir.path(), |c| {
+                c.max_embedding_tokens = 50;
+                c.embedding_truncation = strategy;
+                c.chunk_overlap_tokens = 10;
+            });
+            let embedder = FakeEmbedder::new(config.embedding_dimensions);
+            let inputs = embedder.inputs.clone();
+            let engine = test_support::engine_with(&config, embedder);
+            async move {
+                let vectors = engine.embed_content(&text).await.unwrap();
+                let inputs = inputs.lock().unwrap().clone();
+                assert_eq!(vectors.len(), inputs.len());
+                assert_eq!(inputs, engine.embedding_inputs(&text));
+                inputs
+            }
+        };
+
+        let end = model_inputs(InputTruncation::TruncateEnd, content.clone()).await;
+        assert_eq!(end.len(), 1);
+        assert!(end[0].starts_with("word0 word1 ") && !end[0].contains("word199"));
+        assert!(counter.count(&end[0]) <= 50);
+
+        let middle = model_inputs(InputTruncation::TruncateMiddle, content.clone()).await;
+        assert_eq!(middle.len(), 1);
+        assert!(middle[0].starts_with("word0 word1 ") && middle[0].ends_with("word199"));
+        assert!(middle[0].contains(" … ") && !middle[0].contains("word100 "));
+
+        let chunks = model_inputs(InputTruncation::Chunk, content.clone()).await;
+        assert!(chunks.len() > 1);
+        assert!(chunks[0].starts_with("word0 word1 ") && chunks.last().unwrap().ends_with("word199"));
+        assert!(chunks.iter().all(|chunk| counter.count(chunk) <= 50));
+
+        // Content that fits goes through untouched, whatever the strategy
+        for strategy in [InputTruncation::TruncateEnd, InputTruncation::TruncateMiddle, InputTruncation::Chunk] {
+            assert_eq!(model_inputs(strategy, "short note".to_string()).await, ["short note"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn explain_tells_threshold_misses_from_scope_exclusions() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| c.min_similarity_score = 0.6).await;
+        let exact = save(&store, &engine, Memory::global(MemoryType::Fact, "when do deploys run")).await;
+        let weak = save(&store, &engine, Memory::global(MemoryType::Fact, "deploys run on friday")).await;
+        let other_agent = save(&store, &engine, Memory::for_agent("writer", MemoryType::Fact, "when do deploys run")).await;
+        let other_topic = save(
+            &store,
+            &engine,
+            Memory::for_topic("coder", "docs", MemoryType::Fact, "when do deploys run"),
+        )
+        .await;
+        let query = RetrievalQuery {
+            query: "when do deploys run".to_string(),
+            agent_id: Some("coder".to_string()),
+            topic_id: Some("build".to_string()),
+            ..Default::default()
+        };
+
+        let hit = engine.explain(&store, &query, exact).await.unwrap();
+        assert!(hit.retrieved && hit.passed_threshold && hit.excluded_by.is_empty());
+        assert_eq!(hit.rank, Some(1));
+
+        // Similar enough to rank, but under the threshold
+        let below = engine.explain(&store, &query, weak).await.unwrap();
+        assert!((below.similarity.unwrap() - 0.5).abs() < 1e-4, "{:?}", below.similarity);
+        assert_eq!(below.threshold, 0.6);
+        assert!(!below.passed_threshold && !below.retrieved && below.excluded_by.is_empty());
+        assert_eq!(below.rank, Some(2));
+
+        // A perfect match that the scope rules keep out
+        for (id, reason) in [(other_agent, "belongs to agent"), (other_topic, "topic isolation")] {
+            let excluded = engine.explain(&store, &query, id).await.unwrap();
+            assert!(excluded.passed_threshold && !excluded.retrieved, "{:?}", excluded);
+            assert_eq!(excluded.excluded_by.len(), 1, "{:?}", excluded.excluded_by);
+            assert!(excluded.excluded_by[0].contains(reason), "{:?}", excluded.excluded_by);
+        }
+
+        // And one that the type filter keeps out
+        let constraints_only = RetrievalQuery {
+            types: vec![MemoryType::Constraint],
+            ..query.clone()
+        };
+        let filtered = engine.explain(&store, &constraints_only, exact).await.unwrap();
+        assert_eq!(filtered.excluded_by, ["type fact is not in the query's types"]);
+        assert!(!filtered.retrieved);
+    }
+
+    #[tokio::test]
+    async fn fused_queries_favor_memories_both_agree_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| c.min_similarity_score = 0.4).await;
+        let migrations = save(&store, &engine, Memory::global(MemoryType::Fact, "database migrations")).await;
+        let schedule = save(&store, &engine, Memory::global(MemoryType::Fact, "release schedule")).await;
+        let both = save(&store, &engine, Memory::global(MemoryType::Fact, "database release")).await;
+
+        // Alone, each query puts its exact match first and the shared memory second
+        for (query, exact) in [("database migrations", migrations), ("release schedule", schedule)] {
+            let context = engine.retrieve(&store, query, None, None, 0, None).await.unwrap();
+            assert_eq!(ids(&context), [exact, both]);
+        }
+
+        // Fused, the memory second in both lists beats one that tops a single list
+        let queries = ["database migrations".to_string(), "release schedule".to_string()];
+        let fused = engine.retrieve_multi(&store, &queries, None, None, 0, None).await.unwrap();
+        assert_eq!(fused.memories[0].id, both);
+        assert_eq!(fused.memories.len(), 3);
+        // The reported score is the best similarity, not the fused rank
+        assert!((fused.memories[0].score - 0.5).abs() < 1e-4);
+    }
+
+    #[tokio::test]
+    async fn batch_retrieval_embeds_every_query_in_one_call() {
+        let dir = tempfile::tempdir().unwrap();
+        let embedder = FakeEmbedder::new(test_support::DIMENSIONS);
+        let calls = embedder.calls.clone();
+        let engine = test_support::engine_with(
+            &test_support::config(dir.path(), |c| c.min_similarity_score = 0.6),
+            embedder,
+        );
+        let store = MemoryStore::new(engine.config().clone()).await.unwrap();
+        let migrations = save(&store, &engine, Memory::global(MemoryType::Fact, "database migrations")).await;
+        let schedule = save(&store, &engine, Memory::global(MemoryType::Fact, "release schedule")).await;
+        let agent_note = save(&store, &engine, Memory::for_agent("coder", MemoryType::Fact, "lint before commit")).await;
+
+        let query = |text: &str, agent_id: Option<&str>| RetrievalQuery {
+            query: text.to_string(),
+            agent_id: agent_id.map(str::to_string),
+            ..Default::default()
+        };
+        let queries = [
+            query("release schedule", None),
+            query("database migrations", None),
+            query("lint before commit", Some("coder")),
+        ];
+
+        let before = calls.load(std::sync::atomic::Ordering::SeqCst);
+        let contexts = engine.retrieve_batch(&store, &queries).await.unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst) - before, 1);
+
+        // One context per query, in query order
+        assert_eq!(contexts.len(), 3);
+        assert_eq!(ids(&contexts[0]), [schedule]);
+        assert_eq!(ids(&contexts[1]), [migrations]);
+        assert_eq!(ids(&contexts[2]), [agent_note]);
+    }
+
+    #[tokio::test]
+    async fn normalizing_scope_scores_interleaves_the_scopes() {
+        /// Two global and two agent memories, in falling similarity to the query,
+        /// and the query's ranking of them
+        async fn ranked(normalize: bool) -> (Vec<Uuid>, RetrievalContext) {
+            let dir = tempfile::tempdir().unwrap();
+            let (store, engine) = setup(dir.path(), |c| {
+                c.min_similarity_score = 0.3;
+                c.normalize_scope_scores = normalize;
+            })
+            .await;
+            let mut saved = Vec::new();
+            for memory in [
+                Memory::global(MemoryType::Fact, "deploy the api service today"),
+                Memory::global(MemoryType::Fact, "deploy the api service"),
+                Memory::for_agent("coder", MemoryType::Fact, "deploy the api"),
+                Memory::for_agent("coder", MemoryType::Fact, "deploy the"),
+            ] {
+                saved.push(save(&store, &engine, memory).await);
+            }
+            let context = engine
+                .retrieve(&store, "deploy the api service today", Some("coder"), None, 0, None)
+                .await
+                .unwrap();
+            (saved, context)
+        }
+
+        // Raw scores: both global hits outrank both agent hits
+        let (saved, raw) = ranked(false).await;
+        assert_eq!(ids(&raw), saved);
+
+        // Normalized per scope, each scope's best hit scores 1.0 and its worst 0.0
+        let (saved, normalized) = ranked(true).await;
+        assert_eq!(ids(&normalized), [saved[0], saved[2], saved[1], saved[3]]);
+        let scores: Vec<f32> = normalized.memories.iter().map(|m| m.score).collect();
+        assert_eq!(scores, [1.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn normalize_scores_maps_a_flat_list_to_one() {
+        let memory = |score: f32| RetrievedMemory {
+            score,
+            ..RetrievedMemory::from(Memory::global(MemoryType::Fact, "x"))
+        };
+        let mut spread = vec![memory(0.4), memory(0.7), memory(0.55)];
+        normalize_scores(&mut spread);
+        let scores: Vec<f32> = spread.iter().map(|m| m.score).collect();
+        assert!((scores[0] - 0.0).abs() < 1e-6 && (scores[1] - 1.0).abs() < 1e-6 && (scores[2] - 0.5).abs() < 1e-6);
+
+        let mut flat = vec![memory(0.4), memory(0.4)];
+        normalize_scores(&mut flat);
+        assert!(flat.iter().all(|m| m.score == 1.0));
+        normalize_scores(&mut []);
+    }
+
+
+    #[tokio::test]
+    async fn types_filter_applies_to_every_scope() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        let content = "never push straight to main";
+        let global_fact = save(&store, &engine, Memory::global(MemoryType::Fact, content)).await;
+        let global_rule = save(&store, &engine, Memory::global(MemoryType::Constraint, content)).await;
+        let agent_fact = save(&store, &engine, Memory::for_agent("coder", MemoryType::Fact, content)).await;
+        let agent_pref = save(&store, &engine, Memory::for_agent("coder", MemoryType::Preference, content)).await;
+        let topic_rule = save(
+            &store,
+            &engine,
+            Memory::for_topic("coder", "build", MemoryType::Constraint, content),
+        )
+        .await;
+        let query = RetrievalQuery {
+            query: content.to_string(),
+            agent_id: Some("coder".to_string()),
+            topic_id: Some("build".to_string()),
+            ..Default::default()
+        };
+
+        let all = engine.retrieve_query(&store, &query).await.unwrap();
+        assert_eq!(all.memories.len(), 5);
+
+        let rules = engine
+            .retrieve_query(&store, &RetrievalQuery { types: vec![MemoryType::Constraint], ..query.clone() })
+            .await
+            .unwrap();
+        let mut found = ids(&rules);
+        found.sort();
+        let mut expected = vec![global_rule, topic_rule];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let several = engine
+            .retrieve_query(
+                &store,
+                &RetrievalQuery {
+                    types: vec![MemoryType::Fact, MemoryType::Preference],
+                    ..query.clone()
+                },
+            )
+            .await
+            .unwrap();
+        let mut found = ids(&several);
+        found.sort();
+        let mut expected = vec![global_fact, agent_fact, agent_pref];
+        expected.sort();
+        assert_eq!(found, expected);
+        assert!(several.memories.iter().all(|m| m.memory_type != "constraint"));
+    }
+
+
+    #[tokio::test]
+    async fn manifest_lists_the_prompt_memories_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| c.min_similarity_score = 0.3).await;
+        let contents = ["deploy the api", "deploy the api service today", "deploy the api service"];
+        let mut saved = Vec::new();
+        for content in contents {
+            saved.push(save(&store, &engine, Memory::global(MemoryType::Fact, content)).await);
+        }
+
+        let context = engine
+            .retrieve(&store, "deploy the api service today", None, None, 0, None)
+            .await
+            .unwrap();
+        let manifest = context.manifest();
+        let manifest_ids: Vec<Uuid> = manifest.iter().map(|(id, _)| *id).collect();
+        assert_eq!(manifest_ids, [saved[1], saved[2], saved[0]]);
+        assert!((manifest[0].1 - 1.0).abs() < 1e-4, "{:?}", manifest);
+        assert!(manifest[0].1 > manifest[1].1 && manifest[1].1 > manifest[2].1, "{:?}", manifest);
+        for ((_, score), memory) in manifest.iter().zip(&context.memories) {
+            assert_eq!(*score, memory.score);
+        }
+
+        // The prompt lists the same memories in the same order
+        let prompt = context.format_for_prompt();
+        let lines: Vec<&str> = prompt.lines().filter(|l| l.starts_with("- [")).collect();
+        assert_eq!(
+            lines,
+            [
+                "- [fact] deploy the api service today",
+                "- [fact] deploy the api service",
+                "- [fact] deploy the api",
+            ]
+        );
+
+        assert!(RetrievalContext::empty().manifest().is_empty());
+    }
+
+
+    #[tokio::test]
+    async fn empty_results_fall_back_to_the_nearest_memories_when_enabled() {
+        for fallback in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let (store, engine) = setup(dir.path(), |c| c.fallback_on_empty = fallback).await;
+            let near = save(&store, &engine, Memory::global(MemoryType::Fact, "deploys run on friday")).await;
+            let far = save(&store, &engine, Memory::global(MemoryType::Fact, "lint before commit")).await;
+
+            // Nothing reaches the default 0.7 threshold
+            let context = engine
+                .retrieve(&store, "when do deploys run", None, None, 0, None)
+                .await
+                .unwrap();
+            if !fallback {
+                assert!(context.is_empty());
+                continue;
+            }
+            assert_eq!(ids(&context), [near, far]);
+            assert!(context.memories.iter().all(|m| m.low_confidence));
+            assert!(context.format_for_prompt().contains("- [fact] (low confidence) deploys run on friday"));
+
+            // A hit above the threshold means no fallback, so nothing is flagged
+            let exact = save(&store, &engine, Memory::global(MemoryType::Fact, "when do deploys run")).await;
+            let context = engine
+                .retrieve(&store, "when do deploys run", None, None, 0, None)
+                .await
+                .unwrap();
+            assert_eq!(ids(&context), [exact]);
+            assert!(!context.memories[0].low_confidence);
+        }
+    }
+
+
+    #[tokio::test]
+    async fn all_agents_searches_every_agents_memories() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        let content = "staging is reset nightly";
+        let global = save(&store, &engine, Memory::global(MemoryType::Fact, content)).await;
+        let coder = save(&store, &engine, Memory::for_agent("coder", MemoryType::Fact, content)).await;
+        let writer = save(&store, &engine, Memory::for_agent("writer", MemoryType::Fact, content)).await;
+        let sorted = |mut ids: Vec<Uuid>| {
+            ids.sort();
+            ids
+        };
+        let query = RetrievalQuery {
+            query: content.to_string(),
+            ..Default::default()
+        };
+
+        // No agent: global memories only
+        let context = engine.retrieve_query(&store, &query).await.unwrap();
+        assert_eq!(ids(&context), [global]);
+
+        // One agent: global plus that agent's
+        let one = RetrievalQuery {
+            agent_id: Some("coder".to_string()),
+            ..query.clone()
+        };
+        let context = engine.retrieve_query(&store, &one).await.unwrap();
+        assert_eq!(sorted(ids(&context)), sorted(vec![global, coder]));
+
+        // Every agent, with or without an agent id
+        for agent_id in [None, Some("coder".to_string())] {
+            let every = RetrievalQuery {
+                agent_id,
+                all_agents: true,
+                ..query.clone()
+            };
+            let context = engine.retrieve_query(&store, &every).await.unwrap();
+            assert_eq!(sorted(ids(&context)), sorted(vec![global, coder, writer]));
+        }
+    }
+
+
+    #[tokio::test]
+    async fn results_carry_their_agent_and_topic() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        let content = "the build cache lives in target";
+        let global = save(&store, &engine, Memory::global(MemoryType::Fact, content)).await;
+        let agent = save(&store, &engine, Memory::for_agent("coder", MemoryType::Fact, content)).await;
+        let topic = save(&store, &engine, Memory::for_topic("coder", "build", MemoryType::Fact, content)).await;
+        let pinned = save(
+            &store,
+            &engine,
+            Memory::for_topic("coder", "build", MemoryType::Constraint, "never clear the cache").with_pinned(true),
+        )
+        .await;
+
+        let context = engine
+            .retrieve(&store, content, Some("coder"), Some("build"), 0, None)
+            .await
+            .unwrap();
+        assert_eq!(context.memories.len(), 4);
+        let carried = |id: Uuid| {
+            let memory = context.memories.iter().find(|m| m.id == id).unwrap();
+            (memory.scope.as_str(), memory.agent_id.as_deref(), memory.topic_id.as_deref())
+        };
+        assert_eq!(carried(global), ("global", None, None));
+        assert_eq!(carried(agent), ("agent", Some("coder"), None));
+        assert_eq!(carried(topic), ("topic", Some("coder"), Some("build")));
+        assert_eq!(carried(pinned), ("topic", Some("coder"), Some("build")));
+    }
+
+
+    #[tokio::test]
+    async fn memory_filter_drops_hits_after_scoring() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| c.min_similarity_score = 0.3).await;
+        let tagged = |content: &str, tag: &str| {
+            Memory::global(MemoryType::Fact, content).with_tags(vec![tag.to_string()])
+        };
+        let exact = save(&store, &engine, tagged("deploy the api service today", "docs")).await;
+        let close = save(&store, &engine, tagged("deploy the api service", "ops")).await;
+        let far = save(&store, &engine, tagged("deploy the api", "ops")).await;
+        let query = RetrievalQuery {
+            query: "deploy the api service today".to_string(),
+            ..Default::default()
+        };
+
+        let unfiltered = engine.retrieve_query(&store, &query).await.unwrap();
+        assert_eq!(ids(&unfiltered), [exact, close, far]);
+
+        // Filters see the stored tags
+        let ops = RetrievalQuery {
+            filter: Some(MemoryFilter::new(|m| m.tags.iter().any(|t| t == "ops"))),
+            ..query.clone()
+        };
+        let context = engine.retrieve_query(&store, &ops).await.unwrap();
+        assert_eq!(ids(&context), [close, far]);
+
+        // And the final scores
+        let not_exact = RetrievalQuery {
+            filter: Some(MemoryFilter::new(|m| m.score < 0.95)),
+            ..query.clone()
+        };
+        let context = engine.retrieve_query(&store, &not_exact).await.unwrap();
+        assert_eq!(ids(&context), [close, far]);
+    }
+
+
+    #[tokio::test]
+    async fn assemble_fits_memories_then_the_newest_messages_under_the_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| {
+            c.model_context_limits.insert("tiny".to_string(), 1000);
+        })
+        .await;
+        let memory = save(&store, &engine, Memory::global(MemoryType::Fact, "deploy the api service today")).await;
+        let memory_tokens = engine.token_counter.count("deploy the api service today");
+        assert!(memory_tokens < 100);
+        let mut messages = Vec::new();
+        for i in 0..10 {
+            let message = Message::new("coder", "build", Role::User, format!("message {}", i)).with_tokens(100);
+            store.append_message(&message).unwrap();
+            messages.push(message.id);
+        }
+
+        // 80% of 1000: the memory, then as many 100-token messages as still fit
+        let assembled = engine
+            .assemble(&store, "deploy the api service today", Some("coder"), Some("build"), Some("tiny"))
+            .await
+            .unwrap();
+        assert_eq!(ids(&assembled.context), [memory]);
+        let kept: Vec<Uuid> = assembled.context.recent_messages.iter().map(|m| m.id).collect();
+        assert_eq!(kept, messages[3..]);
+        assert_eq!(assembled.context.total_tokens, memory_tokens + 700);
+        assert_eq!(assembled.budget.limit, 1000);
+        assert_eq!(assembled.budget.used, assembled.context.total_tokens);
+        assert_eq!(assembled.prompt, assembled.context.format_for_prompt());
+
+        // A limit too small for the memory leaves nothing
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| {
+            c.model_context_limits.insert("tiny".to_string(), 2);
+        })
+        .await;
+        save(&store, &engine, Memory::global(MemoryType::Fact, "deploy the api service today")).await;
+        let assembled = engine
+            .assemble(&store, "deploy the api service today", None, None, Some("tiny"))
+            .await
+            .unwrap();
+        assert!(assembled.context.is_empty());
+        assert_eq!(assembled.context.total_tokens, 0);
+        assert_eq!(assembled.prompt, "");
+    }
+
+
+    #[tokio::test]
+    async fn consecutive_repeated_messages_are_collapsed_when_enabled() {
+        for dedup in [true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let (store, engine) = setup(dir.path(), |c| c.dedup_recent_messages = dedup).await;
+            let log = [
+                (Role::User, "retry"),
+                (Role::User, "retry"),
+                (Role::User, "retry"),
+                (Role::Assistant, "retry"),
+                (Role::User, "done?"),
+                (Role::User, "retry"),
+            ];
+            for (role, content) in &log {
+                store.append_message(&Message::new("coder", "build", role.clone(), *content)).unwrap();
+            }
+
+            let context = engine
+                .retrieve(&store, "anything", Some("coder"), Some("build"), 10, None)
+                .await
+                .unwrap();
+            let recent: Vec<(Role, &str)> = context
+                .recent_messages
+                .iter()
+                .map(|m| (m.role.clone(), m.content.as_str()))
+                .collect();
+            if dedup {
+                // Only back-to-back repeats from the same role collapse
+                assert_eq!(
+                    recent,
+                    [
+                        (Role::User, "retry"),
+                        (Role::Assistant, "retry"),
+                        (Role::User, "done?"),
+                        (Role::User, "retry"),
+                    ]
+                );
+            } else {
+                assert_eq!(recent, log);
+            }
+        }
+    }
+
+
+    #[test]
+    fn blend_duplicates_combines_scores_per_strategy() {
+        let twice = RetrievedMemory::from(Memory::global(MemoryType::Fact, "found by two scopes"));
+        let once = RetrievedMemory::from(Memory::global(MemoryType::Fact, "found once"));
+        let hit = |memory: &RetrievedMemory, score: f32| RetrievedMemory { score, ..memory.clone() };
+        let hits = vec![hit(&twice, 0.8), hit(&once, 0.7), hit(&twice, 0.6)];
+
+        for (blend, expected) in [(ScoreBlend::Max, 0.8), (ScoreBlend::Sum, 1.4), (ScoreBlend::Mean, 0.7)] {
+            let blended = blend_duplicates(hits.clone(), blend);
+            let ids: Vec<Uuid> = blended.iter().map(|m| m.id).collect();
+            assert_eq!(ids, [twice.id, once.id], "{:?}", blend);
+            assert!((blended[0].score - expected).abs() < 1e-6, "{:?}: {}", blend, blended[0].score);
+            assert_eq!(blended[1].score, 0.7, "{:?}", blend);
+        }
+    }
+
+
+    #[tokio::test]
+    async fn retrieve_by_message_uses_its_content_agent_and_topic() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        let coder = save(&store, &engine, Memory::for_agent("coder", MemoryType::Fact, "when do deploys run")).await;
+        save(&store, &engine, Memory::for_agent("writer", MemoryType::Fact, "when do deploys run")).await;
+        save(&store, &engine, Memory::global(MemoryType::Fact, "lint before commit")).await;
+
+        let earlier = Message::new("coder", "build", Role::Assistant, "ready when you are");
+        let message = Message::new("coder", "build", Role::User, "  when do deploys run \n")
+            .with_thinking("lint before commit");
+        store.append_message(&earlier).unwrap();
+        store.append_message(&message).unwrap();
+        store
+            .append_message(&Message::new("coder", "docs", Role::User, "other topic"))
+            .unwrap();
+
+        // The thinking doesn't steer the search, and other agents' memories stay out
+        let context = engine.retrieve_by_message(&store, &message, 5, None).await.unwrap();
+        assert_eq!(ids(&context), [coder]);
+        let recent: Vec<Uuid> = context.recent_messages.iter().map(|m| m.id).collect();
+        assert_eq!(recent, [earlier.id, message.id]);
+    }
+
+
+    #[tokio::test]
+    async fn topic_isolation_limits_topic_memories_to_the_current_topic() {
+        for isolation in [true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let (store, engine) = setup(dir.path(), |c| c.topic_isolation = isolation).await;
+            let content = "the flaky test is quarantined";
+            let build = save(&store, &engine, Memory::for_topic("coder", "build", MemoryType::Fact, content)).await;
+            let docs = save(&store, &engine, Memory::for_topic("coder", "docs", MemoryType::Fact, content)).await;
+            save(&store, &engine, Memory::for_topic("writer", "build", MemoryType::Fact, content)).await;
+            let (store, engine) = (&store, &engine);
+            let found = move |topic: Option<&'static str>| {
+                async move {
+                    let context = engine.retrieve(store, content, Some("coder"), topic, 0, None).await.unwrap();
+                    let mut found = ids(&context);
+                    found.sort();
+                    found
+                }
+            };
+            let mut both = vec![build, docs];
+            both.sort();
+
+            if isolation {
+                assert_eq!(found(Some("build")).await, [build]);
+                assert_eq!(found(Some("docs")).await, [docs]);
+                assert!(found(None).await.is_empty());
+            } else {
+                // Any of the agent's topics, but never another agent's
+                assert_eq!(found(Some("build")).await, both);
+                assert_eq!(found(None).await, both);
+            }
+        }
+    }
+
+
+    #[test]
+    fn corrections_shorter_than_the_minimum_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = test_support::engine(&test_support::config(dir.path(), |_| {}));
+
+        // The default minimum is 15 characters, counted after trimming
+        assert!(engine.detect_correction("no, use tabs ok", "").is_some());
+        assert!(engine.detect_correction("no, use tabs o", "").is_none());
+        assert!(engine.detect_correction("   no, use tabs o   ", "").is_none());
+
+        let suggested = engine.suggest_memory_from_correction("never use tabs!", "coder").unwrap();
+        assert_eq!(suggested.memory_type, MemoryType::Constraint);
+        assert!(engine.suggest_memory_from_correction("never use tabs", "coder").is_none());
+        // Characters, not bytes: 14 characters in 15 bytes is still too short
+        assert!(engine.suggest_memory_from_correction("never use tâbs", "coder").is_none());
+
+        let dir = tempfile::tempdir().unwrap();
+        let lenient = test_support::engine(&test_support::config(dir.path(), |c| c.min_correction_length = 0));
+        assert!(lenient.detect_correction("no, tabs", "").is_some());
+        assert!(lenient.suggest_memory_from_correction("never tabs", "coder").is_some());
+    }
+
+
+    #[test]
+    fn sort_by_score_puts_active_memories_first_on_ties() {
+        let memory = |score: f32, active: bool| RetrievedMemory {
+            score,
+            active,
+            ..RetrievedMemory::from(Memory::global(MemoryType::Fact, "x"))
+        };
+        let mut memories = vec![memory(0.5, true), memory(0.9, false), memory(0.9, true), memory(0.7, false)];
+        sort_by_score(&mut memories);
+        let order: Vec<(f32, bool)> = memories.iter().map(|m| (m.score, m.active)).collect();
+        assert_eq!(order, [(0.9, true), (0.9, false), (0.7, false), (0.5, true)]);
+    }
+
+    #[tokio::test]
+    async fn inactive_memories_are_penalized_and_ranked_after_active_ones() {
+        for penalty in [0.1, 0.0] {
+            let dir = tempfile::tempdir().unwrap();
+            let (store, engine) = setup(dir.path(), |c| c.inactive_score_penalty = penalty).await;
+            let content = "when do deploys run";
+            let inactive = save(&store, &engine, Memory::global(MemoryType::Fact, content)).await;
+            let active = save(&store, &engine, Memory::global(MemoryType::Fact, content)).await;
+            store.deactivate_memory(inactive).await.unwrap();
+            let query = RetrievalQuery {
+                query: content.to_string(),
+                ..Default::default()
+            };
+
+            let context = engine.retrieve_query(&store, &query).await.unwrap();
+            assert_eq!(ids(&context), [active]);
+
+            // Even when the penalty is zero and the scores tie
+            let with_inactive = RetrievalQuery {
+                include_inactive: true,
+                ..query.clone()
+            };
+            let context = engine.retrieve_query(&store, &with_inactive).await.unwrap();
+            assert_eq!(ids(&context), [active, inactive]);
+            assert!(context.memories[0].active && !context.memories[1].active);
+            let gap = context.memories[0].score - context.memories[1].score;
+            assert!((gap - penalty).abs() < 1e-4, "penalty {}: gap {}", penalty, gap);
+        }
+    }
+
+
+    #[test]
+    fn boost_matching_tags_adds_the_boost_per_shared_tag() {
+        let tagged = |tags: &[&str]| RetrievedMemory {
+            score: 0.5,
+            ..RetrievedMemory::from(
+                Memory::global(MemoryType::Fact, "x").with_tags(tags.iter().map(|t| t.to_string()).collect()),
+            )
+        };
+        let scores = |query_tags: &[&str], boost: f32| {
+            let mut memories = vec![tagged(&["ops", "db", "misc"]), tagged(&["ops"]), tagged(&[])];
+            let query_tags: Vec<String> = query_tags.iter().map(|t| t.to_string()).collect();
+            boost_matching_tags(&mut memories, &query_tags, boost);
+            memories.iter().map(|m| m.score).collect::<Vec<f32>>()
+        };
+
+        // Tags match case-insensitively
+        let boosted = scores(&["OPS", "Db"], 0.05);
+        assert!((boosted[0] - 0.6).abs() < 1e-6 && (boosted[1] - 0.55).abs() < 1e-6, "{:?}", boosted);
+        assert_eq!(boosted[2], 0.5);
+        assert_eq!(scores(&[], 0.05), [0.5, 0.5, 0.5]);
+        assert_eq!(scores(&["ops"], 0.0), [0.5, 0.5, 0.5]);
+    }
+
+    #[tokio::test]
+    async fn query_tags_lift_tagged_memories_past_closer_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |c| {
+            c.min_similarity_score = 0.3;
+            c.tag_match_boost = 0.1;
+        })
+        .await;
+        let closer = save(&store, &engine, Memory::global(MemoryType::Fact, "deploy the api service")).await;
+        let tagged = save(
+            &store,
+            &engine,
+            Memory::global(MemoryType::Fact, "deploy the api").with_tags(vec!["ops".to_string(), "deploys".to_string()]),
+        )
+        .await;
+        let query = RetrievalQuery {
+            query: "deploy the api service today".to_string(),
+            ..Default::default()
+        };
+
+        let context = engine.retrieve_query(&store, &query).await.unwrap();
+        assert_eq!(ids(&context), [closer, tagged]);
+
+        let with_tags = RetrievalQuery {
+            query_tags: vec!["OPS".to_string(), "deploys".to_string()],
+            ..query.clone()
+        };
+        let context = engine.retrieve_query(&store, &with_tags).await.unwrap();
+        assert_eq!(ids(&context), [tagged, closer]);
+        assert!((context.memories[0].score - 0.997).abs() < 1e-3, "{}", context.memories[0].score);
+    }
+
+
+    #[tokio::test]
+    async fn near_identical_memories_in_the_same_scope_are_superseded() {
+        let content = "deploys run on friday";
+        for action in [None, Some(SupersedeAction::Deactivate), Some(SupersedeAction::Downweight(0.5))] {
+            let dir = tempfile::tempdir().unwrap();
+            let (store, engine) = setup(dir.path(), |c| {
+                c.supersede_threshold = action.map(|_| 0.9);
+                if let Some(action) = action {
+                    c.supersede_action = action;
+                }
+            })
+            .await;
+            // Pinned memories are never superseded, so `old` doesn't replace `pinned`
+            let pinned = save(&store, &engine, Memory::global(MemoryType::Fact, content).with_pinned(true)).await;
+            let other_scope = save(&store, &engine, Memory::for_agent("coder", MemoryType::Fact, content)).await;
+            let unrelated = save(&store, &engine, Memory::global(MemoryType::Fact, "lint before commit")).await;
+            let old = save(&store, &engine, Memory::global(MemoryType::Fact, content)).await;
+
+            let (new, superseded) = engine
+                .embed_and_save_superseding(&store, Memory::global(MemoryType::Fact, content))
+                .await
+                .unwrap();
+            let (active, weight) = {
+                let memory = store.get_memory(old).unwrap().unwrap();
+                (memory.active, memory.weight)
+            };
+            match action {
+                None => {
+                    assert!(superseded.is_empty());
+                    assert!(active && weight == 1.0);
+                }
+                Some(SupersedeAction::Deactivate) => {
+                    assert_eq!(superseded, [old]);
+                    assert!(!active && weight == 1.0);
+                }
+                Some(SupersedeAction::Downweight(_)) => {
+                    assert_eq!(superseded, [old]);
+                    assert!(active && weight == 0.5);
+                }
+            }
+            for id in [pinned, other_scope, unrelated, new.id] {
+                let memory = store.get_memory(id).unwrap().unwrap();
+                assert!(memory.active && memory.weight == 1.0, "{:?} changed", memory.content);
+            }
+        }
+    }
+
+
+    #[test]
+    fn reserved_agent_slots_displace_the_weakest_global_memories() {
+        let hit = |memory: Memory, score: f32| RetrievedMemory {
+            score,
+            ..RetrievedMemory::from(memory)
+        };
+        let ranked = vec![
+            hit(Memory::global(MemoryType::Fact, "g1"), 0.9),
+            hit(Memory::global(MemoryType::Fact, "g2"), 0.8),
+            hit(Memory::global(MemoryType::Fact, "g3"), 0.7),
+            hit(Memory::for_agent("coder", MemoryType::Fact, "a1"), 0.6),
+            hit(Memory::for_topic("coder", "build", MemoryType::Fact, "t1"), 0.5),
+            hit(Memory::global(MemoryType::Fact, "g4"), 0.4),
+        ];
+        let truncated = |limit: usize, reserved: usize| {
+            let mut memories = ranked.clone();
+            truncate_reserving_agent_slots(&mut memories, limit, reserved);
+            memories.into_iter().map(|m| m.content).collect::<Vec<String>>()
+        };
+
+        assert_eq!(truncated(3, 0), ["g1", "g2", "g3"]);
+        assert_eq!(truncated(3, 1), ["g1", "g2", "a1"]);
+        // Topic memories count as agent memories; the result stays in score order
+        assert_eq!(truncated(3, 2), ["g1", "a1", "t1"]);
+        // No more than the agent memories available, nor the limit
+        assert_eq!(truncated(3, 5), ["g1", "a1", "t1"]);
+        assert_eq!(truncated(1, 2), ["a1"]);
+        // Agent memories already above the cut count toward the reservation
+        assert_eq!(truncated(5, 2), ["g1", "g2", "g3", "a1", "t1"]);
+        assert_eq!(truncated(10, 2).len(), 6);
+    }
+
+
+    #[tokio::test]
+    async fn token_cap_keeps_the_newest_contiguous_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        let messages = |tokens: &[u32]| -> Vec<Message> {
+            tokens
+                .iter()
+                .enumerate()
+                .map(|(i, &t)| Message::new("coder", "build", Role::User, format!("message {}", i)).with_tokens(t))
+                .collect()
+        };
+        let kept = |kept: Vec<Message>| kept.into_iter().map(|m| m.content).collect::<Vec<String>>();
+
+        assert_eq!(kept(engine.trim_to_token_cap(messages(&[100, 50, 30, 20]), 60)), ["message 2", "message 3"]);
+        assert_eq!(kept(engine.trim_to_token_cap(messages(&[100, 50, 30, 20]), 200)).len(), 4);
+        assert!(engine.trim_to_token_cap(messages(&[100, 50, 30, 20]), 0).is_empty());
+        // An older message that would fit is not kept past one that doesn't
+        assert_eq!(kept(engine.trim_to_token_cap(messages(&[10, 100, 5]), 50)), ["message 2"]);
+
+        // Messages without a count are counted by the tokenizer
+        let uncounted = messages(&[0, 0]);
+        let size = engine.token_counter.count(&uncounted[1].content);
+        assert_eq!(kept(engine.trim_to_token_cap(uncounted.clone(), size)), ["message 1"]);
+        assert_eq!(kept(engine.trim_to_token_cap(uncounted, size - 1)).len(), 0);
+
+        // Retrieval applies the cap from `max_recent_tokens`
+        for message in messages(&[100, 50, 30, 20]) {
+            store.append_message(&message).unwrap();
+        }
+        let context = engine
+            .retrieve(&store, "anything", Some("coder"), Some("build"), 10, Some(60))
+            .await
+            .unwrap();
+        assert_eq!(kept(context.recent_messages), ["message 2", "message 3"]);
+    }
+
+
+    #[tokio::test]
+    async fn personal_memories_stay_out_of_similarity_retrieval() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, engine) = setup(dir.path(), |_| {}).await;
+        let content = "my standup is at nine";
+        let global = save(&store, &engine, Memory::global(MemoryType::Fact, content)).await;
+        let personal = save(&store, &engine, Memory::personal(MemoryType::Fact, content).with_owner("alice")).await;
+        let query = RetrievalQuery {
+            query: content.to_string(),
+            agent_id: Some("coder".to_string()),
+            caller: Some("alice".to_string()),
+            ..Default::default()
+        };
+
+        // Not even for its owner, across every agent or when asked for by scope
+        for query in [
+            query.clone(),
+            RetrievalQuery { all_agents: true, ..query.clone() },
+            RetrievalQuery { scopes: vec![MemoryScope::Personal, MemoryScope::Global], ..query.clone() },
+        ] {
+            let context = engine.retrieve_query(&store, &query).await.unwrap();
+            assert_eq!(ids(&context), [global]);
+        }
+        let explanation = engine.explain(&store, &query, personal).await.unwrap();
+        assert!(!explanation.retrieved);
+        assert_eq!(explanation.excluded_by, ["personal memories are not retrieved by similarity"]);
+
+        // Nor by the keyword fallback (with new text, so the search cache can't answer)
+        let keywords = RetrievalQuery {
+            query: "standup at nine".to_string(),
+            ..query.clone()
+        };
+        let config = Config {
+            keyword_fallback: true,
+            ..store.config().clone()
+        };
+        let failing = FakeEmbedder {
+            fail: true,
+            ..FakeEmbedder::new(config.embedding_dimensions)
+        };
+        let context = test_support::engine_with(&config, failing)
+            .retrieve_query(&store, &keywords)
+            .await
+            .unwrap();
+        assert_eq!(ids(&context), [global]);
+        assert!(context.memories[0].low_confidence);
+    }
+}
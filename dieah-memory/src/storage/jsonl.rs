@@ -1,16 +1,236 @@
 //! JSONL storage for conversation history
 
+use std::collections::{HashMap, VecDeque};
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
 
 use crate::config::Config;
 use crate::error::{Error, Result};
-use crate::message::Message;
+use crate::embedding::TokenCounter;
+use crate::message::{Message, Role};
+use crate::redaction::Redactor;
+
+/// Version of the topic export envelope written by `export_topic`
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Envelope for an exported topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicExport {
+    /// Export format version
+    pub version: u32,
+
+    /// When the export was written
+    pub exported_at: DateTime<Utc>,
+
+    /// Agent the topic was exported from
+    pub agent_id: String,
+
+    /// Topic that was exported
+    pub topic_id: String,
+
+    /// Messages in log order
+    pub messages: Vec<Message>,
+}
+
+/// Outcome of importing a topic
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ImportReport {
+    /// Messages appended to the target topic
+    pub imported: usize,
+
+    /// Messages skipped because they failed validation
+    pub skipped: usize,
+}
+
+/// Where `JsonlStorage::append` wrote a message: the segment its active log
+/// becomes once rotated, and the byte offset within that segment
+///
+/// Positions stay valid across rotations and order messages within a topic.
+/// Compaction and merges rewrite the log, after which `read_at_offset` reports
+/// an old position as not found instead of returning what now sits there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct LogPosition {
+    /// Rotated segments are numbered from 1; the active log is the segment
+    /// after the newest rotated one
+    pub segment: u32,
+
+    /// Byte offset of the message's line within the (uncompressed) segment
+    pub offset: u64,
+}
+
+/// Outcome of compacting a topic
+#[derive(Debug, Clone, Serialize)]
+pub struct CompactionReport {
+    /// Messages moved out of the live log
+    pub archived: usize,
+
+    /// Where the archived originals were written
+    pub archive_path: PathBuf,
+}
+
+/// Suffix of the directory holding a topic's rotated segments
+const SEGMENT_DIR_SUFFIX: &str = ".segments";
+
+/// Maximum number of archived messages listed in a compaction summary
+const SUMMARY_MAX_LINES: usize = 50;
+
+/// Maximum characters kept from each archived message in a compaction summary
+const SUMMARY_LINE_CHARS: usize = 160;
+
+/// Maximum length of an agent or topic id
+const MAX_ID_LEN: usize = 128;
+
+/// Topic locks kept before idle ones are dropped
+const MAX_IDLE_TOPIC_LOCKS: usize = 1024;
+
+/// Check that an agent or topic id is safe to use as a path component
+///
+/// Rejects empty ids, path separators, `..`, leading dots and control
+/// characters so ids can never escape the conversations directory.
+pub fn validate_id(kind: &str, id: &str) -> Result<()> {
+    if id.is_empty() {
+        return Err(Error::invalid_input(format!("{} must not be empty", kind)));
+    }
+    if id.len() > MAX_ID_LEN {
+        return Err(Error::invalid_input(format!(
+            "{} is longer than {} bytes",
+            kind, MAX_ID_LEN
+        )));
+    }
+    if id.starts_with('.')
+        || id.contains("..")
+        || id.contains(['/', '\\', ':'])
+        || id.chars().any(char::is_control)
+    {
+        return Err(Error::invalid_input(format!("{} contains unsafe characters: {:?}", kind, id)));
+    }
+    Ok(())
+}
 
 /// JSONL storage backend for conversation logs
 pub struct JsonlStorage {
     base_path: PathBuf,
+    redactor: Option<Redactor>,
+    max_inline_attachment_bytes: usize,
+    compress_rotated: bool,
+    rotate_after_bytes: Option<u64>,
+    durability: Durability,
+    /// Appends since the last fsync, per log file (for `Durability::FsyncEvery`)
+    unsynced: Mutex<HashMap<PathBuf, u32>>,
+    /// Parsed messages of recently read topics
+    read_cache: ReadCache,
+    /// Counts tokens for messages stored with none (`Config.recount_zero_tokens`)
+    recounter: Option<TokenCounter>,
+    /// Write lock and message count per topic, see `topic_lock`
+    topics: Mutex<HashMap<(String, String), Arc<Mutex<TopicState>>>>,
+    /// Full parses of a topic log, so tests can tell cached reads apart
+    #[cfg(test)]
+    parses: std::sync::atomic::AtomicUsize,
+}
+
+/// Per-topic state, guarded by the topic's write lock
+#[derive(Debug, Default)]
+struct TopicState {
+    /// Messages in the topic, counted from the log on first use and then kept
+    /// current by this store's writes
+    messages: Option<usize>,
+}
+
+/// Lock a topic's state
+fn lock_state(lock: &Mutex<TopicState>) -> Result<MutexGuard<'_, TopicState>> {
+    lock.lock().map_err(|e| Error::storage(e.to_string()))
+}
+
+/// Size and modification time of every file making up a topic log, so a
+/// cached read can tell whether the log changed underneath it
+type LogStamp = Vec<(PathBuf, u64, Option<SystemTime>)>;
+
+/// Parsed messages per topic, for the `Config.message_cache_topics` most
+/// recently read topics
+///
+/// Entries are checked against the log's `LogStamp` on every read, and
+/// dropped outright by this store's own writes.
+struct ReadCache {
+    capacity: usize,
+    inner: Mutex<ReadCacheInner>,
+}
+
+#[derive(Default)]
+struct ReadCacheInner {
+    entries: HashMap<(String, String), (LogStamp, Arc<Vec<Message>>)>,
+    /// Least recently read first, for eviction
+    order: VecDeque<(String, String)>,
+}
+
+impl ReadCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(ReadCacheInner::default()),
+        }
+    }
+
+    fn get(&self, key: &(String, String), stamp: &LogStamp) -> Option<Arc<Vec<Message>>> {
+        let mut inner = self.inner.lock().ok()?;
+        let messages = match inner.entries.get(key) {
+            Some((cached, messages)) if cached == stamp => messages.clone(),
+            _ => return None,
+        };
+        inner.order.retain(|k| k != key);
+        inner.order.push_back(key.clone());
+        Some(messages)
+    }
+
+    fn insert(&self, key: (String, String), stamp: LogStamp, messages: Arc<Vec<Message>>) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        inner.order.retain(|k| *k != key);
+        inner.order.push_back(key.clone());
+        inner.entries.insert(key, (stamp, messages));
+        while inner.entries.len() > self.capacity {
+            match inner.order.pop_front() {
+                Some(oldest) => inner.entries.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    fn invalidate(&self, agent_id: &str, topic_id: &str) {
+        if let Ok(mut inner) = self.inner.lock() {
+            let key = (agent_id.to_string(), topic_id.to_string());
+            inner.entries.remove(&key);
+            inner.order.retain(|k| *k != key);
+        }
+    }
+}
+
+/// When `JsonlStorage::append` forces written messages to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave flushing to the OS; a crash can lose recent appends
+    None,
+    /// fsync after every append
+    Fsync,
+    /// fsync a log after every n-th append to it, bounding what a crash can lose
+    FsyncEvery(u32),
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Self::FsyncEvery(8)
+    }
 }
 
 impl JsonlStorage {
@@ -19,27 +239,379 @@ impl JsonlStorage {
         let base_path = config.data_dir.join("conversations");
         std::fs::create_dir_all(&base_path)?;
         
-        Ok(Self { base_path })
+        Ok(Self {
+            base_path,
+            redactor: Redactor::from_config(config)?,
+            max_inline_attachment_bytes: config.max_inline_attachment_bytes,
+            compress_rotated: config.compress_rotated,
+            rotate_after_bytes: config.rotate_after_bytes,
+            durability: config.durability,
+            unsynced: Mutex::new(HashMap::new()),
+            read_cache: ReadCache::new(config.message_cache_topics),
+            recounter: config
+                .recount_zero_tokens
+                .then(TokenCounter::for_gpt)
+                .transpose()?,
+            topics: Mutex::new(HashMap::new()),
+            #[cfg(test)]
+            parses: Default::default(),
+        })
+    }
+
+    /// The write lock of a topic
+    ///
+    /// Held by everything that appends to or rewrites a topic's files, so an
+    /// append can't land between compaction (or a merge, rotation or token
+    /// rewrite) reading the log and replacing it. Reads don't take it.
+    fn topic_lock(&self, agent_id: &str, topic_id: &str) -> Result<Arc<Mutex<TopicState>>> {
+        let mut topics = self.topics.lock().map_err(|e| Error::storage(e.to_string()))?;
+        if topics.len() >= MAX_IDLE_TOPIC_LOCKS {
+            // Only the map holds an idle lock, so nobody is waiting on it
+            topics.retain(|_, lock| Arc::strong_count(lock) > 1);
+        }
+        Ok(topics
+            .entry((agent_id.to_string(), topic_id.to_string()))
+            .or_default()
+            .clone())
+    }
+
+    /// Messages in a topic, counting the log if `state` doesn't know yet
+    fn counted(&self, state: &mut TopicState, agent_id: &str, topic_id: &str) -> Result<usize> {
+        match state.messages {
+            Some(count) => Ok(count),
+            None => {
+                let count = self.count(agent_id, topic_id)?;
+                state.messages = Some(count);
+                Ok(count)
+            }
+        }
+    }
+
+    /// Number of messages in a topic
+    ///
+    /// Counted from the log on first use, then kept current by this store's
+    /// own writes, so it is cheap to call after every append. Writes by
+    /// another process aren't seen; use `count` for an exact figure.
+    pub fn message_count(&self, agent_id: &str, topic_id: &str) -> Result<usize> {
+        let lock = self.topic_lock(agent_id, topic_id)?;
+        let mut state = lock_state(&lock)?;
+        self.counted(&mut state, agent_id, topic_id)
+    }
+
+    /// Fill in the token count of a message stored without one
+    fn recount(&self, message: &mut Message) {
+        if let Some(counter) = &self.recounter {
+            if message.tokens == 0 {
+                message.tokens = counter.count(&message.content);
+            }
+        }
+    }
+
+    /// Get the directory holding an agent's logs
+    fn agent_dir(&self, agent_id: &str) -> Result<PathBuf> {
+        validate_id("agent_id", agent_id)?;
+        Ok(self.base_path.join(agent_id))
     }
 
     /// Get the path to the log file for a topic
-    fn log_path(&self, agent_id: &str, topic_id: &str) -> PathBuf {
-        let agent_dir = self.base_path.join(agent_id);
-        agent_dir.join(format!("{}.jsonl", topic_id))
+    fn log_path(&self, agent_id: &str, topic_id: &str) -> Result<PathBuf> {
+        validate_id("topic_id", topic_id)?;
+        Ok(self.agent_dir(agent_id)?.join(format!("{}.jsonl", topic_id)))
+    }
+
+    /// Get the directory holding a topic's rotated segments
+    ///
+    /// Segments live in their own `{topic}.segments/` directory rather than
+    /// beside the active logs, so no topic id (dots are allowed) can be
+    /// mistaken for another topic's segment.
+    fn segment_dir(&self, agent_id: &str, topic_id: &str) -> Result<PathBuf> {
+        validate_id("topic_id", topic_id)?;
+        Ok(self.agent_dir(agent_id)?.join(format!("{}{}", topic_id, SEGMENT_DIR_SUFFIX)))
+    }
+
+    /// Get the path to a rotated segment of a topic log
+    fn segment_path(&self, agent_id: &str, topic_id: &str, index: u32, compressed: bool) -> Result<PathBuf> {
+        let ext = if compressed { "jsonl.gz" } else { "jsonl" };
+        Ok(self
+            .segment_dir(agent_id, topic_id)?
+            .join(format!("{}.{}", index, ext)))
+    }
+
+    /// List rotated segments for a topic, oldest first
+    fn segments(&self, agent_id: &str, topic_id: &str) -> Result<Vec<(u32, PathBuf)>> {
+        let segment_dir = self.segment_dir(agent_id, topic_id)?;
+        if !segment_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut segments = Vec::new();
+        for entry in std::fs::read_dir(&segment_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let index = name
+                .strip_suffix(".jsonl.gz")
+                .or_else(|| name.strip_suffix(".jsonl"))
+                .and_then(|index| index.parse::<u32>().ok());
+            if let Some(index) = index {
+                segments.push((index, entry.path()));
+            }
+        }
+
+        segments.sort_by_key(|(index, _)| *index);
+        Ok(segments)
+    }
+
+    /// Segment number the active log will get when it is next rotated
+    fn active_segment(&self, agent_id: &str, topic_id: &str) -> Result<u32> {
+        Ok(self
+            .segments(agent_id, topic_id)?
+            .last()
+            .map(|(index, _)| index + 1)
+            .unwrap_or(1))
+    }
+
+    /// Delete a topic's rotated segments along with their directory
+    fn remove_segments(&self, agent_id: &str, topic_id: &str) -> Result<()> {
+        let segment_dir = self.segment_dir(agent_id, topic_id)?;
+        if segment_dir.is_dir() {
+            std::fs::remove_dir_all(&segment_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Open readers over all segments of a topic log (rotated first, then active)
+    fn open_readers(&self, agent_id: &str, topic_id: &str) -> Result<Vec<Box<dyn BufRead>>> {
+        let mut readers: Vec<Box<dyn BufRead>> = Vec::new();
+
+        for (_, path) in self.segments(agent_id, topic_id)? {
+            readers.push(open_log(&path)?);
+        }
+
+        let path = self.log_path(agent_id, topic_id)?;
+        if path.exists() {
+            readers.push(Box::new(BufReader::new(File::open(&path)?)));
+        }
+
+        Ok(readers)
+    }
+
+    /// Rotate the active log into a numbered segment, gzipped if `compress_rotated` is set
+    ///
+    /// Returns the segment path, or `None` if there was nothing to rotate.
+    pub fn rotate(&self, agent_id: &str, topic_id: &str) -> Result<Option<PathBuf>> {
+        let lock = self.topic_lock(agent_id, topic_id)?;
+        let _state = lock_state(&lock)?;
+        self.rotate_locked(agent_id, topic_id)
+    }
+
+    /// `rotate`, for callers holding the topic lock
+    fn rotate_locked(&self, agent_id: &str, topic_id: &str) -> Result<Option<PathBuf>> {
+        let path = self.log_path(agent_id, topic_id)?;
+        if !path.exists() || std::fs::metadata(&path)?.len() == 0 {
+            return Ok(None);
+        }
+
+        let next = self.active_segment(agent_id, topic_id)?;
+        let segment = self.segment_path(agent_id, topic_id, next, self.compress_rotated)?;
+        std::fs::create_dir_all(self.segment_dir(agent_id, topic_id)?)?;
+
+        // Appends not yet synced under `Durability::FsyncEvery` are synced with
+        // the segment, since the active log's count starts over
+        let unsynced = self.take_unsynced(&path)?;
+        if self.compress_rotated {
+            let mut input = File::open(&path)?;
+            let output = BufWriter::new(File::create(&segment)?);
+            let mut encoder = GzEncoder::new(output, Compression::default());
+            std::io::copy(&mut input, &mut encoder)?;
+            let mut output = encoder.finish()?;
+            output.flush()?;
+            if unsynced > 0 {
+                output.get_ref().sync_all()?;
+            }
+            std::fs::remove_file(&path)?;
+        } else {
+            std::fs::rename(&path, &segment)?;
+            if unsynced > 0 {
+                File::open(&segment)?.sync_all()?;
+            }
+        }
+        self.read_cache.invalidate(agent_id, topic_id);
+
+        Ok(Some(segment))
+    }
+
+    /// Replace all but the newest `keep_recent` messages with a summary message
+    ///
+    /// The originals are written to `{agent}/.archive/{topic}.{timestamp}.jsonl`
+    /// (outside the live segments, so reads don't see them twice) and rotated
+    /// segments are folded into the new active log. The summary is extractive:
+    /// one line per archived message, newest last.
+    ///
+    /// Older messages flagged `retain` stay in the active log, in order, after
+    /// the summary; returns `None` if there is nothing else to archive.
+    pub fn compact_topic(&self, agent_id: &str, topic_id: &str, keep_recent: usize) -> Result<Option<CompactionReport>> {
+        let lock = self.topic_lock(agent_id, topic_id)?;
+        let mut state = lock_state(&lock)?;
+        self.compact_locked(&mut state, agent_id, topic_id, keep_recent)
+    }
+
+    /// Compact a topic as `compact_topic` does, but only if it holds more than
+    /// `threshold` messages
+    ///
+    /// The check and the compaction happen under one hold of the topic lock,
+    /// so concurrent appends crossing the threshold compact it once.
+    pub fn compact_if_over(
+        &self,
+        agent_id: &str,
+        topic_id: &str,
+        threshold: usize,
+        keep_recent: usize,
+    ) -> Result<Option<CompactionReport>> {
+        let lock = self.topic_lock(agent_id, topic_id)?;
+        let mut state = lock_state(&lock)?;
+        if self.counted(&mut state, agent_id, topic_id)? <= threshold {
+            return Ok(None);
+        }
+        self.compact_locked(&mut state, agent_id, topic_id, keep_recent)
+    }
+
+    /// `compact_topic`, for callers holding the topic lock
+    fn compact_locked(
+        &self,
+        state: &mut TopicState,
+        agent_id: &str,
+        topic_id: &str,
+        keep_recent: usize,
+    ) -> Result<Option<CompactionReport>> {
+        let messages = self.read_shared(agent_id, topic_id)?;
+        state.messages = Some(messages.len());
+        if messages.len() <= keep_recent {
+            return Ok(None);
+        }
+
+        let split = messages.len() - keep_recent;
+        let (older, recent) = messages.split_at(split);
+        let (retained, archived): (Vec<Message>, Vec<Message>) =
+            older.iter().cloned().partition(|m| m.retained());
+        if archived.is_empty() {
+            return Ok(None);
+        }
+
+        // Archive the originals first so nothing is lost if the rewrite fails
+        let archive_dir = self.agent_dir(agent_id)?.join(".archive");
+        std::fs::create_dir_all(&archive_dir)?;
+        let (archive_path, file) = create_archive(&archive_dir, topic_id)?;
+        let mut writer = BufWriter::new(file);
+        for message in &archived {
+            writeln!(writer, "{}", serde_json::to_string(message)?)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        let header = format!("Summary of {} earlier messages (originals archived):", archived.len());
+        let text = summarize(&header, &archived);
+        let tokens = TokenCounter::estimate(&text);
+        let summary = Message::new(agent_id, topic_id, Role::System, text).with_tokens(tokens);
+
+        // Write the new log beside the old one, then swap it in
+        let path = self.log_path(agent_id, topic_id)?;
+        let tmp_path = path.with_extension("jsonl.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writeln!(writer, "{}", serde_json::to_string(&summary)?)?;
+        for message in retained.iter().chain(recent) {
+            writeln!(writer, "{}", serde_json::to_string(message)?)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        self.remove_segments(agent_id, topic_id)?;
+        std::fs::rename(&tmp_path, &path)?;
+        self.take_unsynced(&path)?;
+        self.read_cache.invalidate(agent_id, topic_id);
+        state.messages = Some(1 + retained.len() + recent.len());
+
+        Ok(Some(CompactionReport {
+            archived: archived.len(),
+            archive_path,
+        }))
+    }
+
+    /// Merge `source_topic`'s messages into `dest_topic`, interleaved by timestamp,
+    /// then delete the source log. Returns the merged messages, oldest first.
+    ///
+    /// Messages with equal timestamps keep destination-first order. The merged
+    /// log is written beside the destination and swapped in before the source
+    /// is removed, so a failure part way never loses messages.
+    pub fn merge_topics(&self, agent_id: &str, source_topic: &str, dest_topic: &str) -> Result<Vec<Message>> {
+        if source_topic == dest_topic {
+            return Err(Error::invalid_input("Cannot merge a topic into itself"));
+        }
+
+        // Lock the two topics in a fixed order, so opposite merges can't deadlock
+        let source_lock = self.topic_lock(agent_id, source_topic)?;
+        let dest_lock = self.topic_lock(agent_id, dest_topic)?;
+        let (mut source_state, mut dest_state) = if source_topic < dest_topic {
+            let source = lock_state(&source_lock)?;
+            (source, lock_state(&dest_lock)?)
+        } else {
+            let dest = lock_state(&dest_lock)?;
+            (lock_state(&source_lock)?, dest)
+        };
+
+        let mut merged = self.read_all(agent_id, dest_topic)?;
+        merged.extend(self.read_all(agent_id, source_topic)?.into_iter().map(|mut message| {
+            message.topic_id = dest_topic.to_string();
+            message
+        }));
+        merged.sort_by_key(|m| m.timestamp);
+
+        self.ensure_dir(agent_id)?;
+        let path = self.log_path(agent_id, dest_topic)?;
+        let tmp_path = path.with_extension("jsonl.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for message in &merged {
+            writeln!(writer, "{}", serde_json::to_string(message)?)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        self.remove_segments(agent_id, dest_topic)?;
+        std::fs::rename(&tmp_path, &path)?;
+        self.take_unsynced(&path)?;
+        self.read_cache.invalidate(agent_id, dest_topic);
+        dest_state.messages = Some(merged.len());
+        self.delete_locked(&mut source_state, agent_id, source_topic)?;
+
+        Ok(merged)
     }
 
     /// Ensure the directory exists for a topic
     fn ensure_dir(&self, agent_id: &str) -> Result<()> {
-        let agent_dir = self.base_path.join(agent_id);
+        let agent_dir = self.agent_dir(agent_id)?;
         std::fs::create_dir_all(&agent_dir)?;
         Ok(())
     }
 
-    /// Append a message to the log
-    pub fn append(&self, message: &Message) -> Result<u64> {
+    /// Append a message to the log, returning where it was written
+    pub fn append(&self, message: &Message) -> Result<LogPosition> {
+        for attachment in message.attachments() {
+            attachment.validate(self.max_inline_attachment_bytes)?;
+        }
+
         self.ensure_dir(&message.agent_id)?;
         
-        let path = self.log_path(&message.agent_id, &message.topic_id);
+        let path = self.log_path(&message.agent_id, &message.topic_id)?;
+        let lock = self.topic_lock(&message.agent_id, &message.topic_id)?;
+        let mut state = lock_state(&lock)?;
+
+        // Rotate before writing so the returned position refers to the active segment
+        if let Some(limit) = self.rotate_after_bytes {
+            if path.exists() && std::fs::metadata(&path)?.len() >= limit {
+                self.rotate_locked(&message.agent_id, &message.topic_id)?;
+            }
+        }
+        let segment = self.active_segment(&message.agent_id, &message.topic_id)?;
+
         let mut file = OpenOptions::new()
             .create(true)
             .append(true)
@@ -48,123 +620,538 @@ impl JsonlStorage {
         // Get current offset before writing
         let offset = file.seek(SeekFrom::End(0))?;
         
-        // Write JSON line
-        let json = serde_json::to_string(message)?;
+        let json = self.to_line(message)?;
         writeln!(file, "{}", json)?;
+        self.read_cache.invalidate(&message.agent_id, &message.topic_id);
+        if let Some(count) = state.messages.as_mut() {
+            *count += 1;
+        }
+        self.sync_after_append(&file, &path, 1)?;
         
-        Ok(offset)
+        Ok(LogPosition { segment, offset })
     }
 
-    /// Read all messages for a topic
+    /// Append several messages, for mixed agents and topics, opening each topic
+    /// log once
+    ///
+    /// Every message is validated and serialized before anything is written, so
+    /// one bad message rejects the whole batch. Returns each message's position,
+    /// in input order.
+    pub fn append_many(&self, messages: &[Message]) -> Result<Vec<LogPosition>> {
+        let mut lines = Vec::with_capacity(messages.len());
+        let mut groups: Vec<(PathBuf, Vec<usize>)> = Vec::new();
+        for (i, message) in messages.iter().enumerate() {
+            for attachment in message.attachments() {
+                attachment.validate(self.max_inline_attachment_bytes)?;
+            }
+            let path = self.log_path(&message.agent_id, &message.topic_id)?;
+            lines.push(self.to_line(message)?);
+
+            match groups.iter_mut().find(|(p, _)| *p == path) {
+                Some((_, indices)) => indices.push(i),
+                None => groups.push((path, vec![i])),
+            }
+        }
+
+        let mut positions = vec![LogPosition { segment: 0, offset: 0 }; messages.len()];
+        for (path, indices) in groups {
+            let first = &messages[indices[0]];
+            self.ensure_dir(&first.agent_id)?;
+            let lock = self.topic_lock(&first.agent_id, &first.topic_id)?;
+            let mut state = lock_state(&lock)?;
+
+            if let Some(limit) = self.rotate_after_bytes {
+                if path.exists() && std::fs::metadata(&path)?.len() >= limit {
+                    self.rotate_locked(&first.agent_id, &first.topic_id)?;
+                }
+            }
+            let segment = self.active_segment(&first.agent_id, &first.topic_id)?;
+
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            let mut offset = file.seek(SeekFrom::End(0))?;
+
+            let mut writer = BufWriter::new(&file);
+            for &i in &indices {
+                positions[i] = LogPosition { segment, offset };
+                writeln!(writer, "{}", lines[i])?;
+                offset += lines[i].len() as u64 + 1;
+            }
+            writer.flush()?;
+            drop(writer);
+            self.read_cache.invalidate(&first.agent_id, &first.topic_id);
+            if let Some(count) = state.messages.as_mut() {
+                *count += indices.len();
+            }
+            self.sync_after_append(&file, &path, indices.len())?;
+        }
+
+        Ok(positions)
+    }
+
+    /// Serialize a message as one log line, redacting secrets so they never reach disk
+    fn to_line(&self, message: &Message) -> Result<String> {
+        let json = match &self.redactor {
+            Some(redactor) => {
+                let mut message = message.clone();
+                message.content = redactor.redact(&message.content);
+                if let Some(metadata) = message.metadata.as_mut() {
+                    metadata.thinking = metadata.thinking.as_deref().map(|t| redactor.redact(t));
+                }
+                serde_json::to_string(&message)?
+            }
+            None => serde_json::to_string(message)?,
+        };
+        // serde_json escapes control characters, so one message is always one line
+        debug_assert!(!json.contains('\n') && !json.contains('\r'));
+        Ok(json)
+    }
+
+    /// Apply the configured durability mode after appending `appended` lines to a log
+    fn sync_after_append(&self, file: &File, path: &Path, appended: usize) -> Result<()> {
+        let every = match self.durability {
+            Durability::None => return Ok(()),
+            Durability::Fsync => 1,
+            Durability::FsyncEvery(n) => n.max(1),
+        };
+
+        let mut unsynced = self.unsynced.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let count = unsynced.entry(path.to_path_buf()).or_insert(0);
+        *count += appended as u32;
+        if *count >= every {
+            file.sync_all()?;
+            *count = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Forget a log's count of unsynced appends once it has been rotated,
+    /// rewritten or deleted, returning what the count was
+    fn take_unsynced(&self, path: &Path) -> Result<u32> {
+        let mut unsynced = self.unsynced.lock().map_err(|e| Error::storage(e.to_string()))?;
+        Ok(unsynced.remove(path).unwrap_or(0))
+    }
+
+    /// Read all messages for a topic, across rotated and active segments
+    ///
+    /// With `Config.message_cache_topics` set, a topic whose files haven't
+    /// changed since it was last read is served from memory without re-parsing.
+    /// Callers that only look at the messages should use `read_shared`, which
+    /// doesn't copy them out of the cache.
     pub fn read_all(&self, agent_id: &str, topic_id: &str) -> Result<Vec<Message>> {
-        let path = self.log_path(agent_id, topic_id);
-        
-        if !path.exists() {
-            return Ok(Vec::new());
+        let messages = self.read_shared(agent_id, topic_id)?;
+        Ok(Arc::try_unwrap(messages).unwrap_or_else(|messages| messages.as_ref().clone()))
+    }
+
+    /// Read all messages for a topic as `read_all` does, sharing the cached
+    /// copy instead of cloning it
+    pub fn read_shared(&self, agent_id: &str, topic_id: &str) -> Result<Arc<Vec<Message>>> {
+        if self.read_cache.capacity == 0 {
+            return Ok(Arc::new(self.parse_all(agent_id, topic_id)?));
         }
-        
-        let file = File::open(&path)?;
-        let reader = BufReader::new(file);
-        
+
+        let key = (agent_id.to_string(), topic_id.to_string());
+        let stamp = self.log_stamp(agent_id, topic_id)?;
+        if let Some(messages) = self.read_cache.get(&key, &stamp) {
+            return Ok(messages);
+        }
+
+        let messages = Arc::new(self.parse_all(agent_id, topic_id)?);
+        self.read_cache.insert(key, stamp, messages.clone());
+        Ok(messages)
+    }
+
+    /// Size and modification time of each file of a topic log
+    fn log_stamp(&self, agent_id: &str, topic_id: &str) -> Result<LogStamp> {
+        let mut paths: Vec<PathBuf> = self
+            .segments(agent_id, topic_id)?
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect();
+        let path = self.log_path(agent_id, topic_id)?;
+        if path.exists() {
+            paths.push(path);
+        }
+
+        paths
+            .into_iter()
+            .map(|path| {
+                let meta = std::fs::metadata(&path)?;
+                Ok((path, meta.len(), meta.modified().ok()))
+            })
+            .collect()
+    }
+
+    /// Read and parse every line of a topic log
+    fn parse_all(&self, agent_id: &str, topic_id: &str) -> Result<Vec<Message>> {
+        #[cfg(test)]
+        self.parses.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
         let mut messages = Vec::new();
-        for line in reader.lines() {
-            let line = line?;
-            if line.trim().is_empty() {
-                continue;
+        for reader in self.open_readers(agent_id, topic_id)? {
+            for line in read_lines(reader)? {
+                match serde_json::from_str::<Message>(&line) {
+                    Ok(mut message) => {
+                        self.recount(&mut message);
+                        messages.push(message)
+                    }
+                    Err(e) => tracing::warn!("Skipping malformed line in {}/{}: {}", agent_id, topic_id, e),
+                }
             }
-            let message: Message = serde_json::from_str(&line)?;
-            messages.push(message);
         }
         
         Ok(messages)
     }
 
+    /// Write token counts into the log for messages stored without one,
+    /// returning how many were fixed
+    ///
+    /// Rewrites the active log and every rotated segment in place, gzipped
+    /// segments re-compressed, so the count covers the whole topic. Lines that
+    /// don't parse are kept as they are. Holds the topic lock throughout, so
+    /// this store's appends wait.
+    pub fn rewrite_token_counts(&self, agent_id: &str, topic_id: &str) -> Result<usize> {
+        let lock = self.topic_lock(agent_id, topic_id)?;
+        let _state = lock_state(&lock)?;
+
+        let fallback;
+        let counter = match &self.recounter {
+            Some(counter) => counter,
+            None => {
+                fallback = TokenCounter::for_gpt()?;
+                &fallback
+            }
+        };
+
+        let mut paths: Vec<PathBuf> = self
+            .segments(agent_id, topic_id)?
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect();
+        let path = self.log_path(agent_id, topic_id)?;
+        if path.exists() {
+            paths.push(path);
+        }
+
+        let mut fixed = 0;
+        for path in paths {
+            let mut lines = read_lines(open_log(&path)?)?;
+            let mut changed = false;
+            for line in &mut lines {
+                let Ok(mut message) = serde_json::from_str::<Message>(line) else {
+                    continue;
+                };
+                if message.tokens > 0 {
+                    continue;
+                }
+                message.tokens = counter.count(&message.content);
+                if message.tokens > 0 {
+                    *line = serde_json::to_string(&message)?;
+                    changed = true;
+                    fixed += 1;
+                }
+            }
+            if !changed {
+                continue;
+            }
+
+            let mut tmp_path = path.clone().into_os_string();
+            tmp_path.push(".tmp");
+            let writer = BufWriter::new(File::create(&tmp_path)?);
+            let mut writer = if is_compressed(&path) {
+                let mut encoder = GzEncoder::new(writer, Compression::default());
+                for line in &lines {
+                    writeln!(encoder, "{}", line)?;
+                }
+                encoder.finish()?
+            } else {
+                let mut writer = writer;
+                for line in &lines {
+                    writeln!(writer, "{}", line)?;
+                }
+                writer
+            };
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            std::fs::rename(&tmp_path, &path)?;
+            self.take_unsynced(&path)?;
+        }
+
+        self.read_cache.invalidate(agent_id, topic_id);
+        Ok(fixed)
+    }
+
     /// Read the last N messages for a topic
     pub fn read_last_n(&self, agent_id: &str, topic_id: &str, n: usize) -> Result<Vec<Message>> {
-        let all = self.read_all(agent_id, topic_id)?;
+        let all = self.read_shared(agent_id, topic_id)?;
         let start = all.len().saturating_sub(n);
         Ok(all[start..].to_vec())
     }
 
-    /// Read a message at a specific offset
-    pub fn read_at_offset(&self, agent_id: &str, topic_id: &str, offset: u64) -> Result<Message> {
-        let path = self.log_path(agent_id, topic_id);
-        
+    /// Read up to `n` messages nearest `reference` in time, optionally only those
+    /// within `window` of it, in log order
+    pub fn read_around(
+        &self,
+        agent_id: &str,
+        topic_id: &str,
+        reference: DateTime<Utc>,
+        window: Option<chrono::Duration>,
+        n: usize,
+    ) -> Result<Vec<Message>> {
+        let distance = |m: &Message| (m.timestamp - reference).abs();
+
+        let messages = self.read_shared(agent_id, topic_id)?;
+        let mut nearest: Vec<(usize, &Message)> = messages
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| window.map_or(true, |w| distance(m) <= w))
+            .collect();
+        nearest.sort_by_key(|(_, m)| distance(m));
+        nearest.truncate(n);
+        nearest.sort_by_key(|(i, _)| *i);
+
+        Ok(nearest.into_iter().map(|(_, m)| m.clone()).collect())
+    }
+
+    /// Summarize a whole topic, or `None` if it has no messages
+    pub fn summarize_topic(&self, agent_id: &str, topic_id: &str) -> Result<Option<String>> {
+        let messages = self.read_shared(agent_id, topic_id)?;
+        if messages.is_empty() {
+            return Ok(None);
+        }
+
+        let header = format!(
+            "Summary of topic {}/{} ({} messages):",
+            agent_id,
+            topic_id,
+            messages.len()
+        );
+        Ok(Some(summarize(&header, &messages)))
+    }
+
+    /// Read message `id` at the position `append` returned for it
+    ///
+    /// Fails with `Error::NotFound` if some other message, or nothing, is at
+    /// that position now, e.g. because the topic was compacted since.
+    pub fn read_at_offset(&self, agent_id: &str, topic_id: &str, position: LogPosition, id: Uuid) -> Result<Message> {
+        let not_found = || {
+            Error::not_found(format!(
+                "Message {} at segment {} offset {} of {}/{}",
+                id, position.segment, position.offset, agent_id, topic_id
+            ))
+        };
+
+        let segment = self
+            .segments(agent_id, topic_id)?
+            .into_iter()
+            .find(|(index, _)| *index == position.segment);
+        let path = match segment {
+            Some((_, path)) => path,
+            None if position.segment == self.active_segment(agent_id, topic_id)? => {
+                self.log_path(agent_id, topic_id)?
+            }
+            None => return Err(not_found()),
+        };
+
         let mut file = File::open(&path)?;
-        file.seek(SeekFrom::Start(offset))?;
-        
-        let reader = BufReader::new(file);
-        let line = reader.lines().next()
-            .ok_or_else(|| Error::not_found("No message at offset"))??;
-        
-        let message: Message = serde_json::from_str(&line)?;
+        let mut reader: Box<dyn BufRead> = if is_compressed(&path) {
+            let mut reader = BufReader::new(GzDecoder::new(file));
+            std::io::copy(&mut (&mut reader).take(position.offset), &mut std::io::sink())?;
+            Box::new(reader)
+        } else {
+            file.seek(SeekFrom::Start(position.offset))?;
+            Box::new(BufReader::new(file))
+        };
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut message: Message = serde_json::from_str(line.trim_end()).map_err(|_| not_found())?;
+        if message.id != id {
+            return Err(not_found());
+        }
+        self.recount(&mut message);
         Ok(message)
     }
 
-    /// Count messages in a topic
+    /// Count messages in a topic: the lines that parse as one, so the count
+    /// agrees with `read_all`, which skips malformed lines
     pub fn count(&self, agent_id: &str, topic_id: &str) -> Result<usize> {
-        let path = self.log_path(agent_id, topic_id);
-        
-        if !path.exists() {
-            return Ok(0);
+        let mut count = 0;
+        for reader in self.open_readers(agent_id, topic_id)? {
+            for_each_line(reader, |line| {
+                if serde_json::from_str::<Message>(&line).is_ok() {
+                    count += 1;
+                }
+                Ok(true)
+            })?;
         }
         
-        let file = File::open(&path)?;
-        let reader = BufReader::new(file);
-        
-        Ok(reader.lines().filter(|l| l.as_ref().map(|s| !s.trim().is_empty()).unwrap_or(false)).count())
+        Ok(count)
     }
 
     /// Get total tokens for a topic
     pub fn total_tokens(&self, agent_id: &str, topic_id: &str) -> Result<u32> {
-        let messages = self.read_all(agent_id, topic_id)?;
+        let messages = self.read_shared(agent_id, topic_id)?;
         Ok(messages.iter().map(|m| m.tokens).sum())
     }
 
+    /// Find a message by id, scanning the topic's segments newest first.
+    /// Lines that don't mention the id are skipped without being parsed.
+    pub fn find_message(&self, agent_id: &str, topic_id: &str, id: Uuid) -> Result<Option<Message>> {
+        let needle = id.to_string();
+        let mut found = None;
+        for reader in self.open_readers(agent_id, topic_id)?.into_iter().rev() {
+            for_each_line(reader, |line| {
+                if !line.contains(&needle) {
+                    return Ok(true);
+                }
+                match serde_json::from_str::<Message>(&line) {
+                    Ok(mut message) if message.id == id => {
+                        self.recount(&mut message);
+                        found = Some(message);
+                        Ok(false)
+                    }
+                    _ => Ok(true),
+                }
+            })?;
+            if found.is_some() {
+                break;
+            }
+        }
+
+        Ok(found)
+    }
+
     /// Search messages by content (simple substring match)
     pub fn search(&self, agent_id: &str, topic_id: &str, query: &str) -> Result<Vec<Message>> {
-        let messages = self.read_all(agent_id, topic_id)?;
+        let messages = self.read_shared(agent_id, topic_id)?;
         let query_lower = query.to_lowercase();
         
         Ok(messages
-            .into_iter()
+            .iter()
             .filter(|m| m.content.to_lowercase().contains(&query_lower))
+            .cloned()
             .collect())
     }
 
     /// Export a topic to a single JSON file
     pub fn export_topic(&self, agent_id: &str, topic_id: &str, output_path: &PathBuf) -> Result<()> {
         let messages = self.read_all(agent_id, topic_id)?;
-        
+
+        let export = TopicExport {
+            version: EXPORT_VERSION,
+            exported_at: Utc::now(),
+            agent_id: agent_id.to_string(),
+            topic_id: topic_id.to_string(),
+            messages,
+        };
+
         let file = File::create(output_path)?;
         let writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(writer, &messages)?;
-        
+        serde_json::to_writer_pretty(writer, &export)?;
+
         Ok(())
     }
 
+    /// Stream a topic's log lines (all segments, oldest first) to `writer`
+    /// without loading the topic into memory, for logs too big for `export_topic`.
+    /// Returns the number of lines written.
+    ///
+    /// With `validate`, lines that don't parse as a message are skipped with a
+    /// warning, as `read_all` does; otherwise lines are copied as they are.
+    pub fn export_topic_jsonl(
+        &self,
+        agent_id: &str,
+        topic_id: &str,
+        validate: bool,
+        mut writer: impl Write,
+    ) -> Result<usize> {
+        let mut written = 0;
+        for reader in self.open_readers(agent_id, topic_id)? {
+            for_each_line(reader, |line| {
+                if validate {
+                    if let Err(e) = serde_json::from_str::<Message>(&line) {
+                        tracing::warn!("Skipping malformed line in {}/{}: {}", agent_id, topic_id, e);
+                        return Ok(true);
+                    }
+                }
+                writer.write_all(line.as_bytes())?;
+                writer.write_all(b"\n")?;
+                written += 1;
+                Ok(true)
+            })?;
+        }
+
+        writer.flush()?;
+        Ok(written)
+    }
+
     /// Import messages from a JSON file
-    pub fn import_topic(&self, agent_id: &str, topic_id: &str, input_path: &PathBuf) -> Result<usize> {
+    ///
+    /// Accepts both the versioned `TopicExport` envelope and the legacy bare
+    /// array of messages. Messages that fail validation are skipped and counted.
+    pub fn import_topic(&self, agent_id: &str, topic_id: &str, input_path: &PathBuf) -> Result<ImportReport> {
+        validate_id("topic_id", topic_id)?;
         self.ensure_dir(agent_id)?;
-        
+
         let file = File::open(input_path)?;
         let reader = BufReader::new(file);
-        let messages: Vec<Message> = serde_json::from_reader(reader)?;
-        
-        let count = messages.len();
-        for mut message in messages {
+        let raw: Value = serde_json::from_reader(reader)?;
+
+        let entries = match raw {
+            Value::Array(entries) => entries,
+            Value::Object(mut envelope) => {
+                let version = envelope
+                    .get("version")
+                    .and_then(Value::as_u64)
+                    .ok_or_else(|| Error::invalid_input("Export is missing a version"))?;
+                if version == 0 || version > EXPORT_VERSION as u64 {
+                    return Err(Error::invalid_input(format!(
+                        "Unsupported export version {} (supported: {})",
+                        version, EXPORT_VERSION
+                    )));
+                }
+                match envelope.remove("messages") {
+                    Some(Value::Array(entries)) => entries,
+                    _ => return Err(Error::invalid_input("Export has no messages array")),
+                }
+            }
+            _ => return Err(Error::invalid_input("Export must be an object or an array")),
+        };
+
+        let mut report = ImportReport::default();
+        for entry in entries {
+            let mut message: Message = match serde_json::from_value(entry) {
+                Ok(message) => message,
+                Err(e) => {
+                    tracing::warn!("Skipping invalid message during import: {}", e);
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+
+            if message.content.trim().is_empty() && message.metadata.is_none() {
+                tracing::warn!("Skipping empty message {} during import", message.id);
+                report.skipped += 1;
+                continue;
+            }
+
             // Override agent/topic with the target
             message.agent_id = agent_id.to_string();
             message.topic_id = topic_id.to_string();
             self.append(&message)?;
+            report.imported += 1;
         }
-        
-        Ok(count)
+
+        Ok(report)
     }
 
     /// List all topics for an agent
     pub fn list_topics(&self, agent_id: &str) -> Result<Vec<String>> {
-        let agent_dir = self.base_path.join(agent_id);
+        let agent_dir = self.agent_dir(agent_id)?;
         
         if !agent_dir.exists() {
             return Ok(Vec::new());
@@ -173,11 +1160,23 @@ impl JsonlStorage {
         let mut topics = Vec::new();
         for entry in std::fs::read_dir(&agent_dir)? {
             let entry = entry?;
-            let path = entry.path();
-            if path.extension().map(|e| e == "jsonl").unwrap_or(false) {
-                if let Some(stem) = path.file_stem() {
-                    topics.push(stem.to_string_lossy().to_string());
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            // A topic has an active log, rotated segments, or both
+            let topic = if entry.file_type()?.is_dir() {
+                match name.strip_suffix(SEGMENT_DIR_SUFFIX) {
+                    Some(topic) if validate_id("topic_id", topic).is_ok() => topic,
+                    _ => continue,
+                }
+            } else {
+                match name.strip_suffix(".jsonl") {
+                    Some(topic) => topic,
+                    None => continue,
                 }
+            };
+
+            if !topics.iter().any(|t| t == topic) {
+                topics.push(topic.to_string());
             }
         }
         
@@ -201,22 +1200,809 @@ impl JsonlStorage {
         Ok(agents)
     }
 
-    /// Delete a topic's conversation log
+    /// Delete a topic's conversation log, including rotated segments
     pub fn delete_topic(&self, agent_id: &str, topic_id: &str) -> Result<()> {
-        let path = self.log_path(agent_id, topic_id);
+        let lock = self.topic_lock(agent_id, topic_id)?;
+        let mut state = lock_state(&lock)?;
+        self.delete_locked(&mut state, agent_id, topic_id)
+    }
+
+    /// `delete_topic`, for callers holding the topic lock
+    fn delete_locked(&self, state: &mut TopicState, agent_id: &str, topic_id: &str) -> Result<()> {
+        self.remove_segments(agent_id, topic_id)?;
+        let path = self.log_path(agent_id, topic_id)?;
         if path.exists() {
             std::fs::remove_file(&path)?;
         }
+        self.take_unsynced(&path)?;
+        self.read_cache.invalidate(agent_id, topic_id);
+        state.messages = Some(0);
         Ok(())
     }
 
-    /// Get file size for a topic
+    /// Get on-disk size for a topic, including rotated segments
     pub fn file_size(&self, agent_id: &str, topic_id: &str) -> Result<u64> {
-        let path = self.log_path(agent_id, topic_id);
+        let mut size = 0;
+        for (_, path) in self.segments(agent_id, topic_id)? {
+            size += std::fs::metadata(&path)?.len();
+        }
+        let path = self.log_path(agent_id, topic_id)?;
         if path.exists() {
-            Ok(std::fs::metadata(&path)?.len())
-        } else {
-            Ok(0)
+            size += std::fs::metadata(&path)?.len();
+        }
+        Ok(size)
+    }
+}
+
+/// Whether a log file is a gzipped segment
+fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "gz")
+}
+
+/// Open a log file or segment for reading, decompressing a gzipped one
+fn open_log(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    if is_compressed(path) {
+        Ok(Box::new(BufReader::new(GzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Read the non-blank lines of a log, tolerating CRLF endings
+///
+/// Lines that aren't valid UTF-8 are skipped with a warning instead of failing
+/// the whole read.
+fn read_lines(reader: Box<dyn BufRead>) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    for_each_line(reader, |line| {
+        lines.push(line);
+        Ok(true)
+    })?;
+    Ok(lines)
+}
+
+/// Streaming form of `read_lines`: hand each line to `f` as it is read,
+/// stopping early once `f` returns `false`
+fn for_each_line(mut reader: Box<dyn BufRead>, mut f: impl FnMut(String) -> Result<bool>) -> Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        while matches!(buf.last(), Some(b'\n' | b'\r')) {
+            buf.pop();
+        }
+
+        match String::from_utf8(std::mem::take(&mut buf)) {
+            Ok(line) if line.trim().is_empty() => {}
+            Ok(line) => {
+                if !f(line)? {
+                    break;
+                }
+            }
+            Err(e) => tracing::warn!("Skipping line that is not valid UTF-8: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Create a file for a compaction archive, named by the current time. Never
+/// reuses an existing name, so two compactions within a millisecond can't
+/// overwrite each other's archive.
+fn create_archive(archive_dir: &Path, topic_id: &str) -> Result<(PathBuf, File)> {
+    let stamp = Utc::now().format("%Y%m%dT%H%M%S%3f");
+    let mut path = archive_dir.join(format!("{}.{}.jsonl", topic_id, stamp));
+    let mut attempt = 1;
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                path = archive_dir.join(format!("{}.{}-{}.jsonl", topic_id, stamp, attempt));
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Build an extractive summary of messages: the header followed by the first
+/// line of each of the last `SUMMARY_MAX_LINES` messages
+fn summarize(header: &str, messages: &[Message]) -> String {
+    let mut summary = format!("{}\n", header);
+
+    let start = messages.len().saturating_sub(SUMMARY_MAX_LINES);
+    for message in &messages[start..] {
+        let line: String = message
+            .content
+            .lines()
+            .find(|l| !l.trim().is_empty())
+            .unwrap_or("")
+            .chars()
+            .take(SUMMARY_LINE_CHARS)
+            .collect();
+        if !line.is_empty() {
+            summary.push_str(&format!("- {}: {}\n", message.role, line.trim()));
+        }
+    }
+
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Attachment;
+    use crate::redaction::REDACTED;
+
+    fn storage(dir: &Path, configure: impl FnOnce(&mut Config)) -> JsonlStorage {
+        let mut config = Config::with_data_dir(dir);
+        configure(&mut config);
+        JsonlStorage::new(&config).unwrap()
+    }
+
+    fn contents(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[test]
+    fn validate_id_accepts_ordinary_ids() {
+        for id in ["agent", "topic-123", "foo.1", "a_b c", "ñ"] {
+            assert!(validate_id("topic_id", id).is_ok(), "{:?}", id);
+        }
+        assert!(validate_id("topic_id", &"a".repeat(MAX_ID_LEN)).is_ok());
+    }
+
+    #[test]
+    fn validate_id_rejects_unsafe_ids() {
+        let too_long = "a".repeat(MAX_ID_LEN + 1);
+        for id in [
+            "",
+            "..",
+            "a..b",
+            "../etc",
+            "a/b",
+            "/abs",
+            "a\\b",
+            "c:",
+            ".hidden",
+            ".",
+            "a\nb",
+            "a\0b",
+            "tab\t",
+            too_long.as_str(),
+        ] {
+            let err = validate_id("topic_id", id).unwrap_err();
+            assert!(matches!(err, Error::InvalidInput(_)), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn unsafe_ids_never_touch_the_filesystem() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+
+        assert!(storage.append(&Message::new("..", "foo", Role::User, "x")).is_err());
+        assert!(storage.append(&Message::new("agent", "../../escape", Role::User, "x")).is_err());
+        assert!(storage.delete_topic("agent", "..").is_err());
+        assert!(!dir.path().join("escape.jsonl").exists());
+    }
+
+    #[test]
+    fn secrets_are_redacted_before_reaching_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+        let secret = "sk-abcdefghijklmnopqrstuvwxyz0123";
+
+        storage
+            .append(
+                &Message::new("agent", "foo", Role::User, format!("my key is {}", secret))
+                    .with_thinking(format!("they pasted {}", secret)),
+            )
+            .unwrap();
+        storage
+            .append_many(&[Message::new("agent", "foo", Role::Assistant, format!("echo {}", secret))])
+            .unwrap();
+
+        let raw = std::fs::read_to_string(storage.log_path("agent", "foo").unwrap()).unwrap();
+        assert!(!raw.contains(secret));
+        assert_eq!(raw.matches(REDACTED).count(), 3);
+
+        let messages = storage.read_all("agent", "foo").unwrap();
+        assert_eq!(messages[0].content, format!("my key is {}", REDACTED));
+        assert_eq!(messages[0].thinking(), Some(format!("they pasted {}", REDACTED).as_str()));
+    }
+
+    #[test]
+    fn redaction_can_be_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.redaction_enabled = false);
+        let secret = "sk-abcdefghijklmnopqrstuvwxyz0123";
+
+        storage.append(&Message::new("agent", "foo", Role::User, secret)).unwrap();
+
+        let raw = std::fs::read_to_string(storage.log_path("agent", "foo").unwrap()).unwrap();
+        assert!(raw.contains(secret));
+    }
+
+    #[test]
+    fn rotated_segments_are_read_back_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.compress_rotated = true);
+
+        storage.append(&Message::new("agent", "foo", Role::User, "one")).unwrap();
+        let first = storage.rotate("agent", "foo").unwrap().unwrap();
+        storage.append(&Message::new("agent", "foo", Role::User, "two")).unwrap();
+        let second = storage.rotate("agent", "foo").unwrap().unwrap();
+        storage.append(&Message::new("agent", "foo", Role::User, "three")).unwrap();
+
+        assert!(first.ends_with("foo.segments/1.jsonl.gz"));
+        assert!(second.ends_with("foo.segments/2.jsonl.gz"));
+        assert_eq!(contents(&storage.read_all("agent", "foo").unwrap()), ["one", "two", "three"]);
+        assert_eq!(storage.count("agent", "foo").unwrap(), 3);
+        assert_eq!(storage.list_topics("agent").unwrap(), ["foo"]);
+    }
+
+    #[test]
+    fn appends_rotate_past_the_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.rotate_after_bytes = Some(1));
+
+        for content in ["one", "two", "three"] {
+            storage.append(&Message::new("agent", "foo", Role::User, content)).unwrap();
+        }
+
+        assert_eq!(storage.segments("agent", "foo").unwrap().len(), 2);
+        assert_eq!(contents(&storage.read_all("agent", "foo").unwrap()), ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn dotted_topic_is_not_a_segment_of_its_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+
+        storage.append(&Message::new("agent", "foo", Role::User, "foo")).unwrap();
+        storage.rotate("agent", "foo").unwrap();
+        storage.append(&Message::new("agent", "foo.1", Role::User, "foo.1")).unwrap();
+
+        assert_eq!(contents(&storage.read_all("agent", "foo").unwrap()), ["foo"]);
+        let mut topics = storage.list_topics("agent").unwrap();
+        topics.sort();
+        assert_eq!(topics, ["foo", "foo.1"]);
+
+        storage.delete_topic("agent", "foo").unwrap();
+        assert_eq!(contents(&storage.read_all("agent", "foo.1").unwrap()), ["foo.1"]);
+        assert_eq!(storage.list_topics("agent").unwrap(), ["foo.1"]);
+    }
+
+    #[test]
+    fn compaction_archives_older_messages_and_folds_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+
+        for content in ["one", "two", "three"] {
+            storage.append(&Message::new("agent", "foo", Role::User, content)).unwrap();
+        }
+        storage.rotate("agent", "foo").unwrap();
+        storage
+            .append(&Message::new("agent", "foo", Role::User, "pinned").with_retain(true))
+            .unwrap();
+        for content in ["four", "five"] {
+            storage.append(&Message::new("agent", "foo", Role::User, content)).unwrap();
+        }
+
+        let report = storage.compact_topic("agent", "foo", 2).unwrap().unwrap();
+        assert_eq!(report.archived, 3);
+
+        let messages = storage.read_all("agent", "foo").unwrap();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].role, Role::System);
+        assert_eq!(contents(&messages[1..]), ["pinned", "four", "five"]);
+        assert!(storage.segments("agent", "foo").unwrap().is_empty());
+
+        let archived = std::fs::read_to_string(&report.archive_path).unwrap();
+        let archived: Vec<Message> = archived.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(contents(&archived), ["one", "two", "three"]);
+
+        // Nothing left to archive beyond the kept messages
+        assert!(storage.compact_topic("agent", "foo", 4).unwrap().is_none());
+    }
+
+    #[test]
+    fn retained_messages_survive_every_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+        let pinned = Message::new("agent", "foo", Role::User, "pinned").with_retain(true);
+
+        storage.append(&Message::new("agent", "foo", Role::User, "one")).unwrap();
+        storage.append(&pinned).unwrap();
+        for round in 0..3 {
+            for i in 0..3 {
+                let content = format!("round {} message {}", round, i);
+                storage.append(&Message::new("agent", "foo", Role::User, content)).unwrap();
+            }
+            let report = storage.compact_topic("agent", "foo", 1).unwrap().unwrap();
+
+            // The retained message is never archived, and stays after the summary
+            let archived = std::fs::read_to_string(&report.archive_path).unwrap();
+            assert!(!archived.contains(&pinned.id.to_string()), "round {}", round);
+
+            let messages = storage.read_all("agent", "foo").unwrap();
+            assert_eq!(messages[0].role, Role::System);
+            assert_eq!(messages[1].id, pinned.id);
+            assert!(messages[1].retained());
+            assert_eq!(messages.len(), 3);
+        }
+    }
+
+    #[test]
+    fn appends_are_not_lost_to_concurrent_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.durability = Durability::None);
+
+        std::thread::scope(|s| {
+            for writer in 0..4 {
+                let storage = &storage;
+                s.spawn(move || {
+                    for i in 0..50 {
+                        let content = format!("{}-{}", writer, i);
+                        storage.append(&Message::new("agent", "foo", Role::User, content)).unwrap();
+                    }
+                });
+            }
+            let storage = &storage;
+            s.spawn(move || {
+                for _ in 0..20 {
+                    storage.compact_topic("agent", "foo", 5).unwrap();
+                }
+            });
+        });
+        storage.compact_topic("agent", "foo", 5).unwrap();
+
+        // Every appended message is either still live or in exactly one archive
+        let user_contents = |messages: Vec<Message>| {
+            messages
+                .into_iter()
+                .filter(|m| m.role == Role::User)
+                .map(|m| m.content)
+                .collect::<Vec<_>>()
+        };
+        let mut seen = user_contents(storage.read_all("agent", "foo").unwrap());
+        for entry in std::fs::read_dir(dir.path().join("conversations/agent/.archive")).unwrap() {
+            let archived = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            seen.extend(user_contents(
+                archived.lines().map(|l| serde_json::from_str(l).unwrap()).collect(),
+            ));
+        }
+        seen.sort();
+
+        let mut expected: Vec<String> = (0..4)
+            .flat_map(|writer| (0..50).map(move |i| format!("{}-{}", writer, i)))
+            .collect();
+        expected.sort();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn positions_increase_across_rotation_and_check_the_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.compress_rotated = true);
+        let messages: Vec<Message> = ["one", "two", "three", "four", "five"]
+            .into_iter()
+            .map(|content| Message::new("agent", "foo", Role::User, content))
+            .collect();
+
+        let mut positions = vec![
+            storage.append(&messages[0]).unwrap(),
+            storage.append(&messages[1]).unwrap(),
+        ];
+        storage.rotate("agent", "foo").unwrap().unwrap();
+        positions.push(storage.append(&messages[2]).unwrap());
+        positions.extend(storage.append_many(&messages[3..]).unwrap());
+
+        assert_eq!(positions[0], LogPosition { segment: 1, offset: 0 });
+        assert_eq!(positions[2], LogPosition { segment: 2, offset: 0 });
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", positions);
+
+        // Both the gzipped segment and the active log can be read by position
+        for (message, position) in messages.iter().zip(&positions) {
+            let read = storage.read_at_offset("agent", "foo", *position, message.id).unwrap();
+            assert_eq!(read.content, message.content);
+        }
+
+        let err = storage.read_at_offset("agent", "foo", positions[1], messages[0].id).unwrap_err();
+        assert!(matches!(err, Error::NotFound(_)));
+        let beyond = LogPosition { segment: 3, offset: 0 };
+        assert!(matches!(
+            storage.read_at_offset("agent", "foo", beyond, messages[0].id),
+            Err(Error::NotFound(_))
+        ));
+
+        // Compaction rewrites the log, so old positions no longer resolve
+        storage.compact_topic("agent", "foo", 1).unwrap().unwrap();
+        for (message, position) in messages.iter().zip(&positions) {
+            let err = storage.read_at_offset("agent", "foo", *position, message.id).unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)), "{}", message.content);
+        }
+    }
+
+    #[test]
+    fn crlf_and_damaged_lines_are_tolerated() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+        let one = Message::new("agent", "foo", Role::User, "one");
+        let two = Message::new("agent", "foo", Role::Assistant, "two");
+
+        // CRLF endings, a blank line, a malformed line, a line that isn't
+        // UTF-8 and a final line without a newline
+        let mut raw = Vec::new();
+        raw.extend(serde_json::to_string(&one).unwrap().as_bytes());
+        raw.extend(b"\r\n\r\n{\"not\": \"a message\"}\r\n");
+        raw.extend(b"{\"content\": \"\xff\xfe\"}\r\n");
+        raw.extend(serde_json::to_string(&two).unwrap().as_bytes());
+        std::fs::create_dir_all(dir.path().join("conversations/agent")).unwrap();
+        std::fs::write(storage.log_path("agent", "foo").unwrap(), raw).unwrap();
+
+        assert_eq!(contents(&storage.read_all("agent", "foo").unwrap()), ["one", "two"]);
+        assert_eq!(storage.count("agent", "foo").unwrap(), 2);
+        assert_eq!(storage.message_count("agent", "foo").unwrap(), 2);
+        assert_eq!(storage.find_message("agent", "foo", two.id).unwrap().unwrap().content, "two");
+
+        let mut exported = Vec::new();
+        assert_eq!(storage.export_topic_jsonl("agent", "foo", true, &mut exported).unwrap(), 2);
+        assert!(!exported.contains(&b'\r'));
+    }
+
+    #[test]
+    fn unsynced_counts_are_dropped_with_their_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.durability = Durability::FsyncEvery(100));
+        let pending = |storage: &JsonlStorage| storage.unsynced.lock().unwrap().len();
+
+        for topic in ["foo", "bar", "baz"] {
+            storage.append(&Message::new("agent", topic, Role::User, "one")).unwrap();
+            storage.append(&Message::new("agent", topic, Role::User, "two")).unwrap();
+        }
+        assert_eq!(pending(&storage), 3);
+
+        storage.rotate("agent", "foo").unwrap().unwrap();
+        assert_eq!(pending(&storage), 2);
+
+        storage.compact_topic("agent", "bar", 1).unwrap().unwrap();
+        assert_eq!(pending(&storage), 1);
+
+        storage.delete_topic("agent", "baz").unwrap();
+        assert_eq!(pending(&storage), 0);
+        assert_eq!(contents(&storage.read_all("agent", "foo").unwrap()), ["one", "two"]);
+    }
+
+    #[test]
+    fn cached_reads_share_one_parse() {
+        use std::sync::atomic::Ordering;
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+        for content in ["one", "two", "three"] {
+            storage.append(&Message::new("agent", "foo", Role::User, content)).unwrap();
+        }
+
+        let first = storage.read_shared("agent", "foo").unwrap();
+        let second = storage.read_shared("agent", "foo").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(contents(&storage.read_last_n("agent", "foo", 2).unwrap()), ["two", "three"]);
+        assert_eq!(storage.total_tokens("agent", "foo").unwrap(), 0);
+        assert_eq!(contents(&storage.search("agent", "foo", "TW").unwrap()), ["two"]);
+        assert_eq!(storage.parses.load(Ordering::Relaxed), 1);
+
+        // An append drops the cached copy; readers holding it keep theirs
+        storage.append(&Message::new("agent", "foo", Role::User, "four")).unwrap();
+        assert_eq!(contents(&storage.read_last_n("agent", "foo", 1).unwrap()), ["four"]);
+        assert_eq!(storage.parses.load(Ordering::Relaxed), 2);
+        assert_eq!(first.len(), 3);
+    }
+
+    #[test]
+    fn disabled_cache_parses_every_read() {
+        use std::sync::atomic::Ordering;
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.message_cache_topics = 0);
+        storage.append(&Message::new("agent", "foo", Role::User, "one")).unwrap();
+
+        storage.read_last_n("agent", "foo", 1).unwrap();
+        storage.read_last_n("agent", "foo", 1).unwrap();
+        assert_eq!(storage.parses.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn zero_token_counts_are_recounted_on_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.recount_zero_tokens = true);
+
+        storage.append(&Message::new("agent", "foo", Role::User, "hello there, world")).unwrap();
+        storage
+            .append(&Message::new("agent", "foo", Role::User, "counted").with_tokens(7))
+            .unwrap();
+
+        let messages = storage.read_all("agent", "foo").unwrap();
+        assert!(messages[0].tokens > 0);
+        assert_eq!(messages[1].tokens, 7);
+        assert_eq!(storage.total_tokens("agent", "foo").unwrap(), messages[0].tokens + 7);
+
+        // The log itself still holds the zero
+        let raw = std::fs::read_to_string(storage.log_path("agent", "foo").unwrap()).unwrap();
+        assert!(raw.contains("\"tokens\":0"));
+    }
+
+    #[test]
+    fn token_rewrite_covers_compressed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.compress_rotated = true);
+
+        storage.append(&Message::new("agent", "foo", Role::User, "hello there, world")).unwrap();
+        storage.rotate("agent", "foo").unwrap().unwrap();
+        storage.append(&Message::new("agent", "foo", Role::User, "second message")).unwrap();
+        storage
+            .append(&Message::new("agent", "foo", Role::User, "counted").with_tokens(7))
+            .unwrap();
+
+        assert_eq!(storage.rewrite_token_counts("agent", "foo").unwrap(), 2);
+
+        let segments = storage.segments("agent", "foo").unwrap();
+        assert_eq!(segments.len(), 1);
+        assert!(is_compressed(&segments[0].1));
+        let messages = storage.read_all("agent", "foo").unwrap();
+        assert_eq!(contents(&messages), ["hello there, world", "second message", "counted"]);
+        assert!(messages.iter().all(|m| m.tokens > 0));
+        assert_eq!(messages[2].tokens, 7);
+
+        assert_eq!(storage.rewrite_token_counts("agent", "foo").unwrap(), 0);
+    }
+
+    #[test]
+    fn message_count_follows_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+
+        for content in ["one", "two", "three"] {
+            storage.append(&Message::new("agent", "foo", Role::User, content)).unwrap();
+        }
+        assert_eq!(storage.message_count("agent", "foo").unwrap(), 3);
+
+        storage
+            .append_many(&[
+                Message::new("agent", "foo", Role::User, "four"),
+                Message::new("agent", "foo", Role::User, "five"),
+            ])
+            .unwrap();
+        assert_eq!(storage.message_count("agent", "foo").unwrap(), 5);
+
+        storage.compact_topic("agent", "foo", 2).unwrap().unwrap();
+        assert_eq!(storage.message_count("agent", "foo").unwrap(), 3);
+        assert!(storage.compact_if_over("agent", "foo", 3, 1).unwrap().is_none());
+
+        storage.append(&Message::new("agent", "bar", Role::User, "six")).unwrap();
+        storage.merge_topics("agent", "bar", "foo").unwrap();
+        assert_eq!(storage.message_count("agent", "foo").unwrap(), 4);
+        assert_eq!(storage.message_count("agent", "bar").unwrap(), 0);
+        assert!(storage.merge_topics("agent", "foo", "foo").is_err());
+
+        storage.delete_topic("agent", "foo").unwrap();
+        assert_eq!(storage.message_count("agent", "foo").unwrap(), 0);
+    }
+
+    #[test]
+    fn import_accepts_the_envelope_and_legacy_arrays() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+        let one = Message::new("source", "old", Role::User, "one");
+        let two = Message::new("source", "old", Role::Assistant, "two");
+        storage.append_many(&[one.clone(), two.clone()]).unwrap();
+
+        let envelope = dir.path().join("export.json");
+        storage.export_topic("source", "old", &envelope).unwrap();
+        let report = storage.import_topic("agent", "new", &envelope).unwrap();
+        assert_eq!((report.imported, report.skipped), (2, 0));
+        let imported = storage.read_all("agent", "new").unwrap();
+        assert_eq!(contents(&imported), ["one", "two"]);
+        assert!(imported.iter().all(|m| m.agent_id == "agent" && m.topic_id == "new"));
+
+        // A bare array, with an entry that isn't a message and an empty one
+        let legacy = dir.path().join("legacy.json");
+        let blank = Message::new("source", "old", Role::User, "   ");
+        let array = serde_json::json!([one, { "role": "user" }, blank, two]);
+        std::fs::write(&legacy, array.to_string()).unwrap();
+        let report = storage.import_topic("agent", "legacy", &legacy).unwrap();
+        assert_eq!((report.imported, report.skipped), (2, 2));
+        assert_eq!(contents(&storage.read_all("agent", "legacy").unwrap()), ["one", "two"]);
+    }
+
+    #[test]
+    fn import_rejects_unknown_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+        let path = dir.path().join("export.json");
+
+        for body in [
+            r#"{"version": 99, "messages": []}"#,
+            r#"{"version": 0, "messages": []}"#,
+            r#"{"messages": []}"#,
+            r#"{"version": 1}"#,
+            r#""just a string""#,
+        ] {
+            std::fs::write(&path, body).unwrap();
+            let err = storage.import_topic("agent", "foo", &path).unwrap_err();
+            assert!(matches!(err, Error::InvalidInput(_)), "{}", body);
+        }
+        assert!(storage.read_all("agent", "foo").unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_around_keeps_the_nearest_in_log_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+        let hour = chrono::Duration::hours(1);
+        let base = Utc::now() - hour * 10;
+        for h in 0..6 {
+            let message = Message::new("agent", "foo", Role::User, format!("h{}", h))
+                .with_timestamp(base + hour * h);
+            storage.append(&message).unwrap();
+        }
+
+        let around = |reference, window, n| {
+            let messages = storage.read_around("agent", "foo", reference, window, n).unwrap();
+            messages.into_iter().map(|m| m.content).collect::<Vec<_>>()
+        };
+        assert_eq!(around(base + hour * 3, None, 3), ["h2", "h3", "h4"]);
+        assert_eq!(around(base + hour * 3, Some(chrono::Duration::minutes(90)), 10), ["h2", "h3", "h4"]);
+        assert_eq!(around(base - hour, None, 2), ["h0", "h1"]);
+        assert!(around(base + hour * 100, Some(hour), 5).is_empty());
+        assert!(around(base, None, 0).is_empty());
+    }
+
+    #[test]
+    fn find_message_searches_every_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.compress_rotated = true);
+        let one = Message::new("agent", "foo", Role::User, "one");
+        let two = Message::new("agent", "foo", Role::User, "two");
+
+        storage.append(&one).unwrap();
+        storage.rotate("agent", "foo").unwrap().unwrap();
+        storage.append(&two).unwrap();
+        // Mentions the first id in its content without being that message
+        storage
+            .append(&Message::new("agent", "foo", Role::User, format!("see {}", one.id)))
+            .unwrap();
+
+        let found = |id| storage.find_message("agent", "foo", id).unwrap().map(|m| m.content);
+        assert_eq!(found(one.id).as_deref(), Some("one"));
+        assert_eq!(found(two.id).as_deref(), Some("two"));
+        assert_eq!(found(Uuid::new_v4()), None);
+        assert_eq!(storage.find_message("agent", "missing", one.id).unwrap().map(|m| m.id), None);
+    }
+
+    #[test]
+    fn export_jsonl_streams_every_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.compress_rotated = true);
+
+        storage.append(&Message::new("agent", "foo", Role::User, "one")).unwrap();
+        storage.rotate("agent", "foo").unwrap().unwrap();
+        storage.append(&Message::new("agent", "foo", Role::User, "two")).unwrap();
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(storage.log_path("agent", "foo").unwrap())
+            .unwrap();
+        writeln!(log, "not a message").unwrap();
+
+        // Without validation lines are copied as they are
+        let mut raw = Vec::new();
+        assert_eq!(storage.export_topic_jsonl("agent", "foo", false, &mut raw).unwrap(), 3);
+        assert!(String::from_utf8(raw).unwrap().ends_with("not a message\n"));
+
+        let mut validated = Vec::new();
+        assert_eq!(storage.export_topic_jsonl("agent", "foo", true, &mut validated).unwrap(), 2);
+        let exported: Vec<Message> = String::from_utf8(validated)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(contents(&exported), ["one", "two"]);
+
+        let mut empty = Vec::new();
+        assert_eq!(storage.export_topic_jsonl("agent", "missing", true, &mut empty).unwrap(), 0);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn append_many_keeps_order_across_topics() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+        let batch = [
+            Message::new("agent", "foo", Role::User, "a"),
+            Message::new("agent", "bar", Role::User, "b"),
+            Message::new("agent", "foo", Role::Assistant, "c"),
+            Message::new("other", "foo", Role::User, "d"),
+        ];
+
+        let positions = storage.append_many(&batch).unwrap();
+        assert_eq!(positions.len(), batch.len());
+        assert!(positions[0] < positions[2]);
+        assert_eq!(contents(&storage.read_all("agent", "foo").unwrap()), ["a", "c"]);
+        assert_eq!(contents(&storage.read_all("agent", "bar").unwrap()), ["b"]);
+        assert_eq!(contents(&storage.read_all("other", "foo").unwrap()), ["d"]);
+        assert_eq!(storage.message_count("agent", "foo").unwrap(), 2);
+        for (message, position) in batch.iter().zip(&positions) {
+            let read = storage
+                .read_at_offset(&message.agent_id, &message.topic_id, *position, message.id)
+                .unwrap();
+            assert_eq!(read.content, message.content);
+        }
+    }
+
+    #[test]
+    fn append_many_rejects_the_whole_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {});
+        let sourceless = Attachment {
+            kind: "file".to_string(),
+            name: "empty".to_string(),
+            mime_type: None,
+            data: None,
+            path: None,
+        };
+
+        for bad in [
+            Message::new("../x", "foo", Role::User, "escape"),
+            Message::new("agent", "foo", Role::User, "broken").with_attachments(vec![sourceless]),
+        ] {
+            let batch = [Message::new("agent", "foo", Role::User, "fine"), bad];
+            assert!(storage.append_many(&batch).is_err());
+        }
+        assert_eq!(storage.message_count("agent", "foo").unwrap(), 0);
+        assert!(storage.read_all("agent", "foo").unwrap().is_empty());
+    }
+
+    #[test]
+    fn attachments_round_trip_within_the_size_guard() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.max_inline_attachment_bytes = 16);
+        let attachment = |name: &str, data: Option<&str>, path: Option<&str>| Attachment {
+            kind: "image".to_string(),
+            name: name.to_string(),
+            mime_type: Some("image/png".to_string()),
+            data: data.map(str::to_string),
+            path: path.map(str::to_string),
+        };
+
+        let message = Message::new("agent", "foo", Role::User, "see attached").with_attachments(vec![
+            attachment("diagram.png", None, Some("uploads/diagram.png")),
+            attachment("dot.png", Some("iVBORw0KGgo="), None),
+        ]);
+        storage.append(&message).unwrap();
+
+        let read = storage.read_all("agent", "foo").unwrap();
+        let attachments = read[0].attachments();
+        assert_eq!(attachments.len(), 2);
+        assert_eq!(attachments[0].path.as_deref(), Some("uploads/diagram.png"));
+        assert_eq!(attachments[0].data, None);
+        assert_eq!(attachments[0].mime_type.as_deref(), Some("image/png"));
+        assert_eq!(attachments[1].data.as_deref(), Some("iVBORw0KGgo="));
+        let line = std::fs::read_to_string(storage.log_path("agent", "foo").unwrap()).unwrap();
+        assert!(line.contains("\"type\":\"image\""));
+
+        // Oversized inline data, or both or neither source, is refused before writing
+        for bad in [
+            attachment("big.png", Some(&"A".repeat(17)), None),
+            attachment("both.png", Some("AA=="), Some("uploads/both.png")),
+            attachment("none.png", None, None),
+        ] {
+            let message = Message::new("agent", "foo", Role::User, "bad").with_attachments(vec![bad]);
+            assert!(matches!(storage.append(&message), Err(Error::InvalidInput(_))));
         }
+        assert_eq!(storage.read_all("agent", "foo").unwrap().len(), 1);
     }
 }
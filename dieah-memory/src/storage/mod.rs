@@ -4,6 +4,11 @@ mod jsonl;
 mod sqlite;
 pub mod vector;
 
-pub use jsonl::JsonlStorage;
-pub use sqlite::SqliteStorage;
-pub use vector::{SearchResult, VectorStorage};
+pub use jsonl::{
+    validate_id, CompactionReport, Durability, ImportReport, JsonlStorage, LogPosition,
+    TopicExport, EXPORT_VERSION,
+};
+pub use sqlite::{AgentRecord, MaintenanceReport, MemoryFacets, SqliteStorage, TopicRecord};
+pub use vector::{
+    score_from_distance, DistanceMetric, Neighbor, SearchFilter, SearchResult, VectorStorage,
+};
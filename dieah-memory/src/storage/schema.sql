@@ -38,6 +38,11 @@ CREATE TABLE IF NOT EXISTS memories (
     last_used_at TEXT,
     retrieval_count INTEGER NOT NULL DEFAULT 0,
     active INTEGER NOT NULL DEFAULT 1,
+    pinned INTEGER NOT NULL DEFAULT 0,
+    visibility TEXT NOT NULL DEFAULT 'shared' CHECK (visibility IN ('shared', 'private')),
+    owner_id TEXT,
+    content_hash TEXT,
+    weight REAL NOT NULL DEFAULT 1.0,
     FOREIGN KEY (agent_id) REFERENCES agents(id) ON DELETE CASCADE,
     FOREIGN KEY (topic_id) REFERENCES topics(id) ON DELETE CASCADE
 );
@@ -1,48 +1,102 @@
 //! SQLite storage for metadata and memory records
 
 use rusqlite::{params, Connection, OptionalExtension};
-use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
 use uuid::Uuid;
 
 use crate::config::Config;
 use crate::error::{Error, Result};
 use crate::memory::{Memory, MemoryScope, MemoryType};
 
+/// Columns selected for every memory read, in `MemoryRow::from_row` order
+const MEMORY_COLUMNS: &str = "id, scope, memory_type, agent_id, topic_id, content, context, \
+    tags, created_at, last_used_at, retrieval_count, active, pinned, visibility, owner_id, \
+    content_hash, weight";
+
 /// SQLite storage backend
 pub struct SqliteStorage {
-    conn: Arc<Mutex<Connection>>,
+    /// None when the database could not be opened (see `unavailable`)
+    conn: Option<Arc<Mutex<Connection>>>,
+    path: PathBuf,
+    /// Why the database could not be opened
+    failure: Option<String>,
 }
 
 impl SqliteStorage {
     /// Create a new SQLite storage
     pub fn new(config: &Config) -> Result<Self> {
-        let conn = Connection::open(config.sqlite_path())?;
+        let path = config.sqlite_path();
+        let conn = Connection::open(&path)?;
         
         // Initialize schema
         conn.execute_batch(include_str!("schema.sql"))?;
+
+        // Migrate databases created before newer columns existed
+        ensure_column(&conn, "memories", "pinned", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "memories", "visibility", "TEXT NOT NULL DEFAULT 'shared'")?;
+        ensure_column(&conn, "memories", "owner_id", "TEXT")?;
+        ensure_column(&conn, "memories", "content_hash", "TEXT")?;
+        ensure_column(&conn, "memories", "weight", "REAL NOT NULL DEFAULT 1.0")?;
         
         Ok(Self {
-            conn: Arc::new(Mutex::new(conn)),
+            conn: Some(Arc::new(Mutex::new(conn))),
+            path,
+            failure: None,
         })
     }
 
+    /// A stand-in for a database that failed to open: every operation returns
+    /// `Error::Unavailable` carrying `reason`
+    pub fn unavailable(config: &Config, reason: impl Into<String>) -> Self {
+        Self {
+            conn: None,
+            path: config.sqlite_path(),
+            failure: Some(reason.into()),
+        }
+    }
+
+    /// Why the database is unavailable, or None if it opened normally
+    pub fn failure(&self) -> Option<&str> {
+        self.failure.as_deref()
+    }
+
+    /// Lock the connection, failing if the database never opened
+    fn conn(&self) -> Result<MutexGuard<'_, Connection>> {
+        let conn = self.conn.as_ref().ok_or_else(|| {
+            Error::unavailable(format!(
+                "Metadata database unavailable: {}",
+                self.failure.as_deref().unwrap_or("not opened")
+            ))
+        })?;
+        conn.lock().map_err(|e| Error::storage(e.to_string()))
+    }
+
     /// Save a memory record
     pub fn save_memory(&self, memory: &Memory) -> Result<()> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         
         conn.execute(
             r#"
             INSERT INTO memories (
                 id, scope, memory_type, agent_id, topic_id, content, context,
-                tags, created_at, last_used_at, retrieval_count, active
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+                tags, created_at, last_used_at, retrieval_count, active, pinned,
+                visibility, owner_id, content_hash, weight
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
             ON CONFLICT(id) DO UPDATE SET
                 content = excluded.content,
                 context = excluded.context,
                 tags = excluded.tags,
                 last_used_at = excluded.last_used_at,
                 retrieval_count = excluded.retrieval_count,
-                active = excluded.active
+                active = excluded.active,
+                pinned = excluded.pinned,
+                visibility = excluded.visibility,
+                owner_id = excluded.owner_id,
+                content_hash = excluded.content_hash,
+                weight = excluded.weight
             "#,
             params![
                 memory.id.to_string(),
@@ -57,6 +111,11 @@ impl SqliteStorage {
                 memory.last_used_at.map(|dt| dt.to_rfc3339()),
                 memory.retrieval_count,
                 memory.active,
+                memory.pinned,
+                memory.visibility.to_string(),
+                memory.owner_id,
+                memory.content_hash,
+                memory.weight as f64,
             ],
         )?;
         
@@ -65,31 +124,12 @@ impl SqliteStorage {
 
     /// Get a memory by ID
     pub fn get_memory(&self, id: Uuid) -> Result<Option<Memory>> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         
         let result = conn.query_row(
-            r#"
-            SELECT id, scope, memory_type, agent_id, topic_id, content, context,
-                   tags, created_at, last_used_at, retrieval_count, active
-            FROM memories WHERE id = ?1
-            "#,
+            &format!("SELECT {} FROM memories WHERE id = ?1", MEMORY_COLUMNS),
             params![id.to_string()],
-            |row| {
-                Ok(MemoryRow {
-                    id: row.get(0)?,
-                    scope: row.get(1)?,
-                    memory_type: row.get(2)?,
-                    agent_id: row.get(3)?,
-                    topic_id: row.get(4)?,
-                    content: row.get(5)?,
-                    context: row.get(6)?,
-                    tags: row.get(7)?,
-                    created_at: row.get(8)?,
-                    last_used_at: row.get(9)?,
-                    retrieval_count: row.get(10)?,
-                    active: row.get(11)?,
-                })
-            },
+            MemoryRow::from_row,
         ).optional()?;
         
         result.map(|row| row.into_memory()).transpose()
@@ -101,60 +141,21 @@ impl SqliteStorage {
         scope: Option<MemoryScope>,
         agent_id: Option<&str>,
         topic_id: Option<&str>,
+        contains: Option<&str>,
         active_only: bool,
     ) -> Result<Vec<Memory>> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         
-        let mut sql = String::from(
-            r#"
-            SELECT id, scope, memory_type, agent_id, topic_id, content, context,
-                   tags, created_at, last_used_at, retrieval_count, active
-            FROM memories WHERE 1=1
-            "#
+        let (filter, params_vec) = memory_filter(scope, agent_id, topic_id, contains, active_only);
+        let sql = format!(
+            "SELECT {} FROM memories WHERE 1=1{} ORDER BY created_at DESC",
+            MEMORY_COLUMNS, filter
         );
         
-        let mut params_vec: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
-        
-        if let Some(s) = scope {
-            sql.push_str(" AND scope = ?");
-            params_vec.push(Box::new(s.to_string()));
-        }
-        
-        if let Some(aid) = agent_id {
-            sql.push_str(" AND agent_id = ?");
-            params_vec.push(Box::new(aid.to_string()));
-        }
-        
-        if let Some(tid) = topic_id {
-            sql.push_str(" AND topic_id = ?");
-            params_vec.push(Box::new(tid.to_string()));
-        }
-        
-        if active_only {
-            sql.push_str(" AND active = 1");
-        }
-        
-        sql.push_str(" ORDER BY created_at DESC");
-        
         let mut stmt = conn.prepare(&sql)?;
         let params_refs: Vec<&dyn rusqlite::ToSql> = params_vec.iter().map(|p| p.as_ref()).collect();
         
-        let rows = stmt.query_map(params_refs.as_slice(), |row| {
-            Ok(MemoryRow {
-                id: row.get(0)?,
-                scope: row.get(1)?,
-                memory_type: row.get(2)?,
-                agent_id: row.get(3)?,
-                topic_id: row.get(4)?,
-                content: row.get(5)?,
-                context: row.get(6)?,
-                tags: row.get(7)?,
-                created_at: row.get(8)?,
-                last_used_at: row.get(9)?,
-                retrieval_count: row.get(10)?,
-                active: row.get(11)?,
-            })
-        })?;
+        let rows = stmt.query_map(params_refs.as_slice(), MemoryRow::from_row)?;
         
         let mut memories = Vec::new();
         for row in rows {
@@ -164,16 +165,88 @@ impl SqliteStorage {
         Ok(memories)
     }
 
+    /// Count memories by scope and by type under the same filters as `list_memories`.
+    /// Private memories only count when `caller` owns them, matching what a listing shows.
+    pub fn facet_counts(
+        &self,
+        scope: Option<MemoryScope>,
+        agent_id: Option<&str>,
+        topic_id: Option<&str>,
+        contains: Option<&str>,
+        active_only: bool,
+        caller: Option<&str>,
+    ) -> Result<MemoryFacets> {
+        let conn = self.conn()?;
+
+        let (mut filter, mut params_vec) = memory_filter(scope, agent_id, topic_id, contains, active_only);
+        match caller {
+            Some(caller) => {
+                filter.push_str(" AND (visibility = 'shared' OR owner_id = ?)");
+                params_vec.push(Box::new(caller.to_string()));
+            }
+            None => filter.push_str(" AND visibility = 'shared'"),
+        }
+        let params_refs: Vec<&dyn rusqlite::ToSql> = params_vec.iter().map(|p| p.as_ref()).collect();
+
+        let mut facets = MemoryFacets::default();
+        for (column, counts) in [("scope", &mut facets.by_scope), ("memory_type", &mut facets.by_type)] {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {column}, COUNT(*) FROM memories WHERE 1=1{filter} GROUP BY {column}"
+            ))?;
+            let rows = stmt.query_map(params_refs.as_slice(), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?;
+            for row in rows {
+                let (value, count) = row?;
+                counts.insert(value, count as usize);
+            }
+        }
+
+        Ok(facets)
+    }
+
     /// Delete a memory
     pub fn delete_memory(&self, id: Uuid) -> Result<()> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         conn.execute("DELETE FROM memories WHERE id = ?1", params![id.to_string()])?;
         Ok(())
     }
 
+    /// Delete several memories, returning the ids that existed
+    pub fn delete_memories(&self, ids: &[Uuid]) -> Result<Vec<Uuid>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let conn = self.conn()?;
+
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let id_strings: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        let params_refs: Vec<&dyn rusqlite::ToSql> =
+            id_strings.iter().map(|id| id as &dyn rusqlite::ToSql).collect();
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id FROM memories WHERE id IN ({})",
+            placeholders
+        ))?;
+        let found = stmt
+            .query_map(params_refs.as_slice(), |row| row.get::<_, String>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        conn.execute(
+            &format!("DELETE FROM memories WHERE id IN ({})", placeholders),
+            params_refs.as_slice(),
+        )?;
+
+        found
+            .iter()
+            .map(|id| Uuid::parse_str(id).map_err(|e| Error::storage(e.to_string())))
+            .collect()
+    }
+
     /// Set memory active status
     pub fn set_memory_active(&self, id: Uuid, active: bool) -> Result<()> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         conn.execute(
             "UPDATE memories SET active = ?1 WHERE id = ?2",
             params![active, id.to_string()],
@@ -181,9 +254,177 @@ impl SqliteStorage {
         Ok(())
     }
 
+    /// Least recently used active, unpinned memories beyond the first `keep` in
+    /// `memory`'s scope (same scope, agent, topic and owner), never including
+    /// `memory` itself. Shared memories and each owner's private ones are
+    /// capped separately, so one caller's saves never evict another's. Never-used memories count from their creation time; ties go to
+    /// the less often retrieved.
+    pub fn lru_over_cap(&self, memory: &Memory, keep: usize) -> Result<Vec<Uuid>> {
+        let conn = self.conn()?;
+
+        let in_scope = "scope = ?1 AND agent_id IS ?2 AND topic_id IS ?3 \
+                        AND visibility = ?4 AND owner_id IS ?5 AND active = 1";
+        let count: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM memories WHERE {}", in_scope),
+            params![
+                memory.scope.to_string(),
+                memory.agent_id,
+                memory.topic_id,
+                memory.visibility.to_string(),
+                memory.owner_id
+            ],
+            |row| row.get(0),
+        )?;
+        let excess = (count as usize).saturating_sub(keep);
+        if excess == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id FROM memories WHERE {} AND pinned = 0 AND id != ?6 \
+             ORDER BY julianday(COALESCE(last_used_at, created_at)) ASC, retrieval_count ASC \
+             LIMIT ?7",
+            in_scope
+        ))?;
+        let rows = stmt.query_map(
+            params![
+                memory.scope.to_string(),
+                memory.agent_id,
+                memory.topic_id,
+                memory.visibility.to_string(),
+                memory.owner_id,
+                memory.id.to_string(),
+                excess as i64
+            ],
+            |row| row.get::<_, String>(0),
+        )?;
+
+        let mut ids = Vec::new();
+        for row in rows {
+            ids.push(Uuid::parse_str(&row?).map_err(|e| Error::storage(e.to_string()))?);
+        }
+        Ok(ids)
+    }
+
+    /// Set the multiplier on a memory's similarity scores
+    pub fn set_memory_weight(&self, id: Uuid, weight: f32) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE memories SET weight = ?1 WHERE id = ?2",
+            params![weight as f64, id.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Set memory pinned status
+    pub fn set_memory_pinned(&self, id: Uuid, pinned: bool) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE memories SET pinned = ?1 WHERE id = ?2",
+            params![pinned, id.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// List active pinned memories visible to an agent in a topic: global ones,
+    /// the agent's own, and those of that topic
+    pub fn list_pinned_memories(&self, agent_id: Option<&str>, topic_id: Option<&str>) -> Result<Vec<Memory>> {
+        let conn = self.conn()?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM memories \
+             WHERE active = 1 AND pinned = 1 \
+             AND (scope = 'global' OR (scope = 'agent' AND agent_id = ?1) \
+                  OR (scope = 'topic' AND agent_id = ?1 AND topic_id = ?2)) \
+             ORDER BY created_at DESC",
+            MEMORY_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![agent_id, topic_id], MemoryRow::from_row)?;
+
+        let mut memories = Vec::new();
+        for row in rows {
+            memories.push(row?.into_memory()?);
+        }
+
+        Ok(memories)
+    }
+
+    /// Most recently created active memories of the given types for an agent,
+    /// newest first, optionally only those created at or after `since`.
+    /// Private memories are only included when `caller` owns them.
+    pub fn list_learned(
+        &self,
+        agent_id: &str,
+        types: &[MemoryType],
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        limit: usize,
+        caller: Option<&str>,
+    ) -> Result<Vec<Memory>> {
+        let conn = self.conn()?;
+
+        let mut filter = String::from(" AND active = 1 AND agent_id = ?");
+        let mut params_vec: Vec<Box<dyn rusqlite::ToSql>> = vec![Box::new(agent_id.to_string())];
+
+        if !types.is_empty() {
+            let placeholders = vec!["?"; types.len()].join(", ");
+            filter.push_str(&format!(" AND memory_type IN ({})", placeholders));
+            params_vec.extend(types.iter().map(|t| Box::new(t.to_string()) as Box<dyn rusqlite::ToSql>));
+        }
+
+        if let Some(since) = since {
+            // Compare as instants; stored timestamps vary in fractional precision
+            filter.push_str(" AND julianday(created_at) >= julianday(?)");
+            params_vec.push(Box::new(since.to_rfc3339()));
+        }
+
+        match caller {
+            Some(caller) => {
+                filter.push_str(" AND (visibility = 'shared' OR owner_id = ?)");
+                params_vec.push(Box::new(caller.to_string()));
+            }
+            None => filter.push_str(" AND visibility = 'shared'"),
+        }
+
+        params_vec.push(Box::new(limit as i64));
+        let sql = format!(
+            "SELECT {} FROM memories WHERE 1=1{} ORDER BY created_at DESC LIMIT ?",
+            MEMORY_COLUMNS, filter
+        );
+
+        let mut stmt = conn.prepare(&sql)?;
+        let params_refs: Vec<&dyn rusqlite::ToSql> = params_vec.iter().map(|p| p.as_ref()).collect();
+        let rows = stmt.query_map(params_refs.as_slice(), MemoryRow::from_row)?;
+
+        let mut memories = Vec::new();
+        for row in rows {
+            memories.push(row?.into_memory()?);
+        }
+
+        Ok(memories)
+    }
+
+    /// List memories with no recorded content hash, i.e. never embedded (or saved
+    /// before hashes were tracked)
+    pub fn list_unembedded(&self) -> Result<Vec<Memory>> {
+        let conn = self.conn()?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM memories WHERE content_hash IS NULL ORDER BY created_at",
+            MEMORY_COLUMNS
+        ))?;
+        let rows = stmt.query_map([], MemoryRow::from_row)?;
+
+        let mut memories = Vec::new();
+        for row in rows {
+            memories.push(row?.into_memory()?);
+        }
+
+        Ok(memories)
+    }
+
     /// Update memory retrieval stats
     pub fn mark_memory_used(&self, id: Uuid) -> Result<()> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         conn.execute(
             r#"
             UPDATE memories 
@@ -197,7 +438,7 @@ impl SqliteStorage {
 
     /// Save an agent configuration
     pub fn save_agent(&self, agent: &AgentRecord) -> Result<()> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         
         conn.execute(
             r#"
@@ -224,7 +465,7 @@ impl SqliteStorage {
 
     /// Get an agent by ID
     pub fn get_agent(&self, id: &str) -> Result<Option<AgentRecord>> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         
         conn.query_row(
             "SELECT id, name, model, context_limit, color, created_at FROM agents WHERE id = ?1",
@@ -246,7 +487,7 @@ impl SqliteStorage {
 
     /// List all agents
     pub fn list_agents(&self) -> Result<Vec<AgentRecord>> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         
         let mut stmt = conn.prepare(
             "SELECT id, name, model, context_limit, color, created_at FROM agents ORDER BY name"
@@ -270,7 +511,7 @@ impl SqliteStorage {
 
     /// Save a topic
     pub fn save_topic(&self, topic: &TopicRecord) -> Result<()> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         
         conn.execute(
             r#"
@@ -296,9 +537,29 @@ impl SqliteStorage {
         Ok(())
     }
 
+    /// Delete a topic row
+    pub fn delete_topic(&self, agent_id: &str, topic_id: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "DELETE FROM topics WHERE agent_id = ?1 AND id = ?2",
+            params![agent_id, topic_id],
+        )?;
+        Ok(())
+    }
+
+    /// Point an agent's memories from one topic at another, returning how many moved
+    pub fn move_topic_memories(&self, agent_id: &str, from_topic: &str, to_topic: &str) -> Result<usize> {
+        let conn = self.conn()?;
+        let moved = conn.execute(
+            "UPDATE memories SET topic_id = ?3 WHERE agent_id = ?1 AND topic_id = ?2",
+            params![agent_id, from_topic, to_topic],
+        )?;
+        Ok(moved)
+    }
+
     /// List topics for an agent
     pub fn list_topics(&self, agent_id: &str) -> Result<Vec<TopicRecord>> {
-        let conn = self.conn.lock().map_err(|e| Error::storage(e.to_string()))?;
+        let conn = self.conn()?;
         
         let mut stmt = conn.prepare(
             r#"
@@ -328,6 +589,100 @@ impl SqliteStorage {
         
         rows.collect::<std::result::Result<Vec<_>, _>>().map_err(Error::from)
     }
+
+    /// Checkpoint the WAL and VACUUM the database to reclaim dead pages
+    ///
+    /// VACUUM rewrites the whole file and holds a write lock for the duration,
+    /// so other writers block until it finishes.
+    pub fn maintenance(&self) -> Result<MaintenanceReport> {
+        let conn = self.conn()?;
+        let bytes_before = self.disk_usage()?;
+
+        // Returns a (busy, log, checkpointed) row, which we don't need
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        conn.execute_batch("VACUUM")?;
+
+        Ok(MaintenanceReport {
+            bytes_before,
+            bytes_after: self.disk_usage()?,
+        })
+    }
+
+    /// Size of the database file plus its WAL, if any
+    fn disk_usage(&self) -> Result<u64> {
+        let mut size = std::fs::metadata(&self.path)?.len();
+        let mut wal = self.path.clone().into_os_string();
+        wal.push("-wal");
+        if let Ok(metadata) = std::fs::metadata(&wal) {
+            size += metadata.len();
+        }
+        Ok(size)
+    }
+}
+
+/// Add a column to an existing table if an older schema lacks it
+fn ensure_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))?;
+    }
+
+    Ok(())
+}
+
+/// `AND ...` clauses (and their params) for the memory listing filters
+fn memory_filter(
+    scope: Option<MemoryScope>,
+    agent_id: Option<&str>,
+    topic_id: Option<&str>,
+    contains: Option<&str>,
+    active_only: bool,
+) -> (String, Vec<Box<dyn rusqlite::ToSql>>) {
+    let mut sql = String::new();
+    let mut params_vec: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+
+    if let Some(s) = scope {
+        sql.push_str(" AND scope = ?");
+        params_vec.push(Box::new(s.to_string()));
+    }
+
+    if let Some(aid) = agent_id {
+        sql.push_str(" AND agent_id = ?");
+        params_vec.push(Box::new(aid.to_string()));
+    }
+
+    if let Some(tid) = topic_id {
+        sql.push_str(" AND topic_id = ?");
+        params_vec.push(Box::new(tid.to_string()));
+    }
+
+    if let Some(text) = contains.filter(|t| !t.is_empty()) {
+        sql.push_str(" AND content LIKE ? ESCAPE '\\'");
+        params_vec.push(Box::new(format!("%{}%", escape_like(text))));
+    }
+
+    if active_only {
+        sql.push_str(" AND active = 1");
+    }
+
+    (sql, params_vec)
+}
+
+/// Escape `LIKE` wildcards so `text` matches literally (with `ESCAPE '\'`)
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
 }
 
 /// Intermediate struct for reading from SQLite
@@ -344,26 +699,39 @@ struct MemoryRow {
     last_used_at: Option<String>,
     retrieval_count: u32,
     active: bool,
+    pinned: bool,
+    visibility: String,
+    owner_id: Option<String>,
+    content_hash: Option<String>,
+    weight: f32,
 }
 
 impl MemoryRow {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            scope: row.get(1)?,
+            memory_type: row.get(2)?,
+            agent_id: row.get(3)?,
+            topic_id: row.get(4)?,
+            content: row.get(5)?,
+            context: row.get(6)?,
+            tags: row.get(7)?,
+            created_at: row.get(8)?,
+            last_used_at: row.get(9)?,
+            retrieval_count: row.get(10)?,
+            active: row.get(11)?,
+            pinned: row.get(12)?,
+            visibility: row.get(13)?,
+            owner_id: row.get(14)?,
+            content_hash: row.get(15)?,
+            weight: row.get::<_, f64>(16)? as f32,
+        })
+    }
+
     fn into_memory(self) -> Result<Memory> {
-        let scope = match self.scope.as_str() {
-            "global" => MemoryScope::Global,
-            "agent" => MemoryScope::Agent,
-            "topic" => MemoryScope::Topic,
-            "personal" => MemoryScope::Personal,
-            _ => return Err(Error::storage(format!("Unknown scope: {}", self.scope))),
-        };
-        
-        let memory_type = match self.memory_type.as_str() {
-            "correction" => MemoryType::Correction,
-            "preference" => MemoryType::Preference,
-            "fact" => MemoryType::Fact,
-            "workflow" => MemoryType::Workflow,
-            "constraint" => MemoryType::Constraint,
-            _ => return Err(Error::storage(format!("Unknown memory type: {}", self.memory_type))),
-        };
+        let scope: MemoryScope = self.scope.parse()?;
+        let memory_type: MemoryType = self.memory_type.parse()?;
         
         Ok(Memory {
             id: Uuid::parse_str(&self.id).map_err(|e| Error::storage(e.to_string()))?,
@@ -385,12 +753,17 @@ impl MemoryRow {
             }),
             retrieval_count: self.retrieval_count,
             active: self.active,
+            pinned: self.pinned,
+            visibility: self.visibility.parse()?,
+            owner_id: self.owner_id,
+            content_hash: self.content_hash,
+            weight: self.weight,
         })
     }
 }
 
 /// Agent record stored in SQLite
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AgentRecord {
     pub id: String,
     pub name: String,
@@ -401,7 +774,7 @@ pub struct AgentRecord {
 }
 
 /// Topic record stored in SQLite
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TopicRecord {
     pub id: String,
     pub agent_id: String,
@@ -411,3 +784,207 @@ pub struct TopicRecord {
     pub message_count: u32,
     pub token_count: u32,
 }
+
+/// Memory counts per scope and per type, from `SqliteStorage::facet_counts`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemoryFacets {
+    pub by_scope: BTreeMap<String, usize>,
+    pub by_type: BTreeMap<String, usize>,
+}
+
+/// Database size before and after `SqliteStorage::maintenance`
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MaintenanceReport {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use chrono::{Duration, Utc};
+
+    fn storage(dir: &std::path::Path) -> SqliteStorage {
+        let config = test_support::config(dir, |_| {});
+        config.ensure_dirs().unwrap();
+        SqliteStorage::new(&config).unwrap()
+    }
+
+    /// Save an agent memory created `age_hours` ago
+    fn saved(sqlite: &SqliteStorage, memory: Memory, age_hours: i64) -> Memory {
+        let memory = Memory {
+            created_at: Utc::now() - Duration::hours(age_hours),
+            ..memory
+        };
+        sqlite.save_memory(&memory).unwrap();
+        memory
+    }
+
+    #[test]
+    fn lru_eviction_stays_within_an_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let sqlite = storage(dir.path());
+        let fact = |content: &str| Memory::for_agent("agent", MemoryType::Fact, content);
+
+        let shared_old = saved(&sqlite, fact("shared old"), 5);
+        let shared_new = saved(&sqlite, fact("shared new"), 4);
+        let alice_old = saved(&sqlite, fact("alice old").with_owner("alice"), 3);
+        let alice_pinned = saved(&sqlite, fact("alice pinned").with_owner("alice").with_pinned(true), 2);
+        let alice_new = saved(&sqlite, fact("alice new").with_owner("alice"), 1);
+        let bob = saved(&sqlite, fact("bob").with_owner("bob"), 0);
+
+        // Alice is over a cap of two; her oldest unpinned memory goes
+        assert_eq!(sqlite.lru_over_cap(&alice_new, 2).unwrap(), [alice_old.id]);
+        assert!(!sqlite.lru_over_cap(&alice_new, 3).unwrap().contains(&alice_pinned.id));
+        // Shared memories and Bob's are under the cap on their own
+        assert!(sqlite.lru_over_cap(&shared_new, 2).unwrap().is_empty());
+        assert!(sqlite.lru_over_cap(&bob, 1).unwrap().is_empty());
+        assert_eq!(sqlite.lru_over_cap(&shared_new, 1).unwrap(), [shared_old.id]);
+    }
+
+    #[test]
+    fn contains_matches_literally_at_any_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let sqlite = storage(dir.path());
+        let fact = |content: &str| saved(&sqlite, Memory::global(MemoryType::Fact, content), 0);
+
+        let deploy = fact("Deploy with cargo");
+        let redeploy = fact("Redeploy after review");
+        let percent = fact("100% of builds pass");
+        fact("1000 builds pass");
+        let underscore = fact("Use snake_case names");
+        fact("Use snakeXcase names");
+        let backslash = fact(r"Logs go to C:\tmp\logs");
+        let contains = |text: &str| {
+            let mut ids: Vec<Uuid> = sqlite
+                .list_memories(None, None, None, Some(text), false)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        let sorted = |mut ids: Vec<Uuid>| {
+            ids.sort();
+            ids
+        };
+
+        // A prefix and a substring both match, case-insensitively
+        assert_eq!(contains("deploy"), sorted(vec![deploy.id, redeploy.id]));
+        assert_eq!(contains("with car"), [deploy.id]);
+        // Wildcards and the escape character only match themselves
+        assert_eq!(contains("100%"), [percent.id]);
+        assert_eq!(contains("e_c"), [underscore.id]);
+        assert_eq!(contains(r"C:\tmp"), [backslash.id]);
+        assert_eq!(contains("%"), [percent.id]);
+        assert_eq!(contains("_"), [underscore.id]);
+        // An empty filter is no filter
+        assert_eq!(contains("").len(), 7);
+    }
+
+    #[test]
+    fn maintenance_reclaims_deleted_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let sqlite = storage(dir.path());
+
+        let memories: Vec<Memory> = (0..500)
+            .map(|i| saved(&sqlite, Memory::global(MemoryType::Fact, format!("{} {}", i, "filler ".repeat(200))), 0))
+            .collect();
+        for memory in &memories[10..] {
+            sqlite.delete_memory(memory.id).unwrap();
+        }
+
+        let report = sqlite.maintenance().unwrap();
+        assert!(report.bytes_after < report.bytes_before, "{:?}", report);
+        assert_eq!(report.bytes_after, sqlite.disk_usage().unwrap());
+        // The surviving rows are untouched
+        assert_eq!(sqlite.list_memories(None, None, None, None, false).unwrap().len(), 10);
+        assert!(sqlite.get_memory(memories[0].id).unwrap().is_some());
+
+        // Running it again on a compact database still succeeds
+        sqlite.maintenance().unwrap();
+    }
+
+    #[test]
+    fn facet_counts_match_the_listing() {
+        let dir = tempfile::tempdir().unwrap();
+        let sqlite = storage(dir.path());
+
+        saved(&sqlite, Memory::global(MemoryType::Fact, "cargo builds the workspace"), 0);
+        saved(&sqlite, Memory::global(MemoryType::Constraint, "cargo must run offline"), 0);
+        saved(&sqlite, Memory::for_agent("coder", MemoryType::Fact, "cargo test before pushing"), 0);
+        saved(&sqlite, Memory::for_agent("coder", MemoryType::Preference, "cargo fmt on save"), 0);
+        saved(&sqlite, Memory::for_topic("coder", "rust", MemoryType::Fact, "cargo clippy is strict"), 0);
+        saved(&sqlite, Memory::for_agent("coder", MemoryType::Fact, "cargo secrets").with_owner("alice"), 0);
+        saved(&sqlite, Memory::for_agent("coder", MemoryType::Fact, "npm is not used"), 0);
+        let inactive = saved(&sqlite, Memory::global(MemoryType::Fact, "cargo was slow"), 0);
+        sqlite.set_memory_active(inactive.id, false).unwrap();
+
+        for caller in [None, Some("alice"), Some("bob")] {
+            for (scope, agent_id) in [(None, None), (Some(MemoryScope::Agent), None), (None, Some("coder"))] {
+                let listed: Vec<Memory> = sqlite
+                    .list_memories(scope, agent_id, None, Some("cargo"), true)
+                    .unwrap()
+                    .into_iter()
+                    .filter(|m| m.is_visible_to(caller))
+                    .collect();
+                let mut expected = MemoryFacets::default();
+                for memory in &listed {
+                    *expected.by_scope.entry(memory.scope.to_string()).or_default() += 1;
+                    *expected.by_type.entry(memory.memory_type.to_string()).or_default() += 1;
+                }
+
+                let facets = sqlite.facet_counts(scope, agent_id, None, Some("cargo"), true, caller).unwrap();
+                assert_eq!(facets.by_scope, expected.by_scope, "{:?} {:?} {:?}", caller, scope, agent_id);
+                assert_eq!(facets.by_type, expected.by_type, "{:?} {:?} {:?}", caller, scope, agent_id);
+            }
+        }
+
+        // Only the owner sees the private memory counted
+        let anonymous = sqlite.facet_counts(None, Some("coder"), None, Some("cargo"), true, None).unwrap();
+        let alice = sqlite.facet_counts(None, Some("coder"), None, Some("cargo"), true, Some("alice")).unwrap();
+        assert_eq!(anonymous.by_type["fact"], 2);
+        assert_eq!(alice.by_type["fact"], 3);
+    }
+
+    #[test]
+    fn learned_feed_respects_since_and_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let sqlite = storage(dir.path());
+        let learned = [MemoryType::Correction, MemoryType::Preference, MemoryType::Constraint];
+
+        let oldest = saved(&sqlite, Memory::for_agent("coder", MemoryType::Correction, "use tabs"), 48);
+        let older = saved(&sqlite, Memory::for_agent("coder", MemoryType::Preference, "short names"), 24);
+        let newer = saved(&sqlite, Memory::for_agent("coder", MemoryType::Constraint, "no unsafe"), 2);
+        let newest = saved(&sqlite, Memory::for_agent("coder", MemoryType::Correction, "use spaces"), 1);
+        // Other types, other agents, private and inactive memories stay out
+        saved(&sqlite, Memory::for_agent("coder", MemoryType::Fact, "rust 2021"), 0);
+        saved(&sqlite, Memory::for_agent("writer", MemoryType::Correction, "oxford comma"), 0);
+        saved(&sqlite, Memory::for_agent("coder", MemoryType::Correction, "alice only").with_owner("alice"), 0);
+        let retired = saved(&sqlite, Memory::for_agent("coder", MemoryType::Correction, "retired"), 0);
+        sqlite.set_memory_active(retired.id, false).unwrap();
+
+        let feed = |since: Option<i64>, limit: usize| -> Vec<Uuid> {
+            let since = since.map(|hours| Utc::now() - Duration::hours(hours));
+            sqlite
+                .list_learned("coder", &learned, since, limit, None)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.id)
+                .collect()
+        };
+
+        assert_eq!(feed(None, 10), [newest.id, newer.id, older.id, oldest.id]);
+        assert_eq!(feed(None, 2), [newest.id, newer.id]);
+        assert_eq!(feed(Some(30), 10), [newest.id, newer.id, older.id]);
+        assert_eq!(feed(Some(30), 1), [newest.id]);
+        assert!(feed(Some(0), 10).is_empty());
+
+        // The owner also sees their private correction
+        let alice = sqlite.list_learned("coder", &learned, None, 10, Some("alice")).unwrap();
+        assert_eq!(alice.len(), 5);
+        assert_eq!(alice[0].content, "alice only");
+    }
+}
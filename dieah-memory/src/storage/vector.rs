@@ -1,25 +1,33 @@
 //! Vector storage using LanceDB for semantic search
 
 use arrow_array::{
-    Array, FixedSizeListArray, Float32Array, RecordBatch, RecordBatchIterator, StringArray,
+    Array, BooleanArray, FixedSizeListArray, Float32Array, RecordBatch, RecordBatchIterator,
+    StringArray,
 };
 use arrow_schema::{DataType, Field, Schema};
 use lance_arrow::FixedSizeListArrayExt;
 use lancedb::connect;
-use lancedb::query::{ExecutableQuery, QueryBase};
+use lancedb::query::{ExecutableQuery, QueryBase, Select, VectorQuery};
+use lancedb::table::NewColumnTransform;
 use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 use crate::config::Config;
 use crate::error::{Error, Result};
-use crate::memory::Memory;
-
-const TABLE_NAME: &str = "memories";
+use crate::memory::{Memory, MemoryType, Visibility};
 
 /// Vector storage backend using LanceDB
 pub struct VectorStorage {
     db: lancedb::Connection,
+    /// Table holding this store's vectors (`Config.vector_table_name`)
+    table_name: String,
     dimensions: usize,
+    metric: DistanceMetric,
+    search_timeout: Option<Duration>,
+    missing_distance_score: f32,
+    nprobes: Option<usize>,
+    refine_factor: Option<u32>,
 }
 
 impl VectorStorage {
@@ -30,9 +38,24 @@ impl VectorStorage {
             .await
             .map_err(|e| Error::vector_db(e.to_string()))?;
 
+        let table_name = config.vector_table_name.clone();
+        let valid_name = !table_name.is_empty()
+            && table_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !valid_name {
+            return Err(Error::config(format!("Invalid vector table name: {:?}", table_name)));
+        }
+
         let storage = Self {
             db,
+            table_name,
             dimensions: config.embedding_dimensions,
+            metric: config.distance_metric,
+            search_timeout: config.vector_search_timeout_ms.map(Duration::from_millis),
+            missing_distance_score: clamp_score(config.missing_distance_score),
+            nprobes: config.search_nprobes,
+            refine_factor: config.search_refine_factor,
         };
 
         // Ensure table exists
@@ -41,6 +64,44 @@ impl VectorStorage {
         Ok(storage)
     }
 
+    /// Vector dimension this store writes and searches with
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    /// Vector dimension declared by the table's schema, which is fixed when
+    /// the table is created. None if the table has no usable vector column.
+    pub async fn table_dimensions(&self) -> Result<Option<usize>> {
+        let schema = self
+            .open_table()
+            .await?
+            .schema()
+            .await
+            .map_err(|e| Error::vector_db(e.to_string()))?;
+
+        Ok(match schema.field_with_name("vector").map(|f| f.data_type()) {
+            Ok(DataType::FixedSizeList(_, size)) => usize::try_from(*size).ok(),
+            _ => None,
+        })
+    }
+
+    /// Distance metric searches run with
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    /// Apply the configured ANN tuning to a vector query. Both settings only
+    /// matter once the table has a vector index; a flat scan ignores them.
+    fn tuned(&self, mut query: VectorQuery) -> VectorQuery {
+        if let Some(nprobes) = self.nprobes {
+            query = query.nprobes(nprobes.max(1));
+        }
+        if let Some(factor) = self.refine_factor {
+            query = query.refine_factor(factor.max(1));
+        }
+        query
+    }
+
     /// Get the schema for the memories table
     fn schema(&self) -> Schema {
         Schema::new(vec![
@@ -50,6 +111,8 @@ impl VectorStorage {
             Field::new("memory_type", DataType::Utf8, false),
             Field::new("agent_id", DataType::Utf8, true),
             Field::new("topic_id", DataType::Utf8, true),
+            // JSON array, mirroring SQLite, so search results can carry tags
+            Field::new("tags", DataType::Utf8, false),
             Field::new(
                 "vector",
                 DataType::FixedSizeList(
@@ -58,6 +121,11 @@ impl VectorStorage {
                 ),
                 false,
             ),
+            // Copies of the SQLite access fields, so a degraded store can still
+            // filter hits without it
+            Field::new("visibility", DataType::Utf8, false),
+            Field::new("owner_id", DataType::Utf8, true),
+            Field::new("active", DataType::Boolean, false),
         ])
     }
 
@@ -70,7 +138,7 @@ impl VectorStorage {
             .await
             .map_err(|e| Error::vector_db(e.to_string()))?;
 
-        if !tables.contains(&TABLE_NAME.to_string()) {
+        if !tables.contains(&self.table_name) {
             // Create empty table with schema
             let schema = Arc::new(self.schema());
             
@@ -80,15 +148,82 @@ impl VectorStorage {
             let reader = RecordBatchIterator::new(batches.into_iter().map(Ok), schema);
 
             self.db
-                .create_table(TABLE_NAME, Box::new(reader))
+                .create_table(&self.table_name, Box::new(reader))
                 .execute()
                 .await
                 .map_err(|e| Error::vector_db(e.to_string()))?;
+        } else {
+            self.migrate_table().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Add columns introduced after a table was created, with empty values
+    /// (shared, unowned and active for the access columns); a reindex fills
+    /// them in
+    async fn migrate_table(&self) -> Result<()> {
+        // Not `open_table`: its recovery path comes back through `ensure_table`.
+        // An unreadable table is left for that recovery to recreate.
+        let Ok(table) = self.db.open_table(&self.table_name).execute().await else {
+            return Ok(());
+        };
+        let schema = table
+            .schema()
+            .await
+            .map_err(|e| Error::vector_db(e.to_string()))?;
+
+        let defaults = [
+            ("tags", "'[]'"),
+            ("visibility", "'shared'"),
+            ("owner_id", "CAST(NULL AS STRING)"),
+            ("active", "true"),
+        ];
+        let missing: Vec<(String, String)> = defaults
+            .iter()
+            .filter(|(name, _)| schema.field_with_name(name).is_err())
+            .map(|(name, default)| (name.to_string(), default.to_string()))
+            .collect();
+
+        if !missing.is_empty() {
+            tracing::info!(
+                "Adding columns {:?} to vector table {}",
+                missing.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+                self.table_name
+            );
+            table
+                .add_columns(NewColumnTransform::SqlExpressions(missing), None)
+                .await
+                .map_err(|e| Error::vector_db(e.to_string()))?;
         }
 
         Ok(())
     }
 
+    /// Open the memories table, recreating it once if it is missing or unreadable
+    ///
+    /// A recreated table is empty; run a reindex to repopulate it from SQLite.
+    async fn open_table(&self) -> Result<lancedb::Table> {
+        let err = match self.db.open_table(&self.table_name).execute().await {
+            Ok(table) => return Ok(table),
+            Err(e) => e,
+        };
+
+        tracing::warn!("Vector table unavailable ({}), recreating it empty", err);
+
+        // A corrupt table may still be listed, which would stop ensure_table
+        let _ = self.db.drop_table(&self.table_name).await;
+        self.ensure_table().await.map_err(|e| {
+            Error::vector_db(format!("Vector table unavailable ({}) and could not be recreated: {}", err, e))
+        })?;
+
+        self.db
+            .open_table(&self.table_name)
+            .execute()
+            .await
+            .map_err(|e| Error::vector_db(format!("Vector table unavailable after recreation: {}", e)))
+    }
+
     /// Insert or update a memory in the vector store
     pub async fn upsert_memory(&self, memory: &Memory) -> Result<()> {
         let embedding = memory
@@ -96,27 +231,57 @@ impl VectorStorage {
             .as_ref()
             .ok_or_else(|| Error::vector_db("Memory has no embedding"))?;
 
-        if embedding.len() != self.dimensions {
-            return Err(Error::vector_db(format!(
-                "Embedding dimension mismatch: expected {}, got {}",
-                self.dimensions,
-                embedding.len()
-            )));
+        self.upsert_memory_vectors(memory, std::slice::from_ref(embedding)).await
+    }
+
+    /// Insert or update a memory with one vector per content chunk
+    ///
+    /// Every row carries the memory's id and full content, so a match on any
+    /// chunk resolves to the memory; `search` keeps the best chunk per memory.
+    pub async fn upsert_memory_vectors(&self, memory: &Memory, embeddings: &[Vec<f32>]) -> Result<()> {
+        if embeddings.is_empty() {
+            return Err(Error::vector_db("Memory has no embedding"));
+        }
+
+        for embedding in embeddings {
+            if embedding.len() != self.dimensions {
+                return Err(Error::vector_db(format!(
+                    "Embedding dimension mismatch: expected {}, got {}",
+                    self.dimensions,
+                    embedding.len()
+                )));
+            }
+        }
+
+        // A table created for another dimension rejects the new rows, and only
+        // after the old ones were deleted; refuse before touching them
+        if let Some(table) = self.table_dimensions().await? {
+            if table != self.dimensions {
+                return Err(Error::vector_db(format!(
+                    "Vector table {} holds {}-dimension vectors, not {}; drop the table and reindex",
+                    self.table_name, table, self.dimensions
+                )));
+            }
         }
 
-        // First try to delete existing record
+        // First try to delete existing records
         let _ = self.delete_memory(memory.id).await;
 
-        // Build arrays for the record batch
-        let id_array = StringArray::from(vec![memory.id.to_string()]);
-        let content_array = StringArray::from(vec![memory.content.clone()]);
-        let scope_array = StringArray::from(vec![memory.scope.to_string()]);
-        let type_array = StringArray::from(vec![memory.memory_type.to_string()]);
-        let agent_id_array = StringArray::from(vec![memory.agent_id.clone()]);
-        let topic_id_array = StringArray::from(vec![memory.topic_id.clone()]);
+        // Build arrays for the record batch, one row per vector
+        let rows = embeddings.len();
+        let id_array = StringArray::from(vec![memory.id.to_string(); rows]);
+        let content_array = StringArray::from(vec![memory.content.clone(); rows]);
+        let scope_array = StringArray::from(vec![memory.scope.to_string(); rows]);
+        let type_array = StringArray::from(vec![memory.memory_type.to_string(); rows]);
+        let agent_id_array = StringArray::from(vec![memory.agent_id.clone(); rows]);
+        let topic_id_array = StringArray::from(vec![memory.topic_id.clone(); rows]);
+        let tags_array = StringArray::from(vec![serde_json::to_string(&memory.tags)?; rows]);
+        let visibility_array = StringArray::from(vec![memory.visibility.to_string(); rows]);
+        let owner_id_array = StringArray::from(vec![memory.owner_id.clone(); rows]);
+        let active_array = BooleanArray::from(vec![memory.active; rows]);
 
         // Build the vector array
-        let values = Float32Array::from(embedding.clone());
+        let values = Float32Array::from(embeddings.concat());
         let vector_array = FixedSizeListArray::try_new_from_values(values, self.dimensions as i32)
             .map_err(|e: arrow_schema::ArrowError| Error::vector_db(e.to_string()))?;
 
@@ -130,7 +295,11 @@ impl VectorStorage {
                 Arc::new(type_array),
                 Arc::new(agent_id_array),
                 Arc::new(topic_id_array),
+                Arc::new(tags_array),
                 Arc::new(vector_array),
+                Arc::new(visibility_array),
+                Arc::new(owner_id_array),
+                Arc::new(active_array),
             ],
         )
         .map_err(|e| Error::vector_db(e.to_string()))?;
@@ -138,15 +307,27 @@ impl VectorStorage {
         let batches = vec![batch];
         let reader = RecordBatchIterator::new(batches.into_iter().map(Ok), schema);
 
-        let table = self
-            .db
-            .open_table(TABLE_NAME)
+        let table = self.open_table().await?;
+
+        table
+            .add(Box::new(reader))
             .execute()
             .await
             .map_err(|e| Error::vector_db(e.to_string()))?;
 
+        Ok(())
+    }
+
+    /// Rewrite a memory's tags in place, for edits that don't change content
+    /// and so don't re-embed
+    pub async fn update_tags(&self, id: Uuid, tags: &[String]) -> Result<()> {
+        let table = self.open_table().await?;
+        let json = serde_json::to_string(tags)?;
+
         table
-            .add(Box::new(reader))
+            .update()
+            .only_if(format!("id = '{}'", id))
+            .column("tags", sql_string(&json))
             .execute()
             .await
             .map_err(|e| Error::vector_db(e.to_string()))?;
@@ -154,15 +335,52 @@ impl VectorStorage {
         Ok(())
     }
 
-    /// Delete a memory from the vector store
-    pub async fn delete_memory(&self, id: Uuid) -> Result<()> {
-        let table = self
-            .db
-            .open_table(TABLE_NAME)
+    /// Rewrite a memory's visibility, owner and active flag in place, for
+    /// edits that don't change content and so don't re-embed
+    pub async fn update_access(&self, memory: &Memory) -> Result<()> {
+        let table = self.open_table().await?;
+        let owner_id = match &memory.owner_id {
+            Some(owner_id) => sql_string(owner_id),
+            None => "NULL".to_string(),
+        };
+
+        table
+            .update()
+            .only_if(format!("id = '{}'", memory.id))
+            .column("visibility", sql_string(&memory.visibility.to_string()))
+            .column("owner_id", owner_id)
+            .column("active", memory.active.to_string())
+            .execute()
+            .await
+            .map_err(|e| Error::vector_db(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Point an agent's rows from one topic at another, matching
+    /// `SqliteStorage::move_topic_memories`
+    pub async fn move_topic_memories(&self, agent_id: &str, from_topic: &str, to_topic: &str) -> Result<()> {
+        let table = self.open_table().await?;
+
+        table
+            .update()
+            .only_if(format!(
+                "agent_id = {} AND topic_id = {}",
+                sql_string(agent_id),
+                sql_string(from_topic)
+            ))
+            .column("topic_id", sql_string(to_topic))
             .execute()
             .await
             .map_err(|e| Error::vector_db(e.to_string()))?;
 
+        Ok(())
+    }
+
+    /// Delete a memory from the vector store
+    pub async fn delete_memory(&self, id: Uuid) -> Result<()> {
+        let table = self.open_table().await?;
+
         table
             .delete(&format!("id = '{}'", id))
             .await
@@ -171,53 +389,143 @@ impl VectorStorage {
         Ok(())
     }
 
-    /// Search for similar memories
+    /// Delete several memories from the vector store in one predicate
+    pub async fn delete_memories(&self, ids: &[Uuid]) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let table = self.open_table().await?;
+
+        let id_list = ids
+            .iter()
+            .map(|id| format!("'{}'", id))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        table
+            .delete(&format!("id IN ({})", id_list))
+            .await
+            .map_err(|e| Error::vector_db(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Search for the `limit` memories most similar to an embedding
+    ///
+    /// A chunked memory has several rows, so a query for `limit` rows can come
+    /// back with fewer memories; the row limit is doubled until it yields
+    /// `limit` memories or no more can match.
     pub async fn search(
         &self,
         query_embedding: &[f32],
         limit: usize,
         min_score: f32,
-        scope_filter: Option<&str>,
-        agent_filter: Option<&str>,
+        filter: &SearchFilter<'_>,
     ) -> Result<Vec<SearchResult>> {
-        let table = self
-            .db
-            .open_table(TABLE_NAME)
-            .execute()
-            .await
-            .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?;
+        let mut rows = limit.max(1);
+        loop {
+            let mut page = self.search_page(query_embedding, rows, min_score, filter).await?;
+
+            let complete = page.below_threshold || page.rows < rows;
+            if complete || page.results.len() >= limit {
+                page.results.truncate(limit);
+                return Ok(page.results);
+            }
+            rows = rows.saturating_mul(2);
+        }
+    }
+
+    /// Like `search`, but returns every match scoring at least `min_score`, up
+    /// to `max_cap` results. Re-runs the search with a doubling limit until the
+    /// scores drop below the threshold or the table runs out of rows.
+    pub async fn search_all(
+        &self,
+        query_embedding: &[f32],
+        min_score: f32,
+        filter: &SearchFilter<'_>,
+        max_cap: usize,
+    ) -> Result<Vec<SearchResult>> {
+        let mut limit = SEARCH_ALL_FIRST_PAGE.min(max_cap).max(1);
+        loop {
+            let mut page = self.search_page(query_embedding, limit, min_score, filter).await?;
+
+            let complete = page.below_threshold || page.rows < limit;
+            if complete || page.results.len() >= max_cap {
+                page.results.truncate(max_cap);
+                return Ok(page.results);
+            }
+            limit = limit.saturating_mul(2);
+        }
+    }
+
+    /// One nearest-neighbor query of `limit` rows, keeping those at or above `min_score`
+    async fn search_page(
+        &self,
+        query_embedding: &[f32],
+        limit: usize,
+        min_score: f32,
+        filter: &SearchFilter<'_>,
+    ) -> Result<SearchPage> {
+        let table = self.open_table().await?;
 
-        let mut query = table
+        let query = table
             .vector_search(query_embedding.to_vec())
             .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?
-            .limit(limit);
+            .distance_type(self.metric.distance_type());
+        let mut query = self.tuned(query).limit(limit);
 
         // Build filter string
         let mut filters = Vec::new();
-        if let Some(scope) = scope_filter {
+        if let Some(scope) = filter.scope {
             filters.push(format!("scope = '{}'", scope));
         }
-        if let Some(agent_id) = agent_filter {
-            filters.push(format!("agent_id = '{}'", agent_id));
+        if let Some(agent_id) = filter.agent_id {
+            filters.push(format!("agent_id = {}", sql_string(agent_id)));
+        }
+        if let Some(topic_id) = filter.topic_id {
+            filters.push(format!("topic_id = {}", sql_string(topic_id)));
+        }
+        if !filter.types.is_empty() {
+            let types = filter
+                .types
+                .iter()
+                .map(|t| format!("'{}'", t))
+                .collect::<Vec<_>>()
+                .join(", ");
+            filters.push(format!("memory_type IN ({})", types));
         }
 
         if !filters.is_empty() {
             query = query.only_if(filters.join(" AND "));
         }
 
-        let stream = query
-            .execute()
-            .await
-            .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?;
+        let run = async {
+            let stream = query
+                .execute()
+                .await
+                .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?;
 
-        let batches: Vec<RecordBatch> = stream
-            .try_collect::<Vec<RecordBatch>>()
-            .await
-            .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?;
+            stream
+                .try_collect::<Vec<RecordBatch>>()
+                .await
+                .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))
+        };
+
+        let batches: Vec<RecordBatch> = with_search_timeout(self.search_timeout, run).await?;
 
+        self.read_page(batches, min_score)
+    }
+
+    /// Turn a search's record batches into results scoring at least `min_score`
+    fn read_page(&self, batches: Vec<RecordBatch>, min_score: f32) -> Result<SearchPage> {
         let mut search_results = Vec::new();
+        let mut rows = 0;
+        let mut below_threshold = false;
 
         for batch in batches {
+            rows += batch.num_rows();
+
             // Get column arrays
             let id_col: &Arc<dyn Array> = batch.column_by_name("id")
                 .ok_or_else(|| Error::vector_db("Missing id column"))?;
@@ -227,9 +535,32 @@ impl VectorStorage {
                 .ok_or_else(|| Error::vector_db("Missing scope column"))?;
             let type_col: &Arc<dyn Array> = batch.column_by_name("memory_type")
                 .ok_or_else(|| Error::vector_db("Missing memory_type column"))?;
-            let distance_col: &Arc<dyn Array> = batch.column_by_name("_distance")
-                .ok_or_else(|| Error::vector_db("Missing _distance column"))?;
-            
+            let agent_col: &Arc<dyn Array> = batch.column_by_name("agent_id")
+                .ok_or_else(|| Error::vector_db("Missing agent_id column"))?;
+            let topic_col: &Arc<dyn Array> = batch.column_by_name("topic_id")
+                .ok_or_else(|| Error::vector_db("Missing topic_id column"))?;
+            let tags = batch
+                .column_by_name("tags")
+                .and_then(|col| col.as_any().downcast_ref::<StringArray>());
+            let visibilities = batch
+                .column_by_name("visibility")
+                .and_then(|col| col.as_any().downcast_ref::<StringArray>());
+            let owner_ids = batch
+                .column_by_name("owner_id")
+                .and_then(|col| col.as_any().downcast_ref::<StringArray>());
+            let actives = batch
+                .column_by_name("active")
+                .and_then(|col| col.as_any().downcast_ref::<BooleanArray>());
+            // Not every query plan yields distances; score those rows neutrally
+            let distance_col = batch.column_by_name("_distance");
+            if distance_col.is_none() && batch.num_rows() > 0 {
+                tracing::warn!(
+                    "Search returned no _distance column; scoring {} rows as {}",
+                    batch.num_rows(),
+                    self.missing_distance_score
+                );
+            }
+
             // Downcast to typed arrays
             let ids = id_col.as_any().downcast_ref::<StringArray>()
                 .ok_or_else(|| Error::vector_db("id column is not StringArray"))?;
@@ -239,31 +570,317 @@ impl VectorStorage {
                 .ok_or_else(|| Error::vector_db("scope column is not StringArray"))?;
             let types = type_col.as_any().downcast_ref::<StringArray>()
                 .ok_or_else(|| Error::vector_db("memory_type column is not StringArray"))?;
+            let agent_ids = agent_col.as_any().downcast_ref::<StringArray>()
+                .ok_or_else(|| Error::vector_db("agent_id column is not StringArray"))?;
+            let topic_ids = topic_col.as_any().downcast_ref::<StringArray>()
+                .ok_or_else(|| Error::vector_db("topic_id column is not StringArray"))?;
+            let distances = distance_col
+                .map(|col| {
+                    col.as_any().downcast_ref::<Float32Array>()
+                        .ok_or_else(|| Error::vector_db("_distance column is not Float32Array"))
+                })
+                .transpose()?;
+
+            for i in 0..batch.num_rows() {
+                let score = match distances {
+                    Some(distances) => score_from_distance(distances.value(i), self.metric),
+                    None => self.missing_distance_score,
+                };
+
+                if score < min_score {
+                    below_threshold = true;
+                    continue;
+                }
+
+                let id = Uuid::parse_str(ids.value(i))
+                    .map_err(|e| Error::vector_db(e.to_string()))?;
+
+                // Chunked memories have several rows; keep the best-scoring chunk
+                if let Some(existing) = search_results.iter_mut().find(|r| r.id == id) {
+                    existing.score = existing.score.max(score);
+                    continue;
+                }
+
+                search_results.push(SearchResult {
+                    id,
+                    content: contents.value(i).to_string(),
+                    scope: scopes.value(i).to_string(),
+                    memory_type: types.value(i).to_string(),
+                    agent_id: (!agent_ids.is_null(i)).then(|| agent_ids.value(i).to_string()),
+                    topic_id: (!topic_ids.is_null(i)).then(|| topic_ids.value(i).to_string()),
+                    tags: tags
+                        .filter(|tags| !tags.is_null(i))
+                        .and_then(|tags| serde_json::from_str(tags.value(i)).ok())
+                        .unwrap_or_default(),
+                    // An unreadable visibility is treated as private, so it fails closed
+                    visibility: visibilities
+                        .filter(|v| !v.is_null(i))
+                        .map_or(Ok(Visibility::Shared), |v| v.value(i).parse())
+                        .unwrap_or(Visibility::Private),
+                    owner_id: owner_ids
+                        .filter(|owners| !owners.is_null(i))
+                        .map(|owners| owners.value(i).to_string()),
+                    active: actives
+                        .filter(|actives| !actives.is_null(i))
+                        .map(|actives| actives.value(i))
+                        .unwrap_or(true),
+                    score,
+                });
+            }
+        }
+
+        Ok(SearchPage {
+            results: search_results,
+            rows,
+            below_threshold,
+        })
+    }
+
+    /// Fetch the stored embedding for a memory
+    pub async fn get_vector(&self, id: Uuid) -> Result<Option<Vec<f32>>> {
+        let table = self.open_table().await?;
+
+        let stream = table
+            .query()
+            .only_if(format!("id = '{}'", id))
+            .select(Select::columns(&["vector"]))
+            .limit(1)
+            .execute()
+            .await
+            .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?;
+
+        let batches: Vec<RecordBatch> = stream
+            .try_collect::<Vec<RecordBatch>>()
+            .await
+            .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?;
+
+        for batch in batches {
+            if batch.num_rows() == 0 {
+                continue;
+            }
+
+            let vector_col: &Arc<dyn Array> = batch.column_by_name("vector")
+                .ok_or_else(|| Error::vector_db("Missing vector column"))?;
+            let vectors = vector_col.as_any().downcast_ref::<FixedSizeListArray>()
+                .ok_or_else(|| Error::vector_db("vector column is not FixedSizeListArray"))?;
+            let values = vectors.value(0);
+            let values = values.as_any().downcast_ref::<Float32Array>()
+                .ok_or_else(|| Error::vector_db("vector values are not Float32Array"))?;
+
+            return Ok(Some(values.values().to_vec()));
+        }
+
+        Ok(None)
+    }
+
+    /// Fetch every stored vector for a memory, one per content chunk (empty if
+    /// it has none)
+    pub async fn get_vectors(&self, id: Uuid) -> Result<Vec<Vec<f32>>> {
+        let table = self.open_table().await?;
+
+        let stream = table
+            .query()
+            .only_if(format!("id = '{}'", id))
+            .select(Select::columns(&["vector"]))
+            .execute()
+            .await
+            .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?;
+
+        let batches: Vec<RecordBatch> = stream
+            .try_collect::<Vec<RecordBatch>>()
+            .await
+            .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?;
+
+        let mut embeddings = Vec::new();
+        for batch in batches {
+            let vector_col: &Arc<dyn Array> = batch.column_by_name("vector")
+                .ok_or_else(|| Error::vector_db("Missing vector column"))?;
+            let vectors = vector_col.as_any().downcast_ref::<FixedSizeListArray>()
+                .ok_or_else(|| Error::vector_db("vector column is not FixedSizeListArray"))?;
+            for row in 0..vectors.len() {
+                let values = vectors.value(row);
+                let values = values.as_any().downcast_ref::<Float32Array>()
+                    .ok_or_else(|| Error::vector_db("vector values are not Float32Array"))?;
+                embeddings.push(values.values().to_vec());
+            }
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Find the nearest neighbors of a stored memory, excluding the memory itself.
+    /// Returns None if the memory has no vector.
+    pub async fn neighbors(&self, id: Uuid, limit: usize) -> Result<Option<Vec<Neighbor>>> {
+        let Some(vector) = self.get_vector(id).await? else {
+            return Ok(None);
+        };
+
+        let table = self.open_table().await?;
+
+        let query = table
+            .vector_search(vector)
+            .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?
+            .distance_type(self.metric.distance_type());
+        let stream = self
+            .tuned(query)
+            .limit(limit + 1)
+            .execute()
+            .await
+            .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?;
+
+        let batches: Vec<RecordBatch> = stream
+            .try_collect::<Vec<RecordBatch>>()
+            .await
+            .map_err(|e: lancedb::Error| Error::vector_db(e.to_string()))?;
+
+        let mut neighbors = Vec::new();
+
+        for batch in batches {
+            let id_col: &Arc<dyn Array> = batch.column_by_name("id")
+                .ok_or_else(|| Error::vector_db("Missing id column"))?;
+            let content_col: &Arc<dyn Array> = batch.column_by_name("content")
+                .ok_or_else(|| Error::vector_db("Missing content column"))?;
+            let distance_col: &Arc<dyn Array> = batch.column_by_name("_distance")
+                .ok_or_else(|| Error::vector_db("Missing _distance column"))?;
+
+            let ids = id_col.as_any().downcast_ref::<StringArray>()
+                .ok_or_else(|| Error::vector_db("id column is not StringArray"))?;
+            let contents = content_col.as_any().downcast_ref::<StringArray>()
+                .ok_or_else(|| Error::vector_db("content column is not StringArray"))?;
             let distances = distance_col.as_any().downcast_ref::<Float32Array>()
                 .ok_or_else(|| Error::vector_db("_distance column is not Float32Array"))?;
 
             for i in 0..batch.num_rows() {
-                let distance = distances.value(i);
-                // LanceDB returns L2 distance, convert to similarity score
-                let score = 1.0 / (1.0 + distance);
-
-                if score >= min_score {
-                    search_results.push(SearchResult {
-                        id: Uuid::parse_str(ids.value(i))
-                            .map_err(|e| Error::vector_db(e.to_string()))?,
-                        content: contents.value(i).to_string(),
-                        scope: scopes.value(i).to_string(),
-                        memory_type: types.value(i).to_string(),
-                        score,
-                    });
+                let neighbor_id = Uuid::parse_str(ids.value(i))
+                    .map_err(|e| Error::vector_db(e.to_string()))?;
+                if neighbor_id == id {
+                    continue;
                 }
+
+                let distance = distances.value(i);
+                neighbors.push(Neighbor {
+                    id: neighbor_id,
+                    content: contents.value(i).to_string(),
+                    distance,
+                    score: score_from_distance(distance, self.metric),
+                });
             }
         }
 
-        Ok(search_results)
+        neighbors.truncate(limit);
+        Ok(Some(neighbors))
+    }
+}
+
+/// Distance metric used by the vector index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DistanceMetric {
+    /// Euclidean distance (LanceDB's default)
+    #[default]
+    L2,
+    /// Cosine distance, `1 - cosine_similarity`
+    Cosine,
+}
+
+impl DistanceMetric {
+    fn distance_type(self) -> lancedb::DistanceType {
+        match self {
+            DistanceMetric::L2 => lancedb::DistanceType::L2,
+            DistanceMetric::Cosine => lancedb::DistanceType::Cosine,
+        }
+    }
+}
+
+impl std::fmt::Display for DistanceMetric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DistanceMetric::L2 => write!(f, "l2"),
+            DistanceMetric::Cosine => write!(f, "cosine"),
+        }
     }
 }
 
+/// Distance between two vectors as LanceDB reports it: squared euclidean for
+/// L2, `1 - cosine similarity` for cosine
+pub fn distance_between(a: &[f32], b: &[f32], metric: DistanceMetric) -> f32 {
+    match metric {
+        DistanceMetric::L2 => a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum(),
+        DistanceMetric::Cosine => {
+            let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+            let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+            let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+            1.0 - dot / (norm_a * norm_b)
+        }
+    }
+}
+
+/// Convert a raw distance into a similarity score in `[0.0, 1.0]`
+pub fn score_from_distance(distance: f32, metric: DistanceMetric) -> f32 {
+    match metric {
+        DistanceMetric::L2 => clamp_score(1.0 / (1.0 + distance)),
+        DistanceMetric::Cosine => clamp_score(1.0 - distance),
+    }
+}
+
+/// Clamp a similarity score into `[0.0, 1.0]`, mapping non-finite values to 0.0
+pub fn clamp_score(score: f32) -> f32 {
+    if score.is_finite() {
+        score.clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+/// Await a search, failing with `Error::Timeout` if it runs longer than `limit`
+async fn with_search_timeout<T>(
+    limit: Option<Duration>,
+    search: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, search)
+            .await
+            .map_err(|_| Error::timeout("search timed out"))?,
+        None => search.await,
+    }
+}
+
+/// Quote a value as a SQL string literal for LanceDB predicates and updates,
+/// doubling any single quotes
+fn sql_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// A nearest neighbor of a stored memory, with its raw distance
+#[derive(Debug, Clone)]
+pub struct Neighbor {
+    pub id: Uuid,
+    pub content: String,
+    pub distance: f32,
+    pub score: f32,
+}
+
+/// Row filters for `VectorStorage::search`; `None` or empty means "any"
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchFilter<'a> {
+    pub scope: Option<&'a str>,
+    pub agent_id: Option<&'a str>,
+    pub topic_id: Option<&'a str>,
+    pub types: &'a [MemoryType],
+}
+
+/// Rows fetched by the first `VectorStorage::search_all` query; later ones double it
+const SEARCH_ALL_FIRST_PAGE: usize = 64;
+
+/// One query's worth of results, with what `search_all` needs to decide whether to go on
+struct SearchPage {
+    results: Vec<SearchResult>,
+    /// Rows the query returned, before the score threshold and chunk dedup
+    rows: usize,
+    /// Whether some row scored under `min_score`; rows come nearest first, so
+    /// a larger limit can't find more matches
+    below_threshold: bool,
+}
+
 /// Result from a vector similarity search
 #[derive(Debug, Clone)]
 pub struct SearchResult {
@@ -271,7 +888,412 @@ pub struct SearchResult {
     pub content: String,
     pub scope: String,
     pub memory_type: String,
+    /// Owning agent, for agent- and topic-scoped memories
+    pub agent_id: Option<String>,
+    /// Owning topic, for topic-scoped memories
+    pub topic_id: Option<String>,
+    /// Memory tags as of the last vector write
+    pub tags: Vec<String>,
+    /// Visibility, owner and active flag as of the last vector write; SQLite
+    /// is authoritative when it is available
+    pub visibility: Visibility,
+    pub owner_id: Option<String>,
+    pub active: bool,
+    /// Similarity in `[0.0, 1.0]`, higher is more similar. Always clamped,
+    /// whatever distance metric produced it.
     pub score: f32,
 }
 
+impl SearchResult {
+    /// Whether a caller may see this memory by the visibility stored with its
+    /// vector, as `Memory::is_visible_to` decides
+    pub fn is_visible_to(&self, caller: Option<&str>) -> bool {
+        match self.visibility {
+            Visibility::Shared => true,
+            Visibility::Private => caller.is_some() && self.owner_id.as_deref() == caller,
+        }
+    }
+}
+
 use futures::TryStreamExt;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, DIMENSIONS};
+
+    async fn storage(dir: &std::path::Path, configure: impl FnOnce(&mut Config)) -> VectorStorage {
+        let config = test_support::config(dir, configure);
+        config.ensure_dirs().unwrap();
+        VectorStorage::new(&config).await.unwrap()
+    }
+
+    /// Store a global fact under `vector`
+    async fn insert(storage: &VectorStorage, content: &str, vector: Vec<f32>) -> Memory {
+        let memory = Memory::global(MemoryType::Fact, content).with_embedding(vector);
+        storage.upsert_memory(&memory).await.unwrap();
+        memory
+    }
+
+    /// Unit vector along dimension `i`, pointing the way of `sign`
+    fn axis(i: usize, sign: f32) -> Vec<f32> {
+        let mut vector = vec![0.0; DIMENSIONS];
+        vector[i] = sign;
+        vector
+    }
+
+    #[test]
+    fn sql_string_escapes_quotes() {
+        assert_eq!(sql_string("topic"), "'topic'");
+        assert_eq!(sql_string("o'brien"), "'o''brien'");
+        assert_eq!(sql_string("x' OR '1'='1"), "'x'' OR ''1''=''1'");
+    }
+
+    #[test]
+    fn clamp_score_bounds_every_input() {
+        assert_eq!(clamp_score(0.42), 0.42);
+        assert_eq!(clamp_score(-0.2), 0.0);
+        assert_eq!(clamp_score(1.3), 1.0);
+        for weird in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert_eq!(clamp_score(weird), 0.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn orthogonal_and_opposite_vectors_score_within_range() {
+        for metric in [DistanceMetric::L2, DistanceMetric::Cosine] {
+            let dir = tempfile::tempdir().unwrap();
+            let storage = storage(dir.path(), |c| c.distance_metric = metric).await;
+            let same = insert(&storage, "same", axis(0, 1.0)).await;
+            let orthogonal = insert(&storage, "orthogonal", axis(1, 1.0)).await;
+            let opposite = insert(&storage, "opposite", axis(0, -1.0)).await;
+
+            let results = storage
+                .search(&axis(0, 1.0), 10, 0.0, &SearchFilter::default())
+                .await
+                .unwrap();
+            assert_eq!(results.len(), 3, "{}", metric);
+            assert!(results.iter().all(|r| (0.0..=1.0).contains(&r.score)), "{}: {:?}", metric, results);
+
+            let score = |id: Uuid| results.iter().find(|r| r.id == id).unwrap().score;
+            assert!((score(same.id) - 1.0).abs() < 1e-5, "{}", metric);
+            assert!(score(orthogonal.id) >= score(opposite.id), "{}", metric);
+            if metric == DistanceMetric::Cosine {
+                // 1 - 2 would be -1 without the clamp
+                assert_eq!(score(opposite.id), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn distances_convert_to_scores_per_metric() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+
+        // L2 distances are squared; the score is 1 / (1 + distance)
+        for (distance, score) in [(0.0, 1.0), (1.0, 0.5), (3.0, 0.25), (-0.5, 1.0)] {
+            assert!(close(score_from_distance(distance, DistanceMetric::L2), score), "{}", distance);
+        }
+        // Cosine distance is 1 - similarity, so opposite vectors floor at zero
+        for (distance, score) in [(0.0, 1.0), (0.25, 0.75), (1.0, 0.0), (2.0, 0.0)] {
+            assert!(close(score_from_distance(distance, DistanceMetric::Cosine), score), "{}", distance);
+        }
+        assert_eq!(score_from_distance(f32::NAN, DistanceMetric::L2), 0.0);
+
+        let (x, y, minus_x, long_x) = ([1.0, 0.0], [0.0, 1.0], [-1.0, 0.0], [3.0, 0.0]);
+        assert!(close(distance_between(&x, &x, DistanceMetric::L2), 0.0));
+        assert!(close(distance_between(&x, &y, DistanceMetric::L2), 2.0));
+        assert!(close(distance_between(&x, &long_x, DistanceMetric::L2), 4.0));
+        assert!(close(distance_between(&x, &y, DistanceMetric::Cosine), 1.0));
+        assert!(close(distance_between(&x, &minus_x, DistanceMetric::Cosine), 2.0));
+        // Cosine ignores length
+        assert!(close(distance_between(&x, &long_x, DistanceMetric::Cosine), 0.0));
+    }
+
+    #[tokio::test]
+    async fn local_scores_agree_with_search() {
+        let embedder = test_support::FakeEmbedder::new(DIMENSIONS);
+        for metric in [DistanceMetric::L2, DistanceMetric::Cosine] {
+            let dir = tempfile::tempdir().unwrap();
+            let storage = storage(dir.path(), |c| c.distance_metric = metric).await;
+            for content in ["deploys run on friday", "tests run nightly", "the cache is warm"] {
+                insert(&storage, content, embedder.vector(content)).await;
+            }
+
+            let query = embedder.vector("when do deploys run");
+            let results = storage.search(&query, 10, 0.0, &SearchFilter::default()).await.unwrap();
+            assert_eq!(results.len(), 3);
+            for result in results {
+                let stored = storage.get_vector(result.id).await.unwrap().unwrap();
+                let expected = score_from_distance(distance_between(&query, &stored, metric), metric);
+                assert!((result.score - expected).abs() < 1e-4, "{}: {} vs {}", metric, result.score, expected);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rows_without_distances_get_the_neutral_score() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.missing_distance_score = 0.4).await;
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        // Two search rows, with a `_distance` column only when `distances` is given
+        let batch = |distances: Option<[f32; 2]>| {
+            let mut fields = vec![
+                Field::new("id", DataType::Utf8, false),
+                Field::new("content", DataType::Utf8, false),
+                Field::new("scope", DataType::Utf8, false),
+                Field::new("memory_type", DataType::Utf8, false),
+                Field::new("agent_id", DataType::Utf8, true),
+                Field::new("topic_id", DataType::Utf8, true),
+            ];
+            let mut columns: Vec<Arc<dyn Array>> = vec![
+                Arc::new(StringArray::from(ids.iter().map(Uuid::to_string).collect::<Vec<_>>())),
+                Arc::new(StringArray::from(vec!["first", "second"])),
+                Arc::new(StringArray::from(vec!["global"; 2])),
+                Arc::new(StringArray::from(vec!["fact"; 2])),
+                Arc::new(StringArray::from(vec![None::<String>; 2])),
+                Arc::new(StringArray::from(vec![None::<String>; 2])),
+            ];
+            if let Some(distances) = distances {
+                fields.push(Field::new("_distance", DataType::Float32, true));
+                columns.push(Arc::new(Float32Array::from(distances.to_vec())));
+            }
+            RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).unwrap()
+        };
+        let scores = |page: &SearchPage| page.results.iter().map(|r| (r.id, r.score)).collect::<Vec<_>>();
+
+        let page = storage.read_page(vec![batch(None)], 0.0).unwrap();
+        assert_eq!(page.rows, 2);
+        assert_eq!(scores(&page), [(ids[0], 0.4), (ids[1], 0.4)]);
+        // The access columns are missing too, and fall back to shared and active
+        assert!(page.results.iter().all(|r| r.active && r.visibility == Visibility::Shared && r.tags.is_empty()));
+
+        // The neutral score still has to clear the threshold
+        let page = storage.read_page(vec![batch(None)], 0.5).unwrap();
+        assert!(page.results.is_empty() && page.below_threshold);
+
+        // Rows that do carry distances are scored from them
+        let page = storage.read_page(vec![batch(Some([0.0, 1.0]))], 0.0).unwrap();
+        assert_eq!(scores(&page), [(ids[0], 1.0), (ids[1], 0.5)]);
+    }
+
+    #[tokio::test]
+    async fn slow_searches_time_out() {
+        let slow = || async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(vec![1])
+        };
+
+        let timed_out = with_search_timeout(Some(Duration::from_millis(20)), slow()).await;
+        assert!(matches!(timed_out, Err(Error::Timeout(message)) if message == "search timed out"));
+        assert_eq!(with_search_timeout(Some(Duration::from_secs(5)), slow()).await.unwrap(), [1]);
+        assert_eq!(with_search_timeout(None, slow()).await.unwrap(), [1]);
+        // Errors from the search itself pass through untouched
+        let failing = async { Err::<(), _>(Error::vector_db("broken")) };
+        assert!(matches!(with_search_timeout(Some(Duration::from_secs(5)), failing).await, Err(Error::VectorDb(_))));
+
+        // A real search finishes well inside the configured limit
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.vector_search_timeout_ms = Some(5000)).await;
+        insert(&storage, "one", axis(0, 1.0)).await;
+        assert_eq!(storage.search(&axis(0, 1.0), 1, 0.0, &SearchFilter::default()).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_deleted_table_is_recreated_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(dir.path(), |_| {});
+        let storage = storage(dir.path(), |_| {}).await;
+        let before = insert(&storage, "before", axis(0, 1.0)).await;
+
+        // Deleted out from under the running store
+        let table_dir = config.vector_db_path().join(format!("{}.lance", config.vector_table_name));
+        std::fs::remove_dir_all(&table_dir).unwrap();
+
+        // The next operation recreates it, empty, and writes go through again
+        assert_eq!(storage.get_vector(before.id).await.unwrap(), None);
+        let after = insert(&storage, "after", axis(1, 1.0)).await;
+        let results = storage.search(&axis(1, 1.0), 10, 0.0, &SearchFilter::default()).await.unwrap();
+        assert_eq!(results.iter().map(|r| r.id).collect::<Vec<_>>(), [after.id]);
+        assert_eq!(storage.table_dimensions().await.unwrap(), Some(DIMENSIONS));
+    }
+
+    #[tokio::test]
+    async fn an_unrecoverable_table_is_a_clear_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(dir.path(), |_| {});
+        let storage = storage(dir.path(), |_| {}).await;
+
+        // A file where the database directory was leaves nowhere to recreate it
+        std::fs::remove_dir_all(config.vector_db_path()).unwrap();
+        std::fs::write(config.vector_db_path(), b"not a directory").unwrap();
+
+        match storage.get_vector(Uuid::new_v4()).await {
+            Err(Error::VectorDb(message)) => assert!(message.contains("Vector table unavailable"), "{}", message),
+            other => panic!("expected a vector error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_with_different_table_names_share_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let alpha = storage(dir.path(), |c| c.vector_table_name = "alpha".to_string()).await;
+        let beta = storage(dir.path(), |c| c.vector_table_name = "beta".to_string()).await;
+
+        let in_alpha = insert(&alpha, "alpha memory", axis(0, 1.0)).await;
+        let in_beta = insert(&beta, "beta memory", axis(0, 1.0)).await;
+
+        let found = |results: Vec<SearchResult>| results.into_iter().map(|r| r.id).collect::<Vec<_>>();
+        let filter = SearchFilter::default();
+        assert_eq!(found(alpha.search(&axis(0, 1.0), 10, 0.0, &filter).await.unwrap()), [in_alpha.id]);
+        assert_eq!(found(beta.search(&axis(0, 1.0), 10, 0.0, &filter).await.unwrap()), [in_beta.id]);
+        assert!(beta.get_vector(in_alpha.id).await.unwrap().is_none());
+
+        // Deleting from one leaves the other alone
+        beta.delete_memories(&[in_alpha.id, in_beta.id]).await.unwrap();
+        assert!(alpha.get_vector(in_alpha.id).await.unwrap().is_some());
+
+        for name in ["", "../escape", "has space"] {
+            let config = test_support::config(dir.path(), |c| c.vector_table_name = name.to_string());
+            assert!(matches!(VectorStorage::new(&config).await, Err(Error::Config(_))), "{:?}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn tags_round_trip_through_upserts_and_updates() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |_| {}).await;
+        let tags = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        let memory = Memory::global(MemoryType::Fact, "deploys run on friday")
+            .with_tags(tags(&["deploy", "o'clock"]))
+            .with_embedding(axis(0, 1.0));
+        storage.upsert_memory(&memory).await.unwrap();
+        let untagged = insert(&storage, "untagged", axis(1, 1.0)).await;
+
+        let search = || async move {
+            storage
+                .search(&axis(0, 1.0), 10, 0.0, &SearchFilter::default())
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.id, r.tags))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(search().await, [(memory.id, tags(&["deploy", "o'clock"])), (untagged.id, vec![])]);
+
+        // Tag edits rewrite the column in place and leave the vector alone
+        storage.update_tags(memory.id, &tags(&["release", "won't fix"])).await.unwrap();
+        assert_eq!(search().await[0], (memory.id, tags(&["release", "won't fix"])));
+        assert_eq!(storage.get_vector(memory.id).await.unwrap(), Some(axis(0, 1.0)));
+        storage.update_tags(memory.id, &[]).await.unwrap();
+        assert!(search().await[0].1.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tuning_settings_trade_latency_for_recall() {
+        use lancedb::index::{vector::IvfPqIndexBuilder, Index};
+
+        /// Share of `queries` whose exact nearest stored vector comes back first
+        async fn recall(storage: &VectorStorage, stored: &[(Uuid, Vec<f32>)], queries: &[Vec<f32>]) -> f32 {
+            let mut hits = 0;
+            for query in queries {
+                let distance = |vector: &[f32]| distance_between(query, vector, DistanceMetric::L2);
+                let nearest = stored
+                    .iter()
+                    .min_by(|a, b| distance(&a.1).total_cmp(&distance(&b.1)))
+                    .unwrap()
+                    .0;
+                let top = storage.search(query, 1, 0.0, &SearchFilter::default()).await.unwrap();
+                hits += usize::from(top.first().map(|r| r.id) == Some(nearest));
+            }
+            hits as f32 / queries.len() as f32
+        }
+
+        // Seeded xorshift, so the vectors are the same every run
+        let mut seed = 0x2545f4914f6cdd1du64;
+        let mut random_vector = || {
+            let vector: Vec<f32> = (0..DIMENSIONS)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    (seed >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+                })
+                .collect();
+            let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+            vector.into_iter().map(|v| v / norm).collect::<Vec<f32>>()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let narrow = storage(dir.path(), |c| {
+            c.search_nprobes = Some(1);
+            c.search_refine_factor = None;
+        })
+        .await;
+        let mut stored = Vec::new();
+        for i in 0..300 {
+            let vector = random_vector();
+            let memory = insert(&narrow, &format!("memory {}", i), vector.clone()).await;
+            stored.push((memory.id, vector));
+        }
+        // The settings only apply once there is an index to probe
+        narrow
+            .open_table()
+            .await
+            .unwrap()
+            .create_index(
+                &["vector"],
+                Index::IvfPq(IvfPqIndexBuilder::default().num_partitions(8).num_sub_vectors(8)),
+            )
+            .execute()
+            .await
+            .unwrap();
+        let wide = storage(dir.path(), |c| {
+            c.search_nprobes = Some(8);
+            c.search_refine_factor = Some(20);
+        })
+        .await;
+
+        let queries: Vec<Vec<f32>> = (0..30).map(|_| random_vector()).collect();
+        let (narrow, wide) = (
+            recall(&narrow, &stored, &queries).await,
+            recall(&wide, &stored, &queries).await,
+        );
+        // Every partition plus exact re-ranking finds (nearly) every true neighbor;
+        // one partition and PQ distances alone miss some
+        assert!(wide >= 0.9, "wide recall {}", wide);
+        assert!(narrow < wide, "narrow recall {} vs wide {}", narrow, wide);
+    }
+
+    #[tokio::test]
+    async fn search_all_returns_every_match_up_to_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), |c| c.distance_metric = DistanceMetric::Cosine).await;
+
+        // More close matches than the first page holds, each tilted a little
+        // off the query axis, plus unrelated memories on other axes
+        let mut close = Vec::new();
+        for i in 0..SEARCH_ALL_FIRST_PAGE + 36 {
+            let mut vector = axis(0, 1.0);
+            vector[1 + i % (DIMENSIONS - 1)] = 0.05 * (1 + i / (DIMENSIONS - 1)) as f32;
+            close.push(insert(&storage, &format!("close {}", i), vector).await.id);
+        }
+        for i in 1..11 {
+            insert(&storage, &format!("far {}", i), axis(i, 1.0)).await;
+        }
+        let filter = SearchFilter::default();
+
+        let all = storage.search_all(&axis(0, 1.0), 0.5, &filter, 1000).await.unwrap();
+        assert_eq!(all.len(), close.len());
+        assert!(all.iter().all(|r| close.contains(&r.id) && r.score >= 0.5));
+
+        // The cap keeps the best matches
+        let capped = storage.search_all(&axis(0, 1.0), 0.5, &filter, 30).await.unwrap();
+        assert_eq!(capped.len(), 30);
+        assert!(capped.windows(2).all(|pair| pair[0].score >= pair[1].score));
+        assert!(capped.iter().all(|r| close.contains(&r.id)));
+
+        // A plain search still stops at its limit
+        assert_eq!(storage.search(&axis(0, 1.0), 10, 0.5, &filter).await.unwrap().len(), 10);
+    }
+}
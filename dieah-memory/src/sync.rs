@@ -0,0 +1,265 @@
+//! Memory definitions kept in a file, for syncing curated memories from version control
+//!
+//! The file is a sequence of entries, each a `---`-fenced header of `key: value`
+//! lines followed by the memory content:
+//!
+//! ```text
+//! ---
+//! key: no-prod-migrations
+//! type: constraint
+//! tags: database, deploys
+//! ---
+//! Never run migrations against production without a fresh backup.
+//! ```
+//!
+//! Recognized header keys are `key`, `type` (required), `scope` (default
+//! `global`), `agent_id`, `topic_id` and `tags` (comma-separated). Content runs
+//! until the next `---` line, so it can't contain one itself.
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::memory::{Memory, MemoryScope, MemoryType};
+
+/// Namespace for the stable ids of synced memories
+const SYNC_NAMESPACE: Uuid = Uuid::from_u128(0x5d1e_a4f0_6c2b_4e8a_9f3d_2b7c_1a0e_8d45);
+
+/// One memory defined in a sync file
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncEntry {
+    /// Explicit identity; without one the memory is identified by its content
+    pub key: Option<String>,
+    pub memory_type: MemoryType,
+    pub scope: MemoryScope,
+    pub agent_id: Option<String>,
+    pub topic_id: Option<String>,
+    pub tags: Vec<String>,
+    pub content: String,
+}
+
+impl SyncEntry {
+    /// Id the entry is stored under, the same on every sync
+    ///
+    /// Keyed entries keep their id when their content changes; unkeyed ones get
+    /// a new id, so an edit replaces the old memory.
+    pub fn stable_id(&self) -> Uuid {
+        let name = match &self.key {
+            Some(key) => format!("key:{}", key),
+            None => format!(
+                "content:{}|{}|{}|{}",
+                self.scope,
+                self.agent_id.as_deref().unwrap_or(""),
+                self.topic_id.as_deref().unwrap_or(""),
+                self.content
+            ),
+        };
+        Uuid::new_v5(&SYNC_NAMESPACE, name.as_bytes())
+    }
+
+    /// Build the memory this entry describes, with its stable id
+    pub fn to_memory(&self) -> Result<Memory> {
+        let content = self.content.clone();
+        let mut memory = match self.scope {
+            MemoryScope::Global => Memory::global(self.memory_type, content),
+            MemoryScope::Personal => Memory::personal(self.memory_type, content),
+            MemoryScope::Agent => {
+                let agent_id = self.agent_id.clone().ok_or_else(|| self.missing("agent_id"))?;
+                Memory::for_agent(agent_id, self.memory_type, content)
+            }
+            MemoryScope::Topic => {
+                let agent_id = self.agent_id.clone().ok_or_else(|| self.missing("agent_id"))?;
+                let topic_id = self.topic_id.clone().ok_or_else(|| self.missing("topic_id"))?;
+                Memory::for_topic(agent_id, topic_id, self.memory_type, content)
+            }
+        };
+        memory.id = self.stable_id();
+        Ok(memory.with_tags(self.tags.clone()))
+    }
+
+    fn missing(&self, field: &str) -> Error {
+        Error::invalid_input(format!("{} memory requires {}: {:?}", self.scope, field, self.content))
+    }
+}
+
+/// Outcome of `MemoryStore::sync_from_file`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncReport {
+    /// Entries stored for the first time
+    pub created: usize,
+    /// Entries whose stored memory changed (content, type, tags, or reactivated)
+    pub updated: usize,
+    /// Entries already stored as written
+    pub unchanged: usize,
+    /// Memories from an earlier sync of the file that it no longer contains
+    pub deactivated: usize,
+}
+
+/// Parse a sync file into its entries
+pub fn parse_memory_file(text: &str) -> Result<Vec<SyncEntry>> {
+    let mut entries = Vec::new();
+    let mut lines = text.lines().peekable();
+
+    // Anything before the first header is ignored, e.g. a title or notes
+    while lines.peek().is_some_and(|l| l.trim() != "---") {
+        lines.next();
+    }
+
+    while lines.next().is_some() {
+        let mut header = Vec::new();
+        loop {
+            match lines.next() {
+                Some(line) if line.trim() == "---" => break,
+                Some(line) => header.push(line),
+                None => return Err(Error::invalid_input("Unterminated memory header")),
+            }
+        }
+
+        let mut content = Vec::new();
+        while let Some(line) = lines.next_if(|l| l.trim() != "---") {
+            content.push(line);
+        }
+
+        entries.push(parse_entry(&header, content.join("\n").trim())?);
+    }
+
+    Ok(entries)
+}
+
+fn parse_entry(header: &[&str], content: &str) -> Result<SyncEntry> {
+    if content.is_empty() {
+        return Err(Error::invalid_input("Memory entry has no content"));
+    }
+
+    let mut key = None;
+    let mut memory_type = None;
+    let mut scope = MemoryScope::Global;
+    let mut agent_id = None;
+    let mut topic_id = None;
+    let mut tags = Vec::new();
+
+    for line in header.iter().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| Error::invalid_input(format!("Invalid header line: {:?}", line)))?;
+        let value = value.trim();
+        match name.trim() {
+            "key" => key = Some(value.to_string()),
+            "type" => memory_type = Some(value.parse()?),
+            "scope" => scope = value.parse()?,
+            "agent_id" => agent_id = Some(value.to_string()),
+            "topic_id" => topic_id = Some(value.to_string()),
+            "tags" => {
+                tags = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            other => return Err(Error::invalid_input(format!("Unknown header key: {}", other))),
+        }
+    }
+
+    Ok(SyncEntry {
+        key,
+        memory_type: memory_type.ok_or_else(|| Error::invalid_input("Memory entry has no type"))?,
+        scope,
+        agent_id,
+        topic_id,
+        tags,
+        content: content.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_entries_with_headers_and_content() {
+        let text = "\
+# Team memories
+
+---
+key: no-prod-migrations
+type: constraint
+tags: database, , deploys
+---
+Never run migrations against production
+without a fresh backup.
+
+---
+# comments and blank lines are skipped
+
+type: fact
+scope: topic
+agent_id: asimov
+topic_id: robots
+---
+The three laws apply.
+";
+        let entries = parse_memory_file(text).unwrap();
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].key.as_deref(), Some("no-prod-migrations"));
+        assert_eq!(entries[0].memory_type, MemoryType::Constraint);
+        assert_eq!(entries[0].scope, MemoryScope::Global);
+        assert_eq!(entries[0].tags, ["database", "deploys"]);
+        assert_eq!(
+            entries[0].content,
+            "Never run migrations against production\nwithout a fresh backup."
+        );
+
+        assert_eq!(entries[1].key, None);
+        assert_eq!(entries[1].scope, MemoryScope::Topic);
+        assert_eq!(entries[1].agent_id.as_deref(), Some("asimov"));
+        assert_eq!(entries[1].topic_id.as_deref(), Some("robots"));
+        assert_eq!(entries[1].content, "The three laws apply.");
+    }
+
+    #[test]
+    fn empty_file_has_no_entries() {
+        assert!(parse_memory_file("").unwrap().is_empty());
+        assert!(parse_memory_file("just notes\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        for text in [
+            "---\ntype: fact\n",
+            "---\ntype: fact\n---\n",
+            "---\nkey: k\n---\ncontent",
+            "---\ntype: opinion\n---\ncontent",
+            "---\ntype: fact\nscope: world\n---\ncontent",
+            "---\ntype: fact\ncolour: red\n---\ncontent",
+            "---\ntype fact\n---\ncontent",
+        ] {
+            let err = parse_memory_file(text).unwrap_err();
+            assert!(matches!(err, Error::InvalidInput(_)), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn scoped_entries_require_their_ids() {
+        let entries = parse_memory_file("---\ntype: fact\nscope: agent\n---\ncontent").unwrap();
+        assert!(entries[0].to_memory().is_err());
+
+        let entries = parse_memory_file("---\ntype: fact\nscope: agent\nagent_id: asimov\n---\ncontent").unwrap();
+        let memory = entries[0].to_memory().unwrap();
+        assert_eq!(memory.agent_id.as_deref(), Some("asimov"));
+        assert_eq!(memory.id, entries[0].stable_id());
+    }
+
+    #[test]
+    fn stable_ids_follow_key_or_content() {
+        let keyed = parse_memory_file("---\nkey: k\ntype: fact\n---\nold").unwrap().remove(0);
+        let edited = SyncEntry { content: "new".to_string(), ..keyed.clone() };
+        assert_eq!(keyed.stable_id(), edited.stable_id());
+
+        let unkeyed = SyncEntry { key: None, ..keyed.clone() };
+        let unkeyed_edit = SyncEntry { key: None, ..edited };
+        assert_ne!(unkeyed.stable_id(), unkeyed_edit.stable_id());
+        assert_ne!(unkeyed.stable_id(), keyed.stable_id());
+    }
+}
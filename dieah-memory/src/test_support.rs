@@ -0,0 +1,116 @@
+//! Helpers shared by the unit tests
+
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::embedding::{EmbeddingBackend, EmbeddingService};
+use crate::error::{Error, Result};
+use crate::memory::MemoryStore;
+use crate::retrieval::RetrievalEngine;
+
+/// Vector size produced by `FakeEmbedder` and configured by `config`
+pub const DIMENSIONS: usize = 64;
+
+/// Config for a store under `dir`, adjusted by `configure`
+///
+/// Embeddings are `DIMENSIONS` wide and not probed, to match `FakeEmbedder`.
+pub fn config(dir: &Path, configure: impl FnOnce(&mut Config)) -> Config {
+    let mut config = Config::with_data_dir(dir);
+    config.embedding_dimensions = DIMENSIONS;
+    config.detect_embedding_dimensions = false;
+    configure(&mut config);
+    config
+}
+
+/// A store under `dir`, adjusted by `configure`
+pub async fn store(dir: &Path, configure: impl FnOnce(&mut Config)) -> MemoryStore {
+    MemoryStore::new(config(dir, configure)).await.unwrap()
+}
+
+/// A store under `dir` and a `FakeEmbedder` engine over it, adjusted by `configure`
+pub async fn setup(dir: &Path, configure: impl FnOnce(&mut Config)) -> (MemoryStore, RetrievalEngine) {
+    let engine = engine(&config(dir, configure));
+    let store = MemoryStore::new(engine.config().clone()).await.unwrap();
+    (store, engine)
+}
+
+/// A retrieval engine for `config` backed by `FakeEmbedder`
+pub fn engine(config: &Config) -> RetrievalEngine {
+    engine_with(config, FakeEmbedder::new(config.embedding_dimensions))
+}
+
+/// A retrieval engine for `config` backed by `embedder`
+pub fn engine_with(config: &Config, embedder: FakeEmbedder) -> RetrievalEngine {
+    let service = EmbeddingService::with_backend(config, embedder).unwrap();
+    RetrievalEngine::with_embedding_service(config.clone(), service).unwrap()
+}
+
+/// Deterministic stand-in for the embedding model
+///
+/// Each text becomes a normalized bag of hashed words, so texts sharing words
+/// score close and unrelated texts score near zero.
+#[derive(Clone, Default)]
+pub struct FakeEmbedder {
+    /// Size of the vectors returned
+    pub dimensions: usize,
+    /// Sleep this long in every call
+    pub delay: Option<Duration>,
+    /// Fail every call
+    pub fail: bool,
+    /// Number of calls made
+    pub calls: Arc<AtomicUsize>,
+    /// Every text passed in, in call order
+    pub inputs: Arc<Mutex<Vec<String>>>,
+    /// Calls currently running, and the most that ever ran at once
+    pub running: Arc<AtomicUsize>,
+    pub peak: Arc<AtomicUsize>,
+}
+
+impl FakeEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        Self {
+            dimensions,
+            ..Default::default()
+        }
+    }
+
+    /// Embedding of one text
+    pub fn vector(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dimensions];
+        for word in text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+            // FNV-1a, so buckets don't change between runs
+            let hash = word
+                .to_lowercase()
+                .bytes()
+                .fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+            vector[(hash % self.dimensions as u64) as usize] += 1.0;
+        }
+        if vector.iter().all(|v| *v == 0.0) {
+            vector[0] = 1.0;
+        }
+
+        let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+        vector.iter().map(|v| v / norm).collect()
+    }
+}
+
+impl EmbeddingBackend for FakeEmbedder {
+    fn embed(&mut self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.inputs.lock().unwrap().extend(texts.iter().cloned());
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(running, Ordering::SeqCst);
+        if let Some(delay) = self.delay {
+            std::thread::sleep(delay);
+        }
+        self.running.fetch_sub(1, Ordering::SeqCst);
+
+        if self.fail {
+            return Err(Error::embedding("Fake embedder failure"));
+        }
+        Ok(texts.iter().map(|text| self.vector(text)).collect())
+    }
+}
@@ -0,0 +1,81 @@
+//! Runs `dieah-memory-cli` against a temporary store
+//!
+//! Only subcommands that don't load the embedding model are covered, so these
+//! tests don't need the model files.
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+use dieah_memory::config::Config;
+use dieah_memory::memory::{Memory, MemoryStore, MemoryType};
+
+fn cli(data_dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dieah-memory-cli"))
+        .arg("--data-dir")
+        .arg(data_dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Stdout of a run that must have succeeded
+fn stdout(output: Output) -> String {
+    assert!(
+        output.status.success(),
+        "cli failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// A global and an agent memory, saved without embeddings
+async fn seed(data_dir: &Path) -> (Memory, Memory) {
+    let store = MemoryStore::new(Config::with_data_dir(data_dir)).await.unwrap();
+    let global = store
+        .save_memory(Memory::global(MemoryType::Fact, "The build uses cargo"))
+        .await
+        .unwrap();
+    let agent = store
+        .save_memory(Memory::for_agent("coder", MemoryType::Preference, "Prefer small commits"))
+        .await
+        .unwrap();
+    (global, agent)
+}
+
+#[tokio::test]
+async fn list_and_stats_show_the_stored_memories() {
+    let dir = tempfile::tempdir().unwrap();
+    let (global, agent) = seed(dir.path()).await;
+
+    let listed = stdout(cli(dir.path(), &["list"]));
+    assert_eq!(listed.lines().count(), 2);
+    assert!(listed.contains(&format!("{}\tglobal\tfact\tactive\tThe build uses cargo", global.id)));
+
+    let agents_only = stdout(cli(dir.path(), &["list", "--scope", "agent"]));
+    assert_eq!(agents_only.lines().count(), 1);
+    assert!(agents_only.starts_with(&agent.id.to_string()));
+
+    let stats = stdout(cli(dir.path(), &["stats"]));
+    assert!(stats.lines().any(|line| line == "memories\t2"));
+    assert!(stats.lines().any(|line| line == "scope.global\t1"));
+    assert!(stats.lines().any(|line| line == "scope.agent\t1"));
+}
+
+#[tokio::test]
+async fn delete_removes_a_memory_from_later_exports() {
+    let dir = tempfile::tempdir().unwrap();
+    let (global, agent) = seed(dir.path()).await;
+
+    let deleted = stdout(cli(dir.path(), &["delete", &global.id.to_string()]));
+    assert_eq!(deleted.trim(), format!("deleted {}", global.id));
+
+    let out = dir.path().join("export.json");
+    stdout(cli(dir.path(), &["export", "--out", out.to_str().unwrap()]));
+    let exported: Vec<Memory> = serde_json::from_str(&std::fs::read_to_string(&out).unwrap()).unwrap();
+    assert_eq!(exported.iter().map(|m| m.id).collect::<Vec<_>>(), [agent.id]);
+
+    // Deleting it again fails instead of reporting success
+    let again = cli(dir.path(), &["delete", &global.id.to_string()]);
+    assert!(!again.status.success());
+    assert!(String::from_utf8_lossy(&again.stderr).contains("not found"));
+}